@@ -0,0 +1,101 @@
+use super::{dataset_shape, dataset_table_name, ip_form_vec8, DatasetShape, SqliteDatasetManager};
+use rusqlite::types::Value;
+use std::collections::BTreeMap;
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Result of `SqliteDatasetManager::audit_ip_encodings`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditReport {
+    /// Counts of each IP-keyed dataset by table name
+    pub datasets: BTreeMap<String, IpEncodingCounts>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpEncodingCounts {
+    /// 4 or 16 bytes blobs, the encoding used by the manager
+    pub valid: usize,
+    /// Keys that can not be decoded as an IP
+    pub invalid: usize,
+    /// IPs stored as text by old versions. They are readable but do not match the blob lookups
+    pub ambiguous: usize,
+}
+
+impl AuditReport {
+    /// True if any dataset has keys that are not encoded as the manager expects
+    pub fn needs_migration(&self) -> bool {
+        self.datasets
+            .values()
+            .any(|counts| counts.invalid > 0 || counts.ambiguous > 0)
+    }
+}
+
+fn is_ip_keyed(dataset_type: &SiemDatasetType) -> bool {
+    matches!(
+        dataset_shape(dataset_type),
+        Some(DatasetShape::IpSet)
+            | Some(DatasetShape::IpMap)
+            | Some(DatasetShape::IpMapList)
+            | Some(DatasetShape::IpNet)
+            | Some(DatasetShape::GeoIp)
+    )
+}
+
+impl SqliteDatasetManager {
+    /// Decodes the keys of all the IP-keyed tables, the built-in datasets and the registered
+    /// custom datasets, and counts how they are encoded. Nothing is modified.
+    pub fn audit_ip_encodings(&self) -> Result<AuditReport, String> {
+        let mut dataset_types = vec![
+            SiemDatasetType::BlockIp,
+            SiemDatasetType::IpMac,
+            SiemDatasetType::IpDNS,
+            SiemDatasetType::IpCloudService,
+            SiemDatasetType::IpCloudProvider,
+            SiemDatasetType::IpHeadquarters,
+            SiemDatasetType::GeoIp,
+        ];
+        for dataset_type in self.registered_datasets.keys() {
+            if is_ip_keyed(dataset_type) && !dataset_types.contains(dataset_type) {
+                dataset_types.push(dataset_type.clone());
+            }
+        }
+        let mut report = AuditReport::default();
+        for dataset_type in dataset_types {
+            let name = match dataset_table_name(&dataset_type) {
+                Some(name) => name,
+                None => continue,
+            };
+            if !self.tables_exist(&dataset_type) {
+                continue;
+            }
+            let mut stmt = match self
+                .conn
+                .prepare(&format!("SELECT data_key FROM dataset_{}", name))
+            {
+                Ok(stmt) => stmt,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let rows = match stmt.query_map([], |row| row.get::<_, Value>(0)) {
+                Ok(rows) => rows,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let mut counts = IpEncodingCounts::default();
+            for row in rows {
+                match row {
+                    Ok(Value::Blob(key)) => match ip_form_vec8(&key) {
+                        Ok(_) => counts.valid += 1,
+                        Err(_) => counts.invalid += 1,
+                    },
+                    Ok(Value::Text(key)) => match SiemIp::from_ip_str(&key) {
+                        Ok(_) => counts.ambiguous += 1,
+                        Err(_) => counts.invalid += 1,
+                    },
+                    Ok(_) => counts.invalid += 1,
+                    Err(e) => return Err(format!("{}", e)),
+                }
+            }
+            report.datasets.insert(name, counts);
+        }
+        Ok(report)
+    }
+}
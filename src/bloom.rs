@@ -0,0 +1,164 @@
+use super::query::value_to_string;
+use super::{dataset_shape, dataset_table_name, DatasetShape, SqliteDatasetManager};
+use usiem::components::dataset::SiemDatasetType;
+
+/// Serialized Bloom filter:
+///
+/// `"USBF" | hashes: u8 | bits: u64 LE | bit array`
+///
+/// Keys are hashed in their text form, the same used by `Entry::key`: IPs as text and networks
+/// as IP/prefix. The hashes are FNV-1a 64 combined with double hashing so the filter can be
+/// checked by any implementation with the same parameters.
+const BLOOM_MAGIC: &[u8; 4] = b"USBF";
+const BLOOM_HEADER_LEN: usize = 13;
+
+/// Probabilistic set of the keys of a dataset.
+///
+/// `contains` never returns false for a key that was in the dataset when the filter was
+/// exported, but it can return true for a key that was not in the dataset with a probability
+/// close to the false positive rate used to build it.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    bit_count: u64,
+    hashes: u8,
+}
+
+pub(crate) fn fnv1a(data: &[u8], seed: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `entries` keys with a false positive rate of `fp_rate`
+    pub fn new(entries: usize, fp_rate: f64) -> Result<BloomFilter, String> {
+        if !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(format!(
+                "Invalid false positive rate: {}. Must be between 0 and 1",
+                fp_rate
+            ));
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let entries = entries.max(1) as f64;
+        let bit_count = (-(entries * fp_rate.ln()) / (ln2 * ln2)).ceil().max(8.0) as u64;
+        let hashes = ((bit_count as f64 / entries) * ln2).round().clamp(1.0, 255.0) as u8;
+        Ok(BloomFilter {
+            bits: vec![0; bit_count.div_ceil(8) as usize],
+            bit_count,
+            hashes,
+        })
+    }
+
+    /// Loads a filter created with `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Result<BloomFilter, String> {
+        if data.len() < BLOOM_HEADER_LEN || &data[0..4] != BLOOM_MAGIC {
+            return Err(String::from("Not a serialized Bloom filter"));
+        }
+        let hashes = data[4];
+        let mut bit_count = [0u8; 8];
+        bit_count.copy_from_slice(&data[5..13]);
+        let bit_count = u64::from_le_bytes(bit_count);
+        let bits = &data[BLOOM_HEADER_LEN..];
+        if hashes == 0 || bit_count == 0 || (bits.len() as u64) != bit_count.div_ceil(8) {
+            return Err(String::from("Invalid Bloom filter size"));
+        }
+        Ok(BloomFilter {
+            bits: bits.to_vec(),
+            bit_count,
+            hashes,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BLOOM_HEADER_LEN + self.bits.len());
+        data.extend_from_slice(BLOOM_MAGIC);
+        data.push(self.hashes);
+        data.extend_from_slice(&self.bit_count.to_le_bytes());
+        data.extend_from_slice(&self.bits);
+        data
+    }
+
+    fn positions<'a>(&'a self, key: &str) -> impl Iterator<Item = u64> + 'a {
+        let h1 = fnv1a(key.as_bytes(), 0);
+        let h2 = fnv1a(key.as_bytes(), h1) | 1;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.bit_count)
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        let positions: Vec<u64> = self.positions(key).collect();
+        for pos in positions {
+            self.bits[(pos / 8) as usize] |= 1 << (pos % 8);
+        }
+    }
+
+    /// False means that the key is not in the filter. True means that it probably is.
+    pub fn contains(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|pos| self.bits[(pos / 8) as usize] & (1 << (pos % 8)) != 0)
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Builds a Bloom filter with the keys of a dataset, for components that only need to check
+    /// membership and can tolerate false positives. `fp_rate` is the expected probability of a
+    /// key not in the dataset testing positive. Load the result with `BloomFilter::from_bytes`.
+    pub fn export_bloom(
+        &self,
+        dataset_type: &SiemDatasetType,
+        fp_rate: f64,
+    ) -> Result<Vec<u8>, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let columns = match shape {
+            DatasetShape::IpNet | DatasetShape::GeoIp => "data_key, network",
+            _ => "data_key",
+        };
+        let count: i64 = match self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM dataset_{}", name),
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(count) => count,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut filter = BloomFilter::new(count as usize, fp_rate)?;
+        let mut stmt = match self.conn.prepare(&format!(
+            "SELECT {columns} FROM dataset_{dataset_name}",
+            columns = columns,
+            dataset_name = name
+        )) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut rows = match stmt.query([]) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let key = match row.get(0) {
+                Ok(key) => value_to_string(key),
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let key = match shape {
+                DatasetShape::IpNet | DatasetShape::GeoIp => match row.get::<_, i64>(1) {
+                    Ok(network) => format!("{}/{}", key, network),
+                    Err(e) => return Err(format!("{}", e)),
+                },
+                _ => key,
+            };
+            filter.insert(&key);
+        }
+        Ok(filter.to_bytes())
+    }
+}
@@ -0,0 +1,174 @@
+use super::{pool, ConnectionOptions, SqliteDatasetManager};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the shared in-memory databases so each manager gets its own
+static SHARED_MEMORY_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Options of a new `SqliteDatasetManager`. `SqliteDatasetManager::new` and `debug` are shortcuts for
+/// a builder with a path or in memory and the rest of the defaults.
+#[derive(Debug, Clone)]
+pub struct SqliteDatasetManagerBuilder {
+    path: Option<String>,
+    in_memory: bool,
+    shared_memory: bool,
+    read_only: bool,
+    rebuild_interval_ms: Option<i64>,
+    external_reload_ms: Option<i64>,
+    channel_capacity: Option<usize>,
+    options: ConnectionOptions,
+}
+
+impl Default for SqliteDatasetManagerBuilder {
+    fn default() -> SqliteDatasetManagerBuilder {
+        SqliteDatasetManagerBuilder::new()
+    }
+}
+
+impl SqliteDatasetManagerBuilder {
+    pub fn new() -> SqliteDatasetManagerBuilder {
+        SqliteDatasetManagerBuilder {
+            path: None,
+            in_memory: false,
+            shared_memory: false,
+            read_only: false,
+            rebuild_interval_ms: None,
+            external_reload_ms: None,
+            channel_capacity: None,
+            options: ConnectionOptions::default(),
+        }
+    }
+
+    /// File of the database, created if it doesn't exist
+    pub fn path<S: Into<String>>(mut self, path: S) -> SqliteDatasetManagerBuilder {
+        self.path = Some(path.into());
+        self.in_memory = false;
+        self.shared_memory = false;
+        self
+    }
+
+    /// Uses an in-memory database instead of a file. It isn't migrated, like `SqliteDatasetManager::debug`
+    pub fn in_memory(mut self) -> SqliteDatasetManagerBuilder {
+        self.path = None;
+        self.in_memory = true;
+        self.shared_memory = false;
+        self
+    }
+
+    /// In-memory database that other connections can open with `SqliteDatasetManager::memory_uri`, like
+    /// the ones of `read_pool`. Each manager gets its own database, alive until the manager is dropped
+    pub fn shared_memory(mut self) -> SqliteDatasetManagerBuilder {
+        self.path = None;
+        self.in_memory = true;
+        self.shared_memory = true;
+        self
+    }
+
+    /// Opens the file of another process without writing to it. The updates of the datasets are ignored and
+    /// logged, and the run loop reloads the registered datasets from the file every rebuild interval.
+    /// The tables must exist and the migrations are not run.
+    pub fn read_only(mut self, enabled: bool) -> SqliteDatasetManagerBuilder {
+        self.read_only = enabled;
+        self
+    }
+
+    /// See `SqliteDatasetManager::set_rebuild_interval`
+    pub fn rebuild_interval_ms(mut self, interval_ms: i64) -> SqliteDatasetManagerBuilder {
+        self.rebuild_interval_ms = Some(interval_ms);
+        self
+    }
+
+    /// See `SqliteDatasetManager::set_external_reload`
+    pub fn external_reload_ms(mut self, interval_ms: i64) -> SqliteDatasetManagerBuilder {
+        self.external_reload_ms = Some(interval_ms);
+        self
+    }
+
+    /// Updates that the channel of each dataset holds. Defaults to 128. Once full the components block
+    /// sending, or `try_send` fails, until the next flush drains the channel. Datasets can override it
+    /// with `DatasetOptions::channel_capacity`
+    pub fn channel_capacity(mut self, capacity: usize) -> SqliteDatasetManagerBuilder {
+        self.channel_capacity = Some(capacity);
+        self
+    }
+
+    pub fn busy_timeout(mut self, timeout: std::time::Duration) -> SqliteDatasetManagerBuilder {
+        self.options.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// WAL journal, enabled by default. Disabling it uses the DELETE journal mode
+    pub fn wal(mut self, enabled: bool) -> SqliteDatasetManagerBuilder {
+        self.options.journal_mode = Some(String::from(if enabled { "WAL" } else { "DELETE" }));
+        self
+    }
+
+    /// Replaces all the pragmas run when the connection is opened
+    pub fn connection_options(mut self, options: ConnectionOptions) -> SqliteDatasetManagerBuilder {
+        self.options = options;
+        self
+    }
+
+    /// Opens the database, runs the pragmas and, for writable files, the pending migrations
+    pub fn build(self) -> Result<SqliteDatasetManager, String> {
+        if self.channel_capacity == Some(0) {
+            return Err(String::from("Invalid channel capacity: 0"));
+        }
+        if self.read_only && self.in_memory {
+            return Err(String::from("An in-memory database can't be read-only"));
+        }
+        let memory_uri = if self.shared_memory {
+            Some(format!(
+                "file:usiem_dm_sqlite_{}_{}?mode=memory&cache=shared",
+                std::process::id(),
+                SHARED_MEMORY_ID.fetch_add(1, Ordering::Relaxed)
+            ))
+        } else {
+            None
+        };
+        let connections = match (&self.path, self.in_memory) {
+            (_, true) => match &memory_uri {
+                Some(uri) => SqliteConnectionManager::file(uri)
+                    .with_flags(OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI),
+                None => SqliteConnectionManager::memory(),
+            },
+            (Some(path), false) => pool::file_connections(path, self.read_only),
+            (None, false) => return Err(String::from("No path or in-memory database set")),
+        };
+        let (pool, conn) = pool::manager_connection(connections)?;
+        let mut options = self.options;
+        if self.read_only {
+            // Changing the journal mode writes the database, the one of the file is kept
+            options.journal_mode = None;
+        }
+        options.apply(&conn)?;
+        // The database is dropped with its last connection, this one outlives the manager connection
+        // when it's reopened
+        let keep_alive = match &memory_uri {
+            Some(uri) => match Connection::open_with_flags(uri, OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI) {
+                Ok(conn) => Some(conn),
+                Err(e) => return Err(format!("{}", e)),
+            },
+            None => None,
+        };
+        let mut manager = SqliteDatasetManager::from_connection(pool, conn);
+        manager.read_only = self.read_only;
+        manager.connection_options = options;
+        manager.memory_uri = memory_uri;
+        manager.keep_alive = keep_alive;
+        if let Some(interval_ms) = self.rebuild_interval_ms {
+            manager.set_rebuild_interval(interval_ms)?;
+        }
+        if let Some(interval_ms) = self.external_reload_ms {
+            manager.set_external_reload(Some(interval_ms))?;
+        }
+        if let Some(capacity) = self.channel_capacity {
+            manager.channel_capacity = capacity;
+        }
+        if !self.in_memory && !self.read_only {
+            manager.migrate()?;
+        }
+        Ok(manager)
+    }
+}
@@ -0,0 +1,166 @@
+use super::domains::{is_domain_table, normalize_domain};
+use super::{
+    dataset_shape, dataset_table_name, ip_to_vec8, DatasetShape, SqlFilter, SqliteDatasetManager,
+};
+use log::error;
+use rusqlite::types::Value;
+use rusqlite::OptionalExtension;
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Shapes that can keep only part of their entries in memory
+pub(crate) fn supports_cache_size(dataset_type: &SiemDatasetType) -> bool {
+    matches!(
+        dataset_shape(dataset_type),
+        Some(DatasetShape::TextMap)
+            | Some(DatasetShape::TextSet)
+            | Some(DatasetShape::IpMap)
+            | Some(DatasetShape::IpSet)
+    )
+}
+
+/// Key as stored in the data_key column of the dataset
+pub(crate) fn key_value(dataset_type: &SiemDatasetType, key: &str) -> Result<Value, String> {
+    match dataset_shape(dataset_type) {
+        Some(DatasetShape::IpMap) | Some(DatasetShape::IpSet) => match SiemIp::from_ip_str(key) {
+            Ok(ip) => Ok(Value::Blob(ip_to_vec8(&ip))),
+            Err(_) => Err(format!("Invalid IP: {}", key)),
+        },
+        _ => match dataset_table_name(dataset_type) {
+            Some(name) if is_domain_table(&name) => Ok(Value::Text(normalize_domain(key))),
+            _ => Ok(Value::Text(key.to_string())),
+        },
+    }
+}
+
+/// Adds a condition to a filter. The parameters of the condition go after the ones of the filter
+fn and_filter(filter: Option<&SqlFilter>, condition: &str, params: Vec<Value>) -> SqlFilter {
+    match filter {
+        Some(filter) => {
+            let mut all_params = filter.params.clone();
+            all_params.extend(params);
+            SqlFilter::new(&format!("({}) AND {}", filter.condition, condition), all_params)
+        }
+        None => SqlFilter::new(condition, params),
+    }
+}
+
+impl SqliteDatasetManager {
+    fn create_hits_table(&self, name: &str) -> Result<(), String> {
+        match self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_hits_{dataset_name} (data_key NOT NULL PRIMARY KEY, hits INTEGER NOT NULL)", dataset_name = name), []) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Filter used to load a dataset: the `load_filter` of its options, only the networks of the
+    /// `geoip_countries` and, with a `cache_size`, only the entries most looked up with `lookup`.
+    pub(crate) fn effective_load_filter(&self, dataset_type: &SiemDatasetType) -> Option<SqlFilter> {
+        let options = self.dataset_options.get(dataset_type)?;
+        // Validated when the dataset is registered
+        let mut load_filter = match options.load_filter.as_ref().map(|filter| filter.to_sql(1)) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(e)) => {
+                error!("Ignoring the load filter of {:?}: {}", dataset_type, e);
+                None
+            }
+            None => None,
+        };
+        if let Some(countries) = &options.geoip_countries {
+            let first = load_filter.as_ref().map(|f| f.params.len()).unwrap_or(0) + 1;
+            let placeholders: Vec<String> = (first..first + countries.len())
+                .map(|pos| format!("?{}", pos))
+                .collect();
+            let condition = format!("country IN ({})", placeholders.join(", "));
+            let params = countries.iter().map(|c| Value::Text(c.clone()));
+            load_filter = Some(and_filter(load_filter.as_ref(), &condition, params.collect()));
+        }
+        let cache_size = match options.cache_size {
+            Some(cache_size) => cache_size,
+            None => return load_filter,
+        };
+        let name = dataset_table_name(dataset_type)?;
+        if let Err(e) = self.create_hits_table(&name) {
+            error!("Cannot create the hits table of {:?}: {}", dataset_type, e);
+        }
+        let condition = format!(
+            "data_key IN (SELECT data_key FROM dataset_hits_{dataset_name} ORDER BY hits DESC LIMIT {limit})",
+            dataset_name = name,
+            limit = cache_size
+        );
+        Some(and_filter(load_filter.as_ref(), &condition, Vec::new()))
+    }
+
+    /// Gets the value of a key counting the access. Datasets registered with a `cache_size` only hold the
+    /// entries most looked up in memory (refreshed on each rebuild), the misses are read from SQLite.
+    /// For sets the returned value is the key itself.
+    pub fn lookup(&self, dataset_type: &SiemDatasetType, key: &str) -> Result<Option<String>, String> {
+        if !supports_cache_size(dataset_type) {
+            return Err(format!("Lookups not supported for dataset {:?}", dataset_type));
+        }
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let normalized;
+        let key = if is_domain_table(&name) {
+            normalized = normalize_domain(key);
+            &normalized
+        } else {
+            key
+        };
+        let cached = self
+            .dataset_options
+            .get(dataset_type)
+            .map(|options| options.cache_size.is_some())
+            .unwrap_or(false);
+        let data_key = key_value(dataset_type, key)?;
+        if cached {
+            self.create_hits_table(&name)?;
+            let res = self
+                .conn
+                .execute(
+                    &format!("INSERT OR IGNORE INTO dataset_hits_{dataset_name} (data_key, hits) VALUES (?1, 0)", dataset_name = name),
+                    [&data_key],
+                )
+                .and_then(|_| {
+                    self.conn.execute(
+                        &format!("UPDATE dataset_hits_{dataset_name} SET hits = hits + 1 WHERE data_key = ?1", dataset_name = name),
+                        [&data_key],
+                    )
+                });
+            if let Err(e) = res {
+                return Err(format!("{}", e));
+            }
+        }
+        if let Some(value) = self.local_value(dataset_type, key) {
+            return Ok(Some(value));
+        }
+        if !cached {
+            return Ok(None);
+        }
+        let is_set = matches!(
+            dataset_shape(dataset_type),
+            Some(DatasetShape::TextSet) | Some(DatasetShape::IpSet)
+        );
+        let column = if is_set { "NULL" } else { "data_val" };
+        let res: Result<Option<Option<String>>, _> = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {column} FROM dataset_{dataset_name} WHERE data_key = ?1",
+                    column = column,
+                    dataset_name = name
+                ),
+                [&data_key],
+                |row| row.get(0),
+            )
+            .optional();
+        match res {
+            Ok(Some(_)) if is_set => Ok(Some(key.to_string())),
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Ok(None),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
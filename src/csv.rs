@@ -0,0 +1,233 @@
+use super::cache::key_value;
+use super::query::value_to_string;
+use super::{dataset_shape, dataset_table_name, DatasetShape, ListEncoding, SqliteDatasetManager};
+use rusqlite::params;
+use std::io::{Read, Write};
+use usiem::components::dataset::SiemDatasetType;
+
+/// Separator of the values of map-list datasets inside their CSV column
+const LIST_SEPARATOR: &str = "|";
+
+/// Header of the CSV of each shape
+fn csv_header(shape: DatasetShape) -> &'static [&'static str] {
+    match shape {
+        DatasetShape::TextSet | DatasetShape::IpSet => &["key"],
+        DatasetShape::TextMap | DatasetShape::IpMap => &["key", "value"],
+        DatasetShape::TextMapList | DatasetShape::IpMapList => &["key", "values"],
+        DatasetShape::IpNet => &["ip", "network", "value"],
+        DatasetShape::GeoIp => &[
+            "ip",
+            "network",
+            "country",
+            "city",
+            "latitude",
+            "longitude",
+            "isp",
+        ],
+    }
+}
+
+/// Query returning the columns of the CSV of each shape
+fn csv_sql(shape: DatasetShape, name: &str) -> String {
+    match shape {
+        DatasetShape::TextSet | DatasetShape::IpSet => {
+            format!("SELECT data_key FROM dataset_{} ORDER BY id", name)
+        }
+        DatasetShape::TextMap | DatasetShape::IpMap => {
+            format!("SELECT data_key, data_val FROM dataset_{} ORDER BY id", name)
+        }
+        DatasetShape::TextMapList | DatasetShape::IpMapList => format!(
+            "SELECT t1.data_key, COALESCE(group_concat(t2.data_val, '{separator}'), '') FROM dataset_{dataset_name} as t1 LEFT JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key GROUP BY t1.id ORDER BY t1.id",
+            separator = LIST_SEPARATOR,
+            dataset_name = name
+        ),
+        DatasetShape::IpNet => format!(
+            "SELECT data_key, network, data_val FROM dataset_{} ORDER BY id",
+            name
+        ),
+        DatasetShape::GeoIp => format!(
+            "SELECT data_key, network, country, city, latitude, longitude, isp FROM dataset_{} ORDER BY id",
+            name
+        ),
+    }
+}
+
+/// Quotes a field if it contains a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_record(writer: &mut dyn Write, fields: &[String]) -> Result<(), String> {
+    let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    match writer.write_all(format!("{}\n", line.join(",")).as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Parses CSV text into records. Fields can be quoted with '"' and quotes escaped doubling them.
+/// Empty lines are skipped.
+pub(crate) fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                line += 1;
+                if !record.is_empty() || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("Unterminated quoted field at line {}", line));
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+impl SqliteDatasetManager {
+    /// Writes the contents of a dataset as CSV with a header row. Sets have a single key column, maps a key
+    /// and a value, map-lists a key and the values joined with '|', network maps the IP and prefix of the
+    /// network and the value and GeoIp the network and the location. IPs are written as text.
+    pub fn export_csv(
+        &self,
+        dataset_type: &SiemDatasetType,
+        writer: &mut dyn Write,
+    ) -> Result<(), String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        if shape.has_list_table() && self.list_encoding(&name) == ListEncoding::Json {
+            return Err(format!("CSV export not supported for JSON encoded dataset {:?}", dataset_type));
+        }
+        let header: Vec<String> = csv_header(shape).iter().map(|column| column.to_string()).collect();
+        write_record(writer, &header)?;
+        let mut stmt = match self.conn.prepare(&csv_sql(shape, &name)) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut rows = match stmt.query([]) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let mut fields = Vec::with_capacity(header.len());
+            for i in 0..header.len() {
+                match row.get(i) {
+                    Ok(value) => fields.push(value_to_string(value)),
+                    Err(e) => return Err(format!("{}", e)),
+                }
+            }
+            write_record(writer, &fields)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts the entries of a CSV in the tables of a set or map dataset in one transaction, following
+    /// the `on_conflict` of the dataset. The columns are the ones written by `export_csv`, a header row
+    /// is skipped. The dataset is reloaded on its next rebuild. Returns the entries inserted.
+    pub fn import_csv(
+        &self,
+        dataset_type: &SiemDatasetType,
+        reader: &mut dyn Read,
+    ) -> Result<usize, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let with_value = match shape {
+            DatasetShape::TextSet | DatasetShape::IpSet => false,
+            DatasetShape::TextMap | DatasetShape::IpMap => true,
+            _ => return Err(format!("CSV import not supported for dataset {:?}", dataset_type)),
+        };
+        let mut text = String::new();
+        if let Err(e) = reader.read_to_string(&mut text) {
+            return Err(format!("{}", e));
+        }
+        let mut records = parse_csv(&text)?;
+        let header = csv_header(shape);
+        let has_header = match records.first() {
+            Some(first) => first.iter().map(|field| field.as_str()).eq(header.iter().cloned()),
+            None => false,
+        };
+        if has_header {
+            records.remove(0);
+        }
+        self.create_dataset_tables(dataset_type);
+        let tx = match self.conn.unchecked_transaction() {
+            Ok(tx) => tx,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let sql = self.insert_entry_sql(&name, with_value);
+        let mut inserted = 0;
+        {
+            let mut insert = match tx.prepare(&sql) {
+                Ok(stmt) => stmt,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            for (pos, record) in records.iter().enumerate() {
+                if record.len() != header.len() {
+                    return Err(format!(
+                        "Invalid CSV record {}: expected {} fields but found {}",
+                        pos + 1,
+                        header.len(),
+                        record.len()
+                    ));
+                }
+                let key = key_value(dataset_type, &record[0])?;
+                let res = if with_value {
+                    insert.execute(params![key, record[1]])
+                } else {
+                    insert.execute(params![key])
+                };
+                match res {
+                    Ok(rows) => inserted += rows,
+                    Err(e) => return Err(format!("{}", e)),
+                }
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(format!("{}", e));
+        }
+        Ok(inserted)
+    }
+}
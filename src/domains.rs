@@ -0,0 +1,12 @@
+/// Canonical form of the domains stored in the domain datasets: trimmed, lowercase and without the
+/// trailing dot of a fully qualified name. Components must use it before matching against the dataset.
+pub fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim();
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    domain.to_lowercase()
+}
+
+/// Text sets of the table dataset_{name} that store domains
+pub(crate) fn is_domain_table(name: &str) -> bool {
+    name == "BlockDomain"
+}
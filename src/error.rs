@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// Errors of the loaders and the updates of the datasets. The public methods of the manager return them
+/// as strings.
+#[derive(Debug)]
+pub(crate) enum DatasetError {
+    /// An IP or a network that can't be stored or loaded
+    InvalidIp(String),
+    /// The columns of the table don't match the dataset
+    BadSchema(String),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatasetError::InvalidIp(msg) => write!(f, "Invalid IP: {}", msg),
+            DatasetError::BadSchema(msg) => write!(f, "Bad schema: {}", msg),
+            DatasetError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+impl From<rusqlite::Error> for DatasetError {
+    fn from(e: rusqlite::Error) -> Self {
+        DatasetError::Sqlite(e)
+    }
+}
@@ -0,0 +1,345 @@
+use super::{dataset_shape, dataset_table_name, DatasetShape, SqliteDatasetManager};
+use rusqlite::types::{Value, ValueRef};
+use std::io::{Read, Write};
+use usiem::components::dataset::SiemDatasetType;
+
+/// Binary export format:
+///
+/// `"USDM" | version: u8 | dataset kind: u8 | entries: varint | entries...`
+///
+/// Each entry is a list of values with the columns of the dataset kind. Each value is a type tag
+/// followed by the raw data: nothing for NULL, a zigzag varint for integers, 8 bytes LE for reals
+/// and a varint length followed by the bytes for texts and blobs.
+///
+/// Version 1 stored the IP keys little-endian, they are reversed when imported.
+const BINARY_MAGIC: &[u8; 4] = b"USDM";
+const BINARY_VERSION: u8 = 2;
+/// Last version with the IP keys little-endian
+const BINARY_VERSION_LE_IPS: u8 = 1;
+/// Upper bound of a single value while importing, protects against corrupt inputs
+const BINARY_MAX_VALUE_LEN: u64 = 64 * 1024 * 1024;
+
+const TAG_NULL: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_REAL: u8 = 2;
+const TAG_TEXT: u8 = 3;
+const TAG_BLOB: u8 = 4;
+
+fn shape_tag(shape: DatasetShape) -> u8 {
+    match shape {
+        DatasetShape::TextMap => 1,
+        DatasetShape::TextMapList => 2,
+        DatasetShape::TextSet => 3,
+        DatasetShape::IpSet => 4,
+        DatasetShape::IpMap => 5,
+        DatasetShape::IpMapList => 6,
+        DatasetShape::IpNet => 7,
+        DatasetShape::GeoIp => 8,
+    }
+}
+
+/// Columns of dataset_{name} exported for each kind. Map-list kinds export (key, value) pairs.
+fn shape_columns(shape: DatasetShape) -> &'static [&'static str] {
+    match shape {
+        DatasetShape::TextSet | DatasetShape::IpSet => &["data_key"],
+        DatasetShape::TextMap
+        | DatasetShape::IpMap
+        | DatasetShape::TextMapList
+        | DatasetShape::IpMapList => &["data_key", "data_val"],
+        DatasetShape::IpNet => &["network", "data_key", "data_val"],
+        DatasetShape::GeoIp => &[
+            "network",
+            "data_key",
+            "country",
+            "city",
+            "latitude",
+            "longitude",
+            "isp",
+        ],
+    }
+}
+
+fn is_ip_shape(shape: DatasetShape) -> bool {
+    matches!(
+        shape,
+        DatasetShape::IpSet
+            | DatasetShape::IpMap
+            | DatasetShape::IpMapList
+            | DatasetShape::IpNet
+            | DatasetShape::GeoIp
+    )
+}
+
+fn shape_and_name(dataset_type: &SiemDatasetType) -> Result<(DatasetShape, String), String> {
+    match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+        (Some(shape), Some(name)) => Ok((shape, name)),
+        _ => Err(format!("Dataset type not supported: {:?}", dataset_type)),
+    }
+}
+
+fn export_sql(shape: DatasetShape, name: &str) -> String {
+    if shape.has_list_table() {
+        format!("SELECT t1.data_key, t2.data_val FROM dataset_{dataset_name} as t1 INNER JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key ORDER BY t2.id", dataset_name = name)
+    } else {
+        format!(
+            "SELECT {columns} FROM dataset_{dataset_name} ORDER BY id",
+            columns = shape_columns(shape).join(", "),
+            dataset_name = name
+        )
+    }
+}
+
+fn write_varint(writer: &mut dyn Write, mut value: u64) -> std::io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint(reader: &mut dyn Read) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for i in 0..10 {
+        let byte = read_u8(reader)?;
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("Invalid varint in binary export"))
+}
+
+fn read_u8(reader: &mut dyn Read) -> Result<u8, String> {
+    let mut buf = [0u8; 1];
+    match reader.read_exact(&mut buf) {
+        Ok(_) => Ok(buf[0]),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn read_bytes(reader: &mut dyn Read) -> Result<Vec<u8>, String> {
+    let len = read_varint(reader)?;
+    if len > BINARY_MAX_VALUE_LEN {
+        return Err(format!("Value too long in binary export: {} bytes", len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    match reader.read_exact(&mut buf) {
+        Ok(_) => Ok(buf),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn write_value(writer: &mut dyn Write, value: ValueRef) -> std::io::Result<()> {
+    match value {
+        ValueRef::Null => writer.write_all(&[TAG_NULL]),
+        ValueRef::Integer(v) => {
+            writer.write_all(&[TAG_INTEGER])?;
+            write_varint(writer, ((v << 1) ^ (v >> 63)) as u64)
+        }
+        ValueRef::Real(v) => {
+            writer.write_all(&[TAG_REAL])?;
+            writer.write_all(&v.to_le_bytes())
+        }
+        ValueRef::Text(v) => {
+            writer.write_all(&[TAG_TEXT])?;
+            write_varint(writer, v.len() as u64)?;
+            writer.write_all(v)
+        }
+        ValueRef::Blob(v) => {
+            writer.write_all(&[TAG_BLOB])?;
+            write_varint(writer, v.len() as u64)?;
+            writer.write_all(v)
+        }
+    }
+}
+
+fn read_value(reader: &mut dyn Read) -> Result<Value, String> {
+    match read_u8(reader)? {
+        TAG_NULL => Ok(Value::Null),
+        TAG_INTEGER => {
+            let v = read_varint(reader)?;
+            Ok(Value::Integer(((v >> 1) as i64) ^ -((v & 1) as i64)))
+        }
+        TAG_REAL => {
+            let mut buf = [0u8; 8];
+            match reader.read_exact(&mut buf) {
+                Ok(_) => Ok(Value::Real(f64::from_le_bytes(buf))),
+                Err(e) => Err(format!("{}", e)),
+            }
+        }
+        TAG_TEXT => match String::from_utf8(read_bytes(reader)?) {
+            Ok(v) => Ok(Value::Text(v)),
+            Err(e) => Err(format!("{}", e)),
+        },
+        TAG_BLOB => Ok(Value::Blob(read_bytes(reader)?)),
+        tag => Err(format!("Invalid value tag in binary export: {}", tag)),
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Writes the contents of a dataset in a compact binary format to be imported in another node
+    /// with `import_binary`. Returns the number of entries written.
+    pub fn export_binary(
+        &self,
+        dataset_type: &SiemDatasetType,
+        writer: &mut dyn Write,
+    ) -> Result<usize, String> {
+        let (shape, name) = shape_and_name(dataset_type)?;
+        let sql = export_sql(shape, &name);
+        let count: i64 = match self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM ({})", sql),
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(count) => count,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let header = writer
+            .write_all(BINARY_MAGIC)
+            .and_then(|_| writer.write_all(&[BINARY_VERSION, shape_tag(shape)]))
+            .and_then(|_| write_varint(writer, count as u64));
+        if let Err(e) = header {
+            return Err(format!("{}", e));
+        }
+        let mut stmt = match self.conn.prepare(&sql) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut rows = match stmt.query([]) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let columns = shape_columns(shape).len();
+        let mut written = 0;
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            for i in 0..columns {
+                let value = match row.get_ref(i) {
+                    Ok(value) => value,
+                    Err(e) => return Err(format!("{}", e)),
+                };
+                if let Err(e) = write_value(writer, value) {
+                    return Err(format!("{}", e));
+                }
+            }
+            written += 1;
+        }
+        if written != count as usize {
+            return Err(format!("Dataset {:?} changed during the export", dataset_type));
+        }
+        Ok(written)
+    }
+
+    /// Replaces the contents of a dataset with a binary export created by `export_binary`.
+    /// The export must have been created from a dataset of the same kind.
+    /// Returns the number of entries imported.
+    pub fn import_binary(
+        &mut self,
+        dataset_type: &SiemDatasetType,
+        reader: &mut dyn Read,
+    ) -> Result<usize, String> {
+        let (shape, name) = shape_and_name(dataset_type)?;
+        let mut magic = [0u8; 4];
+        if let Err(e) = reader.read_exact(&mut magic) {
+            return Err(format!("{}", e));
+        }
+        if &magic != BINARY_MAGIC {
+            return Err(String::from("Not a binary dataset export"));
+        }
+        let version = read_u8(reader)?;
+        if version != BINARY_VERSION && version != BINARY_VERSION_LE_IPS {
+            return Err(format!("Unsupported binary export version: {}", version));
+        }
+        let tag = read_u8(reader)?;
+        if tag != shape_tag(shape) {
+            return Err(format!(
+                "Binary export kind {} is not compatible with dataset {:?}",
+                tag, dataset_type
+            ));
+        }
+        let count = read_varint(reader)?;
+        self.create_dataset_tables(dataset_type);
+        let columns = shape_columns(shape);
+        let le_key = if version == BINARY_VERSION_LE_IPS && is_ip_shape(shape) {
+            columns.iter().position(|column| *column == "data_key")
+        } else {
+            None
+        };
+        {
+            let tx = match self.conn.unchecked_transaction() {
+                Ok(tx) => tx,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let mut delete = vec![format!("DELETE FROM dataset_{};", name)];
+            if shape.has_list_table() {
+                delete.push(format!("DELETE FROM dataset_list_{};", name));
+            }
+            if let Err(e) = tx.execute_batch(&delete.join("")) {
+                return Err(format!("{}", e));
+            }
+            let insert_sql = if shape.has_list_table() {
+                format!(
+                    "INSERT OR IGNORE INTO dataset_{dataset_name} (data_key) VALUES (?1)",
+                    dataset_name = name
+                )
+            } else {
+                let placeholders: Vec<String> =
+                    (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+                format!(
+                    "INSERT OR REPLACE INTO dataset_{dataset_name} ({columns}) VALUES ({placeholders})",
+                    dataset_name = name,
+                    columns = columns.join(", "),
+                    placeholders = placeholders.join(", ")
+                )
+            };
+            let mut insert = match tx.prepare(&insert_sql) {
+                Ok(stmt) => stmt,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let mut insert_list = if shape.has_list_table() {
+                match tx.prepare(&format!("INSERT INTO dataset_list_{dataset_name} (data_key, data_val) SELECT id, ?2 FROM dataset_{dataset_name} WHERE data_key = ?1", dataset_name = name)) {
+                    Ok(stmt) => Some(stmt),
+                    Err(e) => return Err(format!("{}", e)),
+                }
+            } else {
+                None
+            };
+            for _ in 0..count {
+                let mut values = Vec::with_capacity(columns.len());
+                for _ in 0..columns.len() {
+                    values.push(read_value(reader)?);
+                }
+                if let Some(Value::Blob(key)) = le_key.and_then(|pos| values.get_mut(pos)) {
+                    if key.len() == 4 || key.len() == 16 {
+                        key.reverse();
+                    }
+                }
+                let res = match &mut insert_list {
+                    Some(insert_list) => insert
+                        .execute([&values[0]])
+                        .and_then(|_| insert_list.execute(rusqlite::params_from_iter(values.iter()))),
+                    None => insert.execute(rusqlite::params_from_iter(values.iter())),
+                };
+                if let Err(e) = res {
+                    return Err(format!("{}", e));
+                }
+            }
+            drop(insert);
+            drop(insert_list);
+            if let Err(e) = tx.commit() {
+                return Err(format!("{}", e));
+            }
+        }
+        if self.registered_datasets.contains_key(dataset_type) {
+            self.rebuild_dataset(dataset_type)?;
+        }
+        Ok(count as usize)
+    }
+}
@@ -0,0 +1,217 @@
+use super::cache::key_value;
+use super::{dataset_shape, dataset_table_name, DatasetShape, SqliteDatasetManager};
+use rusqlite::params;
+use std::collections::BTreeMap;
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Entry of a feed file. Sets have no value
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeedEntry {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// Changes needed to make a dataset equal to a feed. An entry whose value changed is removed
+/// with the old value and added with the new one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeedDiff {
+    pub add: Vec<FeedEntry>,
+    pub remove: Vec<FeedEntry>,
+}
+
+/// Tells if the keys of a dataset are IPs and if it has values
+fn feed_format(dataset_type: &SiemDatasetType) -> Result<(bool, bool), String> {
+    match dataset_shape(dataset_type) {
+        Some(DatasetShape::TextSet) => Ok((false, false)),
+        Some(DatasetShape::TextMap) => Ok((false, true)),
+        Some(DatasetShape::IpSet) => Ok((true, false)),
+        Some(DatasetShape::IpMap) => Ok((true, true)),
+        _ => Err(format!(
+            "Feeds not supported for dataset {:?}",
+            dataset_type
+        )),
+    }
+}
+
+/// Key and value of a line of a feed. With `with_value` the value after the first comma is required,
+/// without it anything after a comma is ignored.
+fn parse_line(
+    line: &str,
+    is_ip: bool,
+    with_value: bool,
+    line_number: usize,
+    feed_path: &str,
+) -> Result<(String, Option<String>), String> {
+    let (key, value) = match line.find(',') {
+        Some(pos) if with_value => (line[..pos].trim(), Some(line[pos + 1..].trim().to_string())),
+        None if with_value => {
+            return Err(format!("Missing value in line {} of feed {}", line_number, feed_path))
+        }
+        Some(pos) => (line[..pos].trim(), None),
+        None => (line, None),
+    };
+    let key = if is_ip {
+        match SiemIp::from_ip_str(key) {
+            Ok(ip) => ip.to_string(),
+            Err(_) => return Err(format!("Invalid IP in line {} of feed {}", line_number, feed_path)),
+        }
+    } else {
+        key.to_string()
+    };
+    Ok((key, value))
+}
+
+fn read_file(feed_path: &str) -> Result<String, String> {
+    match std::fs::read_to_string(feed_path) {
+        Ok(content) => Ok(content),
+        Err(e) => Err(format!("Cannot read feed {}: {}", feed_path, e)),
+    }
+}
+
+/// Reads a feed file: one entry per line, the key and, for maps, the value separated by the
+/// first comma. Empty lines and lines starting with '#' are skipped. IPs are normalized.
+pub(crate) fn read_feed(
+    dataset_type: &SiemDatasetType,
+    feed_path: &str,
+) -> Result<BTreeMap<String, Option<String>>, String> {
+    let (is_ip, is_map) = feed_format(dataset_type)?;
+    let content = read_file(feed_path)?;
+    let mut entries = BTreeMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = parse_line(line, is_ip, is_map, i + 1, feed_path)?;
+        entries.insert(key, value);
+    }
+    Ok(entries)
+}
+
+/// Reads a delta file: the lines of feed entries under a `[add]` section and the keys under a
+/// `[remove]` section. Sections can repeat, the removes are applied first.
+fn read_delta(dataset_type: &SiemDatasetType, delta_path: &str) -> Result<FeedDiff, String> {
+    let (is_ip, is_map) = feed_format(dataset_type)?;
+    let content = read_file(delta_path)?;
+    let mut delta = FeedDiff::default();
+    let mut adding = None;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line {
+            "[add]" => adding = Some(true),
+            "[remove]" => adding = Some(false),
+            _ => {
+                let add = match adding {
+                    Some(add) => add,
+                    None => return Err(format!("Line {} of delta {} is outside of a section", i + 1, delta_path)),
+                };
+                let (key, value) = parse_line(line, is_ip, add && is_map, i + 1, delta_path)?;
+                if add {
+                    delta.add.push(FeedEntry { key, value });
+                } else {
+                    delta.remove.push(FeedEntry { key, value });
+                }
+            }
+        }
+    }
+    Ok(delta)
+}
+
+impl SqliteDatasetManager {
+    /// Compares a feed file with the contents of a dataset in the database and returns the entries
+    /// that importing the feed would add and remove. Nothing is modified.
+    /// Supports text/IP sets and maps. The feed has one entry per line with the key and, for maps,
+    /// the value separated by the first comma. Empty lines and lines starting with '#' are skipped.
+    pub fn diff_against_feed(
+        &self,
+        dataset_type: &SiemDatasetType,
+        feed_path: &str,
+    ) -> Result<FeedDiff, String> {
+        let feed = read_feed(dataset_type, feed_path)?;
+        let mut current = BTreeMap::new();
+        for entry in self.query_entries(dataset_type, None, None)? {
+            current.insert(entry.key, entry.values.into_iter().next());
+        }
+        let mut diff = FeedDiff::default();
+        for (key, value) in &feed {
+            if current.get(key) != Some(value) {
+                diff.add.push(FeedEntry {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        for (key, value) in current {
+            if feed.get(&key) != Some(&value) {
+                diff.remove.push(FeedEntry { key, value });
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Applies a delta file to a dataset in a single transaction and rebuilds it if registered.
+    /// The file has a `[add]` section with feed entries and a `[remove]` section with keys:
+    ///
+    /// ```text
+    /// [add]
+    /// 10.0.0.1,mac1
+    /// [remove]
+    /// 10.0.0.2
+    /// ```
+    /// The adds follow the `on_conflict` option of the dataset. Returns the (added, removed) rows.
+    pub fn apply_delta(
+        &mut self,
+        dataset_type: &SiemDatasetType,
+        delta_path: &str,
+    ) -> Result<(usize, usize), String> {
+        let delta = read_delta(dataset_type, delta_path)?;
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let tx = match self.conn.unchecked_transaction() {
+            Ok(tx) => tx,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut removed = 0;
+        for entry in &delta.remove {
+            let res = tx.execute(
+                &format!("DELETE FROM dataset_{dataset_name} WHERE data_key = ?1", dataset_name = name),
+                params![key_value(dataset_type, &entry.key)?],
+            );
+            match res {
+                Ok(rows) => removed += rows,
+                Err(e) => return Err(format!("Cannot remove {}: {}", entry.key, e)),
+            }
+        }
+        let mut added = 0;
+        for entry in &delta.add {
+            let key = key_value(dataset_type, &entry.key)?;
+            let res = match &entry.value {
+                Some(value) => tx.execute(
+                    &self.insert_entry_sql(&name, true),
+                    params![key, value],
+                ),
+                None => tx.execute(
+                    &self.insert_entry_sql(&name, false),
+                    params![key],
+                ),
+            };
+            match res {
+                Ok(rows) => added += rows,
+                Err(e) => return Err(format!("Cannot add {}: {}", entry.key, e)),
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(format!("{}", e));
+        }
+        if self.registered_datasets.contains_key(dataset_type) {
+            self.rebuild_dataset(dataset_type)?;
+        }
+        Ok((added, removed))
+    }
+}
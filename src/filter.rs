@@ -0,0 +1,103 @@
+use rusqlite::types::Value;
+
+/// Condition on the entries loaded in memory by the loader of a dataset. The columns are the ones of the
+/// table dataset_{name}, the keys table for map-list datasets. The values are bound as parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadFilter {
+    /// The column is equal to the value
+    Equals(String, Value),
+    /// The column is equal to one of the values
+    In(String, Vec<Value>),
+    /// Only the IPv4 keys. The keys of datasets that are not IPs never match
+    Ipv4Keys,
+    /// Only the IPv6 keys. The keys of datasets that are not IPs never match
+    Ipv6Keys,
+    /// All the filters match
+    All(Vec<LoadFilter>),
+}
+
+impl LoadFilter {
+    pub fn equals<V: Into<Value>>(column: &str, value: V) -> LoadFilter {
+        LoadFilter::Equals(column.to_string(), value.into())
+    }
+
+    pub fn one_of<V: Into<Value>>(column: &str, values: Vec<V>) -> LoadFilter {
+        LoadFilter::In(column.to_string(), values.into_iter().map(|v| v.into()).collect())
+    }
+
+    /// Checks the column names, so the filter can be turned into SQL
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        self.to_sql(1).map(|_| ())
+    }
+
+    /// SQL condition of the filter, numbering its parameters from `first`
+    pub(crate) fn to_sql(&self, first: usize) -> Result<SqlFilter, String> {
+        let mut params = Vec::new();
+        let condition = self.condition(first, &mut params)?;
+        Ok(SqlFilter::new(&condition, params))
+    }
+
+    fn condition(&self, first: usize, params: &mut Vec<Value>) -> Result<String, String> {
+        match self {
+            LoadFilter::Equals(column, value) => {
+                validate_column(column)?;
+                params.push(value.clone());
+                Ok(format!("\"{}\" = ?{}", column, first + params.len() - 1))
+            }
+            LoadFilter::In(column, values) => {
+                validate_column(column)?;
+                let mut placeholders = Vec::with_capacity(values.len());
+                for value in values {
+                    params.push(value.clone());
+                    placeholders.push(format!("?{}", first + params.len() - 1));
+                }
+                Ok(format!("\"{}\" IN ({})", column, placeholders.join(", ")))
+            }
+            LoadFilter::Ipv4Keys => Ok(String::from("(typeof(data_key) = 'blob' AND length(data_key) = 4)")),
+            LoadFilter::Ipv6Keys => Ok(String::from("(typeof(data_key) = 'blob' AND length(data_key) = 16)")),
+            LoadFilter::All(filters) => {
+                if filters.is_empty() {
+                    return Ok(String::from("1"));
+                }
+                let mut conditions = Vec::with_capacity(filters.len());
+                for filter in filters {
+                    conditions.push(filter.condition(first, params)?);
+                }
+                Ok(format!("({})", conditions.join(" AND ")))
+            }
+        }
+    }
+}
+
+fn validate_column(column: &str) -> Result<(), String> {
+    let valid = match column.bytes().next() {
+        Some(first) => {
+            !first.is_ascii_digit() && column.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(format!(
+            "Invalid column in load filter, only letters, digits and '_' are allowed: {}",
+            column
+        ));
+    }
+    Ok(())
+}
+
+/// Condition added to the SELECT of the loader of a dataset. It's an SQL expression over the columns of
+/// the table dataset_{name} using the parameters ?1, ?2...
+#[derive(Debug, Clone)]
+pub(crate) struct SqlFilter {
+    pub condition: String,
+    pub params: Vec<Value>,
+}
+
+impl SqlFilter {
+    pub fn new(condition: &str, params: Vec<Value>) -> SqlFilter {
+        SqlFilter {
+            condition: condition.to_string(),
+            params,
+        }
+    }
+}
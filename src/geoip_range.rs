@@ -0,0 +1,76 @@
+use super::{index_name, ip_form_vec8, ip_to_vec8, network_address, SqliteDatasetManager};
+use rusqlite::params;
+use rusqlite::types::Value;
+use usiem::events::field::SiemIp;
+
+/// First and last IP of a network, in network order
+pub(crate) fn network_range(ip: &SiemIp, net: u8) -> (Vec<u8>, Vec<u8>) {
+    let start = network_address(ip, net);
+    let end = match start {
+        SiemIp::V4(start) => SiemIp::V4(start | u32::MAX.checked_shr(net as u32).unwrap_or(0)),
+        SiemIp::V6(start) => SiemIp::V6(start | u128::MAX.checked_shr(net as u32).unwrap_or(0)),
+    };
+    (ip_to_vec8(&start), ip_to_vec8(&end))
+}
+
+impl SqliteDatasetManager {
+    /// Adds the net_start and net_end columns to GeoIp tables created before them, fills the rows
+    /// missing them and indexes them for `lookup_geo_ip`
+    pub(crate) fn ensure_geo_ip_range(&self, name: &str) -> Result<(), String> {
+        let exists = self.conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = 'net_start'",
+            params![format!("dataset_{}", name)],
+            |row| row.get::<_, i64>(0),
+        );
+        let res = match exists {
+            Ok(0) => self.conn.execute_batch(&format!(
+                "ALTER TABLE dataset_{dataset_name} ADD COLUMN net_start BLOB; ALTER TABLE dataset_{dataset_name} ADD COLUMN net_end BLOB;",
+                dataset_name = name
+            )),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        // Covers the whole containment condition, only the row found is read from the table
+        let res = res.and_then(|_| {
+            self.conn.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS {range_index} ON dataset_{dataset_name} (net_start, net_end, network)",
+                range_index = index_name("idx_", name, "net_start"),
+                dataset_name = name
+            ))
+        });
+        if let Err(e) = res {
+            return Err(format!("{}", e));
+        }
+        let res = self.in_transaction(|| {
+            let mut select = self.conn.prepare(&format!(
+                "SELECT id, data_key, network FROM dataset_{} WHERE net_start IS NULL",
+                name
+            ))?;
+            let rows = select.query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Value>(1)?, row.get::<_, u8>(2)?))
+            })?;
+            let mut update = self.conn.prepare(&format!(
+                "UPDATE dataset_{} SET net_start = ?2, net_end = ?3 WHERE id = ?1",
+                name
+            ))?;
+            for row in rows {
+                let (id, key, net) = row?;
+                let ip = match &key {
+                    Value::Blob(key) => ip_form_vec8(key),
+                    Value::Text(key) => SiemIp::from_ip_str(key).map_err(|_| ()),
+                    _ => Err(()),
+                };
+                // Rows that aren't networks are skipped by the loader too
+                if let Ok(ip) = ip {
+                    let (start, end) = network_range(&ip, net);
+                    update.execute(params![id, start, end])?;
+                }
+            }
+            Ok(())
+        });
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
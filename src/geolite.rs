@@ -0,0 +1,122 @@
+use super::csv::parse_csv;
+use super::{max_prefix, network_address, SqliteDatasetManager};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+use usiem::components::dataset::geo_ip::GeoIpInfo;
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Location of a geoname_id of the GeoLite2 Locations file
+struct Location {
+    country: String,
+    city: String,
+}
+
+fn read_csv(reader: &mut dyn Read, file: &str) -> Result<(Vec<String>, Vec<Vec<String>>), String> {
+    let mut text = String::new();
+    if let Err(e) = reader.read_to_string(&mut text) {
+        return Err(format!("Cannot read the {} file: {}", file, e));
+    }
+    let mut records = parse_csv(&text)?;
+    if records.is_empty() {
+        return Err(format!("The {} file has no header", file));
+    }
+    let header = records.remove(0);
+    Ok((header, records))
+}
+
+/// Position of a column in the header of a GeoLite2 file
+fn column(header: &[String], name: &str, file: &str) -> Result<usize, String> {
+    match header.iter().position(|column| column == name) {
+        Some(pos) => Ok(pos),
+        None => Err(format!("Column {} not found in the {} file", name, file)),
+    }
+}
+
+fn field(record: &[String], pos: usize) -> &str {
+    record.get(pos).map(|field| field.as_str()).unwrap_or("")
+}
+
+/// Parses a network in CIDR notation: 1.2.3.0/24 or 2001:db8::/32
+fn parse_network(network: &str) -> Result<(SiemIp, u8), String> {
+    let mut parts = network.splitn(2, '/');
+    let ip = match SiemIp::from_ip_str(parts.next().unwrap_or("")) {
+        Ok(ip) => ip,
+        Err(_) => return Err(format!("Invalid network: {}", network)),
+    };
+    match parts.next().map(|net| net.parse::<u8>()) {
+        Some(Ok(net)) if u32::from(net) <= max_prefix(&ip) => Ok((network_address(&ip, net), net)),
+        _ => Err(format!("Invalid network: {}", network)),
+    }
+}
+
+fn coordinate(value: &str) -> f32 {
+    value.parse().unwrap_or(0.0)
+}
+
+fn read_locations(locations_csv: &mut dyn Read) -> Result<BTreeMap<String, Location>, String> {
+    let (header, records) = read_csv(locations_csv, "locations")?;
+    let geoname_id = column(&header, "geoname_id", "locations")?;
+    let country = column(&header, "country_iso_code", "locations")?;
+    let city = column(&header, "city_name", "locations").ok();
+    let mut locations = BTreeMap::new();
+    for record in records {
+        let location = Location {
+            country: field(&record, country).to_string(),
+            city: city.map(|city| field(&record, city).to_string()).unwrap_or_default(),
+        };
+        locations.insert(field(&record, geoname_id).to_string(), location);
+    }
+    Ok(locations)
+}
+
+impl SqliteDatasetManager {
+    /// Inserts the networks of a MaxMind GeoLite2 Blocks file (IPv4 or IPv6, City or Country edition) in
+    /// the GeoIp dataset, with the country and city of their geoname_id in the Locations file. Networks
+    /// without a location use the one of their registered country. GeoLite2 has no ISP, it's left empty.
+    /// Runs in one transaction, the dataset is reloaded on its next rebuild. Returns the networks inserted.
+    pub fn import_maxmind_geolite2(
+        &self,
+        blocks_csv: &mut dyn Read,
+        locations_csv: &mut dyn Read,
+    ) -> Result<usize, String> {
+        let locations = read_locations(locations_csv)?;
+        let (header, records) = read_csv(blocks_csv, "blocks")?;
+        let network = column(&header, "network", "blocks")?;
+        let geoname_id = column(&header, "geoname_id", "blocks")?;
+        let registered_country = column(&header, "registered_country_geoname_id", "blocks").ok();
+        let latitude = column(&header, "latitude", "blocks").ok();
+        let longitude = column(&header, "longitude", "blocks").ok();
+        let mut networks = Vec::with_capacity(records.len());
+        for record in &records {
+            let (ip, net) = parse_network(field(record, network))?;
+            let location = locations.get(field(record, geoname_id)).or_else(|| {
+                registered_country.and_then(|pos| locations.get(field(record, pos)))
+            });
+            let (country, city) = match location {
+                Some(location) => (location.country.clone(), location.city.clone()),
+                None => (String::new(), String::new()),
+            };
+            let info = GeoIpInfo {
+                country: Cow::Owned(country),
+                city: Cow::Owned(city),
+                latitude: latitude.map(|pos| coordinate(field(record, pos))).unwrap_or(0.0),
+                longitude: longitude.map(|pos| coordinate(field(record, pos))).unwrap_or(0.0),
+                isp: Cow::Borrowed(""),
+            };
+            networks.push((ip, net, info));
+        }
+        self.create_dataset_tables(&SiemDatasetType::GeoIp);
+        let res = self.in_transaction(|| {
+            for (ip, net, info) in &networks {
+                self.insert_geo_ip("GeoIp", ip, *net, info)?;
+            }
+            Ok(())
+        });
+        match res {
+            Ok(_) => Ok(networks.len()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::{dataset_shape, dataset_table_name, DatasetShape, SqliteDatasetManager};
+use rusqlite::{params, OptionalExtension};
+use usiem::components::dataset::SiemDatasetType;
+
+/// Normalizes a MD5, SHA1 or SHA256 hash to lowercase hex. Fails if it isn't hex or has another length.
+pub fn normalize_hash(hash: &str) -> Result<String, String> {
+    let hash = hash.trim();
+    match hash.len() {
+        32 | 40 | 64 => {}
+        len => return Err(format!("Invalid hash length {}: {}", len, hash)),
+    }
+    if !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hash, not hex: {}", hash));
+    }
+    Ok(hash.to_ascii_lowercase())
+}
+
+fn hash_table_name(dataset_type: &SiemDatasetType) -> Result<String, String> {
+    match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+        (Some(DatasetShape::TextMap), Some(name)) => Ok(name),
+        _ => Err(format!("Dataset {:?} can not store hashes", dataset_type)),
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Stores a file hash (MD5, SHA1 or SHA256) of a malware IOC with its threat name in a text map
+    /// dataset, like CustomMapText("malware_hashes"). The hash is validated and stored in lowercase hex.
+    /// The dataset is rebuilt if registered.
+    pub fn add_hash(
+        &mut self,
+        dataset_type: &SiemDatasetType,
+        hash: &str,
+        threat: &str,
+    ) -> Result<(), String> {
+        let name = hash_table_name(dataset_type)?;
+        let hash = normalize_hash(hash)?;
+        self.create_dataset_tables(dataset_type);
+        if let Err(e) = self.conn.execute(
+            &self.insert_entry_sql(&name, true),
+            params![hash, threat],
+        ) {
+            return Err(format!("{}", e));
+        }
+        if self.registered_datasets.contains_key(dataset_type) {
+            self.rebuild_dataset(dataset_type)?;
+        }
+        Ok(())
+    }
+
+    /// Threat name of a file hash in a dataset filled with `add_hash`. The hash can be in any case.
+    pub fn get_threat(
+        &self,
+        dataset_type: &SiemDatasetType,
+        hash: &str,
+    ) -> Result<Option<String>, String> {
+        let name = hash_table_name(dataset_type)?;
+        let hash = normalize_hash(hash)?;
+        let res = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT data_val FROM dataset_{dataset_name} WHERE data_key = ?1",
+                    dataset_name = name
+                ),
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional();
+        match res {
+            Ok(threat) => Ok(threat),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Checks if a file hash is in a dataset filled with `add_hash`. The hash can be in any case.
+    pub fn contains_hash(&self, dataset_type: &SiemDatasetType, hash: &str) -> Result<bool, String> {
+        Ok(self.get_threat(dataset_type, hash)?.is_some())
+    }
+}
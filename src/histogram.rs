@@ -0,0 +1,46 @@
+/// Bucket of a histogram: values lower or equal than `le` not counted in a previous bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bucket {
+    pub le: u64,
+    pub count: u64,
+}
+
+/// Durations and updates processed of the flush cycles of the run loop, returned by
+/// `SqliteDatasetManager::flush_histogram`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub cycles: u64,
+    /// Duration of the cycles in microseconds
+    pub duration_us: Vec<Bucket>,
+    /// Updates received from the channels in each cycle
+    pub updates: Vec<Bucket>,
+}
+
+const DURATION_BOUNDS: &[u64] = &[100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000, u64::MAX];
+const UPDATE_BOUNDS: &[u64] = &[0, 1, 10, 100, 1_000, 10_000, u64::MAX];
+
+fn buckets(bounds: &[u64]) -> Vec<Bucket> {
+    bounds.iter().map(|le| Bucket { le: *le, count: 0 }).collect()
+}
+
+fn record(buckets: &mut [Bucket], value: u64) {
+    if let Some(bucket) = buckets.iter_mut().find(|bucket| value <= bucket.le) {
+        bucket.count += 1;
+    }
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            cycles: 0,
+            duration_us: buckets(DURATION_BOUNDS),
+            updates: buckets(UPDATE_BOUNDS),
+        }
+    }
+
+    pub(crate) fn record(&mut self, duration: std::time::Duration, updates: usize) {
+        self.cycles += 1;
+        record(&mut self.duration_us, duration.as_micros() as u64);
+        record(&mut self.updates, updates as u64);
+    }
+}
@@ -0,0 +1,118 @@
+use super::query::Entry;
+use super::{dataset_shape, dataset_table_name, index_name, ip_to_vec8, DatasetShape, SqlFilter, SqliteDatasetManager};
+use rusqlite::types::Value;
+use rusqlite::{params, OptionalExtension};
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Condition over dataset_{name} that selects the entry with a key, in the text form used by `Entry::key`
+fn key_condition(shape: DatasetShape, key: &str) -> Result<(String, Vec<Value>), String> {
+    let parse_ip = |ip: &str| match SiemIp::from_ip_str(ip) {
+        Ok(ip) => Ok(Value::Blob(ip_to_vec8(&ip))),
+        Err(_) => Err(format!("Invalid IP: {}", ip)),
+    };
+    match shape {
+        DatasetShape::TextMap | DatasetShape::TextMapList | DatasetShape::TextSet => Ok((
+            String::from("data_key = ?1"),
+            vec![Value::Text(key.to_string())],
+        )),
+        DatasetShape::IpSet | DatasetShape::IpMap | DatasetShape::IpMapList => {
+            Ok((String::from("data_key = ?1"), vec![parse_ip(key)?]))
+        }
+        DatasetShape::IpNet | DatasetShape::GeoIp => {
+            let mut parts = key.splitn(2, '/');
+            let ip = parse_ip(parts.next().unwrap_or(""))?;
+            let network: i64 = match parts.next().map(|n| n.parse()) {
+                Some(Ok(network)) => network,
+                _ => return Err(format!("Invalid network, expected IP/prefix: {}", key)),
+            };
+            Ok((
+                String::from("data_key = ?1 AND network = ?2"),
+                vec![ip, Value::Integer(network)],
+            ))
+        }
+    }
+}
+
+fn shape_and_name(dataset_type: &SiemDatasetType) -> Result<(DatasetShape, String), String> {
+    match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+        (Some(shape), Some(name)) => Ok((shape, name)),
+        _ => Err(format!("Dataset type not supported: {:?}", dataset_type)),
+    }
+}
+
+impl SqliteDatasetManager {
+    fn create_labels_table(&self, name: &str) -> Result<(), String> {
+        match self.conn.execute_batch(&format!("CREATE TABLE IF NOT EXISTS dataset_labels_{dataset_name} (entry_id INTEGER NOT NULL, label_key TEXT NOT NULL, label_val TEXT NOT NULL, UNIQUE(entry_id, label_key));CREATE INDEX IF NOT EXISTS {labels_label_index} ON dataset_labels_{dataset_name} (label_key, label_val);", dataset_name = name, labels_label_index = index_name("idx_labels_", name, "label"))) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Labels an entry of a dataset with `label_key=label_val`, replacing the previous value of the label.
+    /// The key is in the text form of `Entry::key`. Labels are stored in the table dataset_labels_{name}
+    /// and are not loaded in memory. Fails if the entry does not exist.
+    pub fn set_label(
+        &self,
+        dataset_type: &SiemDatasetType,
+        key: &str,
+        label_key: &str,
+        label_val: &str,
+    ) -> Result<(), String> {
+        let (shape, name) = shape_and_name(dataset_type)?;
+        let (condition, values) = key_condition(shape, key)?;
+        let id: Option<i64> = match self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT id FROM dataset_{dataset_name} WHERE {condition}",
+                    dataset_name = name,
+                    condition = condition
+                ),
+                rusqlite::params_from_iter(values.iter()),
+                |row| row.get(0),
+            )
+            .optional()
+        {
+            Ok(id) => id,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => return Err(format!("Entry {} not found in dataset {:?}", key, dataset_type)),
+        };
+        self.create_labels_table(&name)?;
+        match self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO dataset_labels_{dataset_name} (entry_id, label_key, label_val) VALUES (?1, ?2, ?3)",
+                dataset_name = name
+            ),
+            params![id, label_key, label_val],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Returns the entries of a dataset labeled with `label_key=label_val`, newest first
+    pub fn entries_with_label(
+        &self,
+        dataset_type: &SiemDatasetType,
+        label_key: &str,
+        label_val: &str,
+    ) -> Result<Vec<Entry>, String> {
+        let (_, name) = shape_and_name(dataset_type)?;
+        self.create_labels_table(&name)?;
+        let filter = SqlFilter::new(
+            &format!(
+                "id IN (SELECT entry_id FROM dataset_labels_{dataset_name} WHERE label_key = ?1 AND label_val = ?2)",
+                dataset_name = name
+            ),
+            vec![
+                Value::Text(label_key.to_string()),
+                Value::Text(label_val.to_string()),
+            ],
+        );
+        self.query_entries(dataset_type, Some(&filter), None)
+    }
+}
@@ -0,0 +1,54 @@
+use super::{dataset_table_name, DatasetCommand, DatasetOptions, SqliteDatasetManager};
+use crossbeam_channel::Sender;
+use log::error;
+use usiem::components::dataset::holder::DatasetHolder;
+use usiem::components::dataset::{SiemDataset, SiemDatasetType};
+
+/// Holder that registers the datasets on first access. The registration is requested to the run loop
+/// of the manager with a `DatasetCommand::Register`, so the manager must be running.
+pub struct LazyDatasetHolder {
+    holder: DatasetHolder,
+    commands: Sender<DatasetCommand>,
+    timeout: std::time::Duration,
+}
+
+impl LazyDatasetHolder {
+    /// Gets a dataset, registering it with the default options if it isn't registered yet.
+    /// Returns None if the type is not supported or the manager doesn't answer within the timeout.
+    pub fn get(&mut self, dataset_type: &SiemDatasetType) -> Option<&SiemDataset> {
+        if self.holder.get(dataset_type).is_none() {
+            dataset_table_name(dataset_type)?;
+            let (reply, response) = crossbeam_channel::bounded(1);
+            let command =
+                DatasetCommand::Register(dataset_type.clone(), DatasetOptions::default(), reply);
+            if self.commands.send(command).is_err() {
+                return None;
+            }
+            match response.recv_timeout(self.timeout) {
+                Ok(Ok(holder)) => self.holder = holder,
+                Ok(Err(e)) => {
+                    error!("Cannot register dataset {:?} lazily: {}", dataset_type, e);
+                    return None;
+                }
+                Err(_) => return None,
+            }
+        }
+        self.holder.get(dataset_type)
+    }
+
+    /// Holder with the datasets registered so far
+    pub fn holder(&self) -> DatasetHolder {
+        self.holder.clone()
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Holder that registers the datasets on first access, waiting up to `timeout` for the run loop
+    pub fn lazy_holder(&self, timeout: std::time::Duration) -> LazyDatasetHolder {
+        LazyDatasetHolder {
+            holder: self.dataset_holder.clone(),
+            commands: self.command_channel(),
+            timeout,
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use crossbeam_channel::{Receiver, Sender};
 use lazy_static::lazy_static;
-use rusqlite::{params, Connection};
+use log::{error, warn};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
 use usiem::components::dataset::holder::DatasetHolder;
 use std::borrow::Cow;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::convert::TryFrom;
-use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::{AtomicPtr, Ordering};
 use std::sync::{Arc, Mutex};
+use usiem::components::command::SiemCommandCall;
 use usiem::components::common::SiemMessage;
 use usiem::components::dataset::geo_ip::{GeoIpDataset, GeoIpInfo, GeoIpSynDataset, UpdateGeoIp};
 use usiem::components::dataset::ip_map::{IpMapDataset, IpMapSynDataset, UpdateIpMap};
@@ -22,12 +24,69 @@ use usiem::components::dataset::{SiemDataset, SiemDatasetType};
 use usiem::components::SiemDatasetManager;
 use usiem::events::field::SiemIp;
 
+mod audit;
+mod bloom;
+mod builder;
+mod cache;
+mod csv;
+mod domains;
+mod error;
+mod export;
+mod feed;
+mod filter;
+mod geolite;
+mod geoip_range;
+mod hashes;
+mod histogram;
+mod labels;
+mod lazy;
+mod maintenance;
+mod migrations;
+mod pool;
+mod query;
+mod read_through;
+mod reload;
+mod retire;
+mod secrets;
+mod sink;
+mod slow_query;
+mod storage;
+mod ttl;
+#[cfg(feature = "http")]
+pub use read_through::HttpResolver;
+pub use audit::{AuditReport, IpEncodingCounts};
+pub use bloom::BloomFilter;
+pub use builder::SqliteDatasetManagerBuilder;
+pub use domains::normalize_domain;
+pub use feed::{FeedDiff, FeedEntry};
+pub use filter::LoadFilter;
+pub use hashes::normalize_hash;
+pub use histogram::{Bucket, Histogram};
+pub use lazy::LazyDatasetHolder;
+pub use migrations::MigrationInfo;
+pub use pool::{PooledConnection, ReadPool};
+pub use query::{DatasetEntry, Entry};
+pub use read_through::ReadThroughResolver;
+pub use secrets::SECRETS_KEY_ENV;
+#[cfg(feature = "kafka-sink")]
+pub use sink::KafkaSink;
+#[cfg(feature = "nats-sink")]
+pub use sink::NatsSink;
+pub use sink::{NoopSink, UpdateEvent, UpdateEventKind, UpdateSink};
+pub use slow_query::SlowQuery;
+pub use storage::StorageKind;
+use error::DatasetError;
+use filter::SqlFilter;
+use read_through::ReadThrough;
+
 #[derive(Debug)]
 struct KeyValTextMap {
     key: String,
     val: String,
 }
 
+// Named after the update types of each channel
+#[allow(clippy::enum_variant_names)]
 enum UpdateListener {
     UpdateTextSet(Sender<UpdateTextSet>, Receiver<UpdateTextSet>, i64),
     UpdateTextMap(Sender<UpdateTextMap>, Receiver<UpdateTextMap>, i64),
@@ -39,6 +98,320 @@ enum UpdateListener {
     UpdateGeoIp(Sender<UpdateGeoIp>, Receiver<UpdateGeoIp>, i64),
 }
 
+impl UpdateListener {
+    fn set_last_update(&mut self, time: i64) {
+        match self {
+            UpdateListener::UpdateTextSet(_, _, t)
+            | UpdateListener::UpdateTextMap(_, _, t)
+            | UpdateListener::UpdateTextMapList(_, _, t)
+            | UpdateListener::UpdateIpSet(_, _, t)
+            | UpdateListener::UpdateNetIp(_, _, t)
+            | UpdateListener::UpdateIpMapList(_, _, t)
+            | UpdateListener::UpdateIpMap(_, _, t)
+            | UpdateListener::UpdateGeoIp(_, _, t) => *t = time,
+        }
+    }
+    fn last_update(&self) -> i64 {
+        match self {
+            UpdateListener::UpdateTextSet(_, _, t)
+            | UpdateListener::UpdateTextMap(_, _, t)
+            | UpdateListener::UpdateTextMapList(_, _, t)
+            | UpdateListener::UpdateIpSet(_, _, t)
+            | UpdateListener::UpdateNetIp(_, _, t)
+            | UpdateListener::UpdateIpMapList(_, _, t)
+            | UpdateListener::UpdateIpMap(_, _, t)
+            | UpdateListener::UpdateGeoIp(_, _, t) => *t,
+        }
+    }
+    /// Updates waiting in the channel and its capacity
+    fn queue_depth(&self) -> (usize, usize) {
+        match self {
+            UpdateListener::UpdateTextSet(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateTextMap(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateTextMapList(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateIpSet(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateNetIp(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateIpMapList(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateIpMap(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+            UpdateListener::UpdateGeoIp(_, r, _) => (r.len(), r.capacity().unwrap_or(0)),
+        }
+    }
+}
+
+/// Flush interval used by the run loop unless the adaptive mode changes it
+const DEFAULT_FLUSH_INTERVAL: i64 = 5000;
+
+/// Updates that the channel of a dataset holds before the components block sending
+const DEFAULT_CHANNEL_CAPACITY: usize = 128;
+
+/// Prepared statements kept by the connection, enough for the inserts and deletes of all the datasets
+const STATEMENT_CACHE_CAPACITY: usize = 256;
+
+/// State of the queues of the registered datasets, returned by `SqliteDatasetManager::flush_stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushStats {
+    /// Milliseconds between flushes of a dataset currently used by the run loop
+    pub interval_ms: i64,
+    /// Updates waiting in all the channels
+    pub queued: usize,
+    /// Highest fill ratio (0.0 to 1.0) of the channels
+    pub max_fill: f64,
+}
+
+/// Activity of a registered dataset, returned by `SqliteDatasetManager::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatasetStats {
+    /// Updates written to SQLite since the dataset was registered
+    pub updates_applied: u64,
+    /// Time of the last rebuild in milliseconds since the epoch. 0 if never built
+    pub last_rebuild_ms: i64,
+    /// Time and description of the last error
+    pub last_error: Option<(i64, String)>,
+    /// Updates waiting in the channel of the dataset
+    pub pending_queue_len: usize,
+}
+
+/// Update of a single dataset inside a `DatasetCommand::MultiUpdate`
+#[derive(Debug)]
+pub enum DatasetUpdate {
+    TextSet(SiemDatasetType, UpdateTextSet),
+    TextMap(SiemDatasetType, UpdateTextMap),
+    TextMapList(SiemDatasetType, UpdateTextMapList),
+    IpSet(SiemDatasetType, UpdateIpSet),
+    IpMap(SiemDatasetType, UpdateIpMap),
+    IpMapList(SiemDatasetType, UpdateIpMapList),
+    IpNet(SiemDatasetType, UpdateNetIp),
+    GeoIp(UpdateGeoIp),
+}
+
+impl DatasetUpdate {
+    fn dataset_type(&self) -> SiemDatasetType {
+        match self {
+            DatasetUpdate::TextSet(typ, _)
+            | DatasetUpdate::TextMap(typ, _)
+            | DatasetUpdate::TextMapList(typ, _)
+            | DatasetUpdate::IpSet(typ, _)
+            | DatasetUpdate::IpMap(typ, _)
+            | DatasetUpdate::IpMapList(typ, _)
+            | DatasetUpdate::IpNet(typ, _) => typ.clone(),
+            DatasetUpdate::GeoIp(_) => SiemDatasetType::GeoIp,
+        }
+    }
+    /// Event sent to the update sink
+    fn event(&self) -> UpdateEvent {
+        match self {
+            DatasetUpdate::TextSet(typ, update) => UpdateEvent::from_text_set(typ, update),
+            DatasetUpdate::TextMap(typ, update) => UpdateEvent::from_text_map(typ, update),
+            DatasetUpdate::TextMapList(typ, update) => UpdateEvent::from_text_map_list(typ, update),
+            DatasetUpdate::IpSet(typ, update) => UpdateEvent::from_ip_set(typ, update),
+            DatasetUpdate::IpMap(typ, update) => UpdateEvent::from_ip_map(typ, update),
+            DatasetUpdate::IpMapList(typ, update) => UpdateEvent::from_ip_map_list(typ, update),
+            DatasetUpdate::IpNet(typ, update) => UpdateEvent::from_ip_net(typ, update),
+            DatasetUpdate::GeoIp(update) => UpdateEvent::from_geo_ip(update),
+        }
+    }
+    fn is_replace(&self) -> bool {
+        matches!(
+            self,
+            DatasetUpdate::TextSet(_, UpdateTextSet::Replace(_))
+                | DatasetUpdate::TextMap(_, UpdateTextMap::Replace(_))
+                | DatasetUpdate::TextMapList(_, UpdateTextMapList::Replace(_))
+                | DatasetUpdate::IpSet(_, UpdateIpSet::Replace(_))
+                | DatasetUpdate::IpMap(_, UpdateIpMap::Replace(_))
+                | DatasetUpdate::IpMapList(_, UpdateIpMapList::Replace(_))
+                | DatasetUpdate::IpNet(_, UpdateNetIp::Replace(_))
+                | DatasetUpdate::GeoIp(UpdateGeoIp::Replace(_))
+        )
+    }
+    fn shape(&self) -> DatasetShape {
+        match self {
+            DatasetUpdate::TextSet(_, _) => DatasetShape::TextSet,
+            DatasetUpdate::TextMap(_, _) => DatasetShape::TextMap,
+            DatasetUpdate::TextMapList(_, _) => DatasetShape::TextMapList,
+            DatasetUpdate::IpSet(_, _) => DatasetShape::IpSet,
+            DatasetUpdate::IpMap(_, _) => DatasetShape::IpMap,
+            DatasetUpdate::IpMapList(_, _) => DatasetShape::IpMapList,
+            DatasetUpdate::IpNet(_, _) => DatasetShape::IpNet,
+            DatasetUpdate::GeoIp(_) => DatasetShape::GeoIp,
+        }
+    }
+}
+
+/// Commands processed by the run loop of the manager. Sent using `SqliteDatasetManager::command_channel`
+#[derive(Debug)]
+pub enum DatasetCommand {
+    /// Updates of different datasets applied in a single transaction: all of them are applied or none.
+    MultiUpdate(Vec<DatasetUpdate>),
+    /// Registers a dataset while the run loop is running. The holder with the new dataset, or the error,
+    /// is sent back through the channel.
+    Register(SiemDatasetType, DatasetOptions, Sender<Result<DatasetHolder, String>>),
+}
+
+/// Behaviour of the Add updates when the key already exists in the dataset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConflict {
+    /// The update fails and the existing entry is kept
+    Abort,
+    /// The update is discarded and the existing entry is kept
+    Ignore,
+    /// The existing entry is replaced with the new one. The values of sets and maps are updated in place,
+    /// keeping the row of the entry and its labels
+    #[default]
+    Replace,
+}
+
+impl OnConflict {
+    fn insert_clause(&self) -> &'static str {
+        match self {
+            OnConflict::Abort => "INSERT OR ABORT",
+            OnConflict::Ignore => "INSERT OR IGNORE",
+            OnConflict::Replace => "INSERT OR REPLACE",
+        }
+    }
+}
+
+/// Options of a dataset used in `SqliteDatasetManager::register_dataset_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct DatasetOptions {
+    /// Behaviour of Add updates for keys already present. Defaults to `OnConflict::Replace`
+    pub on_conflict: OnConflict,
+    /// Only the entries matching the filter are loaded in memory. All the entries are kept in the database
+    pub load_filter: Option<LoadFilter>,
+    /// How the values of IpMapList datasets are stored. Defaults to `ListEncoding::Table`
+    pub list_encoding: ListEncoding,
+    /// Keeps in memory only the `n` entries most looked up with `SqliteDatasetManager::lookup`,
+    /// which reads the rest from SQLite. Text/IP sets and maps only
+    pub cache_size: Option<usize>,
+    /// Only the networks of these countries are loaded in memory. GeoIp only
+    pub geoip_countries: Option<Vec<String>>,
+    /// The tables are created in the temp schema of the connection and lost when the manager is dropped
+    pub ephemeral: bool,
+    /// Entries added expire after these milliseconds, see `SqliteDatasetManager::set_default_ttl`.
+    /// Text/IP sets and maps only
+    pub ttl_ms: Option<i64>,
+    /// Capacity of the update channel of the dataset instead of the one of the manager, see
+    /// `SqliteDatasetManagerBuilder::channel_capacity`. Ignored in synchronous mode
+    pub channel_capacity: Option<usize>,
+}
+
+/// Storage of the values of an IpMapList dataset. It must not change once the tables are created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListEncoding {
+    /// One row per value in the table dataset_list_{name}
+    #[default]
+    Table,
+    /// The values as a JSON array in the data_val column of dataset_{name}, with no list table.
+    /// Any character is allowed in the values. Only the updates and the loader support it.
+    Json,
+}
+
+fn load_filter_sql(filter: Option<&SqlFilter>, name: &str, joined: bool) -> String {
+    match filter {
+        Some(filter) if joined => format!(
+            " WHERE t1.id IN (SELECT id FROM dataset_{dataset_name} WHERE {condition})",
+            dataset_name = name,
+            condition = filter.condition
+        ),
+        Some(filter) => format!(" WHERE {}", filter.condition),
+        None => String::new(),
+    }
+}
+
+fn load_filter_params(filter: Option<&SqlFilter>) -> Vec<&rusqlite::types::Value> {
+    match filter {
+        Some(filter) => filter.params.iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Pragmas run when the connection is opened. None keeps the default of SQLite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// WAL by default, so the readers don't block the writes
+    pub journal_mode: Option<String>,
+    /// NORMAL by default, safe with WAL and much faster than FULL for small writes
+    pub synchronous: Option<String>,
+    pub foreign_keys: Option<bool>,
+    pub busy_timeout: Option<std::time::Duration>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            journal_mode: Some(String::from("WAL")),
+            synchronous: Some(String::from("NORMAL")),
+            foreign_keys: Some(true),
+            busy_timeout: Some(std::time::Duration::from_secs(5)),
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Keeps all the defaults of SQLite
+    pub fn sqlite_defaults() -> ConnectionOptions {
+        ConnectionOptions {
+            journal_mode: None,
+            synchronous: None,
+            foreign_keys: None,
+            busy_timeout: None,
+        }
+    }
+
+    fn apply(&self, conn: &Connection) -> Result<(), String> {
+        // journal_mode returns the mode set, that stays "memory" for in-memory databases
+        if let Some(journal_mode) = &self.journal_mode {
+            if let Err(e) = conn.pragma_update_and_check(None, "journal_mode", journal_mode, |_| Ok(())) {
+                return Err(format!("{}", e));
+            }
+        }
+        if let Some(synchronous) = &self.synchronous {
+            if let Err(e) = conn.pragma_update(None, "synchronous", synchronous) {
+                return Err(format!("{}", e));
+            }
+        }
+        if let Some(foreign_keys) = self.foreign_keys {
+            if let Err(e) = conn.pragma_update(None, "foreign_keys", foreign_keys) {
+                return Err(format!("{}", e));
+            }
+        }
+        if let Some(busy_timeout) = self.busy_timeout {
+            if let Err(e) = conn.busy_timeout(busy_timeout) {
+                return Err(format!("{}", e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do when the table of a dataset disappears while the manager is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingTablePolicy {
+    /// Create the table again, empty, and rebuild the dataset
+    Recreate,
+    /// Keep serving the last version of the dataset
+    Skip,
+}
+
+/// Shape of the tables backing a dataset. Two datasets with the same shape share the same schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DatasetShape {
+    TextMap,
+    TextMapList,
+    TextSet,
+    IpSet,
+    IpMap,
+    IpMapList,
+    IpNet,
+    GeoIp,
+}
+
+impl DatasetShape {
+    /// The map-list shapes store the values in a second table: dataset_list_{name}
+    fn has_list_table(&self) -> bool {
+        matches!(self, DatasetShape::TextMapList | DatasetShape::IpMapList)
+    }
+}
+
 lazy_static! {
     static ref DATASETS: Arc<Mutex<BTreeMap<SiemDatasetType, SiemDataset>>> =
         Arc::new(Mutex::new(BTreeMap::new()));
@@ -51,1083 +424,6340 @@ pub struct SqliteDatasetManager {
     local_chnl_rcv: Receiver<SiemMessage>,
     /// Send actions to this components
     local_chnl_snd: Sender<SiemMessage>,
+    /// Receive commands specific to this manager
+    command_chnl_rcv: Receiver<DatasetCommand>,
+    command_chnl_snd: Sender<DatasetCommand>,
     registered_datasets: BTreeMap<SiemDatasetType, UpdateListener>,
-    conn: Connection,
+    /// Connection of the updates and the loads, taken from `pool`
+    conn: PooledConnection,
+    pool: pool::ConnectionPool,
     dataset_pointers : BTreeMap<SiemDatasetType, Arc<AtomicPtr<SiemDataset>>>,
-    datasets : BTreeMap<SiemDatasetType, SiemDataset>,
-    dataset_holder : DatasetHolder
+    datasets : BTreeMap<SiemDatasetType, Box<SiemDataset>>,
+    /// Replaced datasets and the time they were retired, alive while readers may still use them
+    retired_datasets : VecDeque<(i64, Box<SiemDataset>)>,
+    retire_grace_ms : i64,
+    dataset_holder : DatasetHolder,
+    read_through : BTreeMap<SiemDatasetType, ReadThrough>,
+    missing_table_policy : MissingTablePolicy,
+    batched_swaps : bool,
+    max_datasets : Option<usize>,
+    update_sink : Box<dyn UpdateSink>,
+    /// Rows changed between automatic ANALYZE runs. None disables them
+    analyze_threshold : Option<i64>,
+    changes_at_last_analyze : i64,
+    /// Rebuild cycles between runs of `run_maintenance` from the run loop. None disables them
+    maintenance_interval : Option<u64>,
+    rebuilds_since_maintenance : u64,
+    /// Effective milliseconds between flushes of a dataset
+    flush_interval : i64,
+    /// Floor and ceiling of the flush interval in adaptive mode. None keeps it fixed
+    adaptive_flush : Option<(i64, i64)>,
+    /// Time in milliseconds and message of the last failed update or rebuild of each dataset
+    last_error : BTreeMap<SiemDatasetType, (i64, String)>,
+    /// Updates applied and time of the last rebuild of each dataset, for `stats`
+    updates_applied : BTreeMap<SiemDatasetType, u64>,
+    last_rebuild : BTreeMap<SiemDatasetType, i64>,
+    /// Registered datasets ordered by the time of their last flush: the first one is the next due
+    flush_schedule : BTreeSet<(i64, SiemDatasetType)>,
+    flush_histogram : Histogram,
+    dataset_options : BTreeMap<SiemDatasetType, DatasetOptions>,
+    /// Incremented on each pointer swap and holder rebuild
+    holder_generation : u64,
+    /// Used without the run loop: updates are applied with `force_flush`
+    synchronous : bool,
+    /// Opened with `SqliteDatasetManagerBuilder::read_only`: the updates are ignored
+    read_only : bool,
+    /// Pragmas run when the connection was opened, run again by `relocate`
+    connection_options : ConnectionOptions,
+    /// URI of a shared in-memory database, see `SqliteDatasetManagerBuilder::shared_memory`
+    memory_uri : Option<String>,
+    /// Second connection to the shared in-memory database, that would be lost with the last connection
+    keep_alive : Option<Connection>,
+    /// Capacity of the update channels of the datasets
+    channel_capacity : usize,
+    /// Key of the values of the Secrets datasets, see `with_secrets_key`
+    secrets_key : Option<[u8; 32]>,
+    /// Time of the last deletion of expired entries
+    last_sweep : i64,
+    /// Milliseconds between the checks of the changes made by other processes. None disables them
+    external_reload_ms : Option<i64>,
+    last_external_reload : i64,
+    /// `PRAGMA data_version` and hash of the rows of each dataset seen in the last check
+    external_data_version : Option<i64>,
+    content_hashes : BTreeMap<SiemDatasetType, u64>,
 }
 impl SqliteDatasetManager {
     pub fn new(path: String) -> Result<SqliteDatasetManager, String> {
-        let (kernel_sender, _receiver) = crossbeam_channel::bounded(1000);
-        let (local_chnl_snd, local_chnl_rcv) = crossbeam_channel::unbounded();
-        let conn = match Connection::open(&path) {
-            Ok(conn) => conn,
-            Err(e) => return Err(format!("{}", e)),
-        };
-        return Ok(SqliteDatasetManager {
-            kernel_sender,
-            local_chnl_rcv,
-            local_chnl_snd,
-            registered_datasets: BTreeMap::new(),
-            conn,
-            dataset_pointers : BTreeMap::new(),
-            datasets : BTreeMap::new(),
-            dataset_holder : DatasetHolder::from_datasets(vec![])
-        });
+        SqliteDatasetManagerBuilder::new().path(path).build()
+    }
+
+    /// Opens the database running the pragmas of `options` and the pending migrations.
+    /// `new` uses `ConnectionOptions::default()`
+    pub fn new_with_options(path: String, options: ConnectionOptions) -> Result<SqliteDatasetManager, String> {
+        SqliteDatasetManagerBuilder::new()
+            .path(path)
+            .connection_options(options)
+            .build()
     }
 
     pub fn debug() -> Result<SqliteDatasetManager, String> {
+        SqliteDatasetManagerBuilder::new().in_memory().build()
+    }
+
+    /// Like `debug` with an in-memory database that other connections can open, see `memory_uri`
+    pub fn debug_shared() -> Result<SqliteDatasetManager, String> {
+        SqliteDatasetManagerBuilder::new().shared_memory().build()
+    }
+
+    /// URI of the shared in-memory database, to open it with `OpenFlags::SQLITE_OPEN_URI`.
+    /// None for files and private in-memory databases.
+    pub fn memory_uri(&self) -> Option<&str> {
+        self.memory_uri.as_deref()
+    }
+
+    /// Types of the datasets registered, in order
+    pub fn registered_types(&self) -> Vec<SiemDatasetType> {
+        self.registered_datasets.keys().cloned().collect()
+    }
+
+    pub fn is_registered(&self, dataset_type: &SiemDatasetType) -> bool {
+        self.registered_datasets.contains_key(dataset_type)
+    }
+
+    /// Manager over an opened connection, with the pragmas already applied and without migrating
+    fn from_connection(pool: pool::ConnectionPool, conn: PooledConnection) -> SqliteDatasetManager {
         let (kernel_sender, _receiver) = crossbeam_channel::bounded(1000);
         let (local_chnl_snd, local_chnl_rcv) = crossbeam_channel::unbounded();
-        let conn = match Connection::open_in_memory() {
-            Ok(conn) => conn,
-            Err(_) => return Err(String::from("")),
-        };
-        return Ok(SqliteDatasetManager {
+        let (command_chnl_snd, command_chnl_rcv) = crossbeam_channel::unbounded();
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        SqliteDatasetManager {
             kernel_sender,
             local_chnl_rcv,
             local_chnl_snd,
+            command_chnl_rcv,
+            command_chnl_snd,
             registered_datasets: BTreeMap::new(),
             conn,
+            pool,
             dataset_pointers : BTreeMap::new(),
             datasets : BTreeMap::new(),
-            dataset_holder : DatasetHolder::from_datasets(vec![])
+            retired_datasets : VecDeque::new(),
+            retire_grace_ms : retire::DEFAULT_RETIRE_GRACE_MS,
+            dataset_holder : DatasetHolder::from_datasets(vec![]),
+            read_through : BTreeMap::new(),
+            missing_table_policy : MissingTablePolicy::Recreate,
+            batched_swaps : false,
+            max_datasets : None,
+            update_sink : Box::new(NoopSink),
+            analyze_threshold : None,
+            changes_at_last_analyze : 0,
+            maintenance_interval : None,
+            rebuilds_since_maintenance : 0,
+            flush_interval : DEFAULT_FLUSH_INTERVAL,
+            adaptive_flush : None,
+            last_error : BTreeMap::new(),
+            updates_applied : BTreeMap::new(),
+            last_rebuild : BTreeMap::new(),
+            flush_schedule : BTreeSet::new(),
+            flush_histogram : Histogram::new(),
+            holder_generation : 0,
+            synchronous : false,
+            read_only : false,
+            connection_options : ConnectionOptions::sqlite_defaults(),
+            memory_uri : None,
+            keep_alive : None,
+            channel_capacity : DEFAULT_CHANNEL_CAPACITY,
+            secrets_key : None,
+            last_sweep : 0,
+            external_reload_ms : None,
+            last_external_reload : 0,
+            external_data_version : None,
+            content_hashes : BTreeMap::new(),
+            dataset_options : BTreeMap::new(),
+        }
+    }
+
+    /// Limits the number of datasets that can be registered, to catch runaway registrations of custom datasets.
+    /// Once reached, `register_dataset_with_options` fails with a TooManyDatasets error.
+    pub fn with_max_datasets(mut self, max_datasets: usize) -> SqliteDatasetManager {
+        self.max_datasets = Some(max_datasets);
+        self
+    }
+
+    /// Mode for using the manager without the run loop, like in tests: `force_flush` also processes the
+    /// pending commands, and the datasets registered afterwards get unbounded channels so the components
+    /// never block waiting for a flush.
+    pub fn with_synchronous(mut self) -> SqliteDatasetManager {
+        self.synchronous = true;
+        self
+    }
+
+    /// Channel of the updates of a dataset, with the capacity of its options or the one of the manager
+    fn update_channel<T>(&self, dataset_type: &SiemDatasetType) -> (Sender<T>, Receiver<T>) {
+        if self.synchronous {
+            return crossbeam_channel::unbounded();
+        }
+        let capacity = self
+            .dataset_options
+            .get(dataset_type)
+            .and_then(|options| options.channel_capacity)
+            .unwrap_or(self.channel_capacity);
+        crossbeam_channel::bounded(capacity)
+    }
+
+    /// Number of registered datasets
+    pub fn dataset_count(&self) -> usize {
+        self.registered_datasets.len()
+    }
+
+    /// Smoke test of a loaded network dataset: checks that each probe IP resolves to the expected value,
+    /// or to nothing if None. Fails with all the probes that don't match.
+    pub fn verify_net_dataset(
+        &self,
+        dataset_type: &SiemDatasetType,
+        probes: &[(SiemIp, Option<String>)],
+    ) -> Result<(), String> {
+        let dataset = match self.datasets.get(dataset_type) {
+            Some(dataset) => dataset,
+            None => return Err(format!("Dataset not registered: {:?}", dataset_type)),
+        };
+        let dataset = match <&IpNetSynDataset>::try_from(&**dataset) {
+            Ok(dataset) => dataset,
+            Err(_) => return Err(format!("Dataset {:?} is not a network dataset", dataset_type)),
+        };
+        let mut failed = Vec::new();
+        for (ip, expected) in probes {
+            let found = dataset.get(ip).map(|v| v.to_string());
+            if &found != expected {
+                failed.push(format!("{}: expected {:?}, found {:?}", ip, expected, found));
+            }
+        }
+        if !failed.is_empty() {
+            return Err(format!(
+                "Dataset {:?} failed {} of {} probes: {}",
+                dataset_type,
+                failed.len(),
+                probes.len(),
+                failed.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Path of the main database file. None for in-memory databases
+    fn database_path(&self) -> Result<Option<String>, String> {
+        match self.conn.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            [],
+            |row| row.get::<_, String>(0),
+        ) {
+            Ok(file) if file.is_empty() => Ok(None),
+            Ok(file) => Ok(Some(file)),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Moves the database file to `new_path` and reopens it. The pending updates are written first and the
+    /// WAL is checkpointed, then every registered dataset is reloaded from the new file. The connection options
+    /// and the read-only mode are kept, settings like `with_mmap_size` must be applied again and the tables of
+    /// ephemeral datasets are lost. On failure the manager keeps using the old file.
+    pub fn relocate(&mut self, new_path: &str) -> Result<(), String> {
+        let old_path = match self.database_path()? {
+            Some(path) => path,
+            None => return Err(String::from("Cannot relocate an in-memory database")),
+        };
+        if std::path::Path::new(new_path).exists() {
+            return Err(format!("Cannot relocate the database, {} already exists", new_path));
+        }
+        self.force_flush();
+        if !self.read_only {
+            if let Err(e) = self.conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)") {
+                return Err(format!("{}", e));
+            }
+        }
+        // The old connection stays open until the moved file is opened, and is used again if it can't be
+        if let Err(e) = std::fs::rename(&old_path, new_path) {
+            return Err(format!("Cannot move {} to {}: {}", old_path, new_path, e));
+        }
+        let opened = pool::manager_connection(pool::file_connections(new_path, self.read_only))
+            .and_then(|(pool, conn)| self.connection_options.apply(&conn).map(|_| (pool, conn)));
+        let (pool, conn) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                if let Err(e) = std::fs::rename(new_path, &old_path) {
+                    error!("Cannot move {} back to {}: {}", new_path, old_path, e);
+                }
+                return Err(format!("Cannot open the relocated database {}: {}", new_path, e));
+            }
+        };
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        // The old connection is closed with its pool
+        self.conn = conn;
+        self.pool = pool;
+        for suffix in &["-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", old_path, suffix));
+        }
+        self.rebuild_all()
+    }
+
+    /// Unregisters a dataset and drops its tables. Returns the number of entries it had.
+    pub fn drop_dataset(&mut self, dataset_type: &SiemDatasetType) -> Result<usize, String> {
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let rows = self.row_count(&name);
+        if let Err(e) = self.conn.execute_batch(&format!(
+            "DROP TABLE IF EXISTS dataset_{dataset_name};DROP TABLE IF EXISTS dataset_list_{dataset_name};DROP TABLE IF EXISTS dataset_hits_{dataset_name};",
+            dataset_name = name
+        )) {
+            return Err(format!("{}", e));
+        }
+        if let Some(listener) = self.registered_datasets.remove(dataset_type) {
+            self.flush_schedule
+                .remove(&(listener.last_update(), dataset_type.clone()));
+        }
+        self.dataset_pointers.remove(dataset_type);
+        self.refresh_holder();
+        // The holders given before still point to it
+        if let Some(previous) = self.datasets.remove(dataset_type) {
+            self.retire_dataset(previous);
+        }
+        self.dataset_options.remove(dataset_type);
+        self.read_through.remove(dataset_type);
+        self.last_error.remove(dataset_type);
+        self.updates_applied.remove(dataset_type);
+        self.last_rebuild.remove(dataset_type);
+        self.emit_lifecycle_event("dropped", dataset_type, rows);
+        Ok(rows)
+    }
+
+    /// Deletes all the entries of a dataset in one transaction, keeping its tables, and rebuilds it if
+    /// registered so the components see it empty. Like resetting a blocklist.
+    pub fn clear_dataset(&mut self, dataset_type: &SiemDatasetType) -> Result<(), String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let rows = self.row_count(&name);
+        let res = self.in_transaction(|| {
+            if shape.has_list_table() && self.list_encoding(&name) == ListEncoding::Table {
+                self.conn
+                    .execute(&format!("DELETE FROM dataset_list_{}", name), [])?;
+            }
+            self.conn.execute(&format!("DELETE FROM dataset_{}", name), [])?;
+            Ok(())
         });
+        if let Err(e) = res {
+            return Err(format!("Cannot clear dataset {:?}: {}", dataset_type, e));
+        }
+        if self.registered_datasets.contains_key(dataset_type) {
+            self.rebuild_dataset(dataset_type)?;
+        }
+        self.emit_lifecycle_event("cleared", dataset_type, rows);
+        Ok(())
     }
-    fn create_text_map(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE, data_val TEXT NOT NULL);CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (data_key);", dataset_name = name), []);
+
+    /// Entries in the table dataset_{name}. 0 if it doesn't exist
+    fn row_count(&self, name: &str) -> usize {
+        match self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM dataset_{}", name),
+            [],
+            |row| row.get::<_, i64>(0),
+        ) {
+            Ok(count) => count as usize,
+            Err(_) => 0,
+        }
     }
 
-    fn create_map_text_list(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE);CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (data_key);CREATE TABLE IF NOT EXISTS dataset_list_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key INTEGER NOT NULL UNIQUE, data_val TEXT NOT NULL);CREATE UNIQUE INDEX IF NOT EXISTS idx_list_{dataset_name}_data_key ON dataset_list_{dataset_name} (data_key);", dataset_name = name), []);
+    /// Sends a JSON notification to the kernel recording a registration or drop of a dataset, for audit trails:
+    /// `{"action":"registered","dataset":"BlockIp","timestamp":1600000000000,"rows":10}`
+    fn emit_lifecycle_event(&self, action: &str, dataset_type: &SiemDatasetType, rows: usize) {
+        let event = serde_json::json!({
+            "action": action,
+            "dataset": dataset_table_name(dataset_type),
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+            "rows": rows,
+        });
+        // Never block the manager if the kernel is not reading
+        let _ = self
+            .kernel_sender
+            .try_send(SiemMessage::Notification(0, Cow::Owned(event.to_string())));
     }
-    fn create_map_ip_net(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, network INTEGER NOT NULL, data_key BLOB NOT NULL, data_val TEXT NOT NULL); CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (network, data_key);", dataset_name = name), []);
+
+    /// Sends a JSON notification to the kernel with the datasets rebuilt in a flush cycle:
+    /// `{"action":"rebuilt","datasets":["BlockIp","IpMac"],"timestamp":1600000000000}`
+    fn emit_rebuilt_event(&self, rebuilt: &[SiemDatasetType]) {
+        if rebuilt.is_empty() {
+            return;
+        }
+        let datasets: Vec<Option<String>> = rebuilt.iter().map(dataset_table_name).collect();
+        let event = serde_json::json!({
+            "action": "rebuilt",
+            "datasets": datasets,
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+        });
+        // A full or closed channel only loses the notification
+        let _ = self
+            .kernel_sender
+            .try_send(SiemMessage::Notification(0, Cow::Owned(event.to_string())));
     }
 
-    fn create_geo_ip_net(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, network INTEGER NOT NULL, data_key BLOB NOT NULL, country TEXT NOT NULL, city TEXT NOT NULL, latitude TEXT NOT NULL, longitude TEXT NOT NULL, isp TEXT NOT NULL); CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (network, data_key);", dataset_name = name), []);
+    /// Runs `PRAGMA integrity_check` on the database. It reads the whole file so it can be slow on big databases.
+    /// Fails with a "Corrupt database" error if the file is damaged or is not a database, instead of
+    /// failing later while loading the datasets.
+    pub fn with_integrity_check(self) -> Result<SqliteDatasetManager, String> {
+        let res: rusqlite::Result<Vec<String>> = self
+            .conn
+            .prepare("PRAGMA integrity_check")
+            .and_then(|mut stmt| {
+                let rows = stmt.query_map([], |row| row.get(0))?;
+                rows.collect()
+            });
+        let problems = match res {
+            Ok(rows) => {
+                if rows.len() == 1 && rows[0] == "ok" {
+                    return Ok(self);
+                }
+                rows.join("; ")
+            }
+            Err(e) => format!("{}", e),
+        };
+        Err(format!("Corrupt database: {}. Restore it from a backup, or salvage the data with the .recover command of the sqlite3 shell into a new file, and open the new file", problems))
+    }
+
+    /// Sets the page size of the database (`PRAGMA page_size`), a power of two between 512 and 65536.
+    /// Bigger pages pack cold reference datasets in less space. SQLite only applies it before the first table
+    /// is created, so on an existing database the file is repacked with VACUUM, which rewrites it entirely.
+    /// SQLite has no table compression. A database in WAL mode is switched to DELETE for the VACUUM and back.
+    pub fn with_page_size(self, bytes: u32) -> Result<SqliteDatasetManager, String> {
+        if !(512..=65536).contains(&bytes) || !bytes.is_power_of_two() {
+            return Err(format!("Invalid page size: {}", bytes));
+        }
+        if let Err(e) = self.conn.pragma_update(None, "page_size", bytes) {
+            return Err(format!("{}", e));
+        }
+        if self.page_size()? != bytes {
+            // The page size of a database in WAL mode can't be changed
+            let journal_mode: String = match self.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)) {
+                Ok(mode) => mode,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let wal = journal_mode.eq_ignore_ascii_case("wal");
+            let res = if wal {
+                self.conn.execute_batch("PRAGMA journal_mode=DELETE; VACUUM; PRAGMA journal_mode=WAL;")
+            } else {
+                self.conn.execute_batch("VACUUM")
+            };
+            if let Err(e) = res {
+                return Err(format!("{}", e));
+            }
+            let current = self.page_size()?;
+            if current != bytes {
+                return Err(format!(
+                    "Cannot change the page size of the database from {} to {}",
+                    current, bytes
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    fn page_size(&self) -> Result<u32, String> {
+        match self
+            .conn
+            .query_row("PRAGMA page_size", [], |row| row.get(0))
+        {
+            Ok(size) => Ok(size),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Enables memory-mapped I/O for reads of up to `bytes` bytes of the database file (`PRAGMA mmap_size`).
+    /// Speeds up large read-heavy datasets like GeoIp. Caveats: SQLite caps the value with its compile-time
+    /// SQLITE_MAX_MMAP_SIZE (mmap is disabled on some platforms), I/O errors on a mapped file can kill the
+    /// process with SIGBUS instead of returning an error, and it has no effect on in-memory databases.
+    pub fn with_mmap_size(self, bytes: i64) -> Result<SqliteDatasetManager, String> {
+        match self.conn.pragma_update(None, "mmap_size", bytes) {
+            Ok(_) => Ok(self),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Options of the dataset stored in the table dataset_{name}
+    fn options_of(&self, name: &str) -> Option<&DatasetOptions> {
+        for (dataset_type, options) in &self.dataset_options {
+            if dataset_table_name(dataset_type).as_deref() == Some(name) {
+                return Some(options);
+            }
+        }
+        None
+    }
+
+    /// Increases the version token of the dataset, so a pending `replace_if_version` with the previous
+    /// token fails instead of overwriting this replace
+    fn bump_version(&self, name: &str) -> Result<(), DatasetError> {
+        self.conn.execute("CREATE TABLE IF NOT EXISTS usiem_dataset_versions (dataset_name TEXT NOT NULL PRIMARY KEY, version INTEGER NOT NULL)", [])?;
+        self.conn.execute(
+            "INSERT INTO usiem_dataset_versions (dataset_name, version) VALUES (?1, 1) ON CONFLICT (dataset_name) DO UPDATE SET version = version + 1",
+            params![name],
+        )?;
+        Ok(())
+    }
+
+    /// Runs the statements in a transaction. Inside one already open (like in `apply_multi_update` or
+    /// `drain_pending`) they run in a savepoint, so a failure only undoes their own changes.
+    fn in_transaction<F>(&self, statements: F) -> Result<(), DatasetError>
+    where
+        F: FnOnce() -> Result<(), DatasetError>,
+    {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("SAVEPOINT nested_statements")?;
+            return match statements() {
+                Ok(_) => {
+                    self.conn.execute_batch("RELEASE nested_statements")?;
+                    Ok(())
+                }
+                Err(e) => {
+                    // ROLLBACK TO keeps the savepoint open
+                    if let Err(rollback) = self
+                        .conn
+                        .execute_batch("ROLLBACK TO nested_statements; RELEASE nested_statements")
+                    {
+                        error!("Cannot roll back the failed statements: {}", rollback);
+                    }
+                    Err(e)
+                }
+            };
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        statements()?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Statement creating the tables of the dataset stored in dataset_{name}
+    fn create_table_sql(&self, name: &str) -> &'static str {
+        match self.options_of(name) {
+            Some(options) if options.ephemeral => "CREATE TEMP TABLE",
+            _ => "CREATE TABLE",
+        }
+    }
+
+    /// Conflict policy of the dataset stored in the table dataset_{name}
+    fn on_conflict(&self, name: &str) -> OnConflict {
+        self.options_of(name)
+            .map(|options| options.on_conflict)
+            .unwrap_or_default()
+    }
+
+    /// INSERT of an entry in the table dataset_{name} of a set or map, with or without data_val
+    fn insert_entry_sql(&self, name: &str, with_value: bool) -> String {
+        let on_conflict = self.on_conflict(name);
+        let (insert, upsert) = match (on_conflict, with_value) {
+            (OnConflict::Replace, true) => (
+                "INSERT",
+                " ON CONFLICT(data_key) DO UPDATE SET data_val = excluded.data_val",
+            ),
+            (OnConflict::Replace, false) => ("INSERT", " ON CONFLICT(data_key) DO NOTHING"),
+            _ => (on_conflict.insert_clause(), ""),
+        };
+        if with_value {
+            format!(
+                "{insert} INTO dataset_{dataset_name} (data_key, data_val) VALUES (?1, ?2){upsert}",
+                insert = insert,
+                dataset_name = name,
+                upsert = upsert
+            )
+        } else {
+            format!(
+                "{insert} INTO dataset_{dataset_name} (data_key) VALUES (?1){upsert}",
+                insert = insert,
+                dataset_name = name,
+                upsert = upsert
+            )
+        }
+    }
+
+    /// Encoding of the values of the IpMapList dataset stored in the table dataset_{name}
+    fn list_encoding(&self, name: &str) -> ListEncoding {
+        self.options_of(name)
+            .map(|options| options.list_encoding)
+            .unwrap_or_default()
+    }
+
+    fn create_text_map(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE, data_val TEXT NOT NULL, expires_at INTEGER);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
     }
-    fn update_geo_ip(&self, name: &str, update: UpdateGeoIp) -> rusqlite::Result<()> {
+
+    /// Runs a statement through the cache of prepared statements. The updates format their SQL with the
+    /// dataset name, so each statement is parsed once per dataset.
+    fn execute_cached<P: rusqlite::Params>(&self, sql: &str, params: P) -> rusqlite::Result<usize> {
+        self.conn.prepare_cached(sql)?.execute(params)
+    }
+
+    /// Read-only managers ignore the updates, logging them
+    fn ignored_in_read_only(&self, name: &str) -> bool {
+        if self.read_only {
+            warn!("Read-only database, update of dataset {} ignored", name);
+        }
+        self.read_only
+    }
+
+    fn update_text_map(&self, name: &str, update: UpdateTextMap) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
         match update {
-            UpdateGeoIp::Add((ip, net, info)) => {
-                self.conn.execute(
-                    &format!(
-                        "INSERT INTO dataset_{dataset_name} (data_key, network, country, city, latitude, longitude, isp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-                        dataset_name = name
-                    ),
-                    params![ip_to_vec8(&ip), net, info.country, info.city, info.latitude, info.longitude, info.isp],
+            UpdateTextMap::Add((key, value)) => {
+                let value = self.seal_value(name, &key, &value)?;
+                self.execute_cached(
+                    &self.insert_entry_sql(name, true),
+                    params![key, value],
                 )?;
+                self.set_expiry(name, &key)?;
             }
-            UpdateGeoIp::Remove((ip, net)) => {
-                self.conn.execute(
+            UpdateTextMap::Remove(key) => {
+                self.execute_cached(
                     &format!(
-                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1 AND network = ?2 LIMIT 1",
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip), net],
+                    params![key],
                 )?;
             }
-            UpdateGeoIp::Replace(_dataset) => {
-                self.conn.execute(
-                    &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
-                    [],
-                )?;
-                // TODO...
+            UpdateTextMap::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name}", dataset_name = name),
+                        [],
+                    )?;
+                    let mut insert = self.conn.prepare_cached(&self.insert_entry_sql(name, true))?;
+                    for (key, value) in dataset.internal_ref() {
+                        insert.execute(params![key, self.seal_value(name, key, value)?])?;
+                    }
+                    Ok(())
+                })?;
             }
         }
-        return Ok(());
+        Ok(())
     }
 
-    fn create_map_ip_list(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE);CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (data_key);CREATE TABLE IF NOT EXISTS dataset_list_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key INTEGER NOT NULL UNIQUE, data_val TEXT NOT NULL);CREATE UNIQUE INDEX IF NOT EXISTS idx_list_{dataset_name}_data_key ON dataset_list_{dataset_name} (data_key);", dataset_name = name), []);
+    fn create_map_text_list(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);{create_table} IF NOT EXISTS dataset_list_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key INTEGER NOT NULL, data_val TEXT NOT NULL);CREATE INDEX IF NOT EXISTS {list_data_key_index} ON dataset_list_{dataset_name} (data_key);CREATE INDEX IF NOT EXISTS {list_data_val_index} ON dataset_list_{dataset_name} (data_val);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key"), list_data_key_index = index_name("idx_list_", name, "data_key"), list_data_val_index = index_name("idx_list_", name, "data_val")));
     }
-    fn update_map_ip_list(&self, name: &str, update: UpdateIpMapList) -> rusqlite::Result<()> {
+    fn update_map_text_list(&self, name: &str, update: UpdateTextMapList) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
         match update {
-            UpdateIpMapList::Add((ip, txt)) => {
-                self.conn.execute(
+            UpdateTextMapList::Add((key, values)) => {
+                let on_conflict = self.on_conflict(name);
+                if on_conflict == OnConflict::Replace {
+                    self.execute_cached(
+                        &format!(
+                            "DELETE FROM dataset_list_{dataset_name} WHERE data_key IN (SELECT id FROM dataset_{dataset_name} WHERE data_key = ?1)",
+                            dataset_name = name
+                        ),
+                        params![key],
+                    )?;
+                }
+                let inserted = self.execute_cached(
                     &format!(
-                        "INSERT INTO dataset_{dataset_name} (data_key) VALUES (?1);",
+                        "{insert} INTO dataset_{dataset_name} (data_key) VALUES (?1)",
+                        insert = on_conflict.insert_clause(),
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip)],
+                    params![key],
                 )?;
+                if inserted == 0 {
+                    // Ignored: the key already exists
+                    return Ok(());
+                }
                 let id = self.conn.last_insert_rowid();
-                for el in txt {
-                    self.conn.execute(
+                for value in values {
+                    self.execute_cached(
                         &format!(
-                            "INSERT INTO dataset_list_{dataset_name} (data_key, data_val) VALUES (?1, ?2)",
+                            "INSERT OR IGNORE INTO dataset_list_{dataset_name} (data_key, data_val) VALUES (?1, ?2)",
                             dataset_name = name
                         ),
-                        params![id, el],
+                        params![id, value],
                     )?;
                 }
             }
-            UpdateIpMapList::Remove(ip) => {
-                self.conn.execute(
+            UpdateTextMapList::Remove(key) => {
+                self.execute_cached(
                     &format!(
-                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1 LIMIT 1;DELETE FROM dataset_list_{dataset_name} WHERE data_key = ?1;",
+                        "DELETE FROM dataset_list_{dataset_name} WHERE data_key IN (SELECT id FROM dataset_{dataset_name} WHERE data_key = ?1)",
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip)],
+                    params![key],
                 )?;
-            }
-            UpdateIpMapList::Replace(_dataset) => {
-                self.conn.execute(
-                    &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
-                    [],
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![key],
                 )?;
-                // TODO...
+            }
+            UpdateTextMapList::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute_batch(&format!(
+                        "DELETE FROM dataset_list_{dataset_name};DELETE FROM dataset_{dataset_name};",
+                        dataset_name = name
+                    ))?;
+                    for (key, values) in dataset.internal_ref() {
+                        self.update_map_text_list(name, UpdateTextMapList::Add((key.clone(), values.clone())))?;
+                    }
+                    Ok(())
+                })?;
             }
         }
-        return Ok(());
+        Ok(())
     }
-
-    fn create_ip_map(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE, data_val TEXT NOT NULL);CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (data_key);", dataset_name = name), []);
+    fn create_map_ip_net(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, network INTEGER NOT NULL, data_key BLOB NOT NULL, data_val TEXT NOT NULL); CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (network, data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
     }
-    fn update_map_ip(&self, name: &str, update: UpdateIpMap) -> rusqlite::Result<()> {
+    /// The networks are stored by their first address, so a Remove matches any IP of the network
+    fn update_ip_net(&self, name: &str, update: UpdateNetIp) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
         match update {
-            UpdateIpMap::Add((ip, txt)) => {
-                self.conn.execute(
+            UpdateNetIp::Add((ip, net, value)) => {
+                check_prefix(&ip, u32::from(net))?;
+                self.execute_cached(
                     &format!(
-                        "INSERT INTO dataset_{dataset_name} (data_key, data_val) VALUES (?1, ?2)",
+                        "{insert} INTO dataset_{dataset_name} (network, data_key, data_val) VALUES (?1, ?2, ?3)",
+                        insert = self.on_conflict(name).insert_clause(),
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip), txt],
+                    params![net, ip_to_vec8(&network_address(&ip, net)), value],
                 )?;
             }
-            UpdateIpMap::Remove(ip) => {
-                self.conn.execute(
+            UpdateNetIp::Remove((ip, net)) => {
+                check_prefix(&ip, u32::from(net))?;
+                self.execute_cached(
                     &format!(
-                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1 LIMIT 1",
+                        "DELETE FROM dataset_{dataset_name} WHERE network = ?1 AND data_key = ?2",
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip)],
+                    params![net, ip_to_vec8(&network_address(&ip, net))],
                 )?;
             }
-            UpdateIpMap::Replace(_dataset) => {
-                self.conn.execute(
-                    &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
-                    [],
-                )?;
-            }
-        }
-        return Ok(());
+            UpdateNetIp::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name}", dataset_name = name),
+                        [],
+                    )?;
+                    let mut insert = self.conn.prepare_cached(&format!(
+                        "{insert} INTO dataset_{dataset_name} (network, data_key, data_val) VALUES (?1, ?2, ?3)",
+                        insert = self.on_conflict(name).insert_clause(),
+                        dataset_name = name
+                    ))?;
+                    let (ip4, ip6) = dataset.internal_ref();
+                    for (net, networks) in ip4 {
+                        for (ip, value) in networks {
+                            check_prefix(&SiemIp::V4(*ip), *net)?;
+                            insert.execute(params![net, ip_to_vec8(&SiemIp::V4(*ip)), value])?;
+                        }
+                    }
+                    for (net, networks) in ip6 {
+                        for (ip, value) in networks {
+                            check_prefix(&SiemIp::V6(*ip), *net)?;
+                            insert.execute(params![net, ip_to_vec8(&SiemIp::V6(*ip)), value])?;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
     }
 
-    fn create_ip_set(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE);CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (data_key);", dataset_name = name), []);
+    fn create_geo_ip_net(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, network INTEGER NOT NULL, data_key BLOB NOT NULL, country TEXT NOT NULL, city TEXT NOT NULL, latitude TEXT NOT NULL, longitude TEXT NOT NULL, isp TEXT NOT NULL, net_start BLOB, net_end BLOB); CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (network, data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
+        if let Err(e) = self.ensure_geo_ip_range(name) {
+            error!("Cannot index the networks of dataset {}: {}", name, e);
+        }
     }
-
-    fn update_ip_set(&self, name: &str, update: UpdateIpSet) -> rusqlite::Result<()> {
+    fn insert_geo_ip(&self, name: &str, ip: &SiemIp, net: u8, info: &GeoIpInfo) -> rusqlite::Result<()> {
+        let mut insert = self.conn.prepare_cached(&format!(
+            "{insert} INTO dataset_{dataset_name} (data_key, network, country, city, latitude, longitude, isp, net_start, net_end) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            insert = self.on_conflict(name).insert_clause(),
+            dataset_name = name
+        ))?;
+        let (net_start, net_end) = geoip_range::network_range(ip, net);
+        insert.execute(params![ip_to_vec8(ip), net, info.country, info.city, info.latitude, info.longitude, info.isp, net_start, net_end])?;
+        Ok(())
+    }
+    fn update_geo_ip(&self, name: &str, update: UpdateGeoIp) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
         match update {
-            UpdateIpSet::Add(ip) => {
-                self.conn.execute(
-                    &format!(
-                        "INSERT INTO dataset_{dataset_name} (data_key) VALUES (?1)",
-                        dataset_name = name
-                    ),
-                    params![ip_to_vec8(&ip)],
-                )?;
+            UpdateGeoIp::Add((ip, net, info)) => {
+                self.insert_geo_ip(name, &ip, net, &info)?;
             }
-            UpdateIpSet::Remove(ip) => {
-                self.conn.execute(
+            UpdateGeoIp::Remove((ip, net)) => {
+                self.execute_cached(
                     &format!(
-                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1 LIMIT 1",
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1 AND network = ?2",
                         dataset_name = name
                     ),
-                    params![ip_to_vec8(&ip)],
+                    params![ip_to_vec8(&ip), net],
                 )?;
             }
-            UpdateIpSet::Replace(dataset) => {
-                self.conn.execute(
-                    &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
-                    [],
-                )?;
-                let (ip4, ip6) = dataset.internal_ref();
-                for ip in ip4 {
+            UpdateGeoIp::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
                     self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
+                        [],
+                    )?;
+                    let (ip4, ip6) = dataset.internal_ref();
+                    for (net, networks) in ip4 {
+                        for (ip, info) in networks {
+                            self.insert_geo_ip(name, &SiemIp::V4(*ip), *net as u8, info)?;
+                        }
+                    }
+                    for (net, networks) in ip6 {
+                        for (ip, info) in networks {
+                            self.insert_geo_ip(name, &SiemIp::V6(*ip), *net as u8, info)?;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_map_ip_list(&self, name: &str) {
+        if self.list_encoding(name) == ListEncoding::Json {
+            // Same schema as an IpMap, data_val holds the JSON array
+            return self.create_ip_map(name);
+        }
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);{create_table} IF NOT EXISTS dataset_list_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key INTEGER NOT NULL, data_val TEXT NOT NULL);CREATE INDEX IF NOT EXISTS {list_data_key_index} ON dataset_list_{dataset_name} (data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key"), list_data_key_index = index_name("idx_list_", name, "data_key")));
+    }
+    fn update_map_ip_list(&self, name: &str, update: UpdateIpMapList) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
+        if self.list_encoding(name) == ListEncoding::Json {
+            return self.update_map_ip_list_json(name, update);
+        }
+        match update {
+            UpdateIpMapList::Add((ip, txt)) => {
+                let on_conflict = self.on_conflict(name);
+                if on_conflict == OnConflict::Replace {
+                    self.execute_cached(
                         &format!(
-                            "INSERT INTO dataset_{dataset_name} (data_key) VALUES (?1)",
+                            "DELETE FROM dataset_list_{dataset_name} WHERE data_key IN (SELECT id FROM dataset_{dataset_name} WHERE data_key = ?1)",
                             dataset_name = name
                         ),
-                        params![ip.to_le_bytes().to_vec()],
+                        params![ip_to_vec8(&ip)],
                     )?;
                 }
-                for ip in ip6 {
-                    self.conn.execute(
+                let inserted = self.execute_cached(
+                    &format!(
+                        "{insert} INTO dataset_{dataset_name} (data_key) VALUES (?1);",
+                        insert = on_conflict.insert_clause(),
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+                if inserted == 0 {
+                    // Ignored: the key already exists
+                    return Ok(());
+                }
+                let id = self.conn.last_insert_rowid();
+                for el in txt {
+                    self.execute_cached(
                         &format!(
-                            "INSERT INTO dataset_{dataset_name} (data_key) VALUES (?1)",
+                            "INSERT OR IGNORE INTO dataset_list_{dataset_name} (data_key, data_val) VALUES (?1, ?2)",
                             dataset_name = name
                         ),
-                        params![ip.to_le_bytes().to_vec()],
+                        params![id, el],
                     )?;
                 }
             }
+            UpdateIpMapList::Remove(ip) => {
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_list_{dataset_name} WHERE data_key IN (SELECT id FROM dataset_{dataset_name} WHERE data_key = ?1)",
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+            }
+            UpdateIpMapList::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute_batch(&format!(
+                        "DELETE FROM dataset_list_{dataset_name};DELETE FROM dataset_{dataset_name};",
+                        dataset_name = name
+                    ))?;
+                    self.insert_ip_map_list(name, &dataset)
+                })?;
+            }
         }
-        return Ok(());
+        Ok(())
     }
 
-    fn create_text_list(&self, name: &str) {
-        let _ = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, network INTEGER NOT NULL, data_key BLOB NOT NULL, data_val TEXT NOT NULL); CREATE UNIQUE INDEX IF NOT EXISTS idx_{dataset_name}_data_key ON dataset_{dataset_name} (network, data_key);", dataset_name = name), []);
+    /// Adds all the entries of a dataset, as `UpdateIpMapList::Add` does
+    fn insert_ip_map_list(&self, name: &str, dataset: &IpMapListDataset) -> Result<(), DatasetError> {
+        let (ip4, ip6) = dataset.internal_ref();
+        for (ip, values) in ip4 {
+            self.update_map_ip_list(name, UpdateIpMapList::Add((SiemIp::V4(*ip), values.clone())))?;
+        }
+        for (ip, values) in ip6 {
+            self.update_map_ip_list(name, UpdateIpMapList::Add((SiemIp::V6(*ip), values.clone())))?;
+        }
+        Ok(())
     }
-}
 
-impl SiemDatasetManager for SqliteDatasetManager {
-    fn name(&self) -> &str {
-        &"SqliteDatasetManager"
+    fn update_map_ip_list_json(&self, name: &str, update: UpdateIpMapList) -> Result<(), DatasetError> {
+        match update {
+            UpdateIpMapList::Add((ip, txt)) => {
+                let values = match serde_json::to_string(&txt) {
+                    Ok(values) => values,
+                    Err(e) => return Err(rusqlite::Error::ToSqlConversionFailure(Box::new(e)).into()),
+                };
+                self.execute_cached(
+                    &self.insert_entry_sql(name, true),
+                    params![ip_to_vec8(&ip), values],
+                )?;
+            }
+            UpdateIpMapList::Remove(ip) => {
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+            }
+            UpdateIpMapList::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
+                        [],
+                    )?;
+                    self.insert_ip_map_list(name, &dataset)
+                })?;
+            }
+        }
+        Ok(())
     }
-    fn local_channel(&self) -> Sender<SiemMessage> {
-        self.local_chnl_snd.clone()
+
+    fn create_ip_map(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE, data_val TEXT NOT NULL, expires_at INTEGER);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
     }
-    fn set_kernel_sender(&mut self, sender: Sender<SiemMessage>) {
-        self.kernel_sender = sender;
+    fn update_map_ip(&self, name: &str, update: UpdateIpMap) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
+        match update {
+            UpdateIpMap::Add((ip, txt)) => {
+                self.execute_cached(
+                    &self.insert_entry_sql(name, true),
+                    params![ip_to_vec8(&ip), txt],
+                )?;
+                self.set_expiry(name, &ip_to_vec8(&ip))?;
+            }
+            UpdateIpMap::Remove(ip) => {
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+            }
+            UpdateIpMap::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
+                        [],
+                    )?;
+                    let mut insert = self.conn.prepare_cached(&self.insert_entry_sql(name, true))?;
+                    let (ip4, ip6) = dataset.internal_ref();
+                    for (ip, value) in ip4 {
+                        insert.execute(params![ip_to_vec8(&SiemIp::V4(*ip)), value])?;
+                    }
+                    for (ip, value) in ip6 {
+                        insert.execute(params![ip_to_vec8(&SiemIp::V6(*ip)), value])?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
     }
 
-    fn run(&mut self) {
-        loop {
-            match self.local_chnl_rcv.try_recv() {
-                Ok(_msg) => {}
-                Err(e) => match e {
-                    crossbeam_channel::TryRecvError::Empty => {}
-                    crossbeam_channel::TryRecvError::Disconnected => {
-                        panic!("DatasetManager channel disconected!!")
-                    }
-                },
+    fn create_ip_set(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE, expires_at INTEGER);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
+    }
+
+    fn update_ip_set(&self, name: &str, update: UpdateIpSet) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
+        match update {
+            UpdateIpSet::Add(ip) => {
+                self.execute_cached(
+                    &self.insert_entry_sql(name, false),
+                    params![ip_to_vec8(&ip)],
+                )?;
+                self.set_expiry(name, &ip_to_vec8(&ip))?;
             }
-            let mut updated_datasets = BTreeSet::new();
-            let time = chrono::Utc::now().timestamp_millis();
-            for (dataset_name, listener) in self.registered_datasets.iter() {
-                match listener {
-                    UpdateListener::UpdateIpMap(_s, r, t) => {
-                        if (*t + 5000) < time {
-                            loop {
-                                match r.try_recv() {
-                                    Ok(update) => {
-                                        let name = format!("{:?}", dataset_name);
-                                        let _ = self.update_map_ip(&name[..], update);
-                                        updated_datasets.insert(dataset_name.clone());
-                                    }
-                                    Err(e) => match e {
-                                        crossbeam_channel::TryRecvError::Empty => {
-                                            break;
-                                        }
-                                        crossbeam_channel::TryRecvError::Disconnected => {
-                                            panic!("DatasetManager channel disconected!!")
-                                        }
-                                    },
-                                }
-                            }
-                        }
-                    }
-                    UpdateListener::UpdateIpSet(_s, r, t) => {
-                        if (*t + 5000) < time {
-                            loop {
-                                match r.try_recv() {
-                                    Ok(update) => {
-                                        let name = format!("{:?}", dataset_name);
-                                        let _ = self.update_ip_set(&name[..], update);
-                                        updated_datasets.insert(dataset_name.clone());
-                                    }
-                                    Err(e) => match e {
-                                        crossbeam_channel::TryRecvError::Empty => {
-                                            break;
-                                        }
-                                        crossbeam_channel::TryRecvError::Disconnected => {
-                                            panic!("DatasetManager channel disconected!!")
-                                        }
-                                    },
-                                }
-                            }
-                        }
+            UpdateIpSet::Remove(ip) => {
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![ip_to_vec8(&ip)],
+                )?;
+            }
+            UpdateIpSet::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name} ", dataset_name = name),
+                        [],
+                    )?;
+                    let mut insert = self.conn.prepare_cached(&self.insert_entry_sql(name, false))?;
+                    let (ip4, ip6) = dataset.internal_ref();
+                    let ips = ip4
+                        .iter()
+                        .map(|ip| SiemIp::V4(*ip))
+                        .chain(ip6.iter().map(|ip| SiemIp::V6(*ip)));
+                    for ip in ips {
+                        insert.execute(params![ip_to_vec8(&ip)])?;
                     }
-                    UpdateListener::UpdateIpMapList(_s, r, t) => {
-                        if (*t + 5000) < time {
-                            loop {
-                                match r.try_recv() {
-                                    Ok(update) => {
-                                        let name = format!("{:?}", dataset_name);
-                                        let _ = self.update_map_ip_list(&name[..], update);
-                                        updated_datasets.insert(dataset_name.clone());
-                                    }
-                                    Err(e) => match e {
-                                        crossbeam_channel::TryRecvError::Empty => {
-                                            break;
-                                        }
-                                        crossbeam_channel::TryRecvError::Disconnected => {
-                                            panic!("DatasetManager channel disconected!!")
-                                        }
-                                    },
-                                }
-                            }
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_text_list(&self, name: &str) {
+        let _ = self.conn.execute_batch(&format!("{create_table} IF NOT EXISTS dataset_{dataset_name} (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE, expires_at INTEGER);CREATE UNIQUE INDEX IF NOT EXISTS {data_key_index} ON dataset_{dataset_name} (data_key);", dataset_name = name, create_table = self.create_table_sql(name), data_key_index = index_name("idx_", name, "data_key")));
+    }
+    fn update_text_set(&self, name: &str, update: UpdateTextSet) -> Result<(), DatasetError> {
+        if self.ignored_in_read_only(name) {
+            return Ok(());
+        }
+        let domains = domains::is_domain_table(name);
+        match update {
+            UpdateTextSet::Add(key) => {
+                let key = if domains { Cow::Owned(normalize_domain(&key)) } else { key };
+                self.execute_cached(
+                    &self.insert_entry_sql(name, false),
+                    params![key],
+                )?;
+                self.set_expiry(name, &key)?;
+            }
+            UpdateTextSet::Remove(key) => {
+                let key = if domains { Cow::Owned(normalize_domain(&key)) } else { key };
+                self.execute_cached(
+                    &format!(
+                        "DELETE FROM dataset_{dataset_name} WHERE data_key = ?1",
+                        dataset_name = name
+                    ),
+                    params![key],
+                )?;
+            }
+            UpdateTextSet::Replace(dataset) => {
+                self.in_transaction(|| {
+                    self.bump_version(name)?;
+                    self.conn.execute(
+                        &format!("DELETE FROM dataset_{dataset_name}", dataset_name = name),
+                        [],
+                    )?;
+                    let mut insert = self.conn.prepare_cached(&self.insert_entry_sql(name, false))?;
+                    if domains {
+                        // Different spellings of a domain are inserted once
+                        let keys: BTreeSet<String> =
+                            dataset.internal_ref().iter().map(|key| normalize_domain(key)).collect();
+                        for key in keys {
+                            insert.execute(params![key])?;
                         }
+                        return Ok(());
                     }
-                    UpdateListener::UpdateGeoIp(_s, r, t) => {
-                        if (*t + 5000) < time {
-                            loop {
-                                match r.try_recv() {
-                                    Ok(update) => {
-                                        let name = format!("{:?}", dataset_name);
-                                        let _ = self.update_geo_ip(&name[..], update);
-                                        updated_datasets.insert(dataset_name.clone());
-                                    }
-                                    Err(e) => match e {
-                                        crossbeam_channel::TryRecvError::Empty => {
-                                            break;
-                                        }
-                                        crossbeam_channel::TryRecvError::Disconnected => {
-                                            panic!("DatasetManager channel disconected!!")
-                                        }
-                                    },
-                                }
-                            }
-                        }
+                    for key in dataset.internal_ref() {
+                        insert.execute(params![key])?;
                     }
-                    // TODO
-                    _ => {}
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a registered dataset from the database using the update channel of its listener.
+    fn build_dataset(&self, dataset_type: &SiemDatasetType) -> Result<SiemDataset, String> {
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let listener = match self.registered_datasets.get(dataset_type) {
+            Some(listener) => listener,
+            None => return Err(format!("Dataset not registered: {:?}", dataset_type)),
+        };
+        let filter = self.effective_load_filter(dataset_type);
+        let filter = filter.as_ref();
+        let dataset = match listener {
+            UpdateListener::UpdateTextMap(s, _, _) => match dataset_text_map(&self.conn, &name, filter)
+                .and_then(|d| self.open_values(&name, d))
+            {
+                Ok(d) => SiemDataset::try_from((
+                    dataset_type.clone(),
+                    TextMapSynDataset::new(Arc::from(d), s.clone()),
+                )),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+            UpdateListener::UpdateTextMapList(s, _, _) => {
+                match dataset_map_text_list(&self.conn, &name, filter) {
+                    Ok(d) => SiemDataset::try_from((
+                        dataset_type.clone(),
+                        TextMapListSynDataset::new(Arc::from(d), s.clone()),
+                    )),
+                    Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
                 }
             }
-            let mut new_datasets = Vec::new();
-
-            for data_name in &updated_datasets {
-                match self.registered_datasets.get_mut(data_name) {
-                    Some(v) => match v {
-                        //TODO: Add more cases
-                        UpdateListener::UpdateIpMap(s, _, t) => {
-                            *t = time;
-                            let new_dataset =
-                                match dataset_ip_map(&self.conn, &format!("{:?}", data_name)) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        panic!("Cannot update MapIp dataset")
-                                    }
-                                };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                IpMapSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        UpdateListener::UpdateIpSet(s, _, t) => {
-                            *t = time;
-                            let new_dataset =
-                                match dataset_ip_set(&self.conn, &format!("{:?}", data_name)) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        panic!("Cannot update IpSet dataset")
-                                    }
-                                };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                IpSetSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        UpdateListener::UpdateTextMap(s, _, t) => {
-                            *t = time;
-                            let new_dataset =
-                                match dataset_text_map(&self.conn, &format!("{:?}", data_name)) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        panic!("Cannot update TextMap dataset")
-                                    }
-                                };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                TextMapSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        UpdateListener::UpdateTextMapList(s, _, t) => {
-                            *t = time;
-                            let new_dataset = match dataset_map_text_list(
-                                &self.conn,
-                                &format!("{:?}", data_name),
-                            ) {
-                                Ok(d) => d,
-                                Err(_) => {
-                                    panic!("Cannot update TextMapList dataset")
-                                }
-                            };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                TextMapListSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        UpdateListener::UpdateTextSet(s, _, t) => {
-                            *t = time;
-                            let new_dataset =
-                                match dataset_text_list(&self.conn, &format!("{:?}", data_name)) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        panic!("Cannot update TextSet dataset")
-                                    }
-                                };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                TextSetSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        UpdateListener::UpdateGeoIp(s, _, t) => {
-                            *t = time;
-                            let new_dataset =
-                                match dataset_geo_ip_net(&self.conn, &format!("{:?}", data_name)) {
-                                    Ok(d) => d,
-                                    Err(_) => {
-                                        panic!("Cannot update GeoIp dataset")
-                                    }
-                                };
-                            match SiemDataset::try_from((
-                                data_name.clone(),
-                                GeoIpSynDataset::new(Arc::from(new_dataset), s.clone()),
-                            )) {
-                                Ok(nw) => {
-                                    new_datasets.push(nw);
-                                }
-                                Err(_) => {}
-                            }
-                        }
-                        _ => {}
-                    },
-                    None => {}
+            UpdateListener::UpdateTextSet(s, _, _) => match dataset_text_list(&self.conn, &name, filter) {
+                Ok(d) => SiemDataset::try_from((
+                    dataset_type.clone(),
+                    TextSetSynDataset::new(Arc::from(d), s.clone()),
+                )),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+            UpdateListener::UpdateIpSet(s, _, _) => match dataset_ip_set(&self.conn, &name, filter) {
+                Ok(d) => SiemDataset::try_from((
+                    dataset_type.clone(),
+                    IpSetSynDataset::new(Arc::from(d), s.clone()),
+                )),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+            UpdateListener::UpdateIpMap(s, _, _) => match dataset_ip_map(&self.conn, &name, filter) {
+                Ok(d) => SiemDataset::try_from((
+                    dataset_type.clone(),
+                    IpMapSynDataset::new(Arc::from(d), s.clone()),
+                )),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+            UpdateListener::UpdateIpMapList(s, _, _) => {
+                match dataset_ip_map_list(&self.conn, &name, filter, self.list_encoding(&name)) {
+                    Ok(d) => SiemDataset::try_from((
+                        dataset_type.clone(),
+                        IpMapListSynDataset::new(Arc::from(d), s.clone()),
+                    )),
+                    Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
                 }
             }
+            UpdateListener::UpdateNetIp(s, _, _) => match dataset_ip_net(&self.conn, &name, filter) {
+                Ok(d) => SiemDataset::try_from((
+                    dataset_type.clone(),
+                    IpNetSynDataset::new(Arc::from(d), s.clone()),
+                )),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+            UpdateListener::UpdateGeoIp(s, _, _) => match dataset_geo_ip_net(&self.conn, &name, filter) {
+                Ok(d) => Ok(SiemDataset::GeoIp(GeoIpSynDataset::new(Arc::from(d), s.clone()))),
+                Err(e) => return Err(format!("Cannot load dataset {:?}: {}", dataset_type, e)),
+            },
+        };
+        match dataset {
+            Ok(dataset) => Ok(dataset),
+            Err(e) => Err(e.to_string()),
+        }
+    }
 
-            // Update last build time and also Build the references
-            loop {
-                if new_datasets.is_empty() {
-                    break;
-                } else {
-                    let dataset = new_datasets.remove(0);
-                    let typ = dataset.dataset_type();
-                    let dataset_pointer = match self.dataset_pointers.get(&typ) {
-                        Some(dt) => dt,
-                        None => {
-                            panic!("Dataset not found!?!?");
-                        }
-                    };
-                    self.datasets.insert(dataset.dataset_type(), dataset);
-                    let dataset_ref = match self.datasets.get_mut(&typ) {
-                        Some(dt) => dt,
-                        None => {
-                            panic!("Dataset not found!?!?");
-                        }
-                    };
-                    dataset_pointer.store(dataset_ref, std::sync::atomic::Ordering::Relaxed);
-                }
+    /// Replaces the in-memory version of a dataset and points the holder to the new one.
+    /// The previous version is retired, see `set_retire_grace`.
+    fn store_dataset(&mut self, dataset: SiemDataset) {
+        let typ = dataset.dataset_type();
+        let mut dataset = Box::new(dataset);
+        if let Some(pointer) = self.dataset_pointers.get(&typ) {
+            // Readers that load the new pointer see the dataset fully built
+            pointer.store(&mut *dataset, Ordering::Release);
+        }
+        self.last_rebuild
+            .insert(typ.clone(), chrono::Utc::now().timestamp_millis());
+        if let Some(previous) = self.datasets.insert(typ, dataset) {
+            self.retire_dataset(previous);
+        }
+        self.holder_generation += 1;
+    }
+
+    /// Increases each time a dataset is replaced or the holder is rebuilt. Components can compare it with
+    /// the value seen on their last read to know if the datasets changed.
+    pub fn holder_generation(&self) -> u64 {
+        self.holder_generation
+    }
+
+    /// Reloads a registered dataset from the database
+    fn rebuild_dataset(&mut self, dataset_type: &SiemDatasetType) -> Result<(), String> {
+        let dataset = self.build_dataset(dataset_type)?;
+        self.store_dataset(dataset);
+        Ok(())
+    }
+
+    /// Rebuilds the datasets updated in the last cycle of the run loop. Returns the ones swapped
+    fn rebuild_updated(&mut self, updated_datasets: &BTreeSet<SiemDatasetType>, time: i64) -> Vec<SiemDatasetType> {
+        if self.batched_swaps {
+            return self.rebuild_batched(updated_datasets, time);
+        }
+        let mut rebuilt = Vec::with_capacity(updated_datasets.len());
+        for data_name in updated_datasets {
+            if !self.registered_datasets.contains_key(data_name) {
+                continue;
+            }
+            self.set_last_update(data_name, time);
+            match self.rebuild_dataset(data_name) {
+                Ok(_) => rebuilt.push(data_name.clone()),
+                Err(e) => self.handle_rebuild_error(data_name, e),
             }
         }
+        rebuilt
     }
 
-    fn get_datasets(&self) -> DatasetHolder {
-        self.dataset_holder.clone()
+    /// Builds all the updated datasets and then swaps their pointers, in the order of their types. If any of
+    /// them fails to build, none is swapped and they are rebuilt with the next update.
+    fn rebuild_batched(&mut self, updated_datasets: &BTreeSet<SiemDatasetType>, time: i64) -> Vec<SiemDatasetType> {
+        let mut built = Vec::with_capacity(updated_datasets.len());
+        let mut failed = Vec::new();
+        for data_name in updated_datasets {
+            if !self.registered_datasets.contains_key(data_name) {
+                continue;
+            }
+            self.set_last_update(data_name, time);
+            match self.build_dataset(data_name) {
+                Ok(dataset) => built.push(dataset),
+                Err(e) => failed.push((data_name.clone(), e)),
+            }
+        }
+        if failed.is_empty() {
+            let rebuilt = built.iter().map(|dataset| dataset.dataset_type()).collect();
+            for dataset in built {
+                self.store_dataset(dataset);
+            }
+            return rebuilt;
+        }
+        error!("Cannot rebuild all the updated datasets, keeping the previous version of them");
+        for (data_name, e) in failed {
+            self.handle_rebuild_error(&data_name, e);
+        }
+        Vec::new()
     }
-    fn register_dataset(&mut self, dataset_type: SiemDatasetType) {
-        let time = chrono::Utc::now().timestamp_millis();
-        if !self.registered_datasets.contains_key(&dataset_type) {
-            let (listener, dataset): (UpdateListener, SiemDataset) = match &dataset_type {
-                SiemDatasetType::CustomMapText(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_text_map(&name);
-                    let dataset = match dataset_text_map(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: UserTag")
-                    };
-                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::CustomMapText((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::CustomIpList(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_ip_set(&name);
-                    let dataset = match dataset_ip_set(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: UserTag")
-                    };
-                    let syn_dataset = IpSetSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateIpSet(channel.0, channel.1, time), SiemDataset::CustomIpList((name.clone(),syn_dataset)))
 
+    /// Updates the statistics used by the SQLite query planner. Useful after big imports.
+    pub fn run_analyze(&mut self) -> Result<(), String> {
+        if let Err(e) = self.conn.execute_batch("ANALYZE") {
+            return Err(format!("{}", e));
+        }
+        self.changes_at_last_analyze = self.total_changes()?;
+        Ok(())
+    }
+
+    /// Runs ANALYZE from the run loop each time `rows` rows have changed. Disabled with None, the default.
+    pub fn set_auto_analyze(&mut self, rows: Option<i64>) {
+        self.analyze_threshold = rows;
+    }
+
+    fn total_changes(&self) -> Result<i64, String> {
+        match self
+            .conn
+            .query_row("SELECT total_changes()", [], |row| row.get(0))
+        {
+            Ok(changes) => Ok(changes),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Runs ANALYZE if enough rows have changed since the last run
+    fn auto_analyze(&mut self) {
+        let threshold = match self.analyze_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let changes = match self.total_changes() {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Cannot count the changed rows: {}", e);
+                return;
+            }
+        };
+        if changes - self.changes_at_last_analyze >= threshold {
+            if let Err(e) = self.run_analyze() {
+                error!("Cannot analyze the database: {}", e);
+            }
+        }
+    }
+
+    /// Milliseconds the updates of a dataset wait in its channel before being applied and the dataset rebuilt.
+    /// Lower values propagate the updates faster, higher ones rebuild less often. Defaults to 5000.
+    /// Disables the adaptive flush interval.
+    pub fn set_rebuild_interval(&mut self, interval_ms: i64) -> Result<(), String> {
+        if interval_ms <= 0 {
+            return Err(format!("Invalid rebuild interval: {}", interval_ms));
+        }
+        self.adaptive_flush = None;
+        self.flush_interval = interval_ms;
+        Ok(())
+    }
+
+    /// Enables the adaptive flush interval: it's halved, down to `min_interval_ms`, when a channel is at
+    /// least 75% full and doubled, up to `max_interval_ms`, when all the channels are empty.
+    pub fn set_adaptive_flush(&mut self, min_interval_ms: i64, max_interval_ms: i64) -> Result<(), String> {
+        if min_interval_ms <= 0 || min_interval_ms > max_interval_ms {
+            return Err(format!(
+                "Invalid flush interval bounds: {} - {}",
+                min_interval_ms, max_interval_ms
+            ));
+        }
+        self.adaptive_flush = Some((min_interval_ms, max_interval_ms));
+        self.flush_interval = self.flush_interval.max(min_interval_ms).min(max_interval_ms);
+        Ok(())
+    }
+
+    /// Current flush interval and backlog of the update channels
+    pub fn flush_stats(&self) -> FlushStats {
+        let mut queued = 0;
+        let mut max_fill: f64 = 0.0;
+        for listener in self.registered_datasets.values() {
+            let (len, capacity) = listener.queue_depth();
+            queued += len;
+            if capacity > 0 {
+                max_fill = max_fill.max(len as f64 / capacity as f64);
+            }
+        }
+        FlushStats {
+            interval_ms: self.flush_interval,
+            queued,
+            max_fill,
+        }
+    }
+
+    /// Updates applied, last rebuild, last error and queued updates of each registered dataset
+    pub fn stats(&self) -> BTreeMap<SiemDatasetType, DatasetStats> {
+        self.registered_datasets
+            .iter()
+            .map(|(dataset_type, listener)| {
+                let stats = DatasetStats {
+                    updates_applied: self.updates_applied.get(dataset_type).cloned().unwrap_or(0),
+                    last_rebuild_ms: self.last_rebuild.get(dataset_type).cloned().unwrap_or(0),
+                    last_error: self.last_error.get(dataset_type).cloned(),
+                    pending_queue_len: listener.queue_depth().0,
+                };
+                (dataset_type.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Sets the time of the last flush of a registered dataset, keeping the flush schedule sorted
+    fn set_last_update(&mut self, dataset_type: &SiemDatasetType, time: i64) {
+        if let Some(listener) = self.registered_datasets.get_mut(dataset_type) {
+            self.flush_schedule
+                .remove(&(listener.last_update(), dataset_type.clone()));
+            listener.set_last_update(time);
+            self.flush_schedule.insert((time, dataset_type.clone()));
+        }
+    }
+
+    /// Datasets whose flush interval has elapsed at `time`. Only the due ones are visited.
+    fn due_datasets(&self, time: i64, interval: i64) -> Vec<SiemDatasetType> {
+        self.flush_schedule
+            .iter()
+            .take_while(|(last_update, _)| last_update + interval < time)
+            .map(|(_, dataset_type)| dataset_type.clone())
+            .collect()
+    }
+
+    /// Time until the next dataset is due, used by the run loop to sleep
+    fn next_flush_wait(&self) -> std::time::Duration {
+        let wait = match self.flush_schedule.iter().next() {
+            Some((last_update, _)) => {
+                last_update + self.flush_interval + 1 - chrono::Utc::now().timestamp_millis()
+            }
+            None => self.flush_interval,
+        };
+        std::time::Duration::from_millis(wait.max(0) as u64)
+    }
+
+    /// Histogram of the durations and the updates processed of the flush cycles since the start
+    pub fn flush_histogram(&self) -> Histogram {
+        self.flush_histogram.clone()
+    }
+
+    /// Adjusts the flush interval to the backlog of the channels in adaptive mode
+    fn adapt_flush_interval(&mut self) {
+        let (min_interval, max_interval) = match self.adaptive_flush {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let stats = self.flush_stats();
+        if stats.max_fill >= 0.75 {
+            self.flush_interval = (self.flush_interval / 2).max(min_interval);
+        } else if stats.queued == 0 {
+            self.flush_interval = (self.flush_interval * 2).min(max_interval);
+        }
+    }
+
+    /// Stages the datasets rebuilt in the same cycle of the run loop and swaps their pointers one right after
+    /// the other, once all of them are built. This only shortens the window in which readers of correlated
+    /// datasets see a mix of versions: each dataset has its own pointer, and a reader between two swaps sees
+    /// the new version of one and the old of the other. The pointers are swapped in the order of the dataset
+    /// types, so a reader that reads them in the reverse order never sees a newer version of a dataset with
+    /// an older version of one before it. Disabled by default.
+    pub fn set_batched_swaps(&mut self, enabled: bool) {
+        self.batched_swaps = enabled;
+    }
+
+    /// Time in milliseconds and message of the last failed update or rebuild of a dataset
+    pub fn last_error(&self, dataset_type: &SiemDatasetType) -> Option<(i64, String)> {
+        self.last_error.get(dataset_type).cloned()
+    }
+
+    fn record_error(&mut self, dataset_type: &SiemDatasetType, error: &str) {
+        let time = chrono::Utc::now().timestamp_millis();
+        self.last_error
+            .insert(dataset_type.clone(), (time, error.to_string()));
+    }
+
+    fn handle_rebuild_error(&mut self, dataset_type: &SiemDatasetType, error: String) {
+        error!("Cannot rebuild dataset {:?}: {}", dataset_type, error);
+        self.record_error(dataset_type, &error);
+        if self.tables_exist(dataset_type) {
+            // Keep serving the previous version of the dataset
+            return;
+        }
+        match self.missing_table_policy {
+            MissingTablePolicy::Skip => {
+                warn!("Tables of dataset {:?} are missing, skipping it", dataset_type);
+            }
+            MissingTablePolicy::Recreate => {
+                warn!("Tables of dataset {:?} are missing, creating them again", dataset_type);
+                self.create_dataset_tables(dataset_type);
+                if let Err(e) = self.rebuild_dataset(dataset_type) {
+                    error!("Cannot rebuild dataset {:?}: {}", dataset_type, e);
                 }
-                SiemDatasetType::CustomMapIpNet(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_map_ip_net(&name);
-                    let dataset = match dataset_ip_net(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: CustomMapIpNet")
-                    };
-                    let syn_dataset = IpNetSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateNetIp(channel.0, channel.1, time), SiemDataset::CustomMapIpNet((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::CustomIpMap(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_ip_map(&name);
-                    let dataset = match dataset_ip_map(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: CustomIpMap")
-                    };
-                    let syn_dataset = IpMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateIpMap(channel.0, channel.1, time), SiemDataset::CustomIpMap((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::CustomMapTextList(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_map_text_list(&name);
-                    let dataset = match dataset_map_text_list(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: CustomMapTextList")
-                    };
-                    let syn_dataset = TextMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextMapList(channel.0, channel.1, time), SiemDataset::CustomMapTextList((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::CustomTextList(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_text_list(&name);
-                    let dataset = match dataset_text_list(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: CustomTextList")
-                    };
-                    let syn_dataset = TextSetSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextSet(channel.0, channel.1, time), SiemDataset::CustomTextList((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::Secrets(name) => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_text_map(&name);
-                    let dataset = match dataset_text_map(&self.conn, &name) {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: Secrets")
-                    };
-                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::Secrets((name.clone(),syn_dataset)))
-                }
-                SiemDatasetType::GeoIp => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_geo_ip_net("GeoIp");
-                    let dataset = match dataset_geo_ip_net(&self.conn,"GeoIp") {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: GeoIp")
-                    };
-                    let syn_dataset = GeoIpSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateGeoIp(channel.0, channel.1, time), SiemDataset::GeoIp(syn_dataset))
-                }
-                SiemDatasetType::IpMac => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_ip_map("IpMac");
-                    let dataset = match dataset_ip_map(&self.conn,"IpMac") {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: IpMac")
-                    };
-                    let syn_dataset = IpMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateIpMap(channel.0, channel.1, time), SiemDataset::IpMac(syn_dataset))
+            }
+        }
+    }
+
+    /// Sets what to do when the tables of a dataset are dropped externally. Defaults to `MissingTablePolicy::Recreate`
+    pub fn set_missing_table_policy(&mut self, policy: MissingTablePolicy) {
+        self.missing_table_policy = policy;
+    }
+
+    /// Checks that all the tables of a dataset exist in the database
+    fn tables_exist(&self, dataset_type: &SiemDatasetType) -> bool {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return false,
+        };
+        let mut tables = vec![format!("dataset_{}", name)];
+        if shape.has_list_table() && self.list_encoding(&name) == ListEncoding::Table {
+            tables.push(format!("dataset_list_{}", name));
+        }
+        tables
+            .iter()
+            .all(|table| self.table_schema(table).is_some())
+    }
+
+    fn create_dataset_tables(&self, dataset_type: &SiemDatasetType) {
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return,
+        };
+        match dataset_shape(dataset_type) {
+            Some(DatasetShape::TextMap) => self.create_text_map(&name),
+            Some(DatasetShape::TextMapList) => self.create_map_text_list(&name),
+            Some(DatasetShape::TextSet) => self.create_text_list(&name),
+            Some(DatasetShape::IpSet) => self.create_ip_set(&name),
+            Some(DatasetShape::IpMap) => self.create_ip_map(&name),
+            Some(DatasetShape::IpMapList) => self.create_map_ip_list(&name),
+            Some(DatasetShape::IpNet) => self.create_map_ip_net(&name),
+            Some(DatasetShape::GeoIp) => self.create_geo_ip_net(&name),
+            None => {}
+        }
+    }
+
+    fn refresh_holder(&mut self) {
+        let mut pointer_list = Vec::with_capacity(self.dataset_pointers.len());
+        for pntr in self.dataset_pointers.values() {
+            pointer_list.push(pntr.clone());
+        }
+        self.dataset_holder = DatasetHolder::from_datasets(pointer_list);
+        self.holder_generation += 1;
+    }
+
+    /// Reloads every registered dataset from the database, for example after an external modification.
+    /// All the datasets are loaded before swapping any pointer: if one fails, nothing is replaced.
+    pub fn rebuild_all(&mut self) -> Result<(), String> {
+        let mut new_datasets = Vec::with_capacity(self.registered_datasets.len());
+        for dataset_type in self.registered_datasets.keys() {
+            new_datasets.push(self.build_dataset(dataset_type)?);
+        }
+        for dataset in new_datasets {
+            self.store_dataset(dataset);
+        }
+        self.refresh_holder();
+        Ok(())
+    }
+
+    /// Swaps the contents of two registered datasets with the same shape in a single transaction and rebuilds both.
+    /// Useful for blue/green rollouts: fill a staging dataset and then swap it with the live one.
+    pub fn swap_contents(
+        &mut self,
+        a: &SiemDatasetType,
+        b: &SiemDatasetType,
+    ) -> Result<(), String> {
+        if a == b {
+            return Err(format!("Cannot swap dataset {:?} with itself", a));
+        }
+        for typ in &[a, b] {
+            if !self.registered_datasets.contains_key(typ) {
+                return Err(format!("Dataset not registered: {:?}", typ));
+            }
+        }
+        let shape = match (dataset_shape(a), dataset_shape(b)) {
+            (Some(shape_a), Some(shape_b)) if shape_a == shape_b => shape_a,
+            _ => {
+                return Err(format!(
+                    "Datasets {:?} and {:?} have different shapes",
+                    a, b
+                ))
+            }
+        };
+        let (name_a, name_b) = match (dataset_table_name(a), dataset_table_name(b)) {
+            (Some(name_a), Some(name_b)) => (name_a, name_b),
+            _ => return Err(format!("Cannot swap datasets {:?} and {:?}", a, b)),
+        };
+        // The loader of each encoding reads different tables
+        let encoding = self.list_encoding(&name_a);
+        if shape.has_list_table() && encoding != self.list_encoding(&name_b) {
+            return Err(format!(
+                "Datasets {:?} and {:?} have different list encodings",
+                a, b
+            ));
+        }
+        let mut prefixes = vec!["dataset_"];
+        if shape.has_list_table() && encoding == ListEncoding::Table {
+            prefixes.push("dataset_list_");
+        }
+        let tx = match self.conn.transaction() {
+            Ok(tx) => tx,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        for prefix in prefixes {
+            let res = tx.execute_batch(&format!(
+                "ALTER TABLE {prefix}{a} RENAME TO {prefix}{a}__swap;ALTER TABLE {prefix}{b} RENAME TO {prefix}{a};ALTER TABLE {prefix}{a}__swap RENAME TO {prefix}{b};",
+                prefix = prefix,
+                a = name_a,
+                b = name_b
+            ));
+            if let Err(e) = res {
+                return Err(format!("{}", e));
+            }
+            // The indexes keep their names, the ones of the other dataset
+            let res = swap_index_names(&tx, &format!("{}{}", prefix, name_a), &name_b, &name_a)
+                .and_then(|swapped_a| {
+                    let swapped_b = swap_index_names(&tx, &format!("{}{}", prefix, name_b), &name_a, &name_b)?;
+                    for (index, _) in swapped_a.iter().chain(swapped_b.iter()) {
+                        tx.execute_batch(&format!("DROP INDEX {}", index))?;
+                    }
+                    for (_, sql) in swapped_a.iter().chain(swapped_b.iter()) {
+                        tx.execute_batch(sql)?;
+                    }
+                    Ok(())
+                });
+            if let Err(e) = res {
+                return Err(format!("{}", e));
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(format!("{}", e));
+        }
+        self.rebuild_dataset(a)?;
+        self.rebuild_dataset(b)?;
+        Ok(())
+    }
+
+    /// Copies all the entries of a dataset into another one with the same shape, creating its tables.
+    /// The target must be empty. It's rebuilt if registered. Returns the number of entries copied.
+    pub fn clone_dataset(
+        &mut self,
+        from: &SiemDatasetType,
+        to: SiemDatasetType,
+    ) -> Result<usize, String> {
+        if from == &to {
+            return Err(format!("Cannot clone dataset {:?} into itself", from));
+        }
+        let shape = match (dataset_shape(from), dataset_shape(&to)) {
+            (Some(shape_from), Some(shape_to)) if shape_from == shape_to => shape_from,
+            _ => {
+                return Err(format!(
+                    "Datasets {:?} and {:?} have different shapes",
+                    from, to
+                ))
+            }
+        };
+        let (name_from, name_to) = match (dataset_table_name(from), dataset_table_name(&to)) {
+            (Some(name_from), Some(name_to)) => (name_from, name_to),
+            _ => return Err(format!("Cannot clone dataset {:?} into {:?}", from, to)),
+        };
+        if self.list_encoding(&name_from) != self.list_encoding(&name_to) {
+            return Err(format!(
+                "Datasets {:?} and {:?} have different list encodings",
+                from, to
+            ));
+        }
+        if !self.tables_exist(from) {
+            return Err(format!("Tables of dataset {:?} not found", from));
+        }
+        self.create_dataset_tables(&to);
+        if ttl::supports_ttl(from) {
+            // Tables created before the expiry column have one column less
+            self.ensure_expiry_column(&name_from)?;
+            self.ensure_expiry_column(&name_to)?;
+        }
+        let mut prefixes = vec!["dataset_"];
+        if shape.has_list_table() && self.list_encoding(&name_from) == ListEncoding::Table {
+            prefixes.push("dataset_list_");
+        }
+        let tx = match self.conn.transaction() {
+            Ok(tx) => tx,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let existing: i64 = match tx.query_row(
+            &format!("SELECT COUNT(*) FROM dataset_{}", name_to),
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(count) => count,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if existing > 0 {
+            return Err(format!("Dataset {:?} is not empty", to));
+        }
+        let mut copied = 0;
+        for prefix in prefixes {
+            // Same shape, same columns: the ids are kept so the list values point to their keys
+            let res = tx.execute(
+                &format!(
+                    "INSERT INTO {prefix}{to} SELECT * FROM {prefix}{from}",
+                    prefix = prefix,
+                    from = name_from,
+                    to = name_to
+                ),
+                [],
+            );
+            match res {
+                Ok(rows) if prefix == "dataset_" => copied = rows,
+                Ok(_) => {}
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(format!("{}", e));
+        }
+        if self.registered_datasets.contains_key(&to) {
+            self.rebuild_dataset(&to)?;
+        }
+        Ok(copied)
+    }
+
+    /// Channel to send commands to the run loop of this manager
+    pub fn command_channel(&self) -> Sender<DatasetCommand> {
+        self.command_chnl_snd.clone()
+    }
+
+    fn process_commands(&mut self) {
+        while let Ok(command) = self.command_chnl_rcv.try_recv() {
+            self.process_command(command);
+        }
+    }
+
+    fn process_command(&mut self, command: DatasetCommand) {
+        match command {
+            DatasetCommand::MultiUpdate(updates) => {
+                if let Err(e) = self.apply_multi_update(updates) {
+                    error!("Cannot apply MultiUpdate: {}", e);
                 }
-                SiemDatasetType::IpDNS => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_map_ip_list("IpDNS");
-                    let dataset = match dataset_ip_map_list(&self.conn,"IpDNS") {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: IpDNS")
-                    };
-                    let syn_dataset = IpMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateIpMapList(channel.0, channel.1, time), SiemDataset::IpDNS(syn_dataset))
+            }
+            DatasetCommand::Register(dataset_type, options, reply) => {
+                let res = self
+                    .register_dataset_with_options(dataset_type, options)
+                    .map(|_| self.dataset_holder.clone());
+                if let Err(e) = &res {
+                    error!("Cannot register dataset: {}", e);
                 }
-                SiemDatasetType::MacHost => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_text_map("MacHost");
-                    let dataset = match dataset_text_map(&self.conn,"MacHost") {
-                        Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: MacHost")
-                    };
-                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::MacHost(syn_dataset))
+                let _ = reply.send(res);
+            }
+        }
+    }
+
+    /// Applies updates of different datasets in a single transaction and rebuilds the affected datasets
+    /// after the commit. If any update fails, none of them is applied.
+    pub fn apply_multi_update(&mut self, updates: Vec<DatasetUpdate>) -> Result<(), String> {
+        let mut affected = BTreeSet::new();
+        let tx = match self.conn.unchecked_transaction() {
+            Ok(tx) => tx,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut events = Vec::with_capacity(updates.len());
+        for update in updates {
+            events.push(update.event());
+            let typ = update.dataset_type();
+            match self.apply_update(update) {
+                Ok(typ) => {
+                    affected.insert(typ);
                 }
-                SiemDatasetType::HostUser => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_text_map("HostUser");
-                    let dataset = match dataset_text_map(&self.conn,"HostUser") {
+                Err(e) => {
+                    // Rollback before recording the error
+                    drop(tx);
+                    self.record_error(&typ, &e);
+                    return Err(e);
+                }
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(format!("{}", e));
+        }
+        if self.batched_swaps {
+            let mut built = Vec::with_capacity(affected.len());
+            for typ in &affected {
+                match self.build_dataset(typ) {
+                    Ok(dataset) => built.push(dataset),
+                    Err(e) => {
+                        self.record_error(typ, &e);
+                        return Err(e);
+                    }
+                }
+            }
+            for dataset in built {
+                self.store_dataset(dataset);
+            }
+        } else {
+            for typ in &affected {
+                if let Err(e) = self.rebuild_dataset(typ) {
+                    self.record_error(typ, &e);
+                    return Err(e);
+                }
+            }
+        }
+        self.send_events(&events);
+        Ok(())
+    }
+
+    /// Sets the sink that receives the updates applied by the run loop. Defaults to `NoopSink`
+    pub fn set_update_sink(&mut self, sink: Box<dyn UpdateSink>) {
+        self.update_sink = sink;
+    }
+
+    /// Sends the events of the updates applied to the sink, counting them in the stats of each dataset
+    fn send_events(&mut self, events: &[UpdateEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        for event in events {
+            *self.updates_applied.entry(event.dataset_type.clone()).or_insert(0) += 1;
+        }
+        if let Err(e) = self.update_sink.on_updates(events) {
+            error!("Cannot send {} update events to the sink: {}", events.len(), e);
+        }
+    }
+
+    /// Writes an update in the database without rebuilding the dataset. Returns the updated dataset.
+    fn apply_update(&self, update: DatasetUpdate) -> Result<SiemDatasetType, String> {
+        let typ = update.dataset_type();
+        if !self.registered_datasets.contains_key(&typ) {
+            return Err(format!("Dataset not registered: {:?}", typ));
+        }
+        if dataset_shape(&typ) != Some(update.shape()) {
+            return Err(format!("Invalid update for dataset {:?}", typ));
+        }
+        let name = match dataset_table_name(&typ) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", typ)),
+        };
+        let res = match update {
+            DatasetUpdate::TextSet(_, update) => self.update_text_set(&name, update),
+            DatasetUpdate::TextMap(_, update) => self.update_text_map(&name, update),
+            DatasetUpdate::TextMapList(_, update) => self.update_map_text_list(&name, update),
+            DatasetUpdate::IpSet(_, update) => self.update_ip_set(&name, update),
+            DatasetUpdate::IpMap(_, update) => self.update_map_ip(&name, update),
+            DatasetUpdate::IpMapList(_, update) => self.update_map_ip_list(&name, update),
+            DatasetUpdate::IpNet(_, update) => self.update_ip_net(&name, update),
+            DatasetUpdate::GeoIp(update) => self.update_geo_ip(&name, update),
+        };
+        match res {
+            Ok(_) => Ok(typ),
+            Err(e) => Err(format!("Cannot update dataset {:?}: {}", typ, e)),
+        }
+    }
+
+    /// Version token of a dataset. Starts at 0 and is increased by each Replace update, from
+    /// `replace_if_version` or the channels.
+    pub fn dataset_version(&self, dataset_type: &SiemDatasetType) -> Result<i64, String> {
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        if let Err(e) = self.conn.execute("CREATE TABLE IF NOT EXISTS usiem_dataset_versions (dataset_name TEXT NOT NULL PRIMARY KEY, version INTEGER NOT NULL)", []) {
+            return Err(format!("{}", e));
+        }
+        let version = self
+            .conn
+            .query_row(
+                "SELECT version FROM usiem_dataset_versions WHERE dataset_name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional();
+        match version {
+            Ok(version) => Ok(version.unwrap_or(0)),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Applies a Replace update only if the version token of the dataset is `expected_version`, so a stale
+    /// feed can not overwrite a newer one. On success the token is increased and the new one returned.
+    pub fn replace_if_version(
+        &mut self,
+        update: DatasetUpdate,
+        expected_version: i64,
+    ) -> Result<i64, String> {
+        if !update.is_replace() {
+            return Err(String::from("Only Replace updates are versioned"));
+        }
+        let typ = update.dataset_type();
+        let version = {
+            let tx = match self.conn.unchecked_transaction() {
+                Ok(tx) => tx,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let current = self.dataset_version(&typ)?;
+            if current != expected_version {
+                return Err(format!(
+                    "Version conflict in dataset {:?}: expected {} but the current version is {}",
+                    typ, expected_version, current
+                ));
+            }
+            // The Replace increases the token
+            self.apply_update(update)?;
+            if let Err(e) = tx.commit() {
+                return Err(format!("{}", e));
+            }
+            current + 1
+        };
+        self.rebuild_dataset(&typ)?;
+        Ok(version)
+    }
+
+    /// Returns the registered datasets that contain the key. Text keyed datasets are always checked and
+    /// IP keyed datasets (sets and maps) only if the key is an IP. Network datasets are not checked.
+    pub fn find_key_everywhere(&self, key: &str) -> Result<Vec<SiemDatasetType>, String> {
+        let ip_key = match SiemIp::from_ip_str(key) {
+            Ok(ip) => Some(ip_to_vec8(&ip)),
+            Err(_) => None,
+        };
+        let mut found = Vec::new();
+        for dataset_type in self.registered_datasets.keys() {
+            let name = match dataset_table_name(dataset_type) {
+                Some(name) => name,
+                None => continue,
+            };
+            let sql = format!(
+                "SELECT COUNT(*) FROM dataset_{dataset_name} WHERE data_key = ?1",
+                dataset_name = name
+            );
+            let res = match dataset_shape(dataset_type) {
+                Some(DatasetShape::TextMap)
+                | Some(DatasetShape::TextMapList)
+                | Some(DatasetShape::TextSet) => {
+                    self.conn.query_row(&sql, params![key], |row| row.get(0))
+                }
+                Some(DatasetShape::IpSet)
+                | Some(DatasetShape::IpMap)
+                | Some(DatasetShape::IpMapList) => match &ip_key {
+                    Some(ip_key) => self.conn.query_row(&sql, params![ip_key], |row| row.get(0)),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let count: i64 = match res {
+                Ok(count) => count,
+                Err(e) => return Err(format!("Cannot query dataset {:?}: {}", dataset_type, e)),
+            };
+            if count > 0 {
+                found.push(dataset_type.clone());
+            }
+        }
+        Ok(found)
+    }
+
+    /// Removes the duplicated values of each key in a map-list dataset (IpDNS, UserTag...) and rebuilds it.
+    /// Returns the number of values removed.
+    pub fn dedupe_list_values(&mut self, dataset_type: &SiemDatasetType) -> Result<usize, String> {
+        let name = list_table_name(dataset_type)?;
+        let removed = match self.conn.execute(
+            &format!(
+                "DELETE FROM dataset_list_{dataset_name} WHERE id NOT IN (SELECT MIN(id) FROM dataset_list_{dataset_name} GROUP BY data_key, data_val)",
+                dataset_name = name
+            ),
+            [],
+        ) {
+            Ok(removed) => removed,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if self.registered_datasets.contains_key(dataset_type) {
+            self.rebuild_dataset(dataset_type)?;
+        }
+        Ok(removed)
+    }
+
+    /// Deletes the values of a map-list dataset whose key no longer exists. They are never loaded, only
+    /// take space. Returns the number of values removed.
+    pub fn compact_map_list(&mut self, dataset_type: &SiemDatasetType) -> Result<usize, String> {
+        let name = list_table_name(dataset_type)?;
+        if self.list_encoding(&name) == ListEncoding::Json {
+            return Err(format!("Dataset {:?} has no list table", dataset_type));
+        }
+        match self.conn.execute(
+            &format!(
+                "DELETE FROM dataset_list_{dataset_name} WHERE data_key NOT IN (SELECT id FROM dataset_{dataset_name})",
+                dataset_name = name
+            ),
+            [],
+        ) {
+            Ok(removed) => Ok(removed),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Enables or disables the deduplication of list values on insert for a map-list dataset.
+    /// When enabled, the existing duplicates are removed and a unique index on (data_key, data_val) is created
+    /// so repeated values are ignored from then on. The setting is persisted in the database.
+    pub fn set_list_dedupe(
+        &mut self,
+        dataset_type: &SiemDatasetType,
+        enabled: bool,
+    ) -> Result<(), String> {
+        let name = list_table_name(dataset_type)?;
+        let res = if enabled {
+            self.dedupe_list_values(dataset_type)?;
+            self.conn.execute(&format!("CREATE UNIQUE INDEX IF NOT EXISTS {list_data_key_val_index} ON dataset_list_{dataset_name} (data_key, data_val)", dataset_name = name, list_data_key_val_index = index_name("idx_list_", &name, "data_key_val")), [])
+        } else {
+            self.conn.execute(
+                &format!(
+                    "DROP INDEX IF EXISTS {list_data_key_val_index}",
+                    list_data_key_val_index = index_name("idx_list_", &name, "data_key_val")
+                ),
+                [],
+            )
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Writes the updates waiting in the channels of all the datasets now, without waiting for the flush
+    /// interval, and rebuilds the updated ones. In synchronous mode the commands are processed first.
+    pub fn force_flush(&mut self) {
+        if self.synchronous {
+            self.process_commands();
+        }
+        let registered: Vec<SiemDatasetType> = self.registered_datasets.keys().cloned().collect();
+        for dataset_type in &registered {
+            self.set_last_update(dataset_type, 0);
+        }
+        self.flush_updates();
+    }
+
+    /// Applies to SQLite the updates waiting in the channels of the datasets. Returns the datasets updated,
+    /// the events of the updates applied and the errors of the ones that failed.
+    fn receive_updates(
+        &self,
+        due: &[SiemDatasetType],
+    ) -> (BTreeSet<SiemDatasetType>, Vec<UpdateEvent>, Vec<(SiemDatasetType, String)>) {
+        let mut updated_datasets = BTreeSet::new();
+        let mut events = Vec::new();
+        let mut errors = Vec::new();
+        for dataset_name in due {
+            let listener = match self.registered_datasets.get(dataset_name) {
+                Some(listener) => listener,
+                None => continue,
+            };
+            // Custom datasets use their own name in the tables
+            let name = match dataset_table_name(dataset_name) {
+                Some(name) => name,
+                None => continue,
+            };
+            let received = match listener {
+                UpdateListener::UpdateIpMap(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_ip_map(dataset_name, update),
+                    |update| self.update_map_ip(&name, update),
+                ),
+                UpdateListener::UpdateIpSet(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_ip_set(dataset_name, update),
+                    |update| self.update_ip_set(&name, update),
+                ),
+                UpdateListener::UpdateIpMapList(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_ip_map_list(dataset_name, update),
+                    |update| self.update_map_ip_list(&name, update),
+                ),
+                UpdateListener::UpdateGeoIp(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    UpdateEvent::from_geo_ip,
+                    |update| self.update_geo_ip(&name, update),
+                ),
+                UpdateListener::UpdateTextMap(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_text_map(dataset_name, update),
+                    |update| self.update_text_map(&name, update),
+                ),
+                UpdateListener::UpdateTextSet(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_text_set(dataset_name, update),
+                    |update| self.update_text_set(&name, update),
+                ),
+                UpdateListener::UpdateTextMapList(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_text_map_list(dataset_name, update),
+                    |update| self.update_map_text_list(&name, update),
+                ),
+                UpdateListener::UpdateNetIp(_s, r, _) => drain_channel(
+                    dataset_name,
+                    r,
+                    &mut events,
+                    &mut errors,
+                    |update| UpdateEvent::from_ip_net(dataset_name, update),
+                    |update| self.update_ip_net(&name, update),
+                ),
+            };
+            if received {
+                updated_datasets.insert(dataset_name.clone());
+            }
+        }
+        if self.read_only {
+            // Nothing was written: the due datasets are reloaded to pick the changes of the file
+            return (due.iter().cloned().collect(), Vec::new(), errors);
+        }
+        (updated_datasets, events, errors)
+    }
+
+    /// Writes all the updates waiting in the channels in one transaction, without rebuilding the datasets.
+    /// Used when the run loop stops so the last updates are not lost. An update that fails is rolled back
+    /// alone, without its partial writes. Returns the updates written.
+    pub fn drain_pending(&mut self) -> usize {
+        let registered: Vec<SiemDatasetType> = self.registered_datasets.keys().cloned().collect();
+        let mut received = None;
+        let res = self.in_transaction(|| {
+            received = Some(self.receive_updates(&registered));
+            Ok(())
+        });
+        if let Err(e) = res {
+            error!("Cannot write the pending updates: {}", e);
+        }
+        let (_, events, errors) = match received {
+            Some(received) => received,
+            None => return 0,
+        };
+        for (dataset_name, e) in errors {
+            error!("Cannot update dataset {:?}: {}", dataset_name, e);
+            self.record_error(&dataset_name, &e);
+        }
+        self.send_events(&events);
+        events.len()
+    }
+
+    fn flush_updates(&mut self) {
+        let started = std::time::Instant::now();
+        self.adapt_flush_interval();
+        let interval = self.flush_interval;
+        let time = chrono::Utc::now().timestamp_millis();
+        let due = self.due_datasets(time, interval);
+        let (updated_datasets, events, errors) = self.receive_updates(&due);
+        // Checked again after a full interval, even if nothing was received
+        for dataset_name in &due {
+            self.set_last_update(dataset_name, time);
+        }
+        let processed = events.len() + errors.len();
+        for (dataset_name, e) in errors {
+            error!("Cannot update dataset {:?}: {}", dataset_name, e);
+            self.record_error(&dataset_name, &e);
+        }
+        let rebuilt = self.rebuild_updated(&updated_datasets, time);
+        self.emit_rebuilt_event(&rebuilt);
+        if !rebuilt.is_empty() {
+            self.rebuilds_since_maintenance += 1;
+        }
+        if !updated_datasets.is_empty() {
+            self.auto_analyze();
+        }
+        self.send_events(&events);
+        self.flush_histogram.record(started.elapsed(), processed);
+    }
+
+    /// Registers all the built-in datasets in one call. Returns the result of each registration.
+    pub fn register_standard_datasets(&mut self) -> Vec<(SiemDatasetType, Result<(), String>)> {
+        let standard = vec![
+            SiemDatasetType::GeoIp,
+            SiemDatasetType::IpMac,
+            SiemDatasetType::IpDNS,
+            SiemDatasetType::MacHost,
+            SiemDatasetType::HostUser,
+            SiemDatasetType::BlockIp,
+            SiemDatasetType::BlockDomain,
+            SiemDatasetType::BlockEmailSender,
+            SiemDatasetType::BlockCountry,
+            SiemDatasetType::HostVulnerable,
+            SiemDatasetType::UserTag,
+            SiemDatasetType::AssetTag,
+            SiemDatasetType::IpCloudService,
+            SiemDatasetType::IpCloudProvider,
+            SiemDatasetType::UserHeadquarters,
+            SiemDatasetType::IpHeadquarters,
+            SiemDatasetType::Configuration,
+        ];
+        let mut results = Vec::with_capacity(standard.len());
+        for dataset_type in standard {
+            // Create the tables with the schema expected by the loader of each dataset
+            self.create_dataset_tables(&dataset_type);
+            let res =
+                self.register_dataset_with_options(dataset_type.clone(), DatasetOptions::default());
+            results.push((dataset_type, res));
+        }
+        results
+    }
+
+    /// Registers a dataset like `register_dataset` with custom options.
+    /// Fails if the limit set with `with_max_datasets` has been reached, and for the types without tables:
+    /// HeadquartersWorkingHours, MantainceCalendar and the ones unknown to this version.
+    /// `SiemDatasetManager::register_dataset` only logs the error.
+    pub fn register_dataset_with_options(
+        &mut self,
+        dataset_type: SiemDatasetType,
+        options: DatasetOptions,
+    ) -> Result<(), String> {
+        let time = chrono::Utc::now().timestamp_millis();
+        if !self.registered_datasets.contains_key(&dataset_type) {
+            if let Some(name) = unchecked_table_name(&dataset_type) {
+                validate_dataset_name(&name)?;
+            }
+            if let Some(filter) = &options.load_filter {
+                filter.validate()?;
+            }
+            if options.cache_size.is_some() && !cache::supports_cache_size(&dataset_type) {
+                return Err(format!(
+                    "Cache size not supported for dataset {:?}",
+                    dataset_type
+                ));
+            }
+            if options.ttl_ms.is_some() && !ttl::supports_ttl(&dataset_type) {
+                return Err(format!("TTL not supported for dataset {:?}", dataset_type));
+            }
+            if options.channel_capacity == Some(0) {
+                return Err(format!("Invalid channel capacity for dataset {:?}: 0", dataset_type));
+            }
+            if options.geoip_countries.is_some()
+                && dataset_shape(&dataset_type) != Some(DatasetShape::GeoIp)
+            {
+                return Err(format!(
+                    "Country filter not supported for dataset {:?}",
+                    dataset_type
+                ));
+            }
+            if let Some(max_datasets) = self.max_datasets {
+                if self.registered_datasets.len() >= max_datasets {
+                    return Err(format!(
+                        "TooManyDatasets: cannot register {:?}, the limit of {} datasets has been reached",
+                        dataset_type, max_datasets
+                    ));
+                }
+            }
+            let options_ttl = options.ttl_ms;
+            // Stored before creating the tables: the schema depends on the options
+            self.dataset_options.insert(dataset_type.clone(), options);
+            let filter = self.effective_load_filter(&dataset_type);
+            let filter = filter.as_ref();
+            let (listener, dataset): (UpdateListener, SiemDataset) = match &dataset_type {
+                SiemDatasetType::CustomMapText(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_text_map(name);
+                    let dataset = match dataset_text_map(&self.conn, name, filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: HostUser")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
-                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::HostUser(syn_dataset))
+                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::CustomMapText((name.clone(),syn_dataset)))
                 }
-                SiemDatasetType::BlockIp => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_ip_set("BlockIp");
-                    let dataset = match dataset_ip_set(&self.conn,"BlockIp") {
+                SiemDatasetType::CustomIpList(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_ip_set(name);
+                    let dataset = match dataset_ip_set(&self.conn, name, filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: BlockIp")
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = IpSetSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateIpSet(channel.0, channel.1, time), SiemDataset::CustomIpList((name.clone(),syn_dataset)))
+
+                }
+                SiemDatasetType::CustomMapIpNet(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_map_ip_net(name);
+                    let dataset = match dataset_ip_net(&self.conn, name, filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = IpNetSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateNetIp(channel.0, channel.1, time), SiemDataset::CustomMapIpNet((name.clone(),syn_dataset)))
+                }
+                SiemDatasetType::CustomIpMap(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_ip_map(name);
+                    let dataset = match dataset_ip_map(&self.conn, name, filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = IpMapSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateIpMap(channel.0, channel.1, time), SiemDataset::CustomIpMap((name.clone(),syn_dataset)))
+                }
+                SiemDatasetType::CustomMapTextList(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_map_text_list(name);
+                    let dataset = match dataset_map_text_list(&self.conn, name, filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = TextMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateTextMapList(channel.0, channel.1, time), SiemDataset::CustomMapTextList((name.clone(),syn_dataset)))
+                }
+                SiemDatasetType::CustomTextList(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_text_list(name);
+                    let dataset = match dataset_text_list(&self.conn, name, filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = TextSetSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateTextSet(channel.0, channel.1, time), SiemDataset::CustomTextList((name.clone(),syn_dataset)))
+                }
+                SiemDatasetType::Secrets(name) => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_text_map(name);
+                    let dataset = match dataset_text_map(&self.conn, name, filter).and_then(|d| self.open_values(name, d)) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::Secrets((name.clone(),syn_dataset)))
+                }
+                SiemDatasetType::GeoIp => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_geo_ip_net("GeoIp");
+                    let dataset = match dataset_geo_ip_net(&self.conn,"GeoIp", filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = GeoIpSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateGeoIp(channel.0, channel.1, time), SiemDataset::GeoIp(syn_dataset))
+                }
+                SiemDatasetType::IpMac => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_ip_map("IpMac");
+                    let dataset = match dataset_ip_map(&self.conn,"IpMac", filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = IpMapSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateIpMap(channel.0, channel.1, time), SiemDataset::IpMac(syn_dataset))
+                }
+                SiemDatasetType::IpDNS => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_map_ip_list("IpDNS");
+                    let dataset = match dataset_ip_map_list(&self.conn,"IpDNS", filter, self.list_encoding("IpDNS")) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = IpMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateIpMapList(channel.0, channel.1, time), SiemDataset::IpDNS(syn_dataset))
+                }
+                SiemDatasetType::MacHost => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_text_map("MacHost");
+                    let dataset = match dataset_text_map(&self.conn,"MacHost", filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::MacHost(syn_dataset))
+                }
+                SiemDatasetType::HostUser => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_text_map("HostUser");
+                    let dataset = match dataset_text_map(&self.conn,"HostUser", filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
+                    };
+                    let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
+                    (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::HostUser(syn_dataset))
+                }
+                SiemDatasetType::BlockIp => {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_ip_set("BlockIp");
+                    let dataset = match dataset_ip_set(&self.conn,"BlockIp", filter) {
+                        Ok(d) => d,
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = IpSetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateIpSet(channel.0, channel.1, time), SiemDataset::BlockIp(syn_dataset))
                 }
                 SiemDatasetType::BlockDomain => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_text_list("BlockDomain");
-                    let dataset = match dataset_text_list(&self.conn,"BlockDomain") {
+                    let dataset = match dataset_text_list(&self.conn,"BlockDomain", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: BlockDomain")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextSetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextSet(channel.0, channel.1, time), SiemDataset::BlockDomain(syn_dataset))
                 }
                 SiemDatasetType::BlockEmailSender => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_text_list("BlockEmailSender");
-                    let dataset = match dataset_text_list(&self.conn,"BlockEmailSender") {
+                    let dataset = match dataset_text_list(&self.conn,"BlockEmailSender", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: BlockEmailSender")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextSetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextSet(channel.0, channel.1, time), SiemDataset::BlockEmailSender(syn_dataset))
                 }
                 SiemDatasetType::BlockCountry => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_text_list("BlockCountry");
-                    let dataset = match dataset_text_list(&self.conn,"BlockCountry") {
+                    let dataset = match dataset_text_list(&self.conn,"BlockCountry", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: BlockCountry")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextSetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextSet(channel.0, channel.1, time), SiemDataset::BlockCountry(syn_dataset))
                 }
                 SiemDatasetType::HostVulnerable => {
-                    let channel = crossbeam_channel::bounded(128);
-                    self.create_ip_map("HostVulnerable");
-                    let dataset = match dataset_map_text_list(&self.conn,"HostVulnerable") {
+                    let channel = self.update_channel(&dataset_type);
+                    self.create_map_text_list("HostVulnerable");
+                    let dataset = match dataset_map_text_list(&self.conn,"HostVulnerable", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: HostVulnerable")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextMapList(channel.0, channel.1, time), SiemDataset::HostVulnerable(syn_dataset))
                 }
                 SiemDatasetType::UserTag => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_map_text_list("UserTag");
-                    let dataset = match dataset_map_text_list(&self.conn, "UserTag") {
+                    let dataset = match dataset_map_text_list(&self.conn, "UserTag", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: UserTag")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextMapList(channel.0, channel.1, time), SiemDataset::UserTag(syn_dataset))
                 }
                 SiemDatasetType::AssetTag => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_map_text_list("AssetTag");
-                    let dataset = match dataset_map_text_list(&self.conn, "AssetTag") {
+                    let dataset = match dataset_map_text_list(&self.conn, "AssetTag", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: AssetTag")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapListSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextMapList(channel.0, channel.1, time), SiemDataset::AssetTag(syn_dataset))
                 }
                 SiemDatasetType::IpCloudService => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_map_ip_net("IpCloudService");
-                    let dataset = match dataset_ip_net(&self.conn, "IpCloudService") {
+                    let dataset = match dataset_ip_net(&self.conn, "IpCloudService", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: IpCloudService")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = IpNetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateNetIp(channel.0, channel.1, time), SiemDataset::IpCloudService(syn_dataset))
                 }
                 SiemDatasetType::IpCloudProvider => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_map_ip_net("IpCloudProvider");
-                    let dataset = match dataset_ip_net(&self.conn, "IpCloudProvider") {
+                    let dataset = match dataset_ip_net(&self.conn, "IpCloudProvider", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: IpCloudProvider")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = IpNetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateNetIp(channel.0, channel.1, time), SiemDataset::IpCloudProvider(syn_dataset))
                 }
                 SiemDatasetType::UserHeadquarters => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_text_map("UserHeadquarters");
-                    let dataset = match dataset_text_map(&self.conn, "UserHeadquarters") {
+                    let dataset = match dataset_text_map(&self.conn, "UserHeadquarters", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: UserHeadquarters")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::UserHeadquarters(syn_dataset))
                 }
                 SiemDatasetType::IpHeadquarters => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_map_ip_net("IpHeadquarters");
-                    let dataset = match dataset_ip_net(&self.conn, "IpHeadquarters") {
+                    let dataset = match dataset_ip_net(&self.conn, "IpHeadquarters", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: IpHeadquarters")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = IpNetSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateNetIp(channel.0, channel.1, time), SiemDataset::IpHeadquarters(syn_dataset))
                 }
                 SiemDatasetType::Configuration => {
-                    let channel = crossbeam_channel::bounded(128);
+                    let channel = self.update_channel(&dataset_type);
                     self.create_text_map("Configuration");
-                    let dataset = match dataset_text_map(&self.conn, "Configuration") {
+                    let dataset = match dataset_text_map(&self.conn, "Configuration", filter) {
                         Ok(d) => d,
-                        Err(_) => panic!("Cannot init dataset: Configuration")
+                        Err(e) => return self.init_failed(&dataset_type, e)
                     };
                     let syn_dataset = TextMapSynDataset::new(Arc::new(dataset),channel.0.clone());
                     (UpdateListener::UpdateTextMap(channel.0, channel.1, time), SiemDataset::Configuration(syn_dataset))
                 }
+                // No table layout for them yet
+                SiemDatasetType::HeadquartersWorkingHours | SiemDatasetType::MantainceCalendar => {
+                    self.dataset_options.remove(&dataset_type);
+                    return Err(format!("Dataset type not supported: {:?}", dataset_type));
+                }
+                // Added to uSIEM after this version
                 _ => {
-                    println!("Dataset type not defined!!!");
-                    return;
+                    self.dataset_options.remove(&dataset_type);
+                    return Err(format!("Unknown dataset type: {:?}", dataset_type));
                 }
             };
+            if let (Some(_), Some(name)) = (options_ttl, dataset_table_name(&dataset_type)) {
+                // Tables created before the expiry column
+                if let Err(e) = self.ensure_expiry_column(&name) {
+                    self.dataset_options.remove(&dataset_type);
+                    return Err(e);
+                }
+            }
+            self.flush_schedule.insert((listener.last_update(), dataset_type.clone()));
             self.registered_datasets.insert(dataset_type.clone(), listener);
-            self.datasets.insert(dataset_type.clone(), dataset);
-            match self.datasets.get_mut(&dataset_type) {
-                Some(v) => {
-                    let pntr = Arc::new(AtomicPtr::new(v));
-                    self.dataset_pointers.insert(dataset_type.clone(), pntr);
+            // Moving the box into the map doesn't move the dataset, so the pointer stays valid
+            let mut dataset = Box::new(dataset);
+            let pntr = Arc::new(AtomicPtr::new(&mut *dataset));
+            if let Some(previous) = self.datasets.insert(dataset_type.clone(), dataset) {
+                self.retire_dataset(previous);
+            }
+            self.dataset_pointers.insert(dataset_type.clone(), pntr);
+            self.refresh_holder();
+            if let Some(name) = dataset_table_name(&dataset_type) {
+                self.emit_lifecycle_event("registered", &dataset_type, self.row_count(&name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes a registration whose dataset cannot be loaded
+    fn init_failed(&mut self, dataset_type: &SiemDatasetType, e: DatasetError) -> Result<(), String> {
+        self.dataset_options.remove(dataset_type);
+        Err(format!("Cannot init dataset {:?}: {}", dataset_type, e))
+    }
+}
+
+impl SiemDatasetManager for SqliteDatasetManager {
+    fn name(&self) -> &str {
+        "SqliteDatasetManager"
+    }
+    fn local_channel(&self) -> Sender<SiemMessage> {
+        self.local_chnl_snd.clone()
+    }
+    fn set_kernel_sender(&mut self, sender: Sender<SiemMessage>) {
+        self.kernel_sender = sender;
+    }
+
+    fn run(&mut self) {
+        let local_chnl_rcv = self.local_chnl_rcv.clone();
+        let command_chnl_rcv = self.command_chnl_rcv.clone();
+        loop {
+            self.process_commands();
+            // Sleeps until the next dataset is due or a message arrives
+            crossbeam_channel::select! {
+                recv(local_chnl_rcv) -> msg => match msg {
+                    Ok(SiemMessage::Command(_, SiemCommandCall::STOP_COMPONENT(_))) => {
+                        // The updates waiting for their interval are written before exiting
+                        self.drain_pending();
+                        return;
+                    }
+                    Ok(SiemMessage::Command(_, command)) => {
+                        warn!("Command not supported by the dataset manager: {:?}", command);
+                    }
+                    Ok(_msg) => {}
+                    Err(_) => return,
+                },
+                recv(command_chnl_rcv) -> command => {
+                    if let Ok(command) = command {
+                        self.process_command(command);
+                    }
                 },
-                None => {
-                    panic!("Cannot found dataset!!!");
+                default(self.next_flush_wait()) => {}
+            }
+            self.flush_updates();
+            self.sweep_if_due();
+            self.maintenance_if_due();
+            self.external_reload_if_due();
+            self.free_retired();
+        }
+    }
+
+    fn get_datasets(&self) -> DatasetHolder {
+        self.dataset_holder.clone()
+    }
+    fn register_dataset(&mut self, dataset_type: SiemDatasetType) {
+        if let Err(e) = self.register_dataset_with_options(dataset_type.clone(), DatasetOptions::default()) {
+            error!("Cannot register dataset {:?}: {}", dataset_type, e);
+        }
+    }
+}
+
+/// Name used in the tables of a dataset: dataset_{name}. None for the types not supported and for custom
+/// datasets with an invalid name, which is logged, so the name never reaches the SQL.
+fn dataset_table_name(dataset_type: &SiemDatasetType) -> Option<String> {
+    let name = unchecked_table_name(dataset_type)?;
+    match validate_dataset_name(&name) {
+        Ok(_) => Some(name),
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Name of the tables of a dataset without validating custom names
+fn unchecked_table_name(dataset_type: &SiemDatasetType) -> Option<String> {
+    match dataset_type {
+        SiemDatasetType::CustomMapText(name)
+        | SiemDatasetType::CustomIpList(name)
+        | SiemDatasetType::CustomMapIpNet(name)
+        | SiemDatasetType::CustomIpMap(name)
+        | SiemDatasetType::CustomMapTextList(name)
+        | SiemDatasetType::CustomTextList(name)
+        | SiemDatasetType::Secrets(name) => Some(name.to_string()),
+        SiemDatasetType::GeoIp
+        | SiemDatasetType::IpMac
+        | SiemDatasetType::IpDNS
+        | SiemDatasetType::MacHost
+        | SiemDatasetType::HostUser
+        | SiemDatasetType::BlockIp
+        | SiemDatasetType::BlockDomain
+        | SiemDatasetType::BlockEmailSender
+        | SiemDatasetType::BlockCountry
+        | SiemDatasetType::HostVulnerable
+        | SiemDatasetType::UserTag
+        | SiemDatasetType::AssetTag
+        | SiemDatasetType::IpCloudService
+        | SiemDatasetType::IpCloudProvider
+        | SiemDatasetType::UserHeadquarters
+        | SiemDatasetType::IpHeadquarters
+        | SiemDatasetType::Configuration => Some(format!("{:?}", dataset_type)),
+        _ => None,
+    }
+}
+
+/// Longest name allowed for custom datasets
+const MAX_DATASET_NAME_LEN: usize = 64;
+/// Index names longer than this use a hash of the dataset name
+const MAX_INDEX_NAME_LEN: usize = 64;
+
+/// Checks the name of a custom dataset before using it in table names: 1 to `MAX_DATASET_NAME_LEN`
+/// ASCII letters, digits or underscores
+fn validate_dataset_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_DATASET_NAME_LEN {
+        return Err(format!(
+            "Invalid dataset name, it must have between 1 and {} characters: {}",
+            MAX_DATASET_NAME_LEN, name
+        ));
+    }
+    if !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(format!(
+            "Invalid dataset name, only letters, digits and '_' are allowed: {}",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Indexes of a table created by `index_name` for the dataset `from`, with the statement creating them
+/// under the name they get for the dataset `to`
+fn swap_index_names(conn: &Connection, table: &str, from: &str, to: &str) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name, sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL")?;
+    let indexes = stmt.query_map(params![table], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut swapped = Vec::new();
+    for index in indexes {
+        let (index, sql) = index?;
+        let plain = format!("idx_{}_", from);
+        let hashed = hashed_index_prefix("idx_", from);
+        let suffix = match (index.strip_prefix(&plain), index.strip_prefix(&hashed)) {
+            (Some(suffix), _) if index_name("idx_", from, suffix) == index => suffix,
+            (_, Some(suffix)) if index_name("idx_", from, suffix) == index => suffix,
+            // Not named after the dataset
+            _ => continue,
+        };
+        let renamed = index_name("idx_", to, suffix);
+        let sql = sql.replacen(&format!(" {} ON ", index), &format!(" {} ON ", renamed), 1);
+        swapped.push((index, sql));
+    }
+    Ok(swapped)
+}
+
+/// Name of an index of the tables of a dataset: {prefix}{name}_{suffix}. If it's too long, the name is
+/// shortened and a hash of the full name added, so two long names with the same beginning don't collide.
+fn index_name(prefix: &str, name: &str, suffix: &str) -> String {
+    let index = format!("{}{}_{}", prefix, name, suffix);
+    if index.len() <= MAX_INDEX_NAME_LEN {
+        return index;
+    }
+    format!("{}{}", hashed_index_prefix(prefix, name), suffix)
+}
+
+/// Start of the names of `index_name` too long for SQLite: the dataset name is cut and hashed
+fn hashed_index_prefix(prefix: &str, name: &str) -> String {
+    let short: String = name.chars().take(24).collect();
+    format!("{}{}_{:016x}_", prefix, short, bloom::fnv1a(name.as_bytes(), 0))
+}
+
+/// Table name of a dataset that stores its values in a dataset_list_{name} table
+fn list_table_name(dataset_type: &SiemDatasetType) -> Result<String, String> {
+    match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+        (Some(shape), Some(name)) if shape.has_list_table() => Ok(name),
+        _ => Err(format!("Dataset {:?} is not a map-list dataset", dataset_type)),
+    }
+}
+
+fn dataset_shape(dataset_type: &SiemDatasetType) -> Option<DatasetShape> {
+    match dataset_type {
+        SiemDatasetType::CustomMapText(_)
+        | SiemDatasetType::Secrets(_)
+        | SiemDatasetType::MacHost
+        | SiemDatasetType::HostUser
+        | SiemDatasetType::UserHeadquarters
+        | SiemDatasetType::Configuration => Some(DatasetShape::TextMap),
+        SiemDatasetType::CustomMapTextList(_)
+        | SiemDatasetType::HostVulnerable
+        | SiemDatasetType::UserTag
+        | SiemDatasetType::AssetTag => Some(DatasetShape::TextMapList),
+        SiemDatasetType::CustomTextList(_)
+        | SiemDatasetType::BlockDomain
+        | SiemDatasetType::BlockEmailSender
+        | SiemDatasetType::BlockCountry => Some(DatasetShape::TextSet),
+        SiemDatasetType::CustomIpList(_) | SiemDatasetType::BlockIp => Some(DatasetShape::IpSet),
+        SiemDatasetType::CustomIpMap(_) | SiemDatasetType::IpMac => Some(DatasetShape::IpMap),
+        SiemDatasetType::IpDNS => Some(DatasetShape::IpMapList),
+        SiemDatasetType::CustomMapIpNet(_)
+        | SiemDatasetType::IpCloudService
+        | SiemDatasetType::IpCloudProvider
+        | SiemDatasetType::IpHeadquarters => Some(DatasetShape::IpNet),
+        SiemDatasetType::GeoIp => Some(DatasetShape::GeoIp),
+        _ => None,
+    }
+}
+
+fn ip_form_vec8(v: &[u8]) -> Result<SiemIp, ()> {
+    if v.len() == 4 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(v);
+        Ok(SiemIp::V4(u32::from_be_bytes(bytes)))
+    } else if v.len() == 16 {
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(v);
+        Ok(SiemIp::V6(u128::from_be_bytes(bytes)))
+    } else {
+        Err(())
+    }
+}
+
+/// Longest prefix of the networks of an IP: 32 for IPv4 and 128 for IPv6
+fn max_prefix(ip: &SiemIp) -> u32 {
+    match ip {
+        SiemIp::V4(_) => 32,
+        SiemIp::V6(_) => 128,
+    }
+}
+
+/// Rejects the writes of networks with a prefix longer than their IP version allows
+fn check_prefix(ip: &SiemIp, net: u32) -> Result<(), DatasetError> {
+    if net <= max_prefix(ip) {
+        return Ok(());
+    }
+    Err(DatasetError::InvalidIp(format!("network {}/{}", ip, net)))
+}
+
+/// First address of the network of an IP with a prefix length
+fn network_address(ip: &SiemIp, net: u8) -> SiemIp {
+    match ip {
+        SiemIp::V4(ip) => SiemIp::V4(ip & u32::MAX.checked_shl(32u32.saturating_sub(net as u32)).unwrap_or(0)),
+        SiemIp::V6(ip) => SiemIp::V6(ip & u128::MAX.checked_shl(128u32.saturating_sub(net as u32)).unwrap_or(0)),
+    }
+}
+
+/// Applies the updates waiting in the channel of a dataset, keeping the events of the ones applied and
+/// the errors of the rest. Returns whether any update was received
+fn drain_channel<T, R, E: std::fmt::Display>(
+    dataset_type: &SiemDatasetType,
+    receiver: &Receiver<T>,
+    events: &mut Vec<UpdateEvent>,
+    errors: &mut Vec<(SiemDatasetType, String)>,
+    event: impl Fn(&T) -> UpdateEvent,
+    mut apply: impl FnMut(T) -> Result<R, E>,
+) -> bool {
+    let mut received = false;
+    loop {
+        match receiver.try_recv() {
+            Ok(update) => {
+                let update_event = event(&update);
+                match apply(update) {
+                    Ok(_) => events.push(update_event),
+                    Err(e) => errors.push((dataset_type.clone(), format!("{}", e))),
                 }
-            };
-            let mut pointer_list = Vec::with_capacity(self.dataset_pointers.len());
-            for (_typ, pntr) in &self.dataset_pointers {
-                pointer_list.push(pntr.clone());
+                received = true;
+            }
+            Err(crossbeam_channel::TryRecvError::Empty) => break,
+            Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                errors.push((dataset_type.clone(), String::from("Update channel disconnected")));
+                break;
+            }
+        }
+    }
+    received
+}
+
+/// IPs are stored in network order: compared as blobs, the IPs of the same version sort like the numbers
+fn ip_to_vec8(ip: &SiemIp) -> Vec<u8> {
+    match ip {
+        SiemIp::V4(v4) => v4.to_be_bytes().to_vec(),
+        SiemIp::V6(v6) => v6.to_be_bytes().to_vec(),
+    }
+}
+
+/// Rows with a key that is not an IP are left out of the dataset, so a corrupt row doesn't fail the whole load
+fn skip_malformed_key(name: &str, key: &dyn std::fmt::Debug) {
+    warn!("Skipping malformed IP key {:?} in dataset {}", key, name);
+}
+
+/// IP of a data_key: blobs from ip_to_vec8, old versions stored the IPs as text
+fn ip_from_key(key: &rusqlite::types::Value) -> Result<SiemIp, ()> {
+    match key {
+        rusqlite::types::Value::Blob(k_v) => ip_form_vec8(k_v),
+        rusqlite::types::Value::Text(k_v) => SiemIp::from_ip_str(k_v).map_err(|_| ()),
+        _ => Err(()),
+    }
+}
+
+fn dataset_ip_set(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<IpSetDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT data_key FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| row.get(0))?;
+    let mut dataset = IpSetDataset::new();
+    for row in iterator {
+        let key = row?;
+        let ip = match &key {
+            rusqlite::types::Value::Blob(_) | rusqlite::types::Value::Text(_) => ip_from_key(&key),
+            // Not a table of IPs
+            other => {
+                return Err(DatasetError::BadSchema(format!(
+                    "the data_key of dataset_{} is {} instead of an IP",
+                    name,
+                    other.data_type()
+                )))
+            }
+        };
+        match ip {
+            Ok(ip) => dataset.insert(ip),
+            Err(_) => skip_malformed_key(name, &key),
+        }
+    }
+    Ok(dataset)
+}
+fn dataset_text_list(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<TextSetDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT data_key FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| row.get(0))?;
+    let mut dataset = TextSetDataset::new();
+    for row in iterator {
+        let k_v = row?;
+        dataset.insert(Cow::Owned(k_v))
+    }
+    Ok(dataset)
+}
+fn dataset_ip_map(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<IpMapDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT data_key, data_val FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut dataset = IpMapDataset::new();
+    for row in iterator {
+        let (k, v): (rusqlite::types::Value, String) = row?;
+        match ip_from_key(&k) {
+            Ok(ip) => dataset.insert(ip, Cow::Owned(v)),
+            Err(_) => skip_malformed_key(name, &k),
+        }
+    }
+    Ok(dataset)
+}
+fn dataset_ip_map_list(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+    encoding: ListEncoding,
+) -> Result<IpMapListDataset, DatasetError> {
+    if encoding == ListEncoding::Json {
+        return dataset_ip_map_list_json(conn, name, filter);
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t1.data_key, t2.data_val FROM dataset_{dataset_name} as t1 INNER JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key{filter} ORDER BY t2.id",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, true)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut dataset = IpMapListDataset::new();
+    let mut bt: BTreeMap<Vec<u8>, Vec<Cow<'static, str>>> = BTreeMap::new();
+    for row in iterator {
+        let (k, v): (rusqlite::types::Value, String) = row?;
+        // Grouped by the bytes of the IP, so a text key and a blob key of the same IP are the same entry
+        let k = match ip_from_key(&k) {
+            Ok(ip) => ip_to_vec8(&ip),
+            Err(_) => {
+                skip_malformed_key(name, &k);
+                continue;
+            }
+        };
+        match bt.get_mut(&k) {
+            Some(ve) => ve.push(Cow::Owned(v)),
+            None => {
+                bt.insert(k, vec![Cow::Owned(v)]);
+            }
+        }
+    }
+    for (k, v) in bt.into_iter() {
+        if let Ok(ip) = ip_form_vec8(&k) {
+            dataset.insert(ip, v);
+        }
+    }
+    Ok(dataset)
+}
+fn dataset_ip_map_list_json(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<IpMapListDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT data_key, data_val FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut dataset = IpMapListDataset::new();
+    for row in iterator {
+        let (k, v): (rusqlite::types::Value, String) = row?;
+        let values: Vec<String> = match serde_json::from_str(&v) {
+            Ok(values) => values,
+            Err(e) => return Err(rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e)).into()),
+        };
+        match ip_from_key(&k) {
+            Ok(ip) => dataset.insert(ip, values.into_iter().map(Cow::Owned).collect()),
+            Err(_) => skip_malformed_key(name, &k),
+        }
+    }
+    Ok(dataset)
+}
+fn dataset_ip_net(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<IpNetDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT network, data_key, data_val FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut dataset = IpNetDataset::new();
+    for row in iterator {
+        let (net, ip, val): (i64, rusqlite::types::Value, String) = row?;
+        // A 16 byte key is an IPv6 network, which allows prefixes up to /128
+        let ip = match ip_from_key(&ip) {
+            Ok(ip) => ip,
+            Err(_) => {
+                skip_malformed_key(name, &ip);
+                continue;
+            }
+        };
+        // Like the malformed keys, a network with an invalid prefix doesn't fail the whole load
+        if net < 0 || net > i64::from(max_prefix(&ip)) {
+            warn!("Skipping network {}/{} with an invalid prefix in dataset {}", ip, net, name);
+            continue;
+        }
+        dataset.insert(ip, net as u8, Cow::Owned(val));
+    }
+    Ok(dataset)
+}
+fn dataset_text_map(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<TextMapDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT data_key, data_val FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| {
+        Ok(KeyValTextMap {
+            key: row.get(0)?,
+            val: row.get(1)?,
+        })
+    })?;
+    let mut dataset = TextMapDataset::new();
+    for row in iterator {
+        let k_v = row?;
+        dataset.insert(Cow::Owned(k_v.key), Cow::Owned(k_v.val))
+    }
+    Ok(dataset)
+}
+fn dataset_map_text_list(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<TextMapListDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t1.data_key, t2.data_val FROM dataset_{dataset_name} as t1 INNER JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, true)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut dataset = TextMapListDataset::new();
+    let mut bt: BTreeMap<String, Vec<Cow<'static, str>>> = BTreeMap::new();
+
+    for row in iterator {
+        let (k, v): (String, String) = row?;
+        if bt.contains_key(&k) {
+            if let Some(ve) = bt.get_mut(&k) {
+                ve.push(Cow::Owned(v));
+            }
+        } else {
+            bt.insert(k.to_string(), vec![Cow::Owned(v)]);
+        }
+    }
+    for (k, v) in bt.into_iter() {
+        dataset.insert(Cow::Owned(k), v);
+    }
+    Ok(dataset)
+}
+fn dataset_geo_ip_net(
+    conn: &Connection,
+    name: &str,
+    filter: Option<&SqlFilter>,
+) -> Result<GeoIpDataset, DatasetError> {
+    let mut stmt = conn.prepare(&format!(
+        // The coordinates are stored in TEXT columns
+        "SELECT network, data_key, country, city, CAST(latitude AS REAL), CAST(longitude AS REAL), isp FROM dataset_{dataset_name}{filter}",
+        dataset_name = name,
+        filter = load_filter_sql(filter, name, false)
+    ))?;
+    let iterator = stmt.query_map(params_from_iter(load_filter_params(filter)), |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+        ))
+    })?;
+    let mut dataset = GeoIpDataset::new();
+
+    for row in iterator {
+        let (n, k, country, city, latitude, longitude, isp): (
+            u8,
+            rusqlite::types::Value,
+            String,
+            String,
+            f32,
+            f32,
+            String,
+        ) = row?;
+        match ip_from_key(&k) {
+            Ok(ip) => dataset.insert(
+                ip,
+                n,
+                GeoIpInfo {
+                    country: Cow::Owned(country),
+                    city: Cow::Owned(city),
+                    latitude,
+                    longitude,
+                    isp: Cow::Owned(isp),
+                },
+            ),
+            Err(_) => skip_malformed_key(name, &k),
+        }
+    }
+    Ok(dataset)
+}
+
+#[cfg(test)]
+mod tests {
+    use usiem::{
+        components::{
+            command::{SiemCommandCall, SiemCommandHeader},
+            common::{SiemComponentCapabilities, SiemComponentStateStorage},
+            dataset::holder::DatasetHolder,
+            SiemComponent,
+        },
+        events::SiemLog,
+    };
+
+    use super::*;
+
+    #[derive(Clone)]
+    pub struct BasicComponent {
+        /// Send actions to the kernel
+        kernel_sender: Sender<SiemMessage>,
+        /// Send actions to this components
+        local_chnl_snd: Sender<SiemMessage>,
+        /// Receive logs
+        log_receiver: Receiver<SiemLog>,
+        log_sender: Sender<SiemLog>,
+        datasets: DatasetHolder,
+        id: u64,
+    }
+    impl BasicComponent {
+        pub fn new() -> BasicComponent {
+            let (kernel_sender, _receiver) = crossbeam_channel::bounded(1000);
+            let (local_chnl_snd, _local_chnl_rcv) = crossbeam_channel::unbounded();
+            let (log_sender, log_receiver) = crossbeam_channel::unbounded();
+            BasicComponent {
+                kernel_sender,
+                local_chnl_snd,
+                log_receiver,
+                log_sender,
+                id: 0,
+                datasets: DatasetHolder::from_datasets(vec![]),
+            }
+        }
+    }
+
+    impl SiemComponent for BasicComponent {
+        fn id(&self) -> u64 {
+            self.id
+        }
+        fn set_id(&mut self, id: u64) {
+            self.id = id;
+        }
+        fn name(&self) -> &str {
+            "BasicParser"
+        }
+        fn local_channel(&self) -> Sender<SiemMessage> {
+            self.local_chnl_snd.clone()
+        }
+        fn set_log_channel(&mut self, log_sender: Sender<SiemLog>, receiver: Receiver<SiemLog>) {
+            self.log_receiver = receiver;
+            self.log_sender = log_sender;
+        }
+        fn set_kernel_sender(&mut self, sender: Sender<SiemMessage>) {
+            self.kernel_sender = sender;
+        }
+        fn duplicate(&self) -> Box<dyn SiemComponent> {
+            Box::new(self.clone())
+        }
+        fn set_datasets(&mut self, datasets: DatasetHolder) {
+            self.datasets = datasets;
+        }
+        fn run(&mut self) {
+            if let Some(SiemDataset::IpMac(dataset)) = self.datasets.get(&SiemDatasetType::IpMac) {
+                for i in 0..10000 {
+                    dataset.insert(SiemIp::V4(i), Cow::Owned(format!("IP:{}", i)));
+                }
+            }
+        }
+        fn set_storage(&mut self, _conn: Box<dyn SiemComponentStateStorage>) {}
+
+        /// Capabilities and actions that can be performed on this component
+        fn capabilities(&self) -> SiemComponentCapabilities {
+            SiemComponentCapabilities::new(
+                Cow::Borrowed("BasicDummyComponent"),
+                Cow::Borrowed("Basic dummy component for testing purposes"),
+                Cow::Borrowed(""), // No HTML
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+            )
+        }
+    }
+
+    /// The IP is in the BlockIp dataset of the holder of the manager
+    fn block_ip_contains(manager: &SqliteDatasetManager, ip: &SiemIp) -> bool {
+        match manager.get_datasets().get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => dataset.contains(ip),
+            _ => panic!("Dataset BlockIp not found"),
+        }
+    }
+
+    #[test]
+    fn test_ip_conversion() {
+        let ip100 = SiemIp::V4(100);
+        let arry = ip_to_vec8(&ip100);
+        let ip100_2 = ip_form_vec8(&arry).unwrap();
+        assert_eq!(ip100,ip100_2);
+
+        let ip100 = SiemIp::V6(1234567);
+        let arry = ip_to_vec8(&ip100);
+        let ip100_2 = ip_form_vec8(&arry).unwrap();
+        assert_eq!(ip100,ip100_2);
+    }
+
+    #[test]
+    fn test_swap_contents() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let live = SiemDatasetType::CustomMapText(Cow::Borrowed("live"));
+        let staging = SiemDatasetType::CustomMapText(Cow::Borrowed("staging"));
+        manager.register_dataset(live.clone());
+        manager.register_dataset(staging.clone());
+        manager
+            .conn
+            .execute("INSERT INTO dataset_live (data_key, data_val) VALUES ('feed', 'old')", [])
+            .unwrap();
+        manager
+            .conn
+            .execute("INSERT INTO dataset_staging (data_key, data_val) VALUES ('feed', 'new')", [])
+            .unwrap();
+        manager.swap_contents(&live, &staging).unwrap();
+
+        let datasets = manager.get_datasets();
+        match datasets.get(&live) {
+            Some(SiemDataset::CustomMapText((_, dataset))) => {
+                assert_eq!(dataset.get("feed"), Some(&Cow::Borrowed("new")));
+            }
+            _ => panic!("Dataset live not found"),
+        }
+        match datasets.get(&staging) {
+            Some(SiemDataset::CustomMapText((_, dataset))) => {
+                assert_eq!(dataset.get("feed"), Some(&Cow::Borrowed("old")));
+            }
+            _ => panic!("Dataset staging not found"),
+        }
+        assert!(manager
+            .swap_contents(&live, &SiemDatasetType::BlockIp)
+            .is_err());
+        // The indexes follow the names of the tables
+        let index_table = |manager: &SqliteDatasetManager, index: &str| -> String {
+            manager
+                .conn
+                .query_row("SELECT tbl_name FROM sqlite_master WHERE type = 'index' AND name = ?1", params![index], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(index_table(&manager, "idx_live_data_key"), "dataset_live");
+        assert_eq!(index_table(&manager, "idx_staging_data_key"), "dataset_staging");
+        let long_live = SiemDatasetType::CustomMapText(Cow::Owned("live_".repeat(12)));
+        manager.register_dataset(long_live.clone());
+        manager.swap_contents(&live, &long_live).unwrap();
+        assert_eq!(index_table(&manager, &index_name("idx_", &"live_".repeat(12), "data_key")), format!("dataset_{}", "live_".repeat(12)));
+        assert_eq!(index_table(&manager, "idx_live_data_key"), "dataset_live");
+
+        let json = SiemDatasetType::CustomMapTextList(Cow::Borrowed("json_list"));
+        let table = SiemDatasetType::CustomMapTextList(Cow::Borrowed("table_list"));
+        manager
+            .register_dataset_with_options(
+                json.clone(),
+                DatasetOptions {
+                    list_encoding: ListEncoding::Json,
+                    ..DatasetOptions::default()
+                },
+            )
+            .unwrap();
+        manager.register_dataset(table.clone());
+        assert!(manager.swap_contents(&json, &table).is_err());
+    }
+
+    #[test]
+    fn test_lifecycle_events() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        manager.set_kernel_sender(sender);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        for i in 1..3 {
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(i), Cow::Borrowed("mac"))))
+                .unwrap();
+        }
+        assert_eq!(manager.drop_dataset(&SiemDatasetType::IpMac).unwrap(), 2);
+        assert!(manager.get_datasets().get(&SiemDatasetType::IpMac).is_none());
+        assert_eq!(manager.dataset_count(), 0);
+
+        let events: Vec<serde_json::Value> = receiver
+            .try_iter()
+            .map(|msg| match msg {
+                SiemMessage::Notification(_, txt) => serde_json::from_str(&txt).unwrap(),
+                _ => panic!("Unexpected message"),
+            })
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["action"], "registered");
+        assert_eq!(events[0]["dataset"], "IpMac");
+        assert_eq!(events[0]["rows"], 0);
+        assert_eq!(events[1]["action"], "dropped");
+        assert_eq!(events[1]["rows"], 2);
+        assert!(events[1]["timestamp"].as_i64().unwrap() >= events[0]["timestamp"].as_i64().unwrap());
+    }
+
+    #[test]
+    fn test_rebuilt_events() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        manager.set_kernel_sender(sender);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let holder = manager.get_datasets();
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.insert(SiemIp::V4(1), Cow::Borrowed("mac1")),
+            _ => panic!("Dataset IpMac not found"),
+        }
+        manager.force_flush();
+        let events: Vec<serde_json::Value> = receiver
+            .try_iter()
+            .filter_map(|msg| match msg {
+                SiemMessage::Notification(_, txt) => Some(serde_json::from_str(&txt).unwrap()),
+                _ => None,
+            })
+            .filter(|event: &serde_json::Value| event["action"] == "rebuilt")
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["datasets"], serde_json::json!(["IpMac"]));
+        // Nobody listening anymore
+        drop(receiver);
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.insert(SiemIp::V4(2), Cow::Borrowed("mac2")),
+            _ => panic!("Dataset IpMac not found"),
+        }
+        manager.force_flush();
+        assert_eq!(manager.row_count("IpMac"), 2);
+    }
+
+    #[test]
+    fn test_verify_net_dataset() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpCloudProvider);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_IpCloudProvider (network, data_key, data_val) VALUES (?1, ?2, ?3)",
+                params![16, ip_to_vec8(&ip("52.95.0.0")), "AWS"],
+            )
+            .unwrap();
+        manager
+            .rebuild_dataset(&SiemDatasetType::IpCloudProvider)
+            .unwrap();
+        let probes = vec![
+            (ip("52.95.110.1"), Some(String::from("AWS"))),
+            (ip("192.168.1.1"), None),
+        ];
+        manager
+            .verify_net_dataset(&SiemDatasetType::IpCloudProvider, &probes)
+            .unwrap();
+        let err = manager
+            .verify_net_dataset(
+                &SiemDatasetType::IpCloudProvider,
+                &[(ip("8.8.8.8"), Some(String::from("Google")))],
+            )
+            .unwrap_err();
+        assert!(err.contains("failed 1 of 1 probes"), "{}", err);
+        assert!(manager
+            .verify_net_dataset(&SiemDatasetType::IpMac, &probes)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cache_size() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpMac,
+                DatasetOptions {
+                    cache_size: Some(2),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let ip = |i: u32| SiemIp::V4(i).to_string();
+        for i in 1..6 {
+            manager
+                .update_map_ip(
+                    "IpMac",
+                    UpdateIpMap::Add((SiemIp::V4(i), Cow::Owned(format!("mac{}", i)))),
+                )
+                .unwrap();
+        }
+        for (i, hits) in &[(1, 3), (2, 2), (3, 1)] {
+            for _ in 0..*hits {
+                manager.lookup(&SiemDatasetType::IpMac, &ip(*i)).unwrap();
+            }
+        }
+        manager.rebuild_dataset(&SiemDatasetType::IpMac).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert!(dataset.get(&SiemIp::V4(1)).is_some());
+                assert!(dataset.get(&SiemIp::V4(2)).is_some());
+                assert!(dataset.get(&SiemIp::V4(3)).is_none());
+            }
+            _ => panic!("Dataset IpMac not found"),
+        }
+        // Misses of the in-memory view are read from SQLite
+        assert_eq!(
+            manager.lookup(&SiemDatasetType::IpMac, &ip(5)).unwrap(),
+            Some(String::from("mac5"))
+        );
+        assert_eq!(
+            manager.lookup(&SiemDatasetType::IpMac, &ip(1)).unwrap(),
+            Some(String::from("mac1"))
+        );
+        assert_eq!(manager.lookup(&SiemDatasetType::IpMac, &ip(9)).unwrap(), None);
+        assert!(manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpDNS,
+                DatasetOptions {
+                    cache_size: Some(2),
+                    ..Default::default()
+                },
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_geoip_country() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::GeoIp);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        for (network, net, country) in &[("80.58.0.0", 16, "ES"), ("80.58.61.0", 24, "PT")] {
+            manager
+                .conn
+                .execute(
+                    "INSERT INTO dataset_GeoIp (network, data_key, country, city, latitude, longitude, isp) VALUES (?1, ?2, ?3, '', 0, 0, '')",
+                    params![net, ip_to_vec8(&ip(network)), country],
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            manager.geoip_country(&ip("80.58.1.1")).unwrap(),
+            Some(String::from("ES"))
+        );
+        assert_eq!(
+            manager.geoip_country(&ip("80.58.61.250")).unwrap(),
+            Some(String::from("PT"))
+        );
+        assert_eq!(manager.geoip_country(&ip("8.8.8.8")).unwrap(), None);
+        assert_eq!(manager.geoip_country(&SiemIp::V6(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_direct_lookups() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::GeoIp);
+        manager.create_dataset_tables(&SiemDatasetType::IpMac);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        let info = GeoIpInfo {
+            country: Cow::Borrowed("ES"),
+            city: Cow::Borrowed("Madrid"),
+            latitude: 40.5,
+            longitude: -3.5,
+            isp: Cow::Borrowed("Telefonica"),
+        };
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Add((ip("80.58.61.0"), 24, info))).unwrap();
+        manager
+            .update_map_ip("IpMac", UpdateIpMap::Add((ip("10.0.0.1"), Cow::Borrowed("00:11:22:33:44:55"))))
+            .unwrap();
+        let found = manager.lookup_geo_ip(&ip("80.58.61.250")).unwrap().unwrap();
+        assert_eq!(&found.city[..], "Madrid");
+        assert_eq!(&found.isp[..], "Telefonica");
+        assert_eq!(found.latitude, 40.5);
+        assert!(manager.lookup_geo_ip(&ip("80.58.62.1")).unwrap().is_none());
+        assert_eq!(
+            manager.lookup_ip_map(&SiemDatasetType::IpMac, &ip("10.0.0.1")).unwrap(),
+            Some(String::from("00:11:22:33:44:55"))
+        );
+        assert_eq!(manager.lookup_ip_map(&SiemDatasetType::IpMac, &ip("10.0.0.2")).unwrap(), None);
+        assert!(manager.lookup_ip_map(&SiemDatasetType::BlockIp, &ip("10.0.0.1")).is_err());
+        // Nothing was loaded in memory
+        assert!(manager.get_datasets().get(&SiemDatasetType::GeoIp).is_none());
+    }
+
+    #[test]
+    fn test_geoip_countries() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::GeoIp);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        for (network, country) in &[("80.58.0.0", "ES"), ("81.20.0.0", "PT"), ("82.64.0.0", "FR")] {
+            manager
+                .conn
+                .execute(
+                    "INSERT INTO dataset_GeoIp (network, data_key, country, city, latitude, longitude, isp) VALUES (16, ?1, ?2, '', 0, 0, '')",
+                    params![ip_to_vec8(&ip(network)), country],
+                )
+                .unwrap();
+        }
+        let options = DatasetOptions {
+            geoip_countries: Some(vec![String::from("ES"), String::from("PT")]),
+            ..Default::default()
+        };
+        manager
+            .register_dataset_with_options(SiemDatasetType::GeoIp, options.clone())
+            .unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::GeoIp) {
+            Some(SiemDataset::GeoIp(dataset)) => {
+                assert_eq!(&dataset.get(&ip("80.58.1.1")).unwrap().country[..], "ES");
+                assert_eq!(&dataset.get(&ip("81.20.1.1")).unwrap().country[..], "PT");
+                assert!(dataset.get(&ip("82.64.1.1")).is_none());
+            }
+            _ => panic!("Dataset GeoIp not found"),
+        }
+        assert!(manager
+            .register_dataset_with_options(SiemDatasetType::IpMac, options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_union_ip_datasets() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        let perimeter = SiemDatasetType::CustomIpList(Cow::Borrowed("perimeter"));
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        manager.create_dataset_tables(&perimeter);
+        for i in 1..4 {
+            manager
+                .update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(i)))
+                .unwrap();
+        }
+        for i in 3..5 {
+            manager
+                .update_ip_set("perimeter", UpdateIpSet::Add(SiemIp::V4(i)))
+                .unwrap();
+        }
+        let union = manager
+            .union_ip_datasets(&[SiemDatasetType::BlockIp, perimeter.clone()])
+            .unwrap();
+        let union: BTreeSet<String> = union.iter().map(|ip| ip.to_string()).collect();
+        let expected: BTreeSet<String> = (1..5).map(|i| SiemIp::V4(i).to_string()).collect();
+        assert_eq!(union, expected);
+        assert!(manager
+            .union_ip_datasets(&[perimeter, SiemDatasetType::HostUser])
+            .is_err());
+    }
+
+    #[test]
+    fn test_clone_dataset() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let backup = SiemDatasetType::CustomIpList(Cow::Borrowed("backup"));
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        for i in 1..4 {
+            manager
+                .update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(i)))
+                .unwrap();
+        }
+        assert_eq!(
+            manager
+                .clone_dataset(&SiemDatasetType::BlockIp, backup.clone())
+                .unwrap(),
+            3
+        );
+        let keys = |manager: &SqliteDatasetManager, typ: &SiemDatasetType| -> Vec<String> {
+            manager
+                .query_entries(typ, None, None)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.key)
+                .collect()
+        };
+        assert_eq!(keys(&manager, &backup), keys(&manager, &SiemDatasetType::BlockIp));
+        assert_eq!(keys(&manager, &backup).len(), 3);
+        // The target must be empty and have the same shape
+        assert!(manager
+            .clone_dataset(&SiemDatasetType::BlockIp, backup.clone())
+            .is_err());
+        assert!(manager
+            .clone_dataset(&SiemDatasetType::BlockIp, SiemDatasetType::IpMac)
+            .is_err());
+    }
+
+    #[test]
+    fn test_ip_map_list_replace() {
+        for encoding in &[ListEncoding::Table, ListEncoding::Json] {
+            let mut manager = SqliteDatasetManager::debug().unwrap();
+            let options = DatasetOptions {
+                list_encoding: *encoding,
+                ..Default::default()
+            };
+            manager
+                .register_dataset_with_options(SiemDatasetType::IpDNS, options)
+                .unwrap();
+            for ip in 1..3 {
+                manager
+                    .update_map_ip_list(
+                        "IpDNS",
+                        UpdateIpMapList::Add((SiemIp::V4(ip), vec![Cow::Borrowed("old.com")])),
+                    )
+                    .unwrap();
+            }
+            let mut replacement = IpMapListDataset::new();
+            replacement.insert(SiemIp::V4(2), vec![Cow::Borrowed("a.com"), Cow::Borrowed("b.com")]);
+            replacement.insert(SiemIp::V6(3), vec![Cow::Borrowed("c.com")]);
+            match manager.registered_datasets.get(&SiemDatasetType::IpDNS) {
+                Some(UpdateListener::UpdateIpMapList(s, _, _)) => {
+                    s.send(UpdateIpMapList::Replace(replacement)).unwrap()
+                }
+                _ => panic!("Invalid listener for IpDNS"),
+            }
+            manager.set_last_update(&SiemDatasetType::IpDNS, 0);
+            manager.flush_updates();
+            match manager.get_datasets().get(&SiemDatasetType::IpDNS) {
+                Some(SiemDataset::IpDNS(dataset)) => {
+                    assert!(dataset.get(&SiemIp::V4(1)).is_none());
+                    assert_eq!(
+                        dataset.get(&SiemIp::V4(2)).unwrap(),
+                        &vec![Cow::Borrowed("a.com"), Cow::Borrowed("b.com")]
+                    );
+                    assert_eq!(
+                        dataset.get(&SiemIp::V6(3)).unwrap(),
+                        &vec![Cow::Borrowed("c.com")]
+                    );
+                }
+                _ => panic!("Dataset IpDNS not found"),
+            }
+            if *encoding == ListEncoding::Table {
+                let values: i64 = manager
+                    .conn
+                    .query_row("SELECT COUNT(*) FROM dataset_list_IpDNS", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(values, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_map_list() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        for ip in 1..4 {
+            manager
+                .update_map_ip_list(
+                    "IpDNS",
+                    UpdateIpMapList::Add((
+                        SiemIp::V4(ip),
+                        vec![Cow::Borrowed("a.com"), Cow::Borrowed("b.com")],
+                    )),
+                )
+                .unwrap();
+        }
+        // Parent rows deleted without their values
+        manager
+            .conn
+            .execute(
+                "DELETE FROM dataset_IpDNS WHERE data_key IN (?1, ?2)",
+                params![ip_to_vec8(&SiemIp::V4(1)), ip_to_vec8(&SiemIp::V4(3))],
+            )
+            .unwrap();
+        assert_eq!(manager.compact_map_list(&SiemDatasetType::IpDNS).unwrap(), 4);
+        assert_eq!(manager.compact_map_list(&SiemDatasetType::IpDNS).unwrap(), 0);
+        let values: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_list_IpDNS", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(values, 2);
+        assert!(manager.compact_map_list(&SiemDatasetType::IpMac).is_err());
+    }
+
+    #[test]
+    fn test_dedupe_list_values() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        let count_values = |manager: &SqliteDatasetManager| -> i64 {
+            manager
+                .conn
+                .query_row("SELECT COUNT(*) FROM dataset_list_IpDNS", [], |row| row.get(0))
+                .unwrap()
+        };
+        manager
+            .update_map_ip_list(
+                "IpDNS",
+                UpdateIpMapList::Add((
+                    SiemIp::V4(1),
+                    vec![
+                        Cow::Borrowed("example.com"),
+                        Cow::Borrowed("example.com"),
+                        Cow::Borrowed("example.org"),
+                    ],
+                )),
+            )
+            .unwrap();
+        assert_eq!(count_values(&manager), 3);
+        assert_eq!(manager.dedupe_list_values(&SiemDatasetType::IpDNS).unwrap(), 1);
+        assert_eq!(count_values(&manager), 2);
+        match manager.get_datasets().get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => {
+                assert_eq!(dataset.get(&SiemIp::V4(1)).map(|v| v.len()), Some(2));
+            }
+            _ => panic!("Dataset IpDNS not found"),
+        }
+
+        manager.set_list_dedupe(&SiemDatasetType::IpDNS, true).unwrap();
+        manager
+            .update_map_ip_list(
+                "IpDNS",
+                UpdateIpMapList::Add((
+                    SiemIp::V4(2),
+                    vec![Cow::Borrowed("example.net"), Cow::Borrowed("example.net")],
+                )),
+            )
+            .unwrap();
+        assert_eq!(count_values(&manager), 3);
+        assert!(manager.dedupe_list_values(&SiemDatasetType::BlockIp).is_err());
+    }
+
+    struct MockResolver {
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl ReadThroughResolver for MockResolver {
+        fn resolve(
+            &self,
+            _dataset_type: &SiemDatasetType,
+            key: &str,
+        ) -> Result<Option<String>, String> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Some(format!("reputation:{}", key)))
+        }
+    }
+
+    #[test]
+    fn test_read_through() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let dataset_type = SiemDatasetType::CustomMapText(Cow::Borrowed("reputation"));
+        manager.register_dataset(dataset_type.clone());
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        manager
+            .set_read_through(
+                dataset_type.clone(),
+                Box::new(MockResolver {
+                    calls: calls.clone(),
+                }),
+                60_000,
+            )
+            .unwrap();
+        assert_eq!(
+            manager.read_through_get(&dataset_type, "1.2.3.4").unwrap(),
+            Some(String::from("reputation:1.2.3.4"))
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(
+            manager.read_through_get(&dataset_type, "1.2.3.4").unwrap(),
+            Some(String::from("reputation:1.2.3.4"))
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let cached: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_cache_reputation", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(cached, 1);
+    }
+
+    #[test]
+    fn test_multi_update() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let notes = SiemDatasetType::CustomIpMap(Cow::Borrowed("notes"));
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        manager.register_dataset(notes.clone());
+        manager
+            .apply_multi_update(vec![
+                DatasetUpdate::IpMap(
+                    SiemDatasetType::IpMac,
+                    UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("00:11:22:33:44:55"))),
+                ),
+                DatasetUpdate::IpMapList(
+                    SiemDatasetType::IpDNS,
+                    UpdateIpMapList::Add((SiemIp::V4(1), vec![Cow::Borrowed("host.local")])),
+                ),
+                DatasetUpdate::IpMap(
+                    notes.clone(),
+                    UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("blocked by rule"))),
+                ),
+            ])
+            .unwrap();
+        let datasets = manager.get_datasets();
+        match datasets.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_some()),
+            _ => panic!("Dataset IpMac not found"),
+        }
+        match datasets.get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_some()),
+            _ => panic!("Dataset IpDNS not found"),
+        }
+        match datasets.get(&notes) {
+            Some(SiemDataset::CustomIpMap((_, dataset))) => {
+                assert!(dataset.get(&SiemIp::V4(1)).is_some())
+            }
+            _ => panic!("Dataset notes not found"),
+        }
+
+        // The last update fails: nothing must be applied
+        manager.conn.execute("DROP TABLE dataset_list_IpDNS", []).unwrap();
+        let res = manager.apply_multi_update(vec![
+            DatasetUpdate::IpMap(
+                SiemDatasetType::IpMac,
+                UpdateIpMap::Add((SiemIp::V4(2), Cow::Borrowed("00:11:22:33:44:66"))),
+            ),
+            DatasetUpdate::IpMap(
+                notes.clone(),
+                UpdateIpMap::Add((SiemIp::V4(2), Cow::Borrowed("blocked by rule"))),
+            ),
+            DatasetUpdate::IpMapList(
+                SiemDatasetType::IpDNS,
+                UpdateIpMapList::Add((SiemIp::V4(2), vec![Cow::Borrowed("host2.local")])),
+            ),
+        ]);
+        assert!(res.is_err());
+        for table in &["dataset_IpMac", "dataset_notes", "dataset_IpDNS"] {
+            let count: i64 = manager
+                .conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 1, "Table {} was modified", table);
+        }
+    }
+
+    #[test]
+    fn test_dropped_table_is_recreated() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.conn.execute("DROP TABLE dataset_IpMac", []).unwrap();
+        let mut updated = BTreeSet::new();
+        updated.insert(SiemDatasetType::IpMac);
+        manager.rebuild_updated(&updated, 0);
+        assert!(manager.tables_exist(&SiemDatasetType::IpMac));
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_none()),
+            _ => panic!("Dataset IpMac not found"),
+        }
+
+        manager.set_missing_table_policy(MissingTablePolicy::Skip);
+        manager.conn.execute("DROP TABLE dataset_IpMac", []).unwrap();
+        manager.rebuild_updated(&updated, 0);
+        assert!(!manager.tables_exist(&SiemDatasetType::IpMac));
+        assert!(manager.get_datasets().get(&SiemDatasetType::IpMac).is_some());
+    }
+
+    #[test]
+    fn test_binary_export() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        let mut dataset = IpSetDataset::new();
+        {
+            let tx = manager.conn.unchecked_transaction().unwrap();
+            for i in 0..100_000 {
+                let ip = SiemIp::V4(167772160 + i);
+                tx.execute(
+                    "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                    params![ip_to_vec8(&ip)],
+                )
+                .unwrap();
+                dataset.insert(ip);
+            }
+            tx.commit().unwrap();
+        }
+        let mut exported = Vec::new();
+        assert_eq!(
+            manager
+                .export_binary(&SiemDatasetType::BlockIp, &mut exported)
+                .unwrap(),
+            100_000
+        );
+        assert!(exported.len() < serde_json::to_vec(&dataset).unwrap().len());
+
+        let copy = SiemDatasetType::CustomIpList(Cow::Borrowed("copy"));
+        assert_eq!(
+            manager.import_binary(&copy, &mut &exported[..]).unwrap(),
+            100_000
+        );
+        let keys = |table: &str| -> Vec<Vec<u8>> {
+            let mut stmt = manager
+                .conn
+                .prepare(&format!("SELECT data_key FROM {} ORDER BY id", table))
+                .unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(keys("dataset_BlockIp"), keys("dataset_copy"));
+
+        let res = manager.import_binary(&SiemDatasetType::IpMac, &mut &exported[..]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_binary_export_v1() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(0x0a000001))).unwrap();
+        let mut exported = Vec::new();
+        manager.export_binary(&SiemDatasetType::BlockIp, &mut exported).unwrap();
+        assert_eq!(exported[4], 2);
+        // One IPv4 and one IPv6 key stored little-endian
+        let mut v1 = b"USDM".to_vec();
+        v1.extend_from_slice(&[1, 4, 2, 4, 4]);
+        v1.extend_from_slice(&0x0a000002u32.to_le_bytes());
+        v1.extend_from_slice(&[4, 16]);
+        v1.extend_from_slice(&0x20010db8u128.to_le_bytes());
+        assert_eq!(manager.import_binary(&SiemDatasetType::BlockIp, &mut &v1[..]).unwrap(), 2);
+        match manager.get_datasets().get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => {
+                assert!(dataset.contains(&SiemIp::V4(0x0a000002)));
+                assert!(dataset.contains(&SiemIp::V6(0x20010db8)));
+                assert!(!dataset.contains(&SiemIp::V4(0x0a000001)));
+            }
+            _ => panic!("Dataset BlockIp not found"),
+        }
+    }
+
+    #[test]
+    fn test_export_bloom() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        {
+            let tx = manager.conn.unchecked_transaction().unwrap();
+            for i in 0..10_000 {
+                tx.execute(
+                    "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                    params![ip_to_vec8(&SiemIp::V4(167772160 + i))],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let exported = manager.export_bloom(&SiemDatasetType::BlockIp, 0.01).unwrap();
+        let filter = BloomFilter::from_bytes(&exported).unwrap();
+        for i in 0..10_000 {
+            assert!(filter.contains(&SiemIp::V4(167772160 + i).to_string()));
+        }
+        let false_positives = (0..10_000)
+            .filter(|i| filter.contains(&SiemIp::V4(3232235520 + i).to_string()))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+        assert!(manager.export_bloom(&SiemDatasetType::BlockIp, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_audit_ip_encodings() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        let keys: Vec<rusqlite::types::Value> = vec![
+            ip_to_vec8(&SiemIp::V4(1)).into(),
+            ip_to_vec8(&SiemIp::V6(1)).into(),
+            vec![1u8, 2, 3].into(),
+            String::from("192.168.1.1").into(),
+            String::from("not an ip").into(),
+        ];
+        for key in keys {
+            manager
+                .conn
+                .execute("INSERT INTO dataset_BlockIp (data_key) VALUES (?1)", [key])
+                .unwrap();
+        }
+        let report = manager.audit_ip_encodings().unwrap();
+        assert_eq!(
+            report.datasets.get("BlockIp"),
+            Some(&IpEncodingCounts {
+                valid: 2,
+                invalid: 2,
+                ambiguous: 1,
+            })
+        );
+        assert!(!report.datasets.contains_key("IpMac"));
+        assert!(report.needs_migration());
+    }
+
+    #[test]
+    fn test_keys_with_list_value() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::UserTag);
+        for (user, tags) in &[
+            ("alice", vec!["admin", "dev"]),
+            ("bob", vec!["dev"]),
+            ("carol", vec!["admin"]),
+            ("dave", vec!["admin", "admin"]),
+        ] {
+            manager
+                .conn
+                .execute("INSERT INTO dataset_UserTag (data_key) VALUES (?1)", [user])
+                .unwrap();
+            for tag in tags {
+                manager
+                    .conn
+                    .execute(
+                        "INSERT INTO dataset_list_UserTag (data_key, data_val) SELECT id, ?2 FROM dataset_UserTag WHERE data_key = ?1",
+                        [user, tag],
+                    )
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            manager
+                .keys_with_list_value(&SiemDatasetType::UserTag, "admin")
+                .unwrap(),
+            vec!["alice", "carol", "dave"]
+        );
+        assert!(manager
+            .keys_with_list_value(&SiemDatasetType::UserTag, "none")
+            .unwrap()
+            .is_empty());
+        assert!(manager
+            .keys_with_list_value(&SiemDatasetType::BlockIp, "admin")
+            .is_err());
+    }
+
+    #[test]
+    fn test_load_filter() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let dataset_type = SiemDatasetType::CustomIpMap(Cow::Borrowed("sources"));
+        manager.create_dataset_tables(&dataset_type);
+        manager
+            .conn
+            .execute("ALTER TABLE dataset_sources ADD COLUMN source TEXT", [])
+            .unwrap();
+        for (ip, source) in &[(1, "feed_a"), (2, "feed_b"), (3, "feed_a")] {
+            manager
+                .conn
+                .execute(
+                    "INSERT INTO dataset_sources (data_key, data_val, source) VALUES (?1, 'bad', ?2)",
+                    params![ip_to_vec8(&SiemIp::V4(*ip)), source],
+                )
+                .unwrap();
+        }
+        manager
+            .register_dataset_with_options(
+                dataset_type.clone(),
+                DatasetOptions {
+                    load_filter: Some(LoadFilter::equals("source", String::from("feed_a"))),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let check = |manager: &SqliteDatasetManager| match manager.get_datasets().get(&dataset_type) {
+            Some(SiemDataset::CustomIpMap((_, dataset))) => {
+                assert!(dataset.get(&SiemIp::V4(1)).is_some());
+                assert!(dataset.get(&SiemIp::V4(2)).is_none());
+                assert!(dataset.get(&SiemIp::V4(3)).is_some());
+            }
+            _ => panic!("Dataset sources not found"),
+        };
+        check(&manager);
+        manager.rebuild_dataset(&dataset_type).unwrap();
+        check(&manager);
+    }
+
+    #[test]
+    fn test_load_filter_predicates() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let dataset_type = SiemDatasetType::CustomIpList(Cow::Borrowed("mixed"));
+        manager.create_dataset_tables(&dataset_type);
+        for ip in &[SiemIp::V4(1), SiemIp::V6(2), SiemIp::V4(3)] {
+            manager
+                .conn
+                .execute("INSERT INTO dataset_mixed (data_key) VALUES (?1)", params![ip_to_vec8(ip)])
+                .unwrap();
+        }
+        // The value is bound, not pasted in the SQL
+        let filter = LoadFilter::All(vec![
+            LoadFilter::Ipv4Keys,
+            LoadFilter::one_of("data_key", vec![ip_to_vec8(&SiemIp::V4(3)), b"' OR 1=1 --".to_vec()]),
+        ]);
+        manager
+            .register_dataset_with_options(
+                dataset_type.clone(),
+                DatasetOptions {
+                    load_filter: Some(filter),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        match manager.get_datasets().get(&dataset_type) {
+            Some(SiemDataset::CustomIpList((_, dataset))) => {
+                assert!(!dataset.contains(&SiemIp::V4(1)));
+                assert!(!dataset.contains(&SiemIp::V6(2)));
+                assert!(dataset.contains(&SiemIp::V4(3)));
+            }
+            _ => panic!("Dataset mixed not found"),
+        };
+        let res = manager.register_dataset_with_options(
+            SiemDatasetType::CustomIpList(Cow::Borrowed("injected")),
+            DatasetOptions {
+                load_filter: Some(LoadFilter::equals("1=1 OR source", String::from("feed_a"))),
+                ..Default::default()
+            },
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_find_key_everywhere() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::MacHost);
+        manager.register_dataset(SiemDatasetType::HostUser);
+        manager.register_dataset(SiemDatasetType::Configuration);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager
+            .conn
+            .execute_batch("INSERT INTO dataset_MacHost (data_key, data_val) VALUES ('host1', 'mac1');INSERT INTO dataset_HostUser (data_key, data_val) VALUES ('host1', 'user1');INSERT INTO dataset_Configuration (data_key, data_val) VALUES ('host2', 'value');")
+            .unwrap();
+        let found = manager.find_key_everywhere("host1").unwrap();
+        assert_eq!(
+            found,
+            vec![SiemDatasetType::MacHost, SiemDatasetType::HostUser]
+        );
+        assert!(manager.find_key_everywhere("192.168.1.1").unwrap().is_empty());
+    }
+
+    fn temp_db_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "usiem_dm_sqlite_{}_{}.db",
+            name,
+            std::process::id()
+        ));
+        let path = path.to_string_lossy().to_string();
+        remove_db(&path);
+        path
+    }
+
+    fn remove_db(path: &str) {
+        for suffix in &["", "-wal", "-shm", "-journal"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_storage_kind() {
+        let path = temp_db_path("storage_kind");
+        let mut manager = SqliteDatasetManager::new(path.clone()).unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let options = DatasetOptions {
+            ephemeral: true,
+            ..Default::default()
+        };
+        manager
+            .register_dataset_with_options(SiemDatasetType::BlockIp, options)
+            .unwrap();
+        manager
+            .update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(1)))
+            .unwrap();
+        assert_eq!(
+            manager.dataset_storage_kind(&SiemDatasetType::IpMac),
+            StorageKind::Main
+        );
+        assert_eq!(
+            manager.dataset_storage_kind(&SiemDatasetType::BlockIp),
+            StorageKind::Memory
+        );
+        assert_eq!(
+            manager.dataset_storage_kind(&SiemDatasetType::BlockDomain),
+            StorageKind::Missing
+        );
+        assert_eq!(manager.row_count("BlockIp"), 1);
+        let conn = Connection::open(&path).unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'dataset_BlockIp'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 0);
+        drop(conn);
+        let memory = SqliteDatasetManager::debug().unwrap();
+        memory.create_dataset_tables(&SiemDatasetType::IpMac);
+        assert_eq!(
+            memory.dataset_storage_kind(&SiemDatasetType::IpMac),
+            StorageKind::Memory
+        );
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_read_pool() {
+        let path = temp_db_path("read_pool");
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            assert!(SqliteDatasetManager::debug().unwrap().read_pool(1).is_err());
+            manager.register_dataset(SiemDatasetType::IpMac);
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(0), Cow::Borrowed("mac0"))))
+                .unwrap();
+            let pool = manager.read_pool(2).unwrap();
+            let reader_pool = pool.clone();
+            let reader = std::thread::spawn(move || {
+                let mut max_rows = 0;
+                for _ in 0..200 {
+                    let conn = reader_pool.get().unwrap();
+                    let rows: i64 = conn
+                        .query_row("SELECT COUNT(*) FROM dataset_IpMac", [], |row| row.get(0))
+                        .unwrap();
+                    assert!(rows >= max_rows);
+                    max_rows = rows;
+                    // The loader runs on pooled connections too
+                    let dataset = dataset_ip_map(&conn, "IpMac", None).unwrap();
+                    assert!(dataset.get(&SiemIp::V4(0)).is_some());
+                }
+                max_rows
+            });
+            for i in 1..200 {
+                manager
+                    .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(i), Cow::Borrowed("mac"))))
+                    .unwrap();
+            }
+            assert!(reader.join().unwrap() >= 1);
+            assert_eq!(pool.idle_connections(), 2);
+            let conn = pool.get().unwrap();
+            assert_eq!(pool.idle_connections(), 1);
+            assert!(conn.execute("DELETE FROM dataset_IpMac", []).is_err());
+            assert!(manager.read_pool(0).is_err());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let path = temp_db_path("connection_options");
+        {
+            let manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            let journal_mode: String = manager
+                .conn
+                .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(journal_mode, "wal");
+            let synchronous: i64 = manager
+                .conn
+                .query_row("PRAGMA synchronous", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(synchronous, 1);
+        }
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}-wal", path));
+        let _ = std::fs::remove_file(format!("{}-shm", path));
+        let path = temp_db_path("connection_options_defaults");
+        {
+            let manager =
+                SqliteDatasetManager::new_with_options(path.clone(), ConnectionOptions::sqlite_defaults()).unwrap();
+            let journal_mode: String = manager
+                .conn
+                .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(journal_mode, "delete");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_update_ip_set() {
+        // cargo test --release bench_update_ip_set -- --ignored --nocapture
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_ip_set("IpSetA");
+        manager.create_ip_set("IpSetB");
+        let sql = "INSERT OR REPLACE INTO dataset_IpSetA (data_key) VALUES (?1)";
+        let start = std::time::Instant::now();
+        manager.conn.execute_batch("BEGIN").unwrap();
+        for i in 0..50_000 {
+            manager.conn.execute(sql, params![ip_to_vec8(&SiemIp::V4(i))]).unwrap();
+        }
+        manager.conn.execute_batch("COMMIT").unwrap();
+        let uncached = start.elapsed();
+        let start = std::time::Instant::now();
+        manager.conn.execute_batch("BEGIN").unwrap();
+        for i in 0..50_000 {
+            manager.update_ip_set("IpSetB", UpdateIpSet::Add(SiemIp::V4(i))).unwrap();
+        }
+        manager.conn.execute_batch("COMMIT").unwrap();
+        let cached = start.elapsed();
+        println!("50000 IpSet adds: {:?} parsing each statement, {:?} cached", uncached, cached);
+        assert_eq!(manager.row_count("IpSetB"), 50_000);
+        assert!(cached < uncached);
+    }
+
+    #[test]
+    fn test_relocate() {
+        let path = temp_db_path("relocate_src");
+        let new_path = temp_db_path("relocate_dst");
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            manager.register_dataset(SiemDatasetType::IpMac);
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1"))))
+                .unwrap();
+            // Pending in the channel, written before moving the file
+            match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+                Some(UpdateListener::UpdateIpMap(s, _, _)) => s
+                    .send(UpdateIpMap::Add((SiemIp::V4(2), Cow::Borrowed("mac2"))))
+                    .unwrap(),
+                _ => panic!("Invalid listener for IpMac"),
+            }
+            manager.relocate(&new_path).unwrap();
+            assert!(!std::path::Path::new(&path).exists());
+            assert!(std::path::Path::new(&new_path).exists());
+            assert_eq!(manager.dataset_count(), 1);
+            match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+                Some(SiemDataset::IpMac(dataset)) => {
+                    assert!(dataset.get(&SiemIp::V4(1)).is_some());
+                    assert!(dataset.get(&SiemIp::V4(2)).is_some());
+                }
+                _ => panic!("Dataset IpMac not found"),
+            }
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(3), Cow::Borrowed("mac3"))))
+                .unwrap();
+        }
+        let conn = Connection::open(&new_path).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM dataset_IpMac", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+        drop(conn);
+        assert!(SqliteDatasetManager::debug()
+            .unwrap()
+            .relocate(&new_path)
+            .is_err());
+        remove_db(&new_path);
+    }
+
+    #[test]
+    fn test_relocate_keeps_options() {
+        let path = temp_db_path("relocate_options_src");
+        let new_path = temp_db_path("relocate_options_dst");
+        let ro_path = temp_db_path("relocate_options_ro");
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .path(path.clone())
+            .connection_options(ConnectionOptions {
+                synchronous: Some(String::from("OFF")),
+                busy_timeout: Some(std::time::Duration::from_millis(1234)),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.relocate(&new_path).unwrap();
+        let synchronous: i64 = manager
+            .conn
+            .query_row("PRAGMA synchronous", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(synchronous, 0);
+        let busy_timeout: i64 = manager
+            .conn
+            .query_row("PRAGMA busy_timeout", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(busy_timeout, 1234);
+        // A failed move keeps the connection to the file
+        let missing_dir = std::env::temp_dir().join("usiem_dm_sqlite_missing").join("db.sqlite");
+        assert!(manager.relocate(&missing_dir.to_string_lossy()).is_err());
+        assert_eq!(manager.database_path().unwrap(), Some(new_path.clone()));
+        drop(manager);
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .path(new_path.clone())
+            .read_only(true)
+            .build()
+            .unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.relocate(&ro_path).unwrap();
+        assert!(manager
+            .conn
+            .execute("INSERT INTO dataset_IpMac (data_key, data_val) VALUES (X'01', 'mac')", [])
+            .is_err());
+        drop(manager);
+        remove_db(&path);
+        remove_db(&new_path);
+        remove_db(&ro_path);
+    }
+
+    #[test]
+    fn test_mmap_size() {
+        let path = temp_db_path("mmap");
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone())
+                .unwrap()
+                .with_mmap_size(64 * 1024 * 1024)
+                .unwrap();
+            manager.register_dataset(SiemDatasetType::IpMac);
+            manager
+                .update_map_ip(
+                    "IpMac",
+                    UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("00:11:22:33:44:55"))),
+                )
+                .unwrap();
+            manager.rebuild_dataset(&SiemDatasetType::IpMac).unwrap();
+            match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+                Some(SiemDataset::IpMac(dataset)) => assert_eq!(
+                    dataset.get(&SiemIp::V4(1)),
+                    Some(&Cow::Borrowed("00:11:22:33:44:55"))
+                ),
+                _ => panic!("Dataset IpMac not found"),
+            }
+        }
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_page_size() {
+        let path = temp_db_path("page_size");
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone())
+                .unwrap()
+                .with_page_size(16384)
+                .unwrap();
+            manager.register_dataset(SiemDatasetType::HostUser);
+            assert_eq!(manager.page_size().unwrap(), 16384);
+        }
+        let manager = SqliteDatasetManager::new(path.clone())
+            .unwrap()
+            .with_page_size(8192)
+            .unwrap();
+        assert_eq!(manager.page_size().unwrap(), 8192);
+        assert!(manager.with_page_size(1000).is_err());
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let path = temp_db_path("corrupt");
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            manager.register_dataset(SiemDatasetType::HostUser);
+            let tx = manager.conn.unchecked_transaction().unwrap();
+            for i in 0..2000 {
+                tx.execute(
+                    "INSERT INTO dataset_HostUser (data_key, data_val) VALUES (?1, 'user')",
+                    [format!("host{}", i)],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        assert!(SqliteDatasetManager::new(path.clone())
+            .unwrap()
+            .with_integrity_check()
+            .is_ok());
+        let mut content = std::fs::read(&path).unwrap();
+        for byte in content[4096..12288].iter_mut() {
+            *byte = 0xA5;
+        }
+        std::fs::write(&path, content).unwrap();
+        match SqliteDatasetManager::new(path.clone())
+            .unwrap()
+            .with_integrity_check()
+        {
+            Err(e) => assert!(e.starts_with("Corrupt database"), "{}", e),
+            Ok(_) => panic!("The database should be corrupt"),
+        }
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_rebuild_all() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::MacHost);
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_IpMac (data_key, data_val) VALUES (?1, 'mac1')",
+                params![ip_to_vec8(&SiemIp::V4(1))],
+            )
+            .unwrap();
+        manager
+            .conn
+            .execute("INSERT INTO dataset_MacHost (data_key, data_val) VALUES ('mac1', 'host1')", [])
+            .unwrap();
+        manager.rebuild_all().unwrap();
+        let datasets = manager.get_datasets();
+        match datasets.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert_eq!(dataset.get(&SiemIp::V4(1)), Some(&Cow::Borrowed("mac1")))
+            }
+            _ => panic!("Dataset IpMac not found"),
+        }
+        match datasets.get(&SiemDatasetType::MacHost) {
+            Some(SiemDataset::MacHost(dataset)) => {
+                assert_eq!(dataset.get("mac1"), Some(&Cow::Borrowed("host1")))
+            }
+            _ => panic!("Dataset MacHost not found"),
+        }
+    }
+
+    /// Adds an IP to a map with the policy, then adds it again with another value. Returns the result of the
+    /// second Add, the value loaded and whether the entry kept its row
+    fn readd_with_policy(on_conflict: OnConflict) -> (bool, String, bool) {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let dataset_type = SiemDatasetType::CustomIpMap(Cow::Borrowed("owners"));
+        manager
+            .register_dataset_with_options(
+                dataset_type.clone(),
+                DatasetOptions {
+                    on_conflict,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let id = |manager: &SqliteDatasetManager| -> i64 {
+            manager
+                .conn
+                .query_row("SELECT id FROM dataset_owners", [], |row| row.get(0))
+                .unwrap()
+        };
+        manager
+            .update_map_ip("owners", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("A"))))
+            .unwrap();
+        let first_id = id(&manager);
+        let res = manager.update_map_ip("owners", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("B"))));
+        manager.rebuild_dataset(&dataset_type).unwrap();
+        let value = match manager.get_datasets().get(&dataset_type) {
+            Some(SiemDataset::CustomIpMap((_, dataset))) => dataset.get(&SiemIp::V4(1)).unwrap().to_string(),
+            _ => panic!("Dataset owners not found"),
+        };
+        (res.is_ok(), value, id(&manager) == first_id)
+    }
+
+    #[test]
+    fn test_on_conflict_abort() {
+        assert_eq!(readd_with_policy(OnConflict::Abort), (false, String::from("A"), true));
+    }
+
+    #[test]
+    fn test_on_conflict_ignore() {
+        assert_eq!(readd_with_policy(OnConflict::Ignore), (true, String::from("A"), true));
+    }
+
+    #[test]
+    fn test_on_conflict_replace() {
+        assert_eq!(readd_with_policy(OnConflict::Replace), (true, String::from("B"), true));
+        assert_eq!(OnConflict::default(), OnConflict::Replace);
+    }
+
+    #[test]
+    fn test_readd_updates_value() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::MacHost);
+        manager.update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("A")))).unwrap();
+        manager.update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("B")))).unwrap();
+        manager
+            .update_text_map("MacHost", UpdateTextMap::Add((Cow::Borrowed("00:11"), Cow::Borrowed("A"))))
+            .unwrap();
+        manager
+            .update_text_map("MacHost", UpdateTextMap::Add((Cow::Borrowed("00:11"), Cow::Borrowed("B"))))
+            .unwrap();
+        manager.rebuild_all().unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => assert_eq!(&dataset.get(&SiemIp::V4(1)).unwrap()[..], "B"),
+            _ => panic!("Dataset IpMac not found"),
+        }
+        match manager.get_datasets().get(&SiemDatasetType::MacHost) {
+            Some(SiemDataset::MacHost(dataset)) => assert_eq!(&dataset.get("00:11").unwrap()[..], "B"),
+            _ => panic!("Dataset MacHost not found"),
+        }
+        assert_eq!(manager.row_count("IpMac"), 1);
+    }
+
+    #[test]
+    fn test_remove_entries() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::GeoIp);
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        let ip = SiemIp::V4(1);
+        let info = GeoIpInfo {
+            country: Cow::Borrowed("ES"),
+            city: Cow::Borrowed("Madrid"),
+            latitude: 0.0,
+            longitude: 0.0,
+            isp: Cow::Borrowed(""),
+        };
+        manager.update_map_ip("IpMac", UpdateIpMap::Add((ip.clone(), Cow::Borrowed("mac1")))).unwrap();
+        manager.update_ip_set("BlockIp", UpdateIpSet::Add(ip.clone())).unwrap();
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Add((ip.clone(), 32, info))).unwrap();
+        manager
+            .update_map_ip_list("IpDNS", UpdateIpMapList::Add((ip.clone(), vec![Cow::Borrowed("host1.local")])))
+            .unwrap();
+        manager.update_map_ip("IpMac", UpdateIpMap::Remove(ip.clone())).unwrap();
+        manager.update_ip_set("BlockIp", UpdateIpSet::Remove(ip.clone())).unwrap();
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Remove((ip.clone(), 32))).unwrap();
+        manager.update_map_ip_list("IpDNS", UpdateIpMapList::Remove(ip.clone())).unwrap();
+        manager.rebuild_all().unwrap();
+        let holder = manager.get_datasets();
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => assert!(dataset.get(&ip).is_none()),
+            _ => panic!("Dataset IpMac not found"),
+        }
+        match holder.get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => assert!(!dataset.contains(&ip)),
+            _ => panic!("Dataset BlockIp not found"),
+        }
+        match holder.get(&SiemDatasetType::GeoIp) {
+            Some(SiemDataset::GeoIp(dataset)) => assert!(dataset.get(&ip).is_none()),
+            _ => panic!("Dataset GeoIp not found"),
+        }
+        match holder.get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => assert!(dataset.get(&ip).is_none()),
+            _ => panic!("Dataset IpDNS not found"),
+        }
+        assert_eq!(manager.row_count("IpDNS"), 0);
+        let list_rows: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_list_IpDNS", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(list_rows, 0);
+    }
+
+    #[test]
+    fn test_create_indexes() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::UserTag);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::IpCloudProvider);
+        let exists = |manager: &SqliteDatasetManager, kind: &str, name: &str| -> bool {
+            manager
+                .conn
+                .query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE type = ?1 AND name = ?2",
+                    params![kind, name],
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+                == 1
+        };
+        assert!(exists(&manager, "table", "dataset_UserTag"));
+        assert!(exists(&manager, "table", "dataset_list_UserTag"));
+        // Created by the statements after the CREATE TABLE
+        assert!(exists(&manager, "index", "idx_IpMac_data_key"));
+        assert!(exists(&manager, "index", "idx_IpCloudProvider_data_key"));
+    }
+
+    #[test]
+    fn test_recent_entries() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        for (ip, mac) in &[(1, "mac1"), (2, "mac2"), (3, "mac3")] {
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(*ip), Cow::Borrowed(mac))))
+                .unwrap();
+        }
+        let entries = manager.recent_entries(&SiemDatasetType::IpMac, 2).unwrap();
+        let entries: Vec<(String, Vec<String>)> = entries
+            .into_iter()
+            .map(|entry| (entry.key, entry.values))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (SiemIp::V4(3).to_string(), vec![String::from("mac3")]),
+                (SiemIp::V4(2).to_string(), vec![String::from("mac2")]),
+            ]
+        );
+        assert_eq!(
+            manager
+                .recent_entries(&SiemDatasetType::IpMac, 10)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_diff_against_feed() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::BlockIp);
+        for ip in &["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            manager
+                .conn
+                .execute(
+                    "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                    params![ip_to_vec8(&SiemIp::from_ip_str(ip).unwrap())],
+                )
+                .unwrap();
+        }
+        let feed_path = temp_db_path("feed");
+        std::fs::write(&feed_path, "# blocklist\n10.0.0.2\n10.0.0.3\n\n10.0.0.4\n").unwrap();
+        let diff = manager
+            .diff_against_feed(&SiemDatasetType::BlockIp, &feed_path)
+            .unwrap();
+        remove_db(&feed_path);
+        assert_eq!(
+            diff.add,
+            vec![FeedEntry {
+                key: String::from("10.0.0.4"),
+                value: None
+            }]
+        );
+        assert_eq!(
+            diff.remove,
+            vec![FeedEntry {
+                key: String::from("10.0.0.1"),
+                value: None
+            }]
+        );
+        assert_eq!(manager.recent_entries(&SiemDatasetType::BlockIp, 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        for (ip, mac) in &[(1, "mac1"), (2, "mac2"), (3, "mac3")] {
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(*ip), Cow::Borrowed(*mac))))
+                .unwrap();
+        }
+        let delta_path = temp_db_path("delta");
+        std::fs::write(
+            &delta_path,
+            "# daily delta\n[add]\n0.0.0.4,mac4\n0.0.0.5, mac5\n[remove]\n0.0.0.1\n0.0.0.3,mac3\n0.0.0.9\n",
+        )
+        .unwrap();
+        let counts = manager.apply_delta(&SiemDatasetType::IpMac, &delta_path);
+        std::fs::write(&delta_path, "0.0.0.6,mac6\n").unwrap();
+        let outside = manager.apply_delta(&SiemDatasetType::IpMac, &delta_path);
+        remove_db(&delta_path);
+        assert_eq!(counts.unwrap(), (2, 2));
+        assert!(outside.is_err());
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert!(dataset.get(&SiemIp::V4(1)).is_none());
+                assert_eq!(&dataset.get(&SiemIp::V4(2)).unwrap()[..], "mac2");
+                assert!(dataset.get(&SiemIp::V4(3)).is_none());
+                assert_eq!(&dataset.get(&SiemIp::V4(4)).unwrap()[..], "mac4");
+                assert_eq!(&dataset.get(&SiemIp::V4(5)).unwrap()[..], "mac5");
+            }
+            _ => panic!("Dataset IpMac not found"),
+        }
+    }
+
+    #[test]
+    fn test_dataset_entries() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager
+            .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1"))))
+            .unwrap();
+        manager
+            .conn
+            .execute_batch("CREATE TABLE dataset_BlockCountry (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key TEXT NOT NULL UNIQUE);INSERT INTO dataset_BlockCountry (data_key) VALUES ('NK');INSERT INTO dataset_BlockCountry (data_key) VALUES ('XX');")
+            .unwrap();
+        let mut entries = manager.dataset_entries(&SiemDatasetType::IpMac).unwrap();
+        match entries.next() {
+            Some(DatasetEntry::IpMapEntry(ip, mac)) => {
+                assert_eq!(ip, SiemIp::V4(1));
+                assert_eq!(mac, "mac1");
+            }
+            entry => panic!("Unexpected entry {:?}", entry),
+        }
+        assert!(entries.next().is_none());
+        let countries: Vec<String> = manager
+            .dataset_entries(&SiemDatasetType::BlockCountry)
+            .unwrap()
+            .map(|entry| match entry {
+                DatasetEntry::TextEntry(country) => country,
+                entry => panic!("Unexpected entry {:?}", entry),
+            })
+            .collect();
+        assert_eq!(countries, vec!["NK", "XX"]);
+    }
+
+    #[test]
+    fn test_replace_if_version() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                params![ip_to_vec8(&SiemIp::V4(1))],
+            )
+            .unwrap();
+        let count = |manager: &SqliteDatasetManager| -> i64 {
+            manager
+                .conn
+                .query_row("SELECT COUNT(*) FROM dataset_BlockIp", [], |row| row.get(0))
+                .unwrap()
+        };
+        let replace = || {
+            DatasetUpdate::IpSet(
+                SiemDatasetType::BlockIp,
+                UpdateIpSet::Replace(IpSetDataset::new()),
+            )
+        };
+        assert_eq!(manager.dataset_version(&SiemDatasetType::BlockIp).unwrap(), 0);
+        assert_eq!(manager.replace_if_version(replace(), 0).unwrap(), 1);
+        assert_eq!(count(&manager), 0);
+
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                params![ip_to_vec8(&SiemIp::V4(2))],
+            )
+            .unwrap();
+        let res = manager.replace_if_version(replace(), 0);
+        assert!(res.unwrap_err().starts_with("Version conflict"));
+        assert_eq!(count(&manager), 1);
+        assert_eq!(manager.dataset_version(&SiemDatasetType::BlockIp).unwrap(), 1);
+
+        // A Replace from the channels also increases the token
+        manager.update_ip_set("BlockIp", UpdateIpSet::Replace(IpSetDataset::new())).unwrap();
+        assert_eq!(manager.dataset_version(&SiemDatasetType::BlockIp).unwrap(), 2);
+        let res = manager.replace_if_version(replace(), 1);
+        assert!(res.unwrap_err().starts_with("Version conflict"));
+        assert_eq!(manager.replace_if_version(replace(), 2).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_batched_swaps() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let owners = SiemDatasetType::CustomIpMap(Cow::Borrowed("owners"));
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(owners.clone());
+        manager.set_batched_swaps(true);
+        manager.set_missing_table_policy(MissingTablePolicy::Skip);
+        let holder = manager.get_datasets();
+        let view = |holder: &DatasetHolder| -> (bool, bool) {
+            let mac = match holder.get(&SiemDatasetType::IpMac) {
+                Some(SiemDataset::IpMac(dataset)) => dataset.get(&SiemIp::V4(1)).is_some(),
+                _ => panic!("Dataset IpMac not found"),
+            };
+            let owner = match holder.get(&SiemDatasetType::CustomIpMap(Cow::Borrowed("owners"))) {
+                Some(SiemDataset::CustomIpMap((_, dataset))) => dataset.get(&SiemIp::V4(1)).is_some(),
+                _ => panic!("Dataset owners not found"),
+            };
+            (mac, owner)
+        };
+        let updated: BTreeSet<SiemDatasetType> =
+            vec![SiemDatasetType::IpMac, owners.clone()].into_iter().collect();
+
+        manager
+            .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1"))))
+            .unwrap();
+        manager.conn.execute("DROP TABLE dataset_owners", []).unwrap();
+        manager.rebuild_updated(&updated, 1);
+        assert_eq!(view(&holder), (false, false));
+
+        manager.create_dataset_tables(&owners);
+        manager
+            .update_map_ip("owners", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("alice"))))
+            .unwrap();
+        manager.rebuild_updated(&updated, 2);
+        assert_eq!(view(&holder), (true, true));
+    }
+
+    #[test]
+    fn test_batched_swaps_concurrent_reader() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let first = SiemDatasetType::CustomIpMap(Cow::Borrowed("first"));
+        let second = SiemDatasetType::CustomIpMap(Cow::Borrowed("second"));
+        manager.register_dataset(first.clone());
+        manager.register_dataset(second.clone());
+        manager.set_batched_swaps(true);
+        let updated: BTreeSet<SiemDatasetType> = vec![first.clone(), second.clone()].into_iter().collect();
+        let version = |holder: &DatasetHolder, dataset_type: &SiemDatasetType| -> u32 {
+            match holder.get(dataset_type) {
+                Some(SiemDataset::CustomIpMap((_, dataset))) => dataset
+                    .get(&SiemIp::V4(1))
+                    .map(|version| version.parse().unwrap())
+                    .unwrap_or(0),
+                _ => panic!("Dataset {:?} not found", dataset_type),
+            }
+        };
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let holder = manager.get_datasets();
+            let stop = stop.clone();
+            let (first, second) = (first.clone(), second.clone());
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                while !stop.load(Ordering::Acquire) {
+                    // Reverse order of the swaps
+                    let second_version = version(&holder, &second);
+                    let first_version = version(&holder, &first);
+                    assert!(first_version >= second_version, "{} < {}", first_version, second_version);
+                    reads += 1;
+                }
+                reads
+            })
+        };
+        for i in 1..=200u32 {
+            for name in &["first", "second"] {
+                let mut dataset = IpMapDataset::new();
+                dataset.insert(SiemIp::V4(1), Cow::Owned(i.to_string()));
+                manager.update_map_ip(name, UpdateIpMap::Replace(dataset)).unwrap();
+            }
+            assert_eq!(manager.rebuild_updated(&updated, i as i64).len(), 2);
+        }
+        stop.store(true, Ordering::Release);
+        assert!(reader.join().unwrap() > 0);
+        let holder = manager.get_datasets();
+        assert_eq!((version(&holder, &first), version(&holder, &second)), (200, 200));
+    }
+
+    #[test]
+    fn test_hash_ioc() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let hashes = SiemDatasetType::CustomMapText(Cow::Borrowed("malware_hashes"));
+        manager.register_dataset(hashes.clone());
+        let sha256 = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        manager.add_hash(&hashes, sha256, "Emotet").unwrap();
+        assert!(manager
+            .contains_hash(&hashes, &sha256.to_lowercase())
+            .unwrap());
+        assert_eq!(
+            manager.get_threat(&hashes, &sha256.to_lowercase()).unwrap(),
+            Some(String::from("Emotet"))
+        );
+        match manager.get_datasets().get(&hashes) {
+            Some(SiemDataset::CustomMapText((_, dataset))) => {
+                assert!(dataset.get(&sha256.to_lowercase()).is_some())
+            }
+            _ => panic!("Dataset malware_hashes not found"),
+        }
+        assert!(manager.add_hash(&hashes, "XYZ123", "Bad").is_err());
+        assert!(manager.add_hash(&hashes, &sha256[..63], "Bad").is_err());
+        assert!(manager
+            .add_hash(&SiemDatasetType::BlockIp, sha256, "Emotet")
+            .is_err());
+    }
+
+    #[test]
+    fn test_run_analyze() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        {
+            let tx = manager.conn.unchecked_transaction().unwrap();
+            for i in 0..1000 {
+                tx.execute(
+                    "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                    params![ip_to_vec8(&SiemIp::V4(i))],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let stats = |manager: &SqliteDatasetManager| -> i64 {
+            manager
+                .conn
+                .query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name = 'sqlite_stat1'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        manager.set_auto_analyze(Some(2000));
+        manager.auto_analyze();
+        assert_eq!(stats(&manager), 0);
+        manager.set_auto_analyze(Some(1000));
+        manager.auto_analyze();
+        assert_eq!(stats(&manager), 1);
+        manager.run_analyze().unwrap();
+        let indexed: i64 = manager
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_stat1 WHERE tbl = 'dataset_BlockIp' AND idx IS NOT NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(indexed > 0);
+    }
+
+    #[test]
+    fn test_max_datasets() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_max_datasets(2);
+        for name in &["first", "second"] {
+            manager
+                .register_dataset_with_options(
+                    SiemDatasetType::CustomMapText(Cow::Borrowed(name)),
+                    DatasetOptions::default(),
+                )
+                .unwrap();
+        }
+        assert_eq!(manager.dataset_count(), 2);
+        let res = manager.register_dataset_with_options(
+            SiemDatasetType::CustomMapText(Cow::Borrowed("third")),
+            DatasetOptions::default(),
+        );
+        assert!(res.unwrap_err().starts_with("TooManyDatasets"));
+        manager.register_dataset(SiemDatasetType::HostUser);
+        assert_eq!(manager.dataset_count(), 2);
+        // Registering again an existing dataset is not an error
+        manager
+            .register_dataset_with_options(
+                SiemDatasetType::CustomMapText(Cow::Borrowed("first")),
+                DatasetOptions::default(),
+            )
+            .unwrap();
+        let holder = manager.get_datasets();
+        assert!(holder
+            .get(&SiemDatasetType::CustomMapText(Cow::Borrowed("first")))
+            .is_some());
+        assert!(holder
+            .get(&SiemDatasetType::CustomMapText(Cow::Borrowed("second")))
+            .is_some());
+        assert!(holder
+            .get(&SiemDatasetType::CustomMapText(Cow::Borrowed("third")))
+            .is_none());
+    }
+
+    #[test]
+    fn test_entries_with_label() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        for (ip, mac) in &[(1, "mac1"), (2, "mac2"), (3, "mac3")] {
+            manager
+                .update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(*ip), Cow::Borrowed(mac))))
+                .unwrap();
+        }
+        let key = |ip: u32| SiemIp::V4(ip).to_string();
+        manager
+            .set_label(&SiemDatasetType::IpMac, &key(1), "team", "netsec")
+            .unwrap();
+        manager
+            .set_label(&SiemDatasetType::IpMac, &key(2), "team", "devops")
+            .unwrap();
+        manager
+            .set_label(&SiemDatasetType::IpMac, &key(3), "team", "netsec")
+            .unwrap();
+        manager
+            .set_label(&SiemDatasetType::IpMac, &key(3), "site", "hq")
+            .unwrap();
+        let keys: Vec<String> = manager
+            .entries_with_label(&SiemDatasetType::IpMac, "team", "netsec")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, vec![key(3), key(1)]);
+        assert!(manager
+            .set_label(&SiemDatasetType::IpMac, &key(4), "team", "netsec")
+            .is_err());
+    }
+
+    #[test]
+    fn test_register_standard_datasets() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let results = manager.register_standard_datasets();
+        assert_eq!(results.len(), 17);
+        let holder = manager.get_datasets();
+        for (dataset_type, res) in results {
+            assert!(res.is_ok(), "{:?}: {:?}", dataset_type, res);
+            assert!(holder.get(&dataset_type).is_some(), "{:?}", dataset_type);
+        }
+        assert_eq!(manager.dataset_count(), 17);
+    }
+
+    #[test]
+    fn test_json_list_encoding() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpDNS,
+                DatasetOptions {
+                    list_encoding: ListEncoding::Json,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let values = vec![
+            Cow::Borrowed("a|b"),
+            Cow::Borrowed("say \"hi\""),
+            Cow::Borrowed("x,y"),
+            Cow::Borrowed("[]"),
+        ];
+        manager
+            .update_map_ip_list("IpDNS", UpdateIpMapList::Add((SiemIp::V4(1), values.clone())))
+            .unwrap();
+        assert!(manager.tables_exist(&SiemDatasetType::IpDNS));
+        manager.rebuild_dataset(&SiemDatasetType::IpDNS).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => {
+                assert_eq!(dataset.get(&SiemIp::V4(1)), Some(&values));
             }
-            self.dataset_holder = DatasetHolder::from_datasets(pointer_list);
+            _ => panic!("Dataset IpDNS not found"),
+        }
+        manager
+            .update_map_ip_list("IpDNS", UpdateIpMapList::Remove(SiemIp::V4(1)))
+            .unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::IpDNS).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_none()),
+            _ => panic!("Dataset IpDNS not found"),
         }
     }
-}
 
-fn ip_form_vec8(v: &Vec<u8>) -> Result<SiemIp, ()> {
-    if v.len() == 4 {
-        return Ok(SiemIp::V4(
-            ((v[3] as u32) << 24) | ((v[2] as u32) << 16) | ((v[1] as u32) << 8) | (v[0] as u32),
-        ));
-    } else if v.len() == 16 {
-        return Ok(SiemIp::V6(
-            ((v[15] as u128) << 120)
-                | ((v[14] as u128) << 112)
-                | ((v[13] as u128) << 104)
-                | ((v[12] as u128) << 96)
-                | ((v[11] as u128) << 88)
-                | ((v[10] as u128) << 80)
-                | ((v[9] as u128) << 72)
-                | ((v[8] as u128) << 64)
-                | ((v[7] as u128) << 56)
-                | ((v[6] as u128) << 48)
-                | ((v[5] as u128) << 40)
-                | ((v[4] as u128) << 32)
-                | ((v[3] as u128) << 24)
-                | ((v[2] as u128) << 16)
-                | ((v[1] as u128) << 8)
-                | (v[0] as u128),
-        ));
-    } else {
-        return Err(());
+    #[test]
+    fn test_adaptive_flush() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        assert!(manager.set_adaptive_flush(1000, 500).is_err());
+        manager.set_adaptive_flush(100, 5000).unwrap();
+        assert_eq!(manager.flush_stats().interval_ms, 5000);
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(UpdateListener::UpdateIpMap(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for IpMac"),
+        };
+        for i in 0..120 {
+            sender
+                .send(UpdateIpMap::Add((SiemIp::V4(i), Cow::Borrowed("mac"))))
+                .unwrap();
+        }
+        assert_eq!(manager.flush_stats().queued, 120);
+        manager.flush_updates();
+        manager.flush_updates();
+        assert_eq!(manager.flush_stats().interval_ms, 1250);
+
+        // The burst is written and the channels stay empty
+        manager.set_last_update(&SiemDatasetType::IpMac, 0);
+        manager.flush_updates();
+        assert_eq!(manager.flush_stats().queued, 0);
+        manager.flush_updates();
+        manager.flush_updates();
+        assert!(manager.flush_stats().interval_ms > 1250);
+        for _ in 0..5 {
+            manager.flush_updates();
+        }
+        assert_eq!(manager.flush_stats().interval_ms, 5000);
     }
-}
 
-fn ip_to_vec8(ip: &SiemIp) -> Vec<u8> {
-    match ip {
-        SiemIp::V4(v4) => v4.to_le_bytes().to_vec(),
-        SiemIp::V6(v6) => v6.to_le_bytes().to_vec(),
+    #[test]
+    fn test_ip_set_round_trip() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let ip4 = SiemIp::from_ip_str("203.0.113.9").unwrap();
+        let ip6 = SiemIp::from_ip_str("2001:db8::9").unwrap();
+        manager.update_ip_set("BlockIp", UpdateIpSet::Add(ip4.clone())).unwrap();
+        manager.update_ip_set("BlockIp", UpdateIpSet::Add(ip6.clone())).unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::BlockIp).unwrap();
+        assert!(block_ip_contains(&manager, &ip4));
+        assert!(block_ip_contains(&manager, &ip6));
+        // Replace writes the same encoding
+        let mut replacement = IpSetDataset::new();
+        replacement.insert(ip6.clone());
+        replacement.insert(SiemIp::V4(7));
+        manager.update_ip_set("BlockIp", UpdateIpSet::Replace(replacement)).unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::BlockIp).unwrap();
+        assert!(!block_ip_contains(&manager, &ip4));
+        assert!(block_ip_contains(&manager, &ip6));
+        assert!(block_ip_contains(&manager, &SiemIp::V4(7)));
     }
-}
 
-fn dataset_ip_set(conn: &Connection, name: &str) -> rusqlite::Result<IpSetDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT data_key FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok(row.get(0)?))?;
-    let mut dataset = IpSetDataset::new();
-    for row in iterator {
-        let k_v: String = row?;
-        match SiemIp::from_ip_str(&k_v) {
-            Ok(ip) => dataset.insert(ip),
-            Err(_) => return Err(rusqlite::Error::SqliteSingleThreadedMode),
+    #[test]
+    fn test_bulk_replace() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let mut replacement = IpSetDataset::new();
+        for ip in 0..10_000 {
+            replacement.insert(SiemIp::V4(ip));
+        }
+        let started = std::time::Instant::now();
+        manager.update_ip_set("BlockIp", UpdateIpSet::Replace(replacement)).unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!(manager.row_count("BlockIp"), 10_000);
+
+        // A failed insert keeps the previous contents
+        manager
+            .conn
+            .execute_batch("CREATE TRIGGER fail_insert BEFORE INSERT ON dataset_BlockIp WHEN NEW.data_key = X'00000007' BEGIN SELECT RAISE(ABORT, 'rejected'); END")
+            .unwrap();
+        let mut replacement = IpSetDataset::new();
+        for ip in 1..20 {
+            replacement.insert(SiemIp::V4(ip));
+        }
+        assert!(manager
+            .update_ip_set("BlockIp", UpdateIpSet::Replace(replacement))
+            .is_err());
+        assert_eq!(manager.row_count("BlockIp"), 10_000);
+        let mut replacement = IpMapDataset::new();
+        replacement.insert(SiemIp::V4(1), Cow::Borrowed("mac1"));
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.update_map_ip("IpMac", UpdateIpMap::Replace(replacement)).unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::IpMac).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_some()),
+            _ => panic!("Dataset IpMac not found"),
         }
     }
-    return Ok(dataset);
-}
-fn dataset_text_list(conn: &Connection, name: &str) -> rusqlite::Result<TextSetDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT data_key FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok(row.get(0)?))?;
-    let mut dataset = TextSetDataset::new();
-    for row in iterator {
-        let k_v = row?;
-        dataset.insert(Cow::Owned(k_v))
+
+    #[test]
+    fn test_update_ip_net() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::IpCloudProvider);
+        let holder = manager.get_datasets();
+        let providers = match holder.get(&SiemDatasetType::IpCloudProvider) {
+            Some(SiemDataset::IpCloudProvider(dataset)) => dataset.clone(),
+            _ => panic!("Dataset IpCloudProvider not found"),
+        };
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        providers.insert(ip("52.95.110.7"), 24, Cow::Borrowed("AWS"));
+        providers.insert(ip("35.190.0.0"), 17, Cow::Borrowed("Google"));
+        providers.insert(ip("2600:1f00::"), 24, Cow::Borrowed("AWS"));
+        providers.remove(ip("35.190.1.1"), 17);
+        manager.force_flush();
+        let (network, key): (u8, Vec<u8>) = manager
+            .conn
+            .query_row("SELECT network, data_key FROM dataset_IpCloudProvider WHERE data_val = 'AWS' ORDER BY id", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(network, 24);
+        assert_eq!(ip_form_vec8(&key).unwrap(), ip("52.95.110.0"));
+        manager.rebuild_all().unwrap();
+        match holder.get(&SiemDatasetType::IpCloudProvider) {
+            Some(SiemDataset::IpCloudProvider(dataset)) => {
+                assert_eq!(&dataset.get(&ip("52.95.110.200")).unwrap()[..], "AWS");
+                assert_eq!(&dataset.get(&ip("2600:1fff::1")).unwrap()[..], "AWS");
+                assert!(dataset.get(&ip("52.95.111.1")).is_none());
+                assert!(dataset.get(&ip("35.190.0.1")).is_none());
+            }
+            _ => panic!("Dataset IpCloudProvider not found"),
+        }
     }
-    return Ok(dataset);
-}
-fn dataset_ip_map(conn: &Connection, name: &str) -> rusqlite::Result<IpMapDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT data_key, data_val FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
-    let mut dataset = IpMapDataset::new();
-    for row in iterator {
-        let (k, v): (Vec<u8>, String) = row?;
-        match ip_form_vec8(&k) {
-            Ok(ip) => dataset.insert(ip, Cow::Owned(v)),
-            Err(_) => return Err(rusqlite::Error::SqliteSingleThreadedMode),
+
+    #[test]
+    fn test_ip_net_prefixes() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpHeadquarters);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        manager
+            .update_ip_net("IpHeadquarters", UpdateNetIp::Add((ip("10.20.30.40"), 24, Cow::Borrowed("Madrid"))))
+            .unwrap();
+        manager
+            .update_ip_net("IpHeadquarters", UpdateNetIp::Add((ip("2001:db8:0:1::5"), 64, Cow::Borrowed("Paris"))))
+            .unwrap();
+        assert!(manager
+            .update_ip_net("IpHeadquarters", UpdateNetIp::Add((ip("10.0.0.1"), 33, Cow::Borrowed("Bad"))))
+            .is_err());
+        assert!(manager
+            .update_ip_net("IpHeadquarters", UpdateNetIp::Add((ip("2001:db8::1"), 129, Cow::Borrowed("Bad"))))
+            .is_err());
+        manager.rebuild_dataset(&SiemDatasetType::IpHeadquarters).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpHeadquarters) {
+            Some(SiemDataset::IpHeadquarters(dataset)) => {
+                assert_eq!(&dataset.get(&ip("10.20.30.1")).unwrap()[..], "Madrid");
+                assert!(dataset.get(&ip("10.20.31.1")).is_none());
+                assert_eq!(&dataset.get(&ip("2001:db8:0:1:ffff::1")).unwrap()[..], "Paris");
+                assert!(dataset.get(&ip("2001:db8:0:2::1")).is_none());
+            }
+            _ => panic!("Dataset IpHeadquarters not found"),
+        }
+        // A /64 is only valid for a 16 byte key
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_IpHeadquarters (network, data_key, data_val) VALUES (64, ?1, 'Bad')",
+                params![ip_to_vec8(&ip("10.0.0.0"))],
+            )
+            .unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::IpHeadquarters).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpHeadquarters) {
+            Some(SiemDataset::IpHeadquarters(dataset)) => {
+                assert_eq!(&dataset.get(&ip("10.20.30.1")).unwrap()[..], "Madrid");
+                assert!(dataset.get(&ip("10.0.0.1")).is_none());
+            }
+            _ => panic!("Dataset IpHeadquarters not found"),
         }
     }
-    return Ok(dataset);
-}
-fn dataset_ip_map_list(conn: &Connection, name: &str) -> rusqlite::Result<IpMapListDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT data_key, data_val FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
-    let mut dataset = IpMapListDataset::new();
-    for row in iterator {
-        let (k, v): (Vec<u8>, String) = row?;
-        match ip_form_vec8(&k) {
-            Ok(ip) => dataset.insert(ip, v.split("|").map(|v| Cow::Owned(v.to_string())).collect()),
-            Err(_) => return Err(rusqlite::Error::SqliteSingleThreadedMode),
+
+    #[test]
+    fn test_invalid_ip_error() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpCloudService);
+        let res = manager.update_ip_net("IpCloudService", UpdateNetIp::Add((SiemIp::V4(1), 40, Cow::Borrowed("Bad"))));
+        assert!(matches!(res, Err(DatasetError::InvalidIp(_))));
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_IpCloudService (network, data_key, data_val) VALUES (40, ?1, 'Bad')",
+                params![ip_to_vec8(&SiemIp::V4(0))],
+            )
+            .unwrap();
+        // Skipped by the loader
+        let dataset = dataset_ip_net(&manager.conn, "IpCloudService", None).unwrap();
+        assert!(dataset.get(&SiemIp::V4(0)).is_none());
+    }
+
+    #[test]
+    fn test_update_map_text_list() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::UserTag);
+        let holder = manager.get_datasets();
+        let tags = match holder.get(&SiemDatasetType::UserTag) {
+            Some(SiemDataset::UserTag(dataset)) => dataset.clone(),
+            _ => panic!("Dataset UserTag not found"),
+        };
+        tags.insert(
+            Cow::Borrowed("alice"),
+            vec![Cow::Borrowed("admin"), Cow::Borrowed("vpn")],
+        );
+        tags.insert(Cow::Borrowed("bob"), vec![Cow::Borrowed("guest")]);
+        tags.remove(Cow::Borrowed("bob"));
+        manager.force_flush();
+        // Survives a full reload from the database
+        manager.rebuild_all().unwrap();
+        match holder.get(&SiemDatasetType::UserTag) {
+            Some(SiemDataset::UserTag(dataset)) => {
+                assert_eq!(
+                    dataset.get("alice").unwrap(),
+                    &vec![Cow::Borrowed("admin"), Cow::Borrowed("vpn")]
+                );
+                assert!(dataset.get("bob").is_none());
+            }
+            _ => panic!("Dataset UserTag not found"),
         }
+        let values: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_list_UserTag", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(values, 2);
     }
-    return Ok(dataset);
-}
-fn dataset_ip_net(conn: &Connection, name: &str) -> rusqlite::Result<IpNetDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT network, data_key, data_val FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
-    let mut dataset = IpNetDataset::new();
-    for row in iterator {
-        let (net, ip, val): (u8, Vec<u8>, String) = row?;
-        match ip_form_vec8(&ip) {
-            Ok(ip) => dataset.insert(ip, net,Cow::Owned(val)),
-            Err(_) => return Err(rusqlite::Error::SqliteSingleThreadedMode),
+
+    #[test]
+    fn test_text_list_schema() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_text_list("BlockCountry");
+        for country in &["RU", "KP"] {
+            manager
+                .conn
+                .execute("INSERT INTO dataset_BlockCountry (data_key) VALUES (?1)", params![country])
+                .unwrap();
         }
+        assert!(manager
+            .conn
+            .execute("INSERT INTO dataset_BlockCountry (data_key) VALUES ('RU')", [])
+            .is_err());
+        let dataset = dataset_text_list(&manager.conn, "BlockCountry", None).unwrap();
+        assert!(dataset.contains(&Cow::Borrowed("RU")));
+        assert!(dataset.contains(&Cow::Borrowed("KP")));
+        assert!(!dataset.contains(&Cow::Borrowed("ES")));
+        let indexes: i64 = manager
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = ?1",
+                params![index_name("idx_", "BlockCountry", "data_key")],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 1);
     }
-    return Ok(dataset);
-}
-fn dataset_text_map(conn: &Connection, name: &str) -> rusqlite::Result<TextMapDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT data_key, data_val FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| {
-        Ok(KeyValTextMap {
-            key: row.get(0)?,
-            val: row.get(1)?,
-        })
-    })?;
-    let mut dataset = TextMapDataset::new();
-    for row in iterator {
-        let k_v = row?;
-        dataset.insert(Cow::Owned(k_v.key), Cow::Owned(k_v.val))
+
+    #[test]
+    fn test_host_vulnerable() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::HostVulnerable);
+        assert!(manager.tables_exist(&SiemDatasetType::HostVulnerable));
+        manager
+            .update_map_text_list(
+                "HostVulnerable",
+                UpdateTextMapList::Add((
+                    Cow::Borrowed("web01"),
+                    vec![Cow::Borrowed("CVE-2021-44228"), Cow::Borrowed("CVE-2014-0160")],
+                )),
+            )
+            .unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::HostVulnerable).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::HostVulnerable) {
+            Some(SiemDataset::HostVulnerable(dataset)) => assert_eq!(
+                dataset.get("web01").unwrap(),
+                &vec![Cow::Borrowed("CVE-2021-44228"), Cow::Borrowed("CVE-2014-0160")]
+            ),
+            _ => panic!("Dataset HostVulnerable not found"),
+        }
     }
-    return Ok(dataset);
-}
-fn dataset_map_text_list(conn: &Connection, name: &str) -> rusqlite::Result<TextMapListDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT t1.data_key, t2.data_val FROM dataset_{dataset_name} as t1 INNER JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
-    let mut dataset = TextMapListDataset::new();
-    let mut bt: BTreeMap<String, Vec<Cow<'static, str>>> = BTreeMap::new();
 
-    for row in iterator {
-        let (k, v): (String, String) = row?;
-        if bt.contains_key(&k) {
-            match bt.get_mut(&k) {
-                Some(ve) => ve.push(Cow::Owned(v)),
-                None => {}
+    #[test]
+    fn test_update_text_set() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        let holder = manager.get_datasets();
+        let blocked = match holder.get(&SiemDatasetType::BlockDomain) {
+            Some(SiemDataset::BlockDomain(dataset)) => dataset.clone(),
+            _ => panic!("Dataset BlockDomain not found"),
+        };
+        blocked.insert("evil.com");
+        blocked.insert("malware.net");
+        blocked.remove("malware.net");
+        manager.force_flush();
+        let contains = |domain: &'static str| match holder.get(&SiemDatasetType::BlockDomain) {
+            Some(SiemDataset::BlockDomain(dataset)) => dataset.contains(&Cow::Borrowed(domain)),
+            _ => panic!("Dataset BlockDomain not found"),
+        };
+        assert!(contains("evil.com"));
+        assert!(!contains("malware.net"));
+        let mut replacement = TextSetDataset::new();
+        replacement.insert("phishing.org");
+        blocked.update(replacement);
+        manager.force_flush();
+        assert!(contains("phishing.org"));
+        assert!(!contains("evil.com"));
+    }
+
+    #[test]
+    fn test_custom_dataset_updates() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        let dataset_type = SiemDatasetType::CustomIpList(Cow::Borrowed("allowed"));
+        manager.register_dataset(dataset_type.clone());
+        let holder = manager.get_datasets();
+        match holder.get(&dataset_type) {
+            Some(SiemDataset::CustomIpList((_, dataset))) => dataset.insert(SiemIp::V4(7)),
+            _ => panic!("Dataset CustomIpList not found"),
+        }
+        manager.force_flush();
+        // Written to the table of the custom name, not one named after the dataset type
+        assert_eq!(manager.row_count("allowed"), 1);
+        match holder.get(&dataset_type) {
+            Some(SiemDataset::CustomIpList((_, dataset))) => assert!(dataset.contains(&SiemIp::V4(7))),
+            _ => panic!("Dataset CustomIpList not found"),
+        }
+    }
+
+    #[test]
+    fn test_update_text_map() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::Configuration);
+        let config = match manager.get_datasets().get(&SiemDatasetType::Configuration) {
+            Some(SiemDataset::Configuration(dataset)) => dataset.clone(),
+            _ => panic!("Dataset Configuration not found"),
+        };
+        config.insert("log_level", "debug");
+        config.insert("retention", "30d");
+        manager.set_last_update(&SiemDatasetType::Configuration, 0);
+        manager.flush_updates();
+        match manager.get_datasets().get(&SiemDatasetType::Configuration) {
+            Some(SiemDataset::Configuration(dataset)) => {
+                assert_eq!(&dataset.get("log_level").unwrap()[..], "debug");
+                assert_eq!(&dataset.get("retention").unwrap()[..], "30d");
             }
-        } else {
-            bt.insert(k.to_string(), vec![Cow::Owned(v)]);
+            _ => panic!("Dataset Configuration not found"),
+        }
+        let mut replacement = TextMapDataset::new();
+        replacement.insert("log_level", "info");
+        manager
+            .update_text_map("Configuration", UpdateTextMap::Replace(replacement))
+            .unwrap();
+        manager
+            .update_text_map("Configuration", UpdateTextMap::Remove(Cow::Borrowed("missing")))
+            .unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::Configuration).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::Configuration) {
+            Some(SiemDataset::Configuration(dataset)) => {
+                assert_eq!(&dataset.get("log_level").unwrap()[..], "info");
+                assert!(dataset.get("retention").is_none());
+            }
+            _ => panic!("Dataset Configuration not found"),
         }
     }
-    for (k, v) in bt.into_iter() {
-        dataset.insert(Cow::Owned(k), v);
+
+    #[test]
+    fn test_geoip_replace() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::GeoIp);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        let info = |country: &'static str| GeoIpInfo {
+            country: Cow::Borrowed(country),
+            city: Cow::Borrowed("City"),
+            latitude: 40.5,
+            longitude: -3.5,
+            isp: Cow::Borrowed("ISP"),
+        };
+        manager
+            .update_geo_ip("GeoIp", UpdateGeoIp::Add((ip("82.64.0.0"), 16, info("FR"))))
+            .unwrap();
+        let mut replacement = GeoIpDataset::new();
+        replacement.insert(ip("80.58.0.0"), 16, info("ES"));
+        replacement.insert(ip("81.20.0.0"), 16, info("PT"));
+        replacement.insert(SiemIp::V6(0x2001 << 112), 16, info("DE"));
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::GeoIp) {
+            Some(UpdateListener::UpdateGeoIp(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for GeoIp"),
+        };
+        sender.send(UpdateGeoIp::Replace(replacement)).unwrap();
+        manager.set_last_update(&SiemDatasetType::GeoIp, 0);
+        manager.flush_updates();
+        match manager.get_datasets().get(&SiemDatasetType::GeoIp) {
+            Some(SiemDataset::GeoIp(dataset)) => {
+                let es = dataset.get(&ip("80.58.1.1")).unwrap();
+                assert_eq!(&es.country[..], "ES");
+                assert_eq!(es.latitude, 40.5);
+                assert_eq!(&dataset.get(&ip("81.20.1.1")).unwrap().country[..], "PT");
+                assert_eq!(&dataset.get(&SiemIp::V6((0x2001 << 112) + 1)).unwrap().country[..], "DE");
+                assert!(dataset.get(&ip("82.64.1.1")).is_none());
+            }
+            _ => panic!("Dataset GeoIp not found"),
+        }
     }
-    return Ok(dataset);
-}
-fn dataset_geo_ip_net(conn: &Connection, name: &str) -> rusqlite::Result<GeoIpDataset> {
-    let mut stmt = conn.prepare(&format!(
-        "SELECT network, data_key, country, city, latitude, longitude, isp FROM dataset_{dataset_name}",
-        dataset_name = name
-    ))?;
-    let iterator = stmt.query_map([], |row| {
-        Ok((
-            row.get(0)?,
-            row.get(1)?,
-            row.get(2)?,
-            row.get(3)?,
-            row.get(4)?,
-            row.get(5)?,
-            row.get(6)?,
-        ))
-    })?;
-    let mut dataset = GeoIpDataset::new();
 
-    for row in iterator {
-        let (n, k, country, city, latitude, longitude, isp): (
-            u8,
-            Vec<u8>,
-            String,
-            String,
-            f32,
-            f32,
-            String,
-        ) = row?;
-        match ip_form_vec8(&k) {
-            Ok(k) => {
-                dataset.insert(
-                    k,
-                    n,
-                    GeoIpInfo {
-                        country: Cow::Owned(country),
-                        city: Cow::Owned(city),
-                        latitude,
-                        longitude,
-                        isp: Cow::Owned(isp),
-                    },
-                );
+    #[test]
+    fn test_holder_generation() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let registered = manager.holder_generation();
+        assert!(registered > 0);
+        // Nothing to flush: the datasets are not replaced
+        manager.flush_updates();
+        assert_eq!(manager.holder_generation(), registered);
+        match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(UpdateListener::UpdateIpMap(s, _, _)) => s
+                .send(UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac"))))
+                .unwrap(),
+            _ => panic!("Invalid listener for IpMac"),
+        }
+        manager.set_last_update(&SiemDatasetType::IpMac, 0);
+        manager.flush_updates();
+        assert!(manager.holder_generation() > registered);
+    }
+
+    #[test]
+    fn test_flush_histogram() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(UpdateListener::UpdateIpMap(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for IpMac"),
+        };
+        // Idle cycle, then cycles with 50 and 1 updates
+        manager.flush_updates();
+        for load in &[50, 1] {
+            for i in 0..*load {
+                sender
+                    .send(UpdateIpMap::Add((SiemIp::V4(i), Cow::Borrowed("mac"))))
+                    .unwrap();
             }
-            Err(_) => {}
+            manager.set_last_update(&SiemDatasetType::IpMac, 0);
+            manager.flush_updates();
         }
+        let histogram = manager.flush_histogram();
+        assert_eq!(histogram.cycles, 3);
+        let count = |buckets: &[Bucket], le: u64| -> u64 {
+            buckets.iter().find(|bucket| bucket.le == le).unwrap().count
+        };
+        assert_eq!(count(&histogram.updates, 0), 1);
+        assert_eq!(count(&histogram.updates, 1), 1);
+        assert_eq!(count(&histogram.updates, 100), 1);
+        assert_eq!(
+            histogram.duration_us.iter().map(|bucket| bucket.count).sum::<u64>(),
+            3
+        );
     }
-    return Ok(dataset);
-}
 
-#[cfg(test)]
-mod tests {
-    use usiem::{
-        components::{
-            command::{SiemCommandCall, SiemCommandHeader},
-            common::{SiemComponentCapabilities, SiemComponentStateStorage},
-            dataset::holder::DatasetHolder,
-            SiemComponent,
-        },
-        events::SiemLog,
-    };
+    #[test]
+    fn test_flush_schedule() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        let types: Vec<SiemDatasetType> = (0..10)
+            .map(|i| SiemDatasetType::CustomIpMap(Cow::Owned(format!("stagger_{}", i))))
+            .collect();
+        for (i, typ) in types.iter().enumerate() {
+            manager.register_dataset(typ.clone());
+            // Last flushed about i seconds ago, the default interval is 5 seconds
+            manager.set_last_update(typ, now - (i as i64) * 1000 + 500);
+            match manager.registered_datasets.get(typ) {
+                Some(UpdateListener::UpdateIpMap(s, _, _)) => s
+                    .send(UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("note"))))
+                    .unwrap(),
+                _ => panic!("Invalid listener for {:?}", typ),
+            }
+        }
+        let queued = |manager: &SqliteDatasetManager| -> Vec<usize> {
+            types
+                .iter()
+                .map(|typ| manager.registered_datasets[typ].queue_depth().0)
+                .collect()
+        };
+        // Oldest first
+        let expected: Vec<SiemDatasetType> = types[6..].iter().rev().cloned().collect();
+        assert_eq!(manager.due_datasets(now, 5000), expected);
+        manager.flush_updates();
+        assert_eq!(queued(&manager), vec![1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        // The flushed datasets are not due again until a full interval has elapsed
+        manager.flush_updates();
+        assert_eq!(queued(&manager), vec![1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        assert!(manager.next_flush_wait() <= std::time::Duration::from_millis(1001));
+        let expected: Vec<SiemDatasetType> = types[4..6].iter().rev().cloned().collect();
+        assert_eq!(manager.due_datasets(now + 2000, 5000), expected);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let holder = manager.get_datasets();
+        let ip_mac = match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.clone(),
+            _ => panic!("Dataset IpMac not found"),
+        };
+        for i in 0..10 {
+            ip_mac.insert(SiemIp::V4(i), Cow::Borrowed("mac"));
+        }
+        assert_eq!(manager.stats()[&SiemDatasetType::IpMac].pending_queue_len, 10);
+        manager.force_flush();
+        ip_mac.remove(SiemIp::V4(0));
+        manager.force_flush();
+        let stats = manager.stats();
+        assert_eq!(stats.len(), 2);
+        let ip_mac_stats = &stats[&SiemDatasetType::IpMac];
+        assert_eq!(ip_mac_stats.updates_applied, 11);
+        assert_eq!(ip_mac_stats.pending_queue_len, 0);
+        assert!(ip_mac_stats.last_rebuild_ms > 0);
+        assert!(ip_mac_stats.last_error.is_none());
+        assert_eq!(stats[&SiemDatasetType::BlockIp].updates_applied, 0);
+    }
+
+    #[test]
+    fn test_entry_ttl() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::HostVulnerable);
+        assert!(manager.set_default_ttl(&SiemDatasetType::HostVulnerable, 100).is_err());
+        assert!(manager.set_default_ttl(&SiemDatasetType::BlockDomain, 100).is_err());
+        manager.set_default_ttl(&SiemDatasetType::BlockIp, 60_000).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => dataset.insert(SiemIp::V4(1)),
+            _ => panic!("Dataset BlockIp not found"),
+        }
+        manager.force_flush();
+        assert!(block_ip_contains(&manager, &SiemIp::V4(1)));
+        assert_eq!(manager.sweep_expired(), 0);
+        // Expired a minute ago, without waiting for the TTL
+        manager
+            .conn
+            .execute("UPDATE dataset_BlockIp SET expires_at = expires_at - 120000", [])
+            .unwrap();
+        assert_eq!(manager.sweep_expired(), 1);
+        assert!(!block_ip_contains(&manager, &SiemIp::V4(1)));
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        manager.register_dataset(SiemDatasetType::MacHost);
+        manager.update_text_set("BlockDomain", UpdateTextSet::Add(Cow::Borrowed("evil.com"))).unwrap();
+        manager.update_text_set("BlockDomain", UpdateTextSet::Add(Cow::Borrowed("bad.org"))).unwrap();
+        manager
+            .update_text_map("MacHost", UpdateTextMap::Add((Cow::Borrowed("00:11"), Cow::Borrowed("host1"))))
+            .unwrap();
+        manager
+            .update_text_map("MacHost", UpdateTextMap::Add((Cow::Borrowed("00:22"), Cow::Borrowed("desk, \"2nd\""))))
+            .unwrap();
+        let mut csv = Vec::new();
+        manager.export_csv(&SiemDatasetType::BlockDomain, &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "key\nevil.com\nbad.org\n");
+        let mut csv = Vec::new();
+        manager.export_csv(&SiemDatasetType::MacHost, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "key,value\n00:11,host1\n00:22,\"desk, \"\"2nd\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let csv = "key\nevil.com\n\"bad,domain.org\"\r\nmalware.net\n";
+        let imported = manager
+            .import_csv(&SiemDatasetType::BlockDomain, &mut csv.as_bytes())
+            .unwrap();
+        assert_eq!(imported, 3);
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        match manager.get_datasets().get(&SiemDatasetType::BlockDomain) {
+            Some(SiemDataset::BlockDomain(dataset)) => {
+                assert!(dataset.contains(&Cow::Borrowed("evil.com")));
+                assert!(dataset.contains(&Cow::Borrowed("bad,domain.org")));
+                assert!(dataset.contains(&Cow::Borrowed("malware.net")));
+            }
+            _ => panic!("Dataset BlockDomain not found"),
+        }
+        let csv = "192.168.1.1,00:11\n10.0.0.1,00:22\n";
+        assert_eq!(manager.import_csv(&SiemDatasetType::IpMac, &mut csv.as_bytes()).unwrap(), 2);
+        assert!(manager
+            .import_csv(&SiemDatasetType::IpMac, &mut "not-an-ip,00:33\n".as_bytes())
+            .is_err());
+        assert_eq!(manager.row_count("IpMac"), 2);
+        assert!(manager
+            .import_csv(&SiemDatasetType::BlockDomain, &mut "a,b\n".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn test_import_maxmind_geolite2() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        let locations = "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,subdivision_1_iso_code,subdivision_1_name,subdivision_2_iso_code,subdivision_2_name,city_name,metro_code,time_zone,is_in_european_union
+3117735,en,EU,Europe,ES,Spain,MD,Madrid,,,Madrid,,Europe/Madrid,1
+2510769,en,EU,Europe,ES,Spain,,,,,,,Europe/Madrid,1
+5375480,en,NA,\"North America\",US,\"United States\",CA,California,,,\"Mountain View\",807,America/Los_Angeles,0
+";
+        let blocks_v4 = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,postal_code,latitude,longitude,accuracy_radius
+2.136.0.0/16,3117735,2510769,,0,0,28001,40.4165,-3.7026,100
+8.8.8.0/24,5375480,6252001,,0,0,94035,37.3860,-122.0838,1000
+31.4.0.0/14,,2510769,,0,0,,,,
+";
+        let blocks_v6 = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,postal_code,latitude,longitude,accuracy_radius
+2001:4860::/32,5375480,6252001,,0,0,,37.3860,-122.0838,100
+";
+        assert_eq!(
+            manager
+                .import_maxmind_geolite2(&mut blocks_v4.as_bytes(), &mut locations.as_bytes())
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            manager
+                .import_maxmind_geolite2(&mut blocks_v6.as_bytes(), &mut locations.as_bytes())
+                .unwrap(),
+            1
+        );
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        assert_eq!(manager.geoip_country(&ip("2.136.10.1")).unwrap().as_deref(), Some("ES"));
+        assert_eq!(manager.geoip_country(&ip("8.8.8.8")).unwrap().as_deref(), Some("US"));
+        // Registered country
+        assert_eq!(manager.geoip_country(&ip("31.5.0.1")).unwrap().as_deref(), Some("ES"));
+        assert_eq!(manager.geoip_country(&ip("2001:4860::8888")).unwrap().as_deref(), Some("US"));
+        assert_eq!(manager.geoip_country(&ip("9.9.9.9")).unwrap(), None);
+        let city: String = manager
+            .conn
+            .query_row("SELECT city FROM dataset_GeoIp WHERE network = 24", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(city, "Mountain View");
+        assert!(manager
+            .import_maxmind_geolite2(&mut "network,geoname_id\n1.2.3.4/40,1\n".as_bytes(), &mut locations.as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "secrets-encryption"))]
+    fn test_secrets_key_requires_feature() {
+        assert!(SqliteDatasetManager::debug().unwrap().with_secrets_key(&[7u8; 32]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "secrets-encryption")]
+    fn test_encrypted_secrets() {
+        let secrets = SiemDatasetType::Secrets(Cow::Borrowed("api_keys"));
+        let mut manager = SqliteDatasetManager::debug()
+            .unwrap()
+            .with_secrets_key(&[7u8; 32])
+            .unwrap()
+            .with_synchronous();
+        assert!(SqliteDatasetManager::debug().unwrap().with_secrets_key(&[7u8; 16]).is_err());
+        manager.register_dataset(secrets.clone());
+        let holder = manager.get_datasets();
+        match holder.get(&secrets) {
+            Some(SiemDataset::Secrets((_, dataset))) => dataset.insert(Cow::Borrowed("virustotal"), Cow::Borrowed("s3cr3t-t0k3n")),
+            _ => panic!("Dataset Secrets not found"),
+        }
+        manager.force_flush();
+        let raw: String = manager
+            .conn
+            .query_row("SELECT data_val FROM dataset_api_keys WHERE data_key = 'virustotal'", [], |row| row.get(0))
+            .unwrap();
+        assert!(raw.starts_with("enc1:"));
+        assert!(!raw.contains("s3cr3t-t0k3n"));
+        match manager.get_datasets().get(&secrets) {
+            Some(SiemDataset::Secrets((_, dataset))) => {
+                assert_eq!(dataset.get("virustotal").map(|v| v.to_string()).as_deref(), Some("s3cr3t-t0k3n"))
+            }
+            _ => panic!("Dataset Secrets not found"),
+        }
+        // Moved to another key the value can't be decrypted
+        manager
+            .conn
+            .execute("UPDATE dataset_api_keys SET data_key = 'other'", [])
+            .unwrap();
+        assert!(manager.rebuild_dataset(&secrets).is_err());
+    }
+
+    #[test]
+    fn test_last_error() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpMac,
+                DatasetOptions {
+                    on_conflict: OnConflict::Abort,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        manager.register_dataset(SiemDatasetType::HostUser);
+        assert!(manager.last_error(&SiemDatasetType::IpMac).is_none());
+        let before = chrono::Utc::now().timestamp_millis();
+        manager.set_last_update(&SiemDatasetType::IpMac, 0);
+        match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(listener) => {
+                match listener {
+                    UpdateListener::UpdateIpMap(s, _, _) => {
+                        // OnConflict::Abort rejects the second Add
+                        s.send(UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1"))))
+                            .unwrap();
+                        s.send(UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac2"))))
+                            .unwrap();
+                    }
+                    _ => panic!("Invalid listener for IpMac"),
+                }
+            }
+            None => panic!("IpMac not registered"),
+        }
+        manager.flush_updates();
+        let (time, error) = manager.last_error(&SiemDatasetType::IpMac).unwrap();
+        assert!(time >= before);
+        assert!(error.contains("UNIQUE"), "{}", error);
+        assert!(manager.last_error(&SiemDatasetType::HostUser).is_none());
+
+        manager.conn.execute_batch("DROP TABLE dataset_IpMac").unwrap();
+        let res = manager.apply_multi_update(vec![DatasetUpdate::IpMap(
+            SiemDatasetType::IpMac,
+            UpdateIpMap::Remove(SiemIp::V4(1)),
+        )]);
+        let (_, error) = manager.last_error(&SiemDatasetType::IpMac).unwrap();
+        assert!(error.contains("no such table"), "{}", error);
+        assert_eq!(Some(error), res.err());
+    }
+
+    struct MockSink {
+        events: Arc<Mutex<Vec<UpdateEvent>>>,
+    }
+
+    impl UpdateSink for MockSink {
+        fn on_updates(&mut self, events: &[UpdateEvent]) -> Result<(), String> {
+            self.events.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_update_sink() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        manager.set_update_sink(Box::new(MockSink {
+            events: events.clone(),
+        }));
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.set_last_update(&SiemDatasetType::IpMac, 0);
+        match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(listener) => {
+                match listener {
+                    UpdateListener::UpdateIpMap(s, _, _) => {
+                        s.send(UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1"))))
+                            .unwrap();
+                        s.send(UpdateIpMap::Remove(SiemIp::V4(2))).unwrap();
+                    }
+                    _ => panic!("Invalid listener for IpMac"),
+                }
+            }
+            None => panic!("IpMac not registered"),
+        }
+        manager.flush_updates();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                UpdateEvent {
+                    dataset_type: SiemDatasetType::IpMac,
+                    kind: UpdateEventKind::Add,
+                    key: Some(SiemIp::V4(1).to_string()),
+                },
+                UpdateEvent {
+                    dataset_type: SiemDatasetType::IpMac,
+                    kind: UpdateEventKind::Remove,
+                    key: Some(SiemIp::V4(2).to_string()),
+                },
+            ]
+        );
+        manager.flush_updates();
+        assert_eq!(events.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_malicious_dataset_names() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::CustomMapText(Cow::Borrowed("victim")));
+        let names = vec![
+            "x; DROP TABLE dataset_victim;--",
+            "victim WHERE 1=1",
+            "a'b",
+            "dataset_victim\"",
+        ];
+        for name in names {
+            let types = vec![
+                SiemDatasetType::CustomMapText(Cow::Borrowed(name)),
+                SiemDatasetType::CustomIpList(Cow::Borrowed(name)),
+                SiemDatasetType::CustomMapIpNet(Cow::Borrowed(name)),
+                SiemDatasetType::CustomIpMap(Cow::Borrowed(name)),
+                SiemDatasetType::CustomMapTextList(Cow::Borrowed(name)),
+                SiemDatasetType::CustomTextList(Cow::Borrowed(name)),
+                SiemDatasetType::Secrets(Cow::Borrowed(name)),
+            ];
+            for dataset_type in types {
+                assert!(dataset_table_name(&dataset_type).is_none());
+                assert!(manager
+                    .register_dataset_with_options(dataset_type.clone(), DatasetOptions::default())
+                    .is_err());
+                assert!(manager.drop_dataset(&dataset_type).is_err());
+                assert!(manager.lookup(&dataset_type, "key").is_err());
+            }
+        }
+        assert!(manager.table_schema("dataset_victim").is_some());
+        assert_eq!(manager.dataset_count(), 1);
+    }
+
+    #[test]
+    fn test_long_dataset_names() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        let prefix = "a".repeat(MAX_DATASET_NAME_LEN - 1);
+        let names = vec![format!("{}1", prefix), format!("{}2", prefix)];
+        for name in &names {
+            manager
+                .register_dataset_with_options(
+                    SiemDatasetType::CustomMapTextList(Cow::Owned(name.clone())),
+                    DatasetOptions::default(),
+                )
+                .unwrap();
+        }
+        let indexes = |table: &str| -> Vec<String> {
+            let mut stmt = manager
+                .conn
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL")
+                .unwrap();
+            let rows = stmt.query_map(params![table], |row| row.get(0)).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        let mut all = BTreeSet::new();
+        for name in &names {
+            let mut table_indexes = indexes(&format!("dataset_{}", name));
+            table_indexes.extend(indexes(&format!("dataset_list_{}", name)));
+            assert_eq!(table_indexes.len(), 3, "{:?}", table_indexes);
+            for index in table_indexes {
+                assert!(index.len() <= MAX_INDEX_NAME_LEN, "{}", index);
+                assert!(all.insert(index));
+            }
+        }
+        assert_eq!(all.len(), 6);
+        assert!(manager
+            .register_dataset_with_options(
+                SiemDatasetType::CustomMapTextList(Cow::Owned("a".repeat(MAX_DATASET_NAME_LEN + 1))),
+                DatasetOptions::default(),
+            )
+            .is_err());
+        assert!(manager
+            .register_dataset_with_options(
+                SiemDatasetType::CustomMapText(Cow::Borrowed("bad-name")),
+                DatasetOptions::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_slow_queries() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.set_slow_query_threshold(Some(std::time::Duration::from_millis(0)));
+        manager
+            .conn
+            .execute_batch("CREATE TABLE slow_query_probe (id INTEGER)")
+            .unwrap();
+        assert!(manager
+            .slow_queries()
+            .iter()
+            .any(|query| query.sql.contains("slow_query_probe")));
+        manager.set_slow_query_threshold(None);
+        manager
+            .conn
+            .execute_batch("DROP TABLE slow_query_probe")
+            .unwrap();
+        assert!(!manager
+            .slow_queries()
+            .iter()
+            .any(|query| query.sql.contains("DROP TABLE slow_query_probe")));
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        assert_eq!(manager.schema_version().unwrap(), 0);
+        let versions = |manager: &SqliteDatasetManager| -> Vec<u32> {
+            manager
+                .pending_migrations()
+                .unwrap()
+                .into_iter()
+                .map(|migration| migration.version)
+                .collect()
+        };
+        assert_eq!(versions(&manager), vec![1, 2, 3]);
+        // Nothing is applied
+        assert_eq!(manager.schema_version().unwrap(), 0);
+        manager.conn.pragma_update(None, "user_version", 1).unwrap();
+        assert_eq!(versions(&manager), vec![2, 3]);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        assert_eq!(versions(&manager), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let path = temp_db_path("migrate");
+        {
+            // Database created before the migrations, with the table of a dataset
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("CREATE TABLE dataset_IpMac (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE, data_val TEXT NOT NULL)").unwrap();
+        }
+        {
+            let manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            assert_eq!(manager.schema_version().unwrap(), 3);
+            assert!(manager.pending_migrations().unwrap().is_empty());
+            assert!(manager.table_schema("usiem_dataset_versions").is_some());
+            assert!(manager.table_schema("dataset_IpMac").is_some());
+            let versions: Vec<u32> = {
+                let mut stmt = manager.conn.prepare("SELECT version FROM schema_version ORDER BY version").unwrap();
+                let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+                rows.map(|row| row.unwrap()).collect()
+            };
+            assert_eq!(versions, vec![1, 2, 3]);
+            assert!(manager.migrate().unwrap().is_empty());
+        }
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}-wal", path));
+        let _ = std::fs::remove_file(format!("{}-shm", path));
+    }
 
-    use super::*;
+    #[test]
+    fn test_register_command() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let commands = manager.command_channel();
+        std::thread::spawn(move || manager.run());
 
-    #[derive(Clone)]
-    pub struct BasicComponent {
-        /// Send actions to the kernel
-        kernel_sender: Sender<SiemMessage>,
-        /// Send actions to this components
-        local_chnl_snd: Sender<SiemMessage>,
-        /// Receive logs
-        log_receiver: Receiver<SiemLog>,
-        log_sender: Sender<SiemLog>,
-        datasets: DatasetHolder,
-        id: u64,
+        let (reply, response) = crossbeam_channel::bounded(1);
+        commands
+            .send(DatasetCommand::Register(
+                SiemDatasetType::BlockIp,
+                DatasetOptions::default(),
+                reply,
+            ))
+            .unwrap();
+        let holder = response
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert!(holder.get(&SiemDatasetType::BlockIp).is_some());
+        assert!(holder.get(&SiemDatasetType::IpMac).is_some());
+
+        let (reply, response) = crossbeam_channel::bounded(1);
+        commands
+            .send(DatasetCommand::Register(
+                SiemDatasetType::MantainceCalendar,
+                DatasetOptions::default(),
+                reply,
+            ))
+            .unwrap();
+        assert!(response
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap()
+            .is_err());
     }
-    impl BasicComponent {
-        pub fn new() -> BasicComponent {
-            let (kernel_sender, _receiver) = crossbeam_channel::bounded(1000);
-            let (local_chnl_snd, _local_chnl_rcv) = crossbeam_channel::unbounded();
-            let (log_sender, log_receiver) = crossbeam_channel::unbounded();
-            return BasicComponent {
-                kernel_sender,
-                local_chnl_snd,
-                log_receiver,
-                log_sender,
-                id: 0,
-                datasets: DatasetHolder::from_datasets(vec![]),
-            };
+
+    #[test]
+    fn test_lazy_holder() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let mut holder = manager.lazy_holder(std::time::Duration::from_secs(10));
+        std::thread::spawn(move || manager.run());
+
+        assert!(holder.holder().get(&SiemDatasetType::HostUser).is_none());
+        match holder.get(&SiemDatasetType::HostUser) {
+            Some(SiemDataset::HostUser(_)) => {}
+            _ => panic!("Dataset HostUser not registered"),
         }
+        assert!(holder.holder().get(&SiemDatasetType::HostUser).is_some());
+        assert!(holder.get(&SiemDatasetType::IpMac).is_some());
+        assert!(holder.get(&SiemDatasetType::MantainceCalendar).is_none());
     }
 
-    impl SiemComponent for BasicComponent {
-        fn id(&self) -> u64 {
-            return self.id;
+    #[test]
+    fn test_ip_map_encoding() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let ip4 = SiemIp::from_ip_str("192.168.1.20").unwrap();
+        let ip6 = SiemIp::from_ip_str("2001:db8::20").unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                dataset.insert(ip4.clone(), Cow::Borrowed("00:11:22:33:44:55"));
+                dataset.insert(ip6.clone(), Cow::Borrowed("66:77:88:99:aa:bb"));
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn set_id(&mut self, id: u64) {
-            self.id = id;
+        manager.force_flush();
+        let stored = dataset_ip_map(&manager.conn, "IpMac", None).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                for (ip, mac) in &[(&ip4, "00:11:22:33:44:55"), (&ip6, "66:77:88:99:aa:bb")] {
+                    assert_eq!(&dataset.get(ip).unwrap()[..], *mac);
+                    assert_eq!(&stored.get(ip).unwrap()[..], *mac);
+                }
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn name(&self) -> &str {
-            "BasicParser"
+    }
+
+    #[test]
+    fn test_synchronous() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let holder = manager.get_datasets();
+        let ip_mac = match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.clone(),
+            _ => panic!("Dataset IpMac not found"),
+        };
+        // More than a bounded channel holds: it would block without the run loop
+        for ip in 0..500 {
+            ip_mac.insert(SiemIp::V4(ip), Cow::Borrowed("mac"));
         }
-        fn local_channel(&self) -> Sender<SiemMessage> {
-            self.local_chnl_snd.clone()
+        let (reply, response) = crossbeam_channel::bounded(1);
+        manager
+            .command_channel()
+            .send(DatasetCommand::Register(SiemDatasetType::BlockIp, DatasetOptions::default(), reply))
+            .unwrap();
+        manager.force_flush();
+        assert!(response.try_recv().unwrap().is_ok());
+        assert_eq!(manager.dataset_count(), 2);
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert!(dataset.get(&SiemIp::V4(0)).is_some());
+                assert!(dataset.get(&SiemIp::V4(499)).is_some());
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn set_log_channel(&mut self, log_sender: Sender<SiemLog>, receiver: Receiver<SiemLog>) {
-            self.log_receiver = receiver;
-            self.log_sender = log_sender;
+    }
+
+    #[test]
+    fn test_rebuild_interval() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        assert!(manager.set_rebuild_interval(0).is_err());
+        manager.set_rebuild_interval(100).unwrap();
+        assert_eq!(manager.flush_stats().interval_ms, 100);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let holder = manager.get_datasets();
+        let local_chan = manager.local_channel();
+        std::thread::spawn(move || manager.run());
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                dataset.insert(SiemIp::V4(1), Cow::Borrowed("mac"))
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn set_kernel_sender(&mut self, sender: Sender<SiemMessage>) {
-            self.kernel_sender = sender;
+        let started = std::time::Instant::now();
+        let visible = loop {
+            let found = match holder.get(&SiemDatasetType::IpMac) {
+                Some(SiemDataset::IpMac(dataset)) => dataset.get(&SiemIp::V4(1)).is_some(),
+                _ => false,
+            };
+            if found || started.elapsed() > std::time::Duration::from_secs(2) {
+                break started.elapsed();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        let _ = local_chan.send(SiemMessage::Command(
+            SiemCommandHeader {
+                user: String::from("None"),
+                comp_id: 0,
+                comm_id: 0,
+            },
+            SiemCommandCall::STOP_COMPONENT("Stop!!".to_string()),
+        ));
+        assert!(visible < std::time::Duration::from_millis(500), "{:?}", visible);
+    }
+
+    #[test]
+    fn test_rebuild_failure_keeps_dataset() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let holder = manager.get_datasets();
+        let ip_mac = match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.clone(),
+            _ => panic!("Dataset IpMac not found"),
+        };
+        ip_mac.insert(SiemIp::V4(1), Cow::Borrowed("mac1"));
+        manager.force_flush();
+        // A value that can't be read as text makes the next rebuild fail
+        manager
+            .conn
+            .execute("INSERT INTO dataset_IpMac (data_key, data_val) VALUES (X'0A000003', X'FF')", [])
+            .unwrap();
+        ip_mac.insert(SiemIp::V4(2), Cow::Borrowed("mac2"));
+        manager.force_flush();
+        assert!(manager.last_error(&SiemDatasetType::IpMac).is_some());
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert!(dataset.get(&SiemIp::V4(1)).is_some());
+                assert!(dataset.get(&SiemIp::V4(2)).is_none());
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn duplicate(&self) -> Box<dyn SiemComponent> {
-            return Box::new(self.clone());
+        assert_eq!(manager.row_count("IpMac"), 3);
+    }
+
+    #[test]
+    fn test_skip_malformed_ip_keys() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.update_map_ip("IpMac", UpdateIpMap::Add((SiemIp::V4(1), Cow::Borrowed("mac1")))).unwrap();
+        manager
+            .conn
+            .execute("INSERT INTO dataset_IpMac (data_key, data_val) VALUES (X'0102030405', 'bad')", [])
+            .unwrap();
+        // Text keys of old versions are loaded, keys of other types are skipped
+        manager
+            .conn
+            .execute_batch("INSERT INTO dataset_IpMac (data_key, data_val) VALUES ('10.0.0.2', 'mac2');INSERT INTO dataset_IpMac (data_key, data_val) VALUES (1234, 'bad')")
+            .unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::IpMac).unwrap();
+        match manager.get_datasets().get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => {
+                assert_eq!(&dataset.get(&SiemIp::V4(1)).unwrap()[..], "mac1");
+                assert_eq!(&dataset.get(&SiemIp::from_ip_str("10.0.0.2").unwrap()).unwrap()[..], "mac2");
+            }
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn set_datasets(&mut self, datasets: DatasetHolder) {
-            self.datasets = datasets;
+        assert_eq!(manager.row_count("IpMac"), 4);
+    }
+
+    #[test]
+    fn test_register_invalid_schema() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        // Keys that are not IPs
+        manager
+            .conn
+            .execute_batch("CREATE TABLE dataset_BlockIp (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key INTEGER NOT NULL UNIQUE);INSERT INTO dataset_BlockIp (data_key) VALUES (1234)")
+            .unwrap();
+        let res = manager.register_dataset_with_options(SiemDatasetType::BlockIp, DatasetOptions::default());
+        assert!(res.unwrap_err().starts_with("Cannot init dataset BlockIp"));
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        assert!(manager.get_datasets().get(&SiemDatasetType::BlockIp).is_none());
+        manager.register_dataset(SiemDatasetType::IpMac);
+        assert_eq!(manager.dataset_count(), 1);
+        assert!(manager.get_datasets().get(&SiemDatasetType::IpMac).is_some());
+    }
+
+    #[test]
+    fn test_idle_run_loop() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let local_chan = manager.local_channel();
+        let handle = std::thread::spawn(move || {
+            manager.run();
+            manager
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let _ = local_chan.send(SiemMessage::Command(
+            SiemCommandHeader {
+                user: String::from("None"),
+                comp_id: 0,
+                comm_id: 0,
+            },
+            SiemCommandCall::STOP_COMPONENT("Stop!!".to_string()),
+        ));
+        let manager = handle.join().unwrap();
+        // A spinning loop would flush thousands of times per second
+        assert!(manager.flush_histogram().cycles < 10);
+    }
+
+    #[test]
+    fn test_stop_run_loop() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let holder = manager.get_datasets();
+        let local_chan = manager.local_channel();
+        let (done, finished) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            manager.run();
+            let _ = done.send(manager);
+        });
+        match holder.get(&SiemDatasetType::IpMac) {
+            Some(SiemDataset::IpMac(dataset)) => dataset.insert(SiemIp::V4(1), Cow::Borrowed("mac1")),
+            _ => panic!("Dataset IpMac not found"),
         }
-        fn run(&mut self) {
-            match self.datasets.get(&SiemDatasetType::IpMac) {
-                Some(dataset) => match dataset {
-                    SiemDataset::IpMac(dataset) => {
-                        for i in 0..10000 {
-                            dataset.insert(SiemIp::V4(i), Cow::Owned(format!("IP:{}", i)));
-                        }
-                    }
-                    _ => {}
+        local_chan
+            .send(SiemMessage::Command(
+                SiemCommandHeader {
+                    user: String::from("None"),
+                    comp_id: 0,
+                    comm_id: 0,
                 },
-                None => {}
+                SiemCommandCall::STOP_COMPONENT("Stop!!".to_string()),
+            ))
+            .unwrap();
+        // Well before the flush interval
+        let manager = finished
+            .recv_timeout(std::time::Duration::from_millis(2000))
+            .expect("The run loop didn't stop");
+        assert_eq!(manager.row_count("IpMac"), 1);
+    }
+
+    #[test]
+    fn test_drain_on_stop() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        let holder = manager.get_datasets();
+        let local_chan = manager.local_channel();
+        match holder.get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => {
+                for i in 0..100 {
+                    dataset.insert(SiemIp::V4(i));
+                }
             }
+            _ => panic!("Dataset BlockIp not found"),
         }
-        fn set_storage(&mut self, _conn: Box<dyn SiemComponentStateStorage>) {}
-
-        /// Capabilities and actions that can be performed on this component
-        fn capabilities(&self) -> SiemComponentCapabilities {
-            SiemComponentCapabilities::new(
-                Cow::Borrowed("BasicDummyComponent"),
-                Cow::Borrowed("Basic dummy component for testing purposes"),
-                Cow::Borrowed(""), // No HTML
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-            )
+        match holder.get(&SiemDatasetType::BlockDomain) {
+            Some(SiemDataset::BlockDomain(dataset)) => dataset.insert(Cow::Borrowed("malicious.com")),
+            _ => panic!("Dataset BlockDomain not found"),
         }
+        let handle = std::thread::spawn(move || {
+            manager.run();
+            manager
+        });
+        local_chan
+            .send(SiemMessage::Command(
+                SiemCommandHeader {
+                    user: String::from("None"),
+                    comp_id: 0,
+                    comm_id: 0,
+                },
+                SiemCommandCall::STOP_COMPONENT("Stop!!".to_string()),
+            ))
+            .unwrap();
+        let mut manager = handle.join().unwrap();
+        assert_eq!(manager.row_count("BlockIp"), 100);
+        assert_eq!(manager.row_count("BlockDomain"), 1);
+        // Written but not rebuilt
+        assert_eq!(manager.flush_histogram().cycles, 0);
+        assert_eq!(manager.drain_pending(), 0);
     }
 
     #[test]
-    fn test_ip_conversion() {
-        let ip100 = SiemIp::V4(100);
-        let arry = ip_to_vec8(&ip100);
-        let ip100_2 = ip_form_vec8(&arry).unwrap();
-        assert_eq!(ip100,ip100_2);
-
-        let ip100 = SiemIp::V6(1234567);
-        let arry = ip_to_vec8(&ip100);
-        let ip100_2 = ip_form_vec8(&arry).unwrap();
-        assert_eq!(ip100,ip100_2);
+    fn test_drain_failed_replace() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        let mut current = IpSetDataset::new();
+        for ip in 0..10 {
+            current.insert(SiemIp::V4(ip));
+        }
+        manager.update_ip_set("BlockIp", UpdateIpSet::Replace(current)).unwrap();
+        manager
+            .conn
+            .execute_batch("CREATE TRIGGER fail_insert BEFORE INSERT ON dataset_BlockIp WHEN NEW.data_key = X'00000007' BEGIN SELECT RAISE(ABORT, 'rejected'); END")
+            .unwrap();
+        let holder = manager.get_datasets();
+        match holder.get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => {
+                let mut replacement = IpSetDataset::new();
+                for ip in 5..20 {
+                    replacement.insert(SiemIp::V4(ip));
+                }
+                dataset.update(replacement);
+            }
+            _ => panic!("Dataset BlockIp not found"),
+        }
+        match holder.get(&SiemDatasetType::BlockDomain) {
+            Some(SiemDataset::BlockDomain(dataset)) => dataset.insert(Cow::Borrowed("malicious.com")),
+            _ => panic!("Dataset BlockDomain not found"),
+        }
+        assert_eq!(manager.drain_pending(), 1);
+        // The failed replace is undone, the rest of the drain is written
+        let mut stmt = manager.conn.prepare("SELECT data_key FROM dataset_BlockIp ORDER BY data_key").unwrap();
+        let keys: Vec<Vec<u8>> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        let expected: Vec<Vec<u8>> = (0..10).map(|ip| ip_to_vec8(&SiemIp::V4(ip))).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(manager.row_count("BlockDomain"), 1);
+        assert!(manager.last_error(&SiemDatasetType::BlockIp).is_some());
     }
 
     #[test]
@@ -1181,4 +6811,522 @@ mod tests {
             SiemCommandCall::STOP_COMPONENT("Stop!!".to_string()),
         ));
     }
+
+    #[test]
+    fn test_builder() {
+        assert!(SqliteDatasetManagerBuilder::new().build().is_err());
+        assert!(SqliteDatasetManagerBuilder::new().in_memory().channel_capacity(0).build().is_err());
+        assert!(SqliteDatasetManagerBuilder::new().in_memory().rebuild_interval_ms(0).build().is_err());
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .in_memory()
+            .rebuild_interval_ms(250)
+            .channel_capacity(16)
+            .busy_timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert_eq!(manager.flush_stats().interval_ms, 250);
+        manager.register_dataset(SiemDatasetType::IpMac);
+        let listener = manager.registered_datasets.get(&SiemDatasetType::IpMac).unwrap();
+        assert_eq!(listener.queue_depth(), (0, 16));
+
+        let dir = std::env::temp_dir().join(format!("usiem_builder_{}", std::process::id()));
+        let path = dir.to_string_lossy().to_string();
+        let manager = SqliteDatasetManagerBuilder::new().path(path.clone()).wal(false).build().unwrap();
+        let journal_mode: String = manager
+            .conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "delete");
+        assert!(manager.pending_migrations().unwrap().is_empty());
+        drop(manager);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_channel_capacity() {
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .in_memory()
+            .channel_capacity(8)
+            .build()
+            .unwrap();
+        assert!(manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpMac,
+                DatasetOptions {
+                    channel_capacity: Some(0),
+                    ..Default::default()
+                }
+            )
+            .is_err());
+        manager
+            .register_dataset_with_options(
+                SiemDatasetType::IpMac,
+                DatasetOptions {
+                    channel_capacity: Some(4),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let block_ip = manager.registered_datasets.get(&SiemDatasetType::BlockIp).unwrap();
+        assert_eq!(block_ip.queue_depth(), (0, 8));
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::IpMac) {
+            Some(UpdateListener::UpdateIpMap(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for IpMac"),
+        };
+        for i in 0..4 {
+            sender
+                .try_send(UpdateIpMap::Add((SiemIp::V4(i), Cow::Borrowed("mac"))))
+                .unwrap();
+        }
+        // Full: try_send fails and send blocks until the manager drains the channel
+        assert!(matches!(
+            sender.try_send(UpdateIpMap::Add((SiemIp::V4(4), Cow::Borrowed("mac")))),
+            Err(crossbeam_channel::TrySendError::Full(_))
+        ));
+        assert!(sender
+            .send_timeout(
+                UpdateIpMap::Add((SiemIp::V4(4), Cow::Borrowed("mac"))),
+                std::time::Duration::from_millis(10)
+            )
+            .is_err());
+        assert_eq!(manager.flush_stats().max_fill, 1.0);
+        manager.force_flush();
+        assert_eq!(manager.flush_stats().queued, 0);
+        sender
+            .try_send(UpdateIpMap::Add((SiemIp::V4(4), Cow::Borrowed("mac"))))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_maintenance() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager
+            .conn
+            .execute_batch(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000) INSERT INTO dataset_BlockIp (data_key) SELECT i FROM n; DELETE FROM dataset_BlockIp WHERE data_key > 10;",
+            )
+            .unwrap();
+        let freelist = |manager: &SqliteDatasetManager| -> i64 {
+            manager.conn.query_row("PRAGMA freelist_count", [], |row| row.get(0)).unwrap()
+        };
+        assert!(freelist(&manager) > 0);
+        manager.run_maintenance().unwrap();
+        assert_eq!(freelist(&manager), 0);
+        let count: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_BlockIp", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 10);
+
+        let tx = manager.conn.unchecked_transaction().unwrap();
+        assert!(manager.run_maintenance().is_err());
+        drop(tx);
+
+        assert!(manager.set_maintenance_interval(Some(0)).is_err());
+        manager.set_maintenance_interval(Some(2)).unwrap();
+        manager.rebuilds_since_maintenance = 1;
+        manager.maintenance_if_due();
+        assert_eq!(manager.rebuilds_since_maintenance, 1);
+        manager.rebuilds_since_maintenance = 2;
+        manager.maintenance_if_due();
+        assert_eq!(manager.rebuilds_since_maintenance, 0);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = temp_db_path("read_only");
+        let mut writer = SqliteDatasetManager::new(path.clone()).unwrap();
+        writer.register_dataset(SiemDatasetType::BlockIp);
+        writer.update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(1))).unwrap();
+        assert!(SqliteDatasetManagerBuilder::new().in_memory().read_only(true).build().is_err());
+
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .path(path.clone())
+            .read_only(true)
+            .build()
+            .unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        assert!(block_ip_contains(&manager, &SiemIp::V4(1)));
+
+        // The updates are ignored and the other writes fail
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::BlockIp) {
+            Some(UpdateListener::UpdateIpSet(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for BlockIp"),
+        };
+        sender.send(UpdateIpSet::Add(SiemIp::V4(2))).unwrap();
+        manager.force_flush();
+        assert!(!block_ip_contains(&manager, &SiemIp::V4(2)));
+        assert!(manager
+            .import_csv(&SiemDatasetType::BlockIp, &mut "key\n10.0.0.3\n".as_bytes())
+            .is_err());
+        let count: i64 = writer
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_BlockIp", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // The changes of the writer are loaded on the next flush
+        writer.update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(3))).unwrap();
+        manager.force_flush();
+        assert!(block_ip_contains(&manager, &SiemIp::V4(3)));
+        drop(manager);
+        drop(writer);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_external_reload() {
+        let path = temp_db_path("external_reload");
+        let mut manager = SqliteDatasetManagerBuilder::new()
+            .path(path.clone())
+            .external_reload_ms(50)
+            .build()
+            .unwrap();
+        assert!(manager.set_external_reload(Some(0)).is_err());
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        manager.set_external_reload(Some(50)).unwrap();
+        let generation = manager.holder_generation();
+        manager.external_reload_if_due();
+        assert_eq!(manager.holder_generation(), generation);
+
+        let other = Connection::open(&path).unwrap();
+        other
+            .execute("INSERT INTO dataset_BlockIp (data_key) VALUES ('10.0.0.7')", [])
+            .unwrap();
+        // Not due yet
+        manager.external_reload_if_due();
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        manager.external_reload_if_due();
+        assert!(block_ip_contains(&manager, &SiemIp::from_ip_str("10.0.0.7").unwrap()));
+        // Only the dataset that changed is swapped
+        assert_eq!(manager.holder_generation(), generation + 1);
+        assert_eq!(manager.reload_external_changes(), Vec::new());
+        drop(other);
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_debug_shared() {
+        assert!(SqliteDatasetManager::debug().unwrap().memory_uri().is_none());
+        let mut manager = SqliteDatasetManager::debug_shared().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        let uri = manager.memory_uri().unwrap().to_string();
+        let flags = rusqlite::OpenFlags::default() | rusqlite::OpenFlags::SQLITE_OPEN_URI;
+        let first = Connection::open_with_flags(&uri, flags).unwrap();
+        let second = Connection::open_with_flags(&uri, flags).unwrap();
+        first
+            .execute("INSERT INTO dataset_BlockDomain (data_key) VALUES ('example.com')", [])
+            .unwrap();
+        let count = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM dataset_BlockDomain", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count(&second), 1);
+        assert_eq!(count(&manager.conn), 1);
+        let pool = manager.read_pool(1).unwrap();
+        assert_eq!(count(&pool.get().unwrap()), 1);
+
+        // Each manager has its own database
+        let other = SqliteDatasetManager::debug_shared().unwrap();
+        assert_ne!(other.memory_uri(), manager.memory_uri());
+        let tables: i64 = other
+            .conn
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'dataset_BlockDomain'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn test_normalize_domains() {
+        assert_eq!(normalize_domain(" Example.COM. "), "example.com");
+        assert_eq!(normalize_domain("example.com"), "example.com");
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockDomain);
+        manager.register_dataset(SiemDatasetType::BlockEmailSender);
+        manager.update_text_set("BlockDomain", UpdateTextSet::Add(Cow::Borrowed("Example.COM."))).unwrap();
+        manager.update_text_set("BlockDomain", UpdateTextSet::Add(Cow::Borrowed("example.com"))).unwrap();
+        let keys = |manager: &SqliteDatasetManager, name: &str| -> Vec<String> {
+            let mut stmt = manager
+                .conn
+                .prepare(&format!("SELECT data_key FROM dataset_{} ORDER BY id", name))
+                .unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        assert_eq!(keys(&manager, "BlockDomain"), vec![String::from("example.com")]);
+        manager.rebuild_dataset(&SiemDatasetType::BlockDomain).unwrap();
+        assert_eq!(
+            manager.lookup(&SiemDatasetType::BlockDomain, "EXAMPLE.com.").unwrap(),
+            Some(String::from("example.com"))
+        );
+
+        let mut replacement = TextSetDataset::new();
+        replacement.insert(Cow::Borrowed("Other.org."));
+        replacement.insert(Cow::Borrowed("other.org"));
+        manager.update_text_set("BlockDomain", UpdateTextSet::Replace(replacement)).unwrap();
+        assert_eq!(keys(&manager, "BlockDomain"), vec![String::from("other.org")]);
+        manager.update_text_set("BlockDomain", UpdateTextSet::Remove(Cow::Borrowed("OTHER.org."))).unwrap();
+        assert!(keys(&manager, "BlockDomain").is_empty());
+
+        // Other text sets keep their keys as they are
+        manager
+            .update_text_set("BlockEmailSender", UpdateTextSet::Add(Cow::Borrowed("Spam@Example.COM")))
+            .unwrap();
+        assert_eq!(keys(&manager, "BlockEmailSender"), vec![String::from("Spam@Example.COM")]);
+    }
+
+    #[test]
+    fn test_dataset_len() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        assert!(manager.dataset_len(&SiemDatasetType::BlockIp).is_err());
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        assert_eq!(manager.dataset_len(&SiemDatasetType::BlockIp).unwrap(), 0);
+        for i in 1..4 {
+            manager.update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(i))).unwrap();
+        }
+        assert_eq!(manager.dataset_len(&SiemDatasetType::BlockIp).unwrap(), 3);
+        manager
+            .update_map_ip_list(
+                "IpDNS",
+                UpdateIpMapList::Add((SiemIp::V4(1), vec![Cow::Borrowed("a.com"), Cow::Borrowed("b.com")])),
+            )
+            .unwrap();
+        assert_eq!(manager.dataset_len(&SiemDatasetType::IpDNS).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_clear_dataset() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::BlockIp) {
+            Some(UpdateListener::UpdateIpSet(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for BlockIp"),
+        };
+        for i in 1..4 {
+            sender.send(UpdateIpSet::Add(SiemIp::V4(i))).unwrap();
+        }
+        manager
+            .update_map_ip_list("IpDNS", UpdateIpMapList::Add((SiemIp::V4(1), vec![Cow::Borrowed("a.com")])))
+            .unwrap();
+        manager.force_flush();
+        let served = |manager: &SqliteDatasetManager| match manager.get_datasets().get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => (1..4).filter(|i| dataset.contains(&SiemIp::V4(*i))).count(),
+            _ => panic!("Dataset BlockIp not found"),
+        };
+        assert_eq!(served(&manager), 3);
+
+        manager.clear_dataset(&SiemDatasetType::BlockIp).unwrap();
+        manager.clear_dataset(&SiemDatasetType::IpDNS).unwrap();
+        manager.force_flush();
+        assert_eq!(served(&manager), 0);
+        assert_eq!(manager.dataset_len(&SiemDatasetType::BlockIp).unwrap(), 0);
+        let values: i64 = manager
+            .conn
+            .query_row("SELECT COUNT(*) FROM dataset_list_IpDNS", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(values, 0);
+        // The dataset keeps working
+        sender.send(UpdateIpSet::Add(SiemIp::V4(1))).unwrap();
+        manager.force_flush();
+        assert_eq!(served(&manager), 1);
+    }
+
+    /// Records of all the tests, which log concurrently
+    static CAPTURED_LOGS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if let Ok(mut logs) = CAPTURED_LOGS.lock() {
+                logs.push((record.level(), format!("{}", record.args())));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    #[test]
+    fn test_log_unknown_dataset() {
+        capture_logs();
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::HeadquartersWorkingHours);
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        assert!(logs.iter().any(|(level, message)| {
+            *level == log::Level::Error
+                && message.contains("Cannot register dataset HeadquartersWorkingHours")
+                && message.contains("not supported")
+        }));
+    }
+
+    #[test]
+    fn test_register_unsupported_dataset() {
+        let mut manager = SqliteDatasetManager::debug().unwrap().with_synchronous();
+        for dataset_type in &[SiemDatasetType::HeadquartersWorkingHours, SiemDatasetType::MantainceCalendar] {
+            let res = manager.register_dataset_with_options(dataset_type.clone(), DatasetOptions::default());
+            assert_eq!(res, Err(format!("Dataset type not supported: {:?}", dataset_type)));
+            assert!(manager.get_datasets().get(dataset_type).is_none());
+        }
+        assert_eq!(manager.dataset_count(), 0);
+        assert!(manager.dataset_options.is_empty());
+
+        // The updates of IpDNS reach the rebuild
+        manager.register_dataset(SiemDatasetType::IpDNS);
+        let sender = match manager.registered_datasets.get(&SiemDatasetType::IpDNS) {
+            Some(UpdateListener::UpdateIpMapList(s, _, _)) => s.clone(),
+            _ => panic!("Invalid listener for IpDNS"),
+        };
+        sender
+            .send(UpdateIpMapList::Add((SiemIp::V4(1), vec![Cow::Borrowed("example.com")])))
+            .unwrap();
+        manager.force_flush();
+        match manager.get_datasets().get(&SiemDatasetType::IpDNS) {
+            Some(SiemDataset::IpDNS(dataset)) => assert!(dataset.get(&SiemIp::V4(1)).is_some()),
+            _ => panic!("Dataset IpDNS not found"),
+        }
+    }
+
+    #[test]
+    fn test_lookup_geo_ip_overlapping() {
+        let manager = SqliteDatasetManager::debug().unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::GeoIp);
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        let info = |city: &'static str| GeoIpInfo {
+            country: Cow::Borrowed("ES"),
+            city: Cow::Borrowed(city),
+            latitude: 0.0,
+            longitude: 0.0,
+            isp: Cow::Borrowed(""),
+        };
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Add((ip("10.0.0.0"), 8, info("Net8")))).unwrap();
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Add((ip("10.1.2.0"), 24, info("Net24")))).unwrap();
+        manager.update_geo_ip("GeoIp", UpdateGeoIp::Add((ip("10.1.0.0"), 16, info("Net16")))).unwrap();
+        // Rows written before the range columns are filled when the table is checked again
+        manager
+            .conn
+            .execute(
+                "INSERT INTO dataset_GeoIp (network, data_key, country, city, latitude, longitude, isp) VALUES (32, ?1, 'ES', 'Host', 0, 0, '')",
+                params![ip_to_vec8(&ip("10.1.2.3"))],
+            )
+            .unwrap();
+        manager.create_dataset_tables(&SiemDatasetType::GeoIp);
+        let city = |txt: &str| manager.lookup_geo_ip(&ip(txt)).unwrap().map(|info| info.city.to_string());
+        assert_eq!(city("10.1.2.3"), Some(String::from("Host")));
+        assert_eq!(city("10.1.2.4"), Some(String::from("Net24")));
+        assert_eq!(city("10.1.3.1"), Some(String::from("Net16")));
+        assert_eq!(city("10.200.0.1"), Some(String::from("Net8")));
+        assert_eq!(city("11.0.0.1"), None);
+        // The bytes of 10.1.2.3 at the start of an IPv6 are not in the IPv4 networks
+        assert_eq!(manager.lookup_geo_ip(&SiemIp::V6(0x0a01_0203 << 96)).unwrap().map(|info| info.city), None);
+    }
+
+    #[test]
+    fn test_ip_keys_big_endian() {
+        let ip = |txt: &str| SiemIp::from_ip_str(txt).unwrap();
+        let ordered = [
+            (ip("0.0.0.255"), ip("0.0.1.0")),
+            (ip("9.255.255.255"), ip("10.0.0.0")),
+            (ip("10.0.0.1"), ip("192.168.0.1")),
+            (SiemIp::V6(255), SiemIp::V6(256)),
+            (ip("2001:db8::1"), ip("2001:db9::")),
+        ];
+        for (a, b) in &ordered {
+            assert!(ip_to_vec8(a) < ip_to_vec8(b), "{} < {}", a, b);
+            assert_eq!(&ip_form_vec8(&ip_to_vec8(a)).unwrap(), a);
+            assert_eq!(&ip_form_vec8(&ip_to_vec8(b)).unwrap(), b);
+        }
+        assert_eq!(ip_to_vec8(&ip("10.0.0.1")), vec![10, 0, 0, 1]);
+        assert_eq!(ip_to_vec8(&SiemIp::V6(1)).len(), 16);
+
+        // Keys written little-endian by a database of the previous schema
+        let path = temp_db_path("big_endian");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("CREATE TABLE dataset_BlockIp (id INTEGER PRIMARY KEY AUTOINCREMENT, data_key BLOB NOT NULL UNIQUE, expires_at INTEGER);PRAGMA user_version = 2;").unwrap();
+            // Each key is the other one reversed
+            for key in &[1u32, 1 << 24] {
+                conn.execute(
+                    "INSERT INTO dataset_BlockIp (data_key) VALUES (?1)",
+                    params![key.to_le_bytes().to_vec()],
+                )
+                .unwrap();
+            }
+        }
+        {
+            let mut manager = SqliteDatasetManager::new(path.clone()).unwrap();
+            assert_eq!(manager.schema_version().unwrap(), 3);
+            manager.register_dataset(SiemDatasetType::BlockIp);
+            match manager.get_datasets().get(&SiemDatasetType::BlockIp) {
+                Some(SiemDataset::BlockIp(dataset)) => {
+                    assert!(dataset.contains(&ip("0.0.0.1")));
+                    assert!(dataset.contains(&ip("1.0.0.0")));
+                }
+                _ => panic!("Dataset BlockIp not found"),
+            }
+        }
+        remove_db(&path);
+    }
+
+    #[test]
+    fn test_registered_types() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        assert!(manager.registered_types().is_empty());
+        manager.register_dataset(SiemDatasetType::IpMac);
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        let registered = manager.registered_types();
+        assert_eq!(registered.len(), 2);
+        assert!(registered.contains(&SiemDatasetType::IpMac));
+        assert!(registered.contains(&SiemDatasetType::BlockIp));
+        assert!(manager.is_registered(&SiemDatasetType::BlockIp));
+        assert!(!manager.is_registered(&SiemDatasetType::GeoIp));
+    }
+
+    #[test]
+    fn test_retired_datasets() {
+        let mut manager = SqliteDatasetManager::debug().unwrap();
+        manager.register_dataset(SiemDatasetType::BlockIp);
+        manager.update_ip_set("BlockIp", UpdateIpSet::Add(SiemIp::V4(1))).unwrap();
+        let holder = manager.get_datasets();
+        let before = holder.get(&SiemDatasetType::BlockIp).unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::BlockIp).unwrap();
+        manager.rebuild_dataset(&SiemDatasetType::BlockIp).unwrap();
+        // Still readable after the swaps
+        match before {
+            SiemDataset::BlockIp(dataset) => assert!(!dataset.contains(&SiemIp::V4(1))),
+            _ => panic!("Dataset BlockIp not found"),
+        }
+        assert_eq!(manager.retired_datasets.len(), 2);
+        match holder.get(&SiemDatasetType::BlockIp) {
+            Some(SiemDataset::BlockIp(dataset)) => assert!(dataset.contains(&SiemIp::V4(1))),
+            _ => panic!("Dataset BlockIp not found"),
+        }
+        assert!(manager.set_retire_grace(0).is_err());
+        assert!(manager.set_retire_grace(DEFAULT_FLUSH_INTERVAL - 1).is_err());
+        manager.set_retire_grace(DEFAULT_FLUSH_INTERVAL).unwrap();
+        assert_eq!(manager.retired_datasets.len(), 2);
+        // Freed once the grace period passes
+        for (retired_at, _) in manager.retired_datasets.iter_mut() {
+            *retired_at -= DEFAULT_FLUSH_INTERVAL;
+        }
+        manager.free_retired();
+        assert!(manager.retired_datasets.is_empty());
+    }
 }
@@ -0,0 +1,43 @@
+use super::SqliteDatasetManager;
+use log::error;
+
+impl SqliteDatasetManager {
+    /// Repacks the database file with VACUUM, releasing the pages freed by the deletes and the replaced
+    /// datasets, and refreshes the statistics of the query planner with ANALYZE. VACUUM rewrites the whole
+    /// file and can't run inside a transaction.
+    pub fn run_maintenance(&self) -> Result<(), String> {
+        if !self.conn.is_autocommit() {
+            return Err(String::from("Cannot run the maintenance inside a transaction"));
+        }
+        match self.conn.execute_batch("VACUUM; ANALYZE;") {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Runs `run_maintenance` from the run loop every `cycles` flushes that rebuilt a dataset.
+    /// Disabled with None, the default.
+    pub fn set_maintenance_interval(&mut self, cycles: Option<u64>) -> Result<(), String> {
+        if cycles == Some(0) {
+            return Err(String::from("Invalid maintenance interval: 0"));
+        }
+        self.maintenance_interval = cycles;
+        self.rebuilds_since_maintenance = 0;
+        Ok(())
+    }
+
+    /// Runs the maintenance if enough rebuild cycles have passed since the last one
+    pub(crate) fn maintenance_if_due(&mut self) {
+        let cycles = match self.maintenance_interval {
+            Some(cycles) => cycles,
+            None => return,
+        };
+        if self.rebuilds_since_maintenance < cycles {
+            return;
+        }
+        self.rebuilds_since_maintenance = 0;
+        if let Err(e) = self.run_maintenance() {
+            error!("Cannot run the maintenance of the database: {}", e);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use super::{DatasetError, SqliteDatasetManager};
+use rusqlite::params;
+
+/// Schema migration of the database, identified by the `user_version` it leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationInfo {
+    pub version: u32,
+    pub description: String,
+    /// Statements run by the migration. Empty if it only sets the version or rewrites the rows in code
+    pub sql: String,
+}
+
+/// Rewrite of the rows that can't be expressed in SQL, run after the statements of its migration
+type MigrationStep = fn(&SqliteDatasetManager) -> Result<(), DatasetError>;
+
+/// Migrations in order. A database with `user_version` N needs the ones with a higher version.
+/// New migrations go at the end with the next version, the applied ones must not change.
+const MIGRATIONS: &[(u32, &str, &str, Option<MigrationStep>)] = &[
+    (
+        1,
+        "Baseline schema: the tables of each dataset are created when it's registered",
+        "",
+        None,
+    ),
+    (
+        2,
+        "Table of dataset versions used by replace_if_version",
+        "CREATE TABLE IF NOT EXISTS usiem_dataset_versions (dataset_name TEXT NOT NULL PRIMARY KEY, version INTEGER NOT NULL)",
+        None,
+    ),
+    (
+        3,
+        "IPs stored in network order, so their blobs sort like the IPs",
+        "",
+        Some(ip_keys_to_big_endian),
+    ),
+];
+
+/// Reverses the bytes of the IP keys written little-endian by the previous versions. Only the IP
+/// datasets and their hit counters store the keys as blobs. Each key goes first through a value one
+/// byte longer, so the rewrite never collides with a key not yet reversed.
+fn ip_keys_to_big_endian(manager: &SqliteDatasetManager) -> Result<(), DatasetError> {
+    let tables: Vec<String> = {
+        let mut stmt = manager.conn.prepare(
+            "SELECT m.name FROM sqlite_master m WHERE m.type = 'table' AND m.name LIKE 'dataset\\_%' ESCAPE '\\' AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'data_key')",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    for table in tables {
+        let keys: Vec<(i64, Vec<u8>)> = {
+            let mut stmt = manager.conn.prepare(&format!(
+                "SELECT rowid, data_key FROM {} WHERE typeof(data_key) = 'blob' AND length(data_key) IN (4, 16)",
+                table
+            ))?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let mut update = manager
+            .conn
+            .prepare(&format!("UPDATE {} SET data_key = ?2 WHERE rowid = ?1", table))?;
+        for (rowid, key) in &keys {
+            let mut moved = key.clone();
+            moved.push(0);
+            update.execute(params![rowid, moved])?;
+        }
+        for (rowid, key) in keys {
+            let reversed: Vec<u8> = key.into_iter().rev().collect();
+            update.execute(params![rowid, reversed])?;
+        }
+    }
+    Ok(())
+}
+
+impl SqliteDatasetManager {
+    /// Version of the schema of the database (`PRAGMA user_version`). 0 for databases never migrated.
+    pub fn schema_version(&self) -> Result<u32, String> {
+        match self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+        {
+            Ok(version) => Ok(version),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Migrations that would run on this database given its `user_version`. Nothing is applied.
+    pub fn pending_migrations(&self) -> Result<Vec<MigrationInfo>, String> {
+        let current = self.schema_version()?;
+        Ok(MIGRATIONS
+            .iter()
+            .filter(|(version, _, _, _)| *version > current)
+            .map(|(version, description, sql, _)| MigrationInfo {
+                version: *version,
+                description: description.to_string(),
+                sql: sql.to_string(),
+            })
+            .collect())
+    }
+
+    /// Applies the pending migrations in order, each one in its own transaction, and records them in the
+    /// schema_version table. Returns the migrations applied. `new` runs it when the database is opened.
+    pub fn migrate(&self) -> Result<Vec<MigrationInfo>, String> {
+        if let Err(e) = self.conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL PRIMARY KEY, description TEXT NOT NULL, applied_at INTEGER NOT NULL)", []) {
+            return Err(format!("{}", e));
+        }
+        let pending = self.pending_migrations()?;
+        for migration in &pending {
+            let res = self.in_transaction(|| {
+                if !migration.sql.is_empty() {
+                    self.conn.execute_batch(&migration.sql)?;
+                }
+                let step = MIGRATIONS
+                    .iter()
+                    .find(|(version, _, _, _)| *version == migration.version)
+                    .and_then(|(_, _, _, step)| *step);
+                if let Some(step) = step {
+                    step(self)?;
+                }
+                self.conn.execute(
+                    "INSERT OR REPLACE INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![migration.version, migration.description, chrono::Utc::now().timestamp_millis()],
+                )?;
+                self.conn.pragma_update(None, "user_version", migration.version)?;
+                Ok(())
+            });
+            if let Err(e) = res {
+                return Err(format!("Cannot apply migration {}: {}", migration.version, e));
+            }
+        }
+        Ok(pending)
+    }
+}
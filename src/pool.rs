@@ -0,0 +1,98 @@
+use super::SqliteDatasetManager;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OpenFlags;
+use std::time::Duration;
+
+/// Time a pooled connection (and the manager connection) waits for a lock held by the other side
+const POOL_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time `ReadPool::get` waits for a connection when all of them are in use
+const POOL_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connection taken from a pool, given back to it when dropped
+pub type PooledConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+/// Pool of the connection of the manager
+pub(crate) type ConnectionPool = r2d2::Pool<SqliteConnectionManager>;
+
+/// Pool of read-only connections to the database of a manager, to read the tables from other threads
+/// while the run loop keeps writing through its own connection. Clones share the same connections.
+#[derive(Clone)]
+pub struct ReadPool {
+    pool: ConnectionPool,
+}
+
+/// Pool with the single connection used by the manager for the updates and the loads, and the connection
+/// taken from it. The connections of a private in-memory database are each a different database, so the
+/// pool never opens a second one
+pub(crate) fn manager_connection(
+    connections: SqliteConnectionManager,
+) -> Result<(ConnectionPool, PooledConnection), String> {
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .max_lifetime(None)
+        .idle_timeout(None)
+        .build(connections);
+    let pool = match pool {
+        Ok(pool) => pool,
+        Err(e) => return Err(format!("{}", e)),
+    };
+    match pool.get() {
+        Ok(conn) => Ok((pool, conn)),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Connections to a database file, without writing to it when `read_only`
+pub(crate) fn file_connections(path: &str, read_only: bool) -> SqliteConnectionManager {
+    if read_only {
+        SqliteConnectionManager::file(path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+    } else {
+        SqliteConnectionManager::file(path)
+    }
+}
+
+impl ReadPool {
+    /// Takes an idle connection. When all of them are in use it waits for one to be given back, failing after
+    /// 30 seconds
+    pub fn get(&self) -> Result<PooledConnection, String> {
+        match self.pool.get_timeout(POOL_CONNECTION_TIMEOUT) {
+            Ok(conn) => Ok(conn),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Connections waiting to be used
+    pub fn idle_connections(&self) -> usize {
+        self.pool.state().idle_connections as usize
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Pool of `size` read-only connections to the database file. Only for file databases and shared
+    /// in-memory ones: a private in-memory database can't be opened by another connection.
+    /// The manager connection also waits for the locks of the readers instead of failing with "database is locked".
+    pub fn read_pool(&self, size: usize) -> Result<ReadPool, String> {
+        let path = match (self.database_path()?, self.memory_uri()) {
+            (Some(path), _) => path,
+            (None, Some(uri)) => uri.to_string(),
+            (None, None) => return Err(String::from("Cannot pool an in-memory database")),
+        };
+        if size == 0 || size > u32::MAX as usize {
+            return Err(format!("Invalid pool size: {}", size));
+        }
+        if let Err(e) = self.conn.busy_timeout(POOL_BUSY_TIMEOUT) {
+            return Err(format!("{}", e));
+        }
+        let connections = file_connections(&path, true).with_init(|conn| conn.busy_timeout(POOL_BUSY_TIMEOUT));
+        let pool = r2d2::Pool::builder()
+            .max_size(size as u32)
+            .connection_timeout(POOL_CONNECTION_TIMEOUT)
+            .build(connections);
+        match pool {
+            Ok(pool) => Ok(ReadPool { pool }),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use super::{
+    dataset_shape, dataset_table_name, ip_form_vec8, ip_to_vec8, load_filter_params, load_filter_sql,
+    max_prefix, network_address, DatasetShape, SqlFilter, SqliteDatasetManager,
+};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, OptionalExtension, Row};
+use std::borrow::Cow;
+use usiem::components::dataset::geo_ip::GeoIpInfo;
+use usiem::components::dataset::SiemDatasetType;
+use usiem::events::field::SiemIp;
+
+/// Row of a dataset as stored in the database
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Insertion order of the entry
+    pub id: i64,
+    /// Key of the entry. IPs are formated as text and networks as IP/prefix
+    pub key: String,
+    /// Values associated with the key. Empty for sets, one element for maps, the list for map-lists
+    /// and country, city, latitude, longitude and isp for GeoIp
+    pub values: Vec<String>,
+}
+
+/// Entry of a dataset with the types of its kind, returned by `SqliteDatasetManager::dataset_entries`
+#[derive(Debug)]
+pub enum DatasetEntry {
+    /// Entry of a text set: BlockDomain, BlockEmailSender, BlockCountry, CustomTextList
+    TextEntry(String),
+    /// Entry of an IP set: BlockIp, CustomIpList
+    IpEntry(SiemIp),
+    /// Entry of a text map: MacHost, HostUser, UserHeadquarters, Configuration, CustomMapText, Secrets
+    TextMapEntry(String, String),
+    /// Entry of a text map-list: HostVulnerable, UserTag, AssetTag, CustomMapTextList
+    TextMapListEntry(String, Vec<String>),
+    /// Entry of an IP map: IpMac, CustomIpMap
+    IpMapEntry(SiemIp, String),
+    /// Entry of an IP map-list: IpDNS
+    IpMapListEntry(SiemIp, Vec<String>),
+    /// Network and prefix of an IP network map: IpCloudService, IpCloudProvider, IpHeadquarters, CustomMapIpNet
+    IpNetEntry(SiemIp, u8, String),
+    /// Network and prefix of the GeoIp dataset
+    GeoIpEntry(SiemIp, u8, GeoIpInfo),
+}
+
+fn ip_value(value: Value) -> Result<SiemIp, String> {
+    match value {
+        Value::Blob(v) => match ip_form_vec8(&v) {
+            Ok(ip) => Ok(ip),
+            Err(_) => Err(format!("Invalid IP key of {} bytes", v.len())),
+        },
+        Value::Text(v) => match SiemIp::from_ip_str(&v) {
+            Ok(ip) => Ok(ip),
+            Err(_) => Err(format!("Invalid IP key: {}", v)),
+        },
+        _ => Err(String::from("Invalid IP key")),
+    }
+}
+
+fn float_value(value: Value) -> Result<f32, String> {
+    match value {
+        Value::Real(v) => Ok(v as f32),
+        Value::Integer(v) => Ok(v as f32),
+        Value::Text(v) => match v.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(format!("Invalid coordinate: {}", v)),
+        },
+        _ => Err(String::from("Invalid coordinate")),
+    }
+}
+
+fn row_values(row: &Row) -> Result<Vec<Value>, String> {
+    let mut values = Vec::new();
+    for i in 0..row.as_ref().column_count() {
+        match row.get(i) {
+            Ok(value) => values.push(value),
+            Err(e) => return Err(format!("{}", e)),
+        }
+    }
+    Ok(values)
+}
+
+fn dataset_entry(shape: DatasetShape, values: Vec<Value>) -> Result<DatasetEntry, String> {
+    let mut values = values.into_iter();
+    let mut next = || values.next().unwrap_or(Value::Null);
+    Ok(match shape {
+        DatasetShape::TextSet => DatasetEntry::TextEntry(value_to_string(next())),
+        DatasetShape::IpSet => DatasetEntry::IpEntry(ip_value(next())?),
+        DatasetShape::TextMap => {
+            DatasetEntry::TextMapEntry(value_to_string(next()), value_to_string(next()))
+        }
+        DatasetShape::IpMap => DatasetEntry::IpMapEntry(ip_value(next())?, value_to_string(next())),
+        DatasetShape::TextMapList => {
+            DatasetEntry::TextMapListEntry(value_to_string(next()), Vec::new())
+        }
+        DatasetShape::IpMapList => DatasetEntry::IpMapListEntry(ip_value(next())?, Vec::new()),
+        DatasetShape::IpNet => {
+            let ip = ip_value(next())?;
+            let network = match next() {
+                Value::Integer(network) => network as u8,
+                _ => return Err(String::from("Invalid network prefix")),
+            };
+            DatasetEntry::IpNetEntry(ip, network, value_to_string(next()))
+        }
+        DatasetShape::GeoIp => {
+            let ip = ip_value(next())?;
+            let network = match next() {
+                Value::Integer(network) => network as u8,
+                _ => return Err(String::from("Invalid network prefix")),
+            };
+            let country = Cow::Owned(value_to_string(next()));
+            let city = Cow::Owned(value_to_string(next()));
+            let latitude = float_value(next())?;
+            let longitude = float_value(next())?;
+            let isp = Cow::Owned(value_to_string(next()));
+            DatasetEntry::GeoIpEntry(
+                ip,
+                network,
+                GeoIpInfo {
+                    country,
+                    city,
+                    latitude,
+                    longitude,
+                    isp,
+                },
+            )
+        }
+    })
+}
+
+pub(crate) fn value_to_string(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(v) => v.to_string(),
+        Value::Real(v) => v.to_string(),
+        Value::Text(v) => v,
+        Value::Blob(v) => match ip_form_vec8(&v) {
+            Ok(ip) => ip.to_string(),
+            Err(_) => String::from_utf8_lossy(&v).to_string(),
+        },
+    }
+}
+
+/// Columns of dataset_{name} with the key and the values of the entry
+fn entry_columns(shape: DatasetShape) -> &'static str {
+    match shape {
+        DatasetShape::TextSet
+        | DatasetShape::IpSet
+        | DatasetShape::TextMapList
+        | DatasetShape::IpMapList => "id, data_key",
+        DatasetShape::TextMap | DatasetShape::IpMap => "id, data_key, data_val",
+        DatasetShape::IpNet => "id, data_key, network, data_val",
+        DatasetShape::GeoIp => "id, data_key, network, country, city, latitude, longitude, isp",
+    }
+}
+
+fn row_to_entry(shape: DatasetShape, row: &Row) -> rusqlite::Result<Entry> {
+    let id: i64 = row.get(0)?;
+    let key = value_to_string(row.get(1)?);
+    let mut values = Vec::new();
+    let (key, first_value) = match shape {
+        DatasetShape::IpNet | DatasetShape::GeoIp => {
+            let network: i64 = row.get(2)?;
+            (format!("{}/{}", key, network), 3)
+        }
+        _ => (key, 2),
+    };
+    for i in first_value..row.as_ref().column_count() {
+        values.push(value_to_string(row.get(i)?));
+    }
+    Ok(Entry { id, key, values })
+}
+
+/// Networks containing an IP, from the longest prefix to the shortest
+fn containing_networks(ip: &SiemIp) -> Vec<(u8, SiemIp)> {
+    (0..=max_prefix(ip) as u8)
+        .rev()
+        .map(|net| (net, network_address(ip, net)))
+        .collect()
+}
+
+impl SqliteDatasetManager {
+    /// Returns the `n` entries of a dataset inserted last, newest first.
+    pub fn recent_entries(
+        &self,
+        dataset_type: &SiemDatasetType,
+        n: usize,
+    ) -> Result<Vec<Entry>, String> {
+        self.query_entries(dataset_type, None, Some(n))
+    }
+
+    /// Reads the entries of a dataset matching the filter from the database, newest first
+    pub(crate) fn query_entries(
+        &self,
+        dataset_type: &SiemDatasetType,
+        filter: Option<&SqlFilter>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Entry>, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        // A negative LIMIT returns all the rows
+        let limit = limit.map(|n| n as i64).unwrap_or(-1);
+        let mut stmt = match self.conn.prepare(&format!(
+            "SELECT {columns} FROM dataset_{dataset_name}{filter} ORDER BY id DESC LIMIT {limit}",
+            columns = entry_columns(shape),
+            dataset_name = name,
+            filter = load_filter_sql(filter, &name, false),
+            limit = limit
+        )) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let rows = match stmt.query_map(params_from_iter(load_filter_params(filter)), |row| {
+            row_to_entry(shape, row)
+        }) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut entries = Vec::new();
+        for row in rows {
+            match row {
+                Ok(entry) => entries.push(entry),
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+        if shape.has_list_table() {
+            let mut stmt = match self.conn.prepare(&format!(
+                "SELECT data_val FROM dataset_list_{dataset_name} WHERE data_key = ?1 ORDER BY id",
+                dataset_name = name
+            )) {
+                Ok(stmt) => stmt,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            for entry in entries.iter_mut() {
+                let rows = match stmt.query_map(params![entry.id], |row| row.get(0)) {
+                    Ok(rows) => rows,
+                    Err(e) => return Err(format!("{}", e)),
+                };
+                for row in rows {
+                    match row {
+                        Ok(value) => entry.values.push(value),
+                        Err(e) => return Err(format!("{}", e)),
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads all the entries of a dataset from the database in insertion order, with the types of its kind.
+    /// The entries are read before returning the iterator, so it does not keep the database busy.
+    pub fn dataset_entries(
+        &self,
+        dataset_type: &SiemDatasetType,
+    ) -> Result<std::vec::IntoIter<DatasetEntry>, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let sql = if shape.has_list_table() {
+            format!("SELECT t1.id, t1.data_key, t2.data_val FROM dataset_{dataset_name} as t1 LEFT JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key ORDER BY t1.id, t2.id", dataset_name = name)
+        } else {
+            let columns = match shape {
+                DatasetShape::TextSet | DatasetShape::IpSet => "data_key",
+                DatasetShape::IpNet => "data_key, network, data_val",
+                DatasetShape::GeoIp => "data_key, network, country, city, latitude, longitude, isp",
+                _ => "data_key, data_val",
+            };
+            format!(
+                "SELECT {columns} FROM dataset_{dataset_name} ORDER BY id",
+                columns = columns,
+                dataset_name = name
+            )
+        };
+        let mut stmt = match self.conn.prepare(&sql) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut rows = match stmt.query([]) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut entries = Vec::new();
+        let mut last_id = None;
+        loop {
+            let row = match rows.next() {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let mut values = row_values(row)?;
+            if !shape.has_list_table() {
+                entries.push(dataset_entry(shape, values)?);
+                continue;
+            }
+            // Map-lists: (id, key, value) rows grouped by id. Keys without values have a NULL value
+            let list_value = values.pop();
+            let id = values.remove(0);
+            if last_id.as_ref() != Some(&id) {
+                entries.push(dataset_entry(shape, values)?);
+                last_id = Some(id);
+            }
+            if let Some(Value::Text(value)) = list_value {
+                match entries.last_mut() {
+                    Some(DatasetEntry::TextMapListEntry(_, list))
+                    | Some(DatasetEntry::IpMapListEntry(_, list)) => list.push(value),
+                    _ => {}
+                }
+            }
+        }
+        Ok(entries.into_iter())
+    }
+
+    /// Returns the keys of a TextMapList dataset (UserTag, AssetTag...) that have `value` in their list.
+    pub fn keys_with_list_value(
+        &self,
+        dataset_type: &SiemDatasetType,
+        value: &str,
+    ) -> Result<Vec<String>, String> {
+        let name = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(DatasetShape::TextMapList), Some(name)) => name,
+            _ => return Err(format!("Dataset {:?} is not a TextMapList dataset", dataset_type)),
+        };
+        let mut stmt = match self.conn.prepare(&format!(
+            "SELECT DISTINCT t1.data_key FROM dataset_{dataset_name} as t1 INNER JOIN dataset_list_{dataset_name} as t2 ON t1.id = t2.data_key WHERE t2.data_val = ?1 ORDER BY t1.id",
+            dataset_name = name
+        )) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let rows = match stmt.query_map(params![value], |row| row.get(0)) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut keys = Vec::new();
+        for row in rows {
+            match row {
+                Ok(key) => keys.push(key),
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Country of the most specific GeoIp network containing the IP. Only the country column is read,
+    /// one query per prefix length from the longest to the shortest.
+    pub fn geoip_country(&self, ip: &SiemIp) -> Result<Option<String>, String> {
+        let mut stmt = match self.conn.prepare_cached(
+            "SELECT country FROM dataset_GeoIp WHERE network = ?1 AND data_key = ?2",
+        ) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        for (net, network_ip) in containing_networks(ip) {
+            match stmt
+                .query_row(params![net, ip_to_vec8(&network_ip)], |row| row.get(0))
+                .optional()
+            {
+                Ok(Some(country)) => return Ok(Some(country)),
+                Ok(None) => {}
+                Err(e) => return Err(format!("{}", e)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Entries of a dataset counted in its table, without loading it. Map-lists count their keys
+    pub fn dataset_len(&self, dataset_type: &SiemDatasetType) -> Result<usize, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let count = match shape {
+            DatasetShape::TextMapList | DatasetShape::IpMapList => "COUNT(DISTINCT data_key)",
+            _ => "COUNT(*)",
+        };
+        match self.conn.query_row(
+            &format!("SELECT {count} FROM dataset_{dataset_name}", count = count, dataset_name = name),
+            [],
+            |row| row.get::<_, i64>(0),
+        ) {
+            Ok(len) => Ok(len as usize),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Value of an IP in an IP map read from its table with the data_key index, without loading the
+    /// dataset in memory
+    pub fn lookup_ip_map(&self, dataset_type: &SiemDatasetType, ip: &SiemIp) -> Result<Option<String>, String> {
+        let name = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(DatasetShape::IpMap), Some(name)) => name,
+            _ => return Err(format!("Dataset {:?} is not an IP map", dataset_type)),
+        };
+        let mut stmt = match self.conn.prepare_cached(&format!(
+            "SELECT data_val FROM dataset_{dataset_name} WHERE data_key = ?1",
+            dataset_name = name
+        )) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        match stmt.query_row(params![ip_to_vec8(ip)], |row| row.get(0)).optional() {
+            Ok(value) => Ok(value),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Location of the most specific GeoIp network containing the IP, read from the table like
+    /// `geoip_country` without loading the dataset in memory. A single range scan of the network-order
+    /// bounds, the length check keeps IPv4 and IPv6 networks apart
+    pub fn lookup_geo_ip(&self, ip: &SiemIp) -> Result<Option<GeoIpInfo>, String> {
+        let mut stmt = match self.conn.prepare_cached(
+            // The coordinates are stored in TEXT columns
+            "SELECT country, city, CAST(latitude AS REAL), CAST(longitude AS REAL), isp FROM dataset_GeoIp WHERE net_start <= ?1 AND net_end >= ?1 AND length(net_start) = length(?1) ORDER BY network DESC LIMIT 1",
+        ) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let res = stmt
+            .query_row(params![ip_to_vec8(ip)], |row| {
+                Ok(GeoIpInfo {
+                    country: Cow::Owned(row.get(0)?),
+                    city: Cow::Owned(row.get(1)?),
+                    latitude: row.get(2)?,
+                    longitude: row.get(3)?,
+                    isp: Cow::Owned(row.get(4)?),
+                })
+            })
+            .optional();
+        match res {
+            Ok(info) => Ok(info),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Union of the IPs of several IP sets, IP maps or IP map-lists, without duplicates
+    pub fn union_ip_datasets(&self, types: &[SiemDatasetType]) -> Result<Vec<SiemIp>, String> {
+        let mut selects = Vec::with_capacity(types.len());
+        for dataset_type in types {
+            match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+                (Some(DatasetShape::IpSet), Some(name))
+                | (Some(DatasetShape::IpMap), Some(name))
+                | (Some(DatasetShape::IpMapList), Some(name)) => {
+                    selects.push(format!("SELECT data_key FROM dataset_{}", name))
+                }
+                _ => return Err(format!("Dataset {:?} is not keyed by IP", dataset_type)),
+            }
+        }
+        if selects.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut stmt = match self
+            .conn
+            .prepare(&format!("{} ORDER BY data_key", selects.join(" UNION ")))
+        {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let rows = match stmt.query_map([], |row| row.get::<_, Vec<u8>>(0)) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let mut ips = Vec::new();
+        for row in rows {
+            let key = match row {
+                Ok(key) => key,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            match ip_form_vec8(&key) {
+                Ok(ip) => ips.push(ip),
+                Err(_) => return Err(format!("Invalid IP key: {:?}", key)),
+            }
+        }
+        Ok(ips)
+    }
+}
@@ -0,0 +1,183 @@
+use super::{dataset_shape, dataset_table_name, DatasetShape, SqliteDatasetManager};
+use rusqlite::{params, OptionalExtension};
+use std::borrow::Cow;
+use usiem::components::dataset::{SiemDataset, SiemDatasetType};
+use usiem::events::field::SiemIp;
+
+/// Resolves the keys that are not present in a dataset using an external source like a reputation service.
+pub trait ReadThroughResolver: Send {
+    /// Returns the value associated with the key, or None if the external source does not know it.
+    fn resolve(&self, dataset_type: &SiemDatasetType, key: &str) -> Result<Option<String>, String>;
+}
+
+pub(crate) struct ReadThrough {
+    resolver: Box<dyn ReadThroughResolver>,
+    ttl_ms: i64,
+}
+
+/// Resolves keys with an HTTP GET. The `{key}` placeholder in the URL is replaced with the encoded key.
+/// A 404 response means that the key is unknown, any other error is returned to the caller and not cached.
+#[cfg(feature = "http")]
+pub struct HttpResolver {
+    url: String,
+    timeout: std::time::Duration,
+}
+
+#[cfg(feature = "http")]
+impl HttpResolver {
+    pub fn new(url: &str, timeout: std::time::Duration) -> HttpResolver {
+        HttpResolver {
+            url: url.to_string(),
+            timeout,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl ReadThroughResolver for HttpResolver {
+    fn resolve(&self, _dataset_type: &SiemDatasetType, key: &str) -> Result<Option<String>, String> {
+        let url = self.url.replace("{key}", &url_encode(key));
+        match ureq::get(&url).timeout(self.timeout).call() {
+            Ok(response) => match response.into_string() {
+                Ok(body) => Ok(Some(body)),
+                Err(e) => Err(format!("{}", e)),
+            },
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl SqliteDatasetManager {
+    /// Configures a resolver for the misses of a dataset. Resolved values are cached in the table
+    /// dataset_cache_{name} during `ttl_ms` milliseconds. Only text/IP sets and maps are supported.
+    pub fn set_read_through(
+        &mut self,
+        dataset_type: SiemDatasetType,
+        resolver: Box<dyn ReadThroughResolver>,
+        ttl_ms: i64,
+    ) -> Result<(), String> {
+        match dataset_shape(&dataset_type) {
+            Some(DatasetShape::TextMap)
+            | Some(DatasetShape::TextSet)
+            | Some(DatasetShape::IpMap)
+            | Some(DatasetShape::IpSet) => {}
+            _ => {
+                return Err(format!(
+                    "Read-through not supported for dataset {:?}",
+                    dataset_type
+                ))
+            }
+        }
+        let name = match dataset_table_name(&dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        if let Err(e) = self.conn.execute(&format!("CREATE TABLE IF NOT EXISTS dataset_cache_{dataset_name} (data_key TEXT NOT NULL PRIMARY KEY, data_val TEXT, expires_at INTEGER NOT NULL)", dataset_name = name), []) {
+            return Err(format!("{}", e));
+        }
+        self.read_through
+            .insert(dataset_type, ReadThrough { resolver, ttl_ms });
+        Ok(())
+    }
+
+    /// Gets the value of a key from the in-memory dataset. On a miss, the cache of the read-through resolver
+    /// is checked and, if the entry is missing or expired, the resolver is queried and the result cached.
+    /// For sets the returned value is the key itself.
+    pub fn read_through_get(
+        &self,
+        dataset_type: &SiemDatasetType,
+        key: &str,
+    ) -> Result<Option<String>, String> {
+        if let Some(value) = self.local_value(dataset_type, key) {
+            return Ok(Some(value));
+        }
+        let read_through = match self.read_through.get(dataset_type) {
+            Some(read_through) => read_through,
+            None => return Ok(None),
+        };
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        let cached: Option<Option<String>> = match self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT data_val FROM dataset_cache_{dataset_name} WHERE data_key = ?1 AND expires_at > ?2",
+                    dataset_name = name
+                ),
+                params![key, now],
+                |row| row.get(0),
+            )
+            .optional()
+        {
+            Ok(cached) => cached,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+        let value = read_through.resolver.resolve(dataset_type, key)?;
+        if let Err(e) = self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO dataset_cache_{dataset_name} (data_key, data_val, expires_at) VALUES (?1, ?2, ?3)",
+                dataset_name = name
+            ),
+            params![key, value, now + read_through.ttl_ms],
+        ) {
+            return Err(format!("{}", e));
+        }
+        Ok(value)
+    }
+
+    pub(crate) fn local_value(&self, dataset_type: &SiemDatasetType, key: &str) -> Option<String> {
+        let dataset = self.datasets.get(dataset_type)?;
+        match &**dataset {
+            SiemDataset::MacHost(d)
+            | SiemDataset::HostUser(d)
+            | SiemDataset::UserHeadquarters(d)
+            | SiemDataset::Configuration(d)
+            | SiemDataset::CustomMapText((_, d))
+            | SiemDataset::Secrets((_, d)) => d.get(key).map(|v| v.to_string()),
+            SiemDataset::BlockDomain(d)
+            | SiemDataset::BlockEmailSender(d)
+            | SiemDataset::BlockCountry(d)
+            | SiemDataset::CustomTextList((_, d)) => {
+                if d.contains(&Cow::Owned(key.to_string())) {
+                    Some(key.to_string())
+                } else {
+                    None
+                }
+            }
+            SiemDataset::IpMac(d) | SiemDataset::CustomIpMap((_, d)) => {
+                let ip = SiemIp::from_ip_str(key).ok()?;
+                d.get(&ip).map(|v| v.to_string())
+            }
+            SiemDataset::BlockIp(d) | SiemDataset::CustomIpList((_, d)) => {
+                let ip = SiemIp::from_ip_str(key).ok()?;
+                if d.contains(&ip) {
+                    Some(key.to_string())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
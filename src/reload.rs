@@ -0,0 +1,122 @@
+use super::{dataset_shape, dataset_table_name, SqliteDatasetManager};
+use log::error;
+use rusqlite::types::ValueRef;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use usiem::components::dataset::SiemDatasetType;
+
+impl SqliteDatasetManager {
+    /// Reloads the registered datasets every `interval_ms` milliseconds if another connection or process
+    /// changed the database file. Only the datasets whose rows changed are swapped. Disabled with None, the default.
+    pub fn set_external_reload(&mut self, interval_ms: Option<i64>) -> Result<(), String> {
+        if let Some(interval_ms) = interval_ms {
+            if interval_ms <= 0 {
+                return Err(format!("Invalid external reload interval: {}", interval_ms));
+            }
+        }
+        self.external_reload_ms = interval_ms;
+        // Baseline of the registered datasets, already loaded
+        self.external_data_version = Some(self.data_version()?);
+        let registered: Vec<SiemDatasetType> = self.registered_datasets.keys().cloned().collect();
+        for dataset_type in registered {
+            let hash = self.content_hash(&dataset_type)?;
+            self.content_hashes.insert(dataset_type, hash);
+        }
+        Ok(())
+    }
+
+    /// Changes with each commit of other connections to the database
+    fn data_version(&self) -> Result<i64, String> {
+        match self.conn.query_row("PRAGMA data_version", [], |row| row.get(0)) {
+            Ok(version) => Ok(version),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Hash of the rows of the tables of a dataset
+    fn content_hash(&self, dataset_type: &SiemDatasetType) -> Result<u64, String> {
+        let (shape, name) = match (dataset_shape(dataset_type), dataset_table_name(dataset_type)) {
+            (Some(shape), Some(name)) => (shape, name),
+            _ => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        let mut tables = vec![format!("dataset_{}", name)];
+        if shape.has_list_table() {
+            tables.push(format!("dataset_list_{}", name));
+        }
+        let mut hasher = DefaultHasher::new();
+        for table in tables {
+            let mut stmt = match self.conn.prepare(&format!("SELECT * FROM {} ORDER BY rowid", table)) {
+                Ok(stmt) => stmt,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            let columns = stmt.column_count();
+            let mut rows = match stmt.query([]) {
+                Ok(rows) => rows,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            loop {
+                let row = match rows.next() {
+                    Ok(Some(row)) => row,
+                    Ok(None) => break,
+                    Err(e) => return Err(format!("{}", e)),
+                };
+                for i in 0..columns {
+                    match row.get_ref(i) {
+                        Ok(ValueRef::Null) => 0u8.hash(&mut hasher),
+                        Ok(ValueRef::Integer(value)) => value.hash(&mut hasher),
+                        Ok(ValueRef::Real(value)) => value.to_bits().hash(&mut hasher),
+                        Ok(ValueRef::Text(value)) | Ok(ValueRef::Blob(value)) => value.hash(&mut hasher),
+                        Err(e) => return Err(format!("{}", e)),
+                    }
+                }
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Rebuilds the registered datasets whose rows changed since their last check. Returns the ones swapped
+    pub(crate) fn reload_external_changes(&mut self) -> Vec<SiemDatasetType> {
+        let time = chrono::Utc::now().timestamp_millis();
+        self.last_external_reload = time;
+        let version = match self.data_version() {
+            Ok(version) => version,
+            Err(e) => {
+                error!("Cannot check the changes of the database: {}", e);
+                return Vec::new();
+            }
+        };
+        if self.external_data_version == Some(version) {
+            return Vec::new();
+        }
+        self.external_data_version = Some(version);
+        let registered: Vec<SiemDatasetType> = self.registered_datasets.keys().cloned().collect();
+        let mut changed = BTreeSet::new();
+        for dataset_type in registered {
+            let hash = match self.content_hash(&dataset_type) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    error!("Cannot check the changes of dataset {:?}: {}", dataset_type, e);
+                    continue;
+                }
+            };
+            if self.content_hashes.insert(dataset_type.clone(), hash) != Some(hash) {
+                changed.insert(dataset_type);
+            }
+        }
+        let rebuilt = self.rebuild_updated(&changed, time);
+        self.emit_rebuilt_event(&rebuilt);
+        rebuilt
+    }
+
+    /// Runs `reload_external_changes` if the external reload interval has passed since the last check
+    pub(crate) fn external_reload_if_due(&mut self) {
+        let interval = match self.external_reload_ms {
+            Some(interval) => interval,
+            None => return,
+        };
+        if chrono::Utc::now().timestamp_millis() - self.last_external_reload >= interval {
+            self.reload_external_changes();
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use super::SqliteDatasetManager;
+use usiem::components::dataset::SiemDataset;
+
+/// Milliseconds a replaced dataset is kept alive by default
+pub(crate) const DEFAULT_RETIRE_GRACE_MS: i64 = 60_000;
+
+/// Shortest grace period allowed, also never below the flush interval
+const MIN_RETIRE_GRACE_MS: i64 = 1_000;
+
+impl SqliteDatasetManager {
+    /// Milliseconds a replaced or dropped dataset stays in memory. The components read the datasets through
+    /// the pointers of their `DatasetHolder`, so one that loaded the pointer before a swap may still be using
+    /// the previous version. References returned by `DatasetHolder::get` must not be kept longer than this:
+    /// clone the dataset, which only clones its `Arc`, to keep it. Defaults to 60 seconds, and it can't be
+    /// shorter than 1 second or the flush interval: freeing a dataset a reader still uses is undefined behaviour.
+    pub fn set_retire_grace(&mut self, grace_ms: i64) -> Result<(), String> {
+        let min_grace_ms = MIN_RETIRE_GRACE_MS.max(self.flush_interval);
+        if grace_ms < min_grace_ms {
+            return Err(format!(
+                "Invalid retire grace period: {}, it must be at least {} ms",
+                grace_ms, min_grace_ms
+            ));
+        }
+        self.retire_grace_ms = grace_ms;
+        self.free_retired();
+        Ok(())
+    }
+
+    /// Keeps a dataset no longer pointed by the holder until the grace period passes
+    pub(crate) fn retire_dataset(&mut self, dataset: Box<SiemDataset>) {
+        self.retired_datasets
+            .push_back((chrono::Utc::now().timestamp_millis(), dataset));
+        self.free_retired();
+    }
+
+    /// Frees the retired datasets older than the grace period
+    pub(crate) fn free_retired(&mut self) {
+        let now = chrono::Utc::now().timestamp_millis();
+        while let Some((retired_at, _)) = self.retired_datasets.front() {
+            if now - retired_at < self.retire_grace_ms {
+                break;
+            }
+            self.retired_datasets.pop_front();
+        }
+    }
+}
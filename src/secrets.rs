@@ -0,0 +1,182 @@
+use super::error::DatasetError;
+use super::SqliteDatasetManager;
+use std::borrow::Cow;
+use usiem::components::dataset::text_map::TextMapDataset;
+use usiem::components::dataset::SiemDatasetType;
+
+/// Prefix of the encrypted values: `enc1:` followed by the hex of the nonce and the ciphertext
+const ENCRYPTED_PREFIX: &str = "enc1:";
+/// Environment variable with the key of the Secrets datasets, 64 hex characters
+pub const SECRETS_KEY_ENV: &str = "USIEM_SECRETS_KEY";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(String::from("Invalid hex string"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| match u8::from_str_radix(&text[i..i + 2], 16) {
+            Ok(b) => Ok(b),
+            Err(_) => Err(String::from("Invalid hex string")),
+        })
+        .collect()
+}
+
+#[cfg(feature = "secrets-encryption")]
+mod cipher {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+    pub const NONCE_LEN: usize = 12;
+
+    pub fn available() -> bool {
+        true
+    }
+
+    /// ChaCha20-Poly1305 with a random nonce. The entry key is authenticated so a value can't be moved to another key.
+    /// Returns the nonce followed by the ciphertext.
+    pub fn encrypt(key: &[u8; 32], entry_key: &str, value: &str) -> Result<Vec<u8>, String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        if let Err(e) = getrandom::getrandom(&mut nonce) {
+            return Err(format!("{}", e));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: entry_key.as_bytes(),
+        };
+        match cipher.encrypt(Nonce::from_slice(&nonce), payload) {
+            Ok(ciphertext) => {
+                let mut sealed = nonce.to_vec();
+                sealed.extend(ciphertext);
+                Ok(sealed)
+            }
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    pub fn decrypt(key: &[u8; 32], entry_key: &str, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        if sealed.len() < NONCE_LEN {
+            return Err(String::from("Encrypted value too short"));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let payload = Payload {
+            msg: &sealed[NONCE_LEN..],
+            aad: entry_key.as_bytes(),
+        };
+        match cipher.decrypt(Nonce::from_slice(&sealed[..NONCE_LEN]), payload) {
+            Ok(value) => Ok(value),
+            Err(_) => Err(String::from("Cannot decrypt the value, wrong key or corrupted data")),
+        }
+    }
+}
+
+#[cfg(not(feature = "secrets-encryption"))]
+mod cipher {
+    pub fn available() -> bool {
+        false
+    }
+
+    pub fn encrypt(_key: &[u8; 32], _entry_key: &str, _value: &str) -> Result<Vec<u8>, String> {
+        Err(String::from("Built without the secrets-encryption feature"))
+    }
+
+    pub fn decrypt(_key: &[u8; 32], _entry_key: &str, _sealed: &[u8]) -> Result<Vec<u8>, String> {
+        Err(String::from("Built without the secrets-encryption feature"))
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Encrypts the values of the Secrets datasets with ChaCha20-Poly1305 and this 32 byte key. The values
+    /// are decrypted when the datasets are loaded, the keys of the entries stay in clear text.
+    /// Other readers of the tables (lookup, export_csv, dataset_entries...) get the encrypted values.
+    /// Requires the secrets-encryption feature.
+    pub fn with_secrets_key(mut self, key: &[u8]) -> Result<SqliteDatasetManager, String> {
+        if !cipher::available() {
+            return Err(String::from("Built without the secrets-encryption feature"));
+        }
+        if key.len() != 32 {
+            return Err(format!("The secrets key must have 32 bytes, found {}", key.len()));
+        }
+        let mut secrets_key = [0u8; 32];
+        secrets_key.copy_from_slice(key);
+        self.secrets_key = Some(secrets_key);
+        Ok(self)
+    }
+
+    /// Like `with_secrets_key` reading the key in hex from the USIEM_SECRETS_KEY environment variable
+    pub fn with_secrets_key_from_env(self) -> Result<SqliteDatasetManager, String> {
+        let key = match std::env::var(SECRETS_KEY_ENV) {
+            Ok(key) => key,
+            Err(_) => return Err(format!("{} is not set", SECRETS_KEY_ENV)),
+        };
+        let key = from_hex(key.trim())?;
+        self.with_secrets_key(&key)
+    }
+
+    /// Key used for the values of the table dataset_{name}. Only Secrets datasets are encrypted
+    fn secrets_key_of(&self, name: &str) -> Option<&[u8; 32]> {
+        let key = self.secrets_key.as_ref()?;
+        let is_secrets = self.dataset_options.keys().any(|dataset_type| match dataset_type {
+            SiemDatasetType::Secrets(secrets) => secrets == name,
+            _ => false,
+        });
+        if is_secrets {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    /// Value stored in dataset_{name} for an entry: encrypted for Secrets datasets with a key
+    pub(crate) fn seal_value<'a>(&self, name: &str, entry_key: &str, value: &'a str) -> rusqlite::Result<Cow<'a, str>> {
+        let key = match self.secrets_key_of(name) {
+            Some(key) => key,
+            None => return Ok(Cow::Borrowed(value)),
+        };
+        match cipher::encrypt(key, entry_key, value) {
+            Ok(sealed) => Ok(Cow::Owned(format!("{}{}", ENCRYPTED_PREFIX, to_hex(&sealed)))),
+            Err(e) => Err(rusqlite::Error::ToSqlConversionFailure(e.into())),
+        }
+    }
+
+    /// Decrypts the values of a Secrets dataset loaded from dataset_{name}. Values stored before the key
+    /// was set are kept as they are.
+    pub(crate) fn open_values(&self, name: &str, dataset: TextMapDataset) -> Result<TextMapDataset, DatasetError> {
+        let key = match self.secrets_key_of(name) {
+            Some(key) => key,
+            None => return Ok(dataset),
+        };
+        let mut opened = TextMapDataset::new();
+        for (entry_key, value) in dataset.internal_ref() {
+            let sealed = match value.strip_prefix(ENCRYPTED_PREFIX) {
+                Some(sealed) => sealed,
+                None => {
+                    opened.insert(entry_key.clone(), value.clone());
+                    continue;
+                }
+            };
+            let res = from_hex(sealed)
+                .and_then(|sealed| cipher::decrypt(key, entry_key, &sealed))
+                .and_then(|value| match String::from_utf8(value) {
+                    Ok(value) => Ok(value),
+                    Err(e) => Err(format!("{}", e)),
+                });
+            match res {
+                Ok(value) => opened.insert(entry_key.clone(), Cow::Owned(value)),
+                Err(e) => {
+                    return Err(DatasetError::Sqlite(rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        format!("Secret {}: {}", entry_key, e).into(),
+                    )))
+                }
+            }
+        }
+        Ok(opened)
+    }
+}
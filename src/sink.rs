@@ -0,0 +1,232 @@
+use super::dataset_table_name;
+use usiem::components::dataset::geo_ip::UpdateGeoIp;
+use usiem::components::dataset::ip_map::UpdateIpMap;
+use usiem::components::dataset::ip_map_list::UpdateIpMapList;
+use usiem::components::dataset::ip_net::UpdateNetIp;
+use usiem::components::dataset::ip_set::UpdateIpSet;
+use usiem::components::dataset::text_map::UpdateTextMap;
+use usiem::components::dataset::text_map_list::UpdateTextMapList;
+use usiem::components::dataset::text_set::UpdateTextSet;
+use usiem::components::dataset::SiemDatasetType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateEventKind {
+    Add,
+    Remove,
+    Replace,
+}
+
+/// Update applied to a dataset, sent to the `UpdateSink`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateEvent {
+    pub dataset_type: SiemDatasetType,
+    pub kind: UpdateEventKind,
+    /// Key of the entry added or removed. IPs as text and networks as IP/prefix. None for Replace
+    pub key: Option<String>,
+}
+
+impl UpdateEvent {
+    pub(crate) fn from_ip_set(dataset_type: &SiemDatasetType, update: &UpdateIpSet) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateIpSet::Add(ip) => (UpdateEventKind::Add, Some(ip.to_string())),
+            UpdateIpSet::Remove(ip) => (UpdateEventKind::Remove, Some(ip.to_string())),
+            UpdateIpSet::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_ip_map(dataset_type: &SiemDatasetType, update: &UpdateIpMap) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateIpMap::Add((ip, _)) => (UpdateEventKind::Add, Some(ip.to_string())),
+            UpdateIpMap::Remove(ip) => (UpdateEventKind::Remove, Some(ip.to_string())),
+            UpdateIpMap::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_ip_map_list(
+        dataset_type: &SiemDatasetType,
+        update: &UpdateIpMapList,
+    ) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateIpMapList::Add((ip, _)) => (UpdateEventKind::Add, Some(ip.to_string())),
+            UpdateIpMapList::Remove(ip) => (UpdateEventKind::Remove, Some(ip.to_string())),
+            UpdateIpMapList::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_text_map(dataset_type: &SiemDatasetType, update: &UpdateTextMap) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateTextMap::Add((key, _)) => (UpdateEventKind::Add, Some(key.to_string())),
+            UpdateTextMap::Remove(key) => (UpdateEventKind::Remove, Some(key.to_string())),
+            UpdateTextMap::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_text_map_list(
+        dataset_type: &SiemDatasetType,
+        update: &UpdateTextMapList,
+    ) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateTextMapList::Add((key, _)) => (UpdateEventKind::Add, Some(key.to_string())),
+            UpdateTextMapList::Remove(key) => (UpdateEventKind::Remove, Some(key.to_string())),
+            UpdateTextMapList::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_text_set(dataset_type: &SiemDatasetType, update: &UpdateTextSet) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateTextSet::Add(key) => (UpdateEventKind::Add, Some(key.to_string())),
+            UpdateTextSet::Remove(key) => (UpdateEventKind::Remove, Some(key.to_string())),
+            UpdateTextSet::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_ip_net(dataset_type: &SiemDatasetType, update: &UpdateNetIp) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateNetIp::Add((ip, net, _)) => (UpdateEventKind::Add, Some(format!("{}/{}", ip, net))),
+            UpdateNetIp::Remove((ip, net)) => {
+                (UpdateEventKind::Remove, Some(format!("{}/{}", ip, net)))
+            }
+            UpdateNetIp::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: dataset_type.clone(),
+            kind,
+            key,
+        }
+    }
+    pub(crate) fn from_geo_ip(update: &UpdateGeoIp) -> UpdateEvent {
+        let (kind, key) = match update {
+            UpdateGeoIp::Add((ip, net, _)) => (UpdateEventKind::Add, Some(format!("{}/{}", ip, net))),
+            UpdateGeoIp::Remove((ip, net)) => {
+                (UpdateEventKind::Remove, Some(format!("{}/{}", ip, net)))
+            }
+            UpdateGeoIp::Replace(_) => (UpdateEventKind::Replace, None),
+        };
+        UpdateEvent {
+            dataset_type: SiemDatasetType::GeoIp,
+            kind,
+            key,
+        }
+    }
+
+    /// JSON representation used by the Kafka and NATS sinks
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "dataset": dataset_table_name(&self.dataset_type),
+            "kind": format!("{:?}", self.kind),
+            "key": self.key,
+        })
+        .to_string()
+    }
+}
+
+/// Receives the updates applied by the run loop, after each batch is written and the datasets rebuilt.
+/// Errors are logged and do not stop the manager.
+pub trait UpdateSink: Send {
+    fn on_updates(&mut self, events: &[UpdateEvent]) -> Result<(), String>;
+}
+
+/// Sink used by default, discards the events
+pub struct NoopSink;
+
+impl UpdateSink for NoopSink {
+    fn on_updates(&mut self, _events: &[UpdateEvent]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Publishes each event as JSON in a Kafka topic
+#[cfg(feature = "kafka-sink")]
+pub struct KafkaSink {
+    producer: kafka::producer::Producer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka-sink")]
+impl KafkaSink {
+    pub fn new(hosts: Vec<String>, topic: &str) -> Result<KafkaSink, String> {
+        let producer = match kafka::producer::Producer::from_hosts(hosts)
+            .with_required_acks(kafka::producer::RequiredAcks::One)
+            .create()
+        {
+            Ok(producer) => producer,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "kafka-sink")]
+impl UpdateSink for KafkaSink {
+    fn on_updates(&mut self, events: &[UpdateEvent]) -> Result<(), String> {
+        let topic = &self.topic;
+        let records: Vec<kafka::producer::Record<(), String>> = events
+            .iter()
+            .map(|event| kafka::producer::Record::from_value(topic, event.to_json()))
+            .collect();
+        match self.producer.send_all(&records) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+}
+
+/// Publishes each event as JSON in a NATS subject
+#[cfg(feature = "nats-sink")]
+pub struct NatsSink {
+    connection: nats::Connection,
+    subject: String,
+}
+
+#[cfg(feature = "nats-sink")]
+impl NatsSink {
+    pub fn new(url: &str, subject: &str) -> Result<NatsSink, String> {
+        let connection = match nats::connect(url) {
+            Ok(connection) => connection,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        Ok(NatsSink {
+            connection,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats-sink")]
+impl UpdateSink for NatsSink {
+    fn on_updates(&mut self, events: &[UpdateEvent]) -> Result<(), String> {
+        for event in events {
+            if let Err(e) = self.connection.publish(&self.subject, event.to_json()) {
+                return Err(format!("{}", e));
+            }
+        }
+        Ok(())
+    }
+}
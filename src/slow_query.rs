@@ -0,0 +1,61 @@
+use super::SqliteDatasetManager;
+use lazy_static::lazy_static;
+use log::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Slow queries kept for `slow_queries`, the oldest are discarded
+const MAX_SLOW_QUERIES: usize = 100;
+
+/// Statement that took longer than the slow query threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowQuery {
+    pub sql: String,
+    pub duration: Duration,
+}
+
+// The profile callback of SQLite is a plain function, so the threshold and the log are global
+static SLOW_QUERY_THRESHOLD_US: AtomicU64 = AtomicU64::new(u64::MAX);
+
+lazy_static! {
+    static ref SLOW_QUERIES: Mutex<Vec<SlowQuery>> = Mutex::new(Vec::new());
+}
+
+fn profile(sql: &str, duration: Duration) {
+    if (duration.as_micros() as u64) < SLOW_QUERY_THRESHOLD_US.load(Ordering::Relaxed) {
+        return;
+    }
+    warn!("Slow query ({} ms): {}", duration.as_millis(), sql);
+    if let Ok(mut queries) = SLOW_QUERIES.lock() {
+        if queries.len() >= MAX_SLOW_QUERIES {
+            queries.remove(0);
+        }
+        queries.push(SlowQuery {
+            sql: sql.to_string(),
+            duration,
+        });
+    }
+}
+
+impl SqliteDatasetManager {
+    /// Logs the statements that take at least `threshold` to run, with their duration. None disables it.
+    /// The threshold is shared by all the managers of the process that enable it.
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        match threshold {
+            Some(threshold) => {
+                SLOW_QUERY_THRESHOLD_US.store(threshold.as_micros() as u64, Ordering::Relaxed);
+                self.conn.profile(Some(profile));
+            }
+            None => self.conn.profile(None),
+        }
+    }
+
+    /// Last slow queries logged by the managers of the process, oldest first
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        match SLOW_QUERIES.lock() {
+            Ok(queries) => queries.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use super::{dataset_table_name, SqliteDatasetManager};
+use rusqlite::params;
+use usiem::components::dataset::SiemDatasetType;
+
+/// Where the table of a dataset lives, returned by `SqliteDatasetManager::dataset_storage_kind`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageKind {
+    /// The main database file
+    Main,
+    /// The file of an attached database, with its schema name
+    Attached(String),
+    /// The temp schema or an in-memory database: lost when the connection is closed
+    Memory,
+    /// No table backs the dataset
+    Missing,
+}
+
+impl SqliteDatasetManager {
+    /// Schema and file of the databases of the connection, in the order SQLite resolves unqualified names
+    fn databases(&self) -> Vec<(String, String)> {
+        let mut stmt = match self.conn.prepare("SELECT name, file FROM pragma_database_list") {
+            Ok(stmt) => stmt,
+            Err(_) => return Vec::new(),
+        };
+        let mut databases: Vec<(String, String)> =
+            match stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+                Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+                Err(_) => return Vec::new(),
+            };
+        // The temp schema is listed after main but searched first
+        databases.sort_by_key(|(name, _)| name != "temp");
+        databases
+    }
+
+    /// Schema and file of the database holding a table, as the unqualified name would be resolved
+    fn table_location(&self, table: &str) -> Option<(String, String)> {
+        self.databases().into_iter().find(|(schema, _)| {
+            let sql = format!(
+                "SELECT COUNT(*) FROM \"{}\".sqlite_master WHERE type = 'table' AND name = ?1",
+                schema.replace('"', "\"\"")
+            );
+            let count: i64 = self
+                .conn
+                .query_row(&sql, params![table], |row| row.get(0))
+                .unwrap_or(0);
+            count > 0
+        })
+    }
+
+    /// Schema of the database holding a table
+    pub(crate) fn table_schema(&self, table: &str) -> Option<String> {
+        self.table_location(table).map(|(schema, _)| schema)
+    }
+
+    /// Tells if a dataset is stored in the main file, an attached database or only in memory
+    pub fn dataset_storage_kind(&self, dataset_type: &SiemDatasetType) -> StorageKind {
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return StorageKind::Missing,
+        };
+        match self.table_location(&format!("dataset_{}", name)) {
+            None => StorageKind::Missing,
+            Some((schema, file)) if schema == "temp" || file.is_empty() => StorageKind::Memory,
+            Some((schema, _)) if schema == "main" => StorageKind::Main,
+            Some((schema, _)) => StorageKind::Attached(schema),
+        }
+    }
+}
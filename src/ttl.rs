@@ -0,0 +1,146 @@
+use super::{dataset_shape, dataset_table_name, index_name, DatasetShape, SqliteDatasetManager};
+use log::error;
+use rusqlite::{params, ToSql};
+use std::collections::BTreeSet;
+use usiem::components::dataset::SiemDatasetType;
+
+/// Shapes whose entries can expire
+pub(crate) fn supports_ttl(dataset_type: &SiemDatasetType) -> bool {
+    matches!(
+        dataset_shape(dataset_type),
+        Some(DatasetShape::TextMap)
+            | Some(DatasetShape::TextSet)
+            | Some(DatasetShape::IpMap)
+            | Some(DatasetShape::IpSet)
+    )
+}
+
+impl SqliteDatasetManager {
+    /// Entries added to a registered dataset expire `ms` milliseconds later. The run loop deletes the
+    /// expired entries once per flush interval and rebuilds the dataset, see `sweep_expired`.
+    /// Adding an entry again renews its expiry. Text/IP sets and maps only
+    pub fn set_default_ttl(&mut self, dataset_type: &SiemDatasetType, ms: i64) -> Result<(), String> {
+        if !supports_ttl(dataset_type) {
+            return Err(format!("TTL not supported for dataset {:?}", dataset_type));
+        }
+        if ms <= 0 {
+            return Err(format!("Invalid TTL for dataset {:?}: {}", dataset_type, ms));
+        }
+        let name = match dataset_table_name(dataset_type) {
+            Some(name) => name,
+            None => return Err(format!("Dataset type not supported: {:?}", dataset_type)),
+        };
+        if !self.dataset_options.contains_key(dataset_type) {
+            return Err(format!("Dataset {:?} is not registered", dataset_type));
+        }
+        self.ensure_expiry_column(&name)?;
+        if let Some(options) = self.dataset_options.get_mut(dataset_type) {
+            options.ttl_ms = Some(ms);
+        }
+        Ok(())
+    }
+
+    /// TTL of the entries of the table dataset_{name}
+    fn ttl_of(&self, name: &str) -> Option<i64> {
+        self.options_of(name).and_then(|options| options.ttl_ms)
+    }
+
+    /// Adds the expires_at column to tables created before it existed
+    pub(crate) fn ensure_expiry_column(&self, name: &str) -> Result<(), String> {
+        let exists = self.conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = 'expires_at'",
+            params![format!("dataset_{}", name)],
+            |row| row.get::<_, i64>(0),
+        );
+        match exists {
+            Ok(0) => {
+                if let Err(e) = self.conn.execute(
+                    &format!("ALTER TABLE dataset_{} ADD COLUMN expires_at INTEGER", name),
+                    [],
+                ) {
+                    return Err(format!("{}", e));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => return Err(format!("{}", e)),
+        }
+        match self.conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {expires_at_index} ON dataset_{dataset_name} (expires_at)",
+                expires_at_index = index_name("idx_", name, "expires_at"),
+                dataset_name = name
+            ),
+            [],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    /// Sets the expiry of an entry just added to dataset_{name}, if the dataset has a TTL
+    pub(crate) fn set_expiry(&self, name: &str, key: &dyn ToSql) -> rusqlite::Result<()> {
+        let ttl = match self.ttl_of(name) {
+            Some(ttl) => ttl,
+            None => return Ok(()),
+        };
+        let expires_at = chrono::Utc::now().timestamp_millis() + ttl;
+        self.execute_cached(
+            &format!(
+                "UPDATE dataset_{dataset_name} SET expires_at = ?2 WHERE data_key = ?1",
+                dataset_name = name
+            ),
+            params![key, expires_at],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the expired entries of the datasets with a TTL and rebuilds the ones that changed.
+    /// Returns the entries deleted.
+    pub fn sweep_expired(&mut self) -> usize {
+        let time = chrono::Utc::now().timestamp_millis();
+        self.last_sweep = time;
+        let with_ttl: Vec<(SiemDatasetType, String)> = self
+            .dataset_options
+            .iter()
+            .filter(|(_, options)| options.ttl_ms.is_some())
+            .filter_map(|(dataset_type, _)| {
+                dataset_table_name(dataset_type).map(|name| (dataset_type.clone(), name))
+            })
+            .collect();
+        let mut deleted = 0;
+        let mut expired = BTreeSet::new();
+        for (dataset_type, name) in with_ttl {
+            let res = self.conn.execute(
+                &format!(
+                    "DELETE FROM dataset_{dataset_name} WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+                    dataset_name = name
+                ),
+                params![time],
+            );
+            match res {
+                Ok(0) => {}
+                Ok(rows) => {
+                    deleted += rows;
+                    expired.insert(dataset_type);
+                }
+                Err(e) => {
+                    error!("Cannot delete the expired entries of {:?}: {}", dataset_type, e);
+                    self.record_error(&dataset_type, &format!("{}", e));
+                }
+            }
+        }
+        let rebuilt = self.rebuild_updated(&expired, time);
+        self.emit_rebuilt_event(&rebuilt);
+        deleted
+    }
+
+    /// Runs `sweep_expired` if a flush interval has passed since the last sweep
+    pub(crate) fn sweep_if_due(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if chrono::Utc::now().timestamp_millis() - self.last_sweep >= self.flush_interval {
+            self.sweep_expired();
+        }
+    }
+}